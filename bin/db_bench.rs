@@ -154,7 +154,8 @@ impl BenchMark {
         let iterator = self.db.get_db_iterator();
         let start = std::time::Instant::now();
         let mut count: u128 = 0;
-        for (k, _v) in iterator {
+        for kv in iterator {
+            let (k, _v) = kv.unwrap();
             debug_assert_eq!(Vec::from(count.to_be_bytes()), k);
             count += 1;
         }
@@ -1,90 +1,289 @@
-use std::alloc::Layout;
+use std::alloc::{handle_alloc_error, Allocator, Global, Layout};
+use std::fmt;
 use std::hash::Hash;
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
+use std::ops::Deref;
 use std::ptr;
 use std::ptr::NonNull;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 const CACHE_CAP: usize = 256;
 
 const NUM_SHARD_BITS: usize = 4;
-const NUM_SHARD: usize = 1 << NUM_SHARD_BITS;
+pub(crate) const NUM_SHARD: usize = 1 << NUM_SHARD_BITS;
 
-pub struct ShardLRUCache<K: Eq + Hash + Send + Sync, V: Send + Sync> {
-    caches: [Mutex<LRUCache<K, V>>; NUM_SHARD],
+/// A sharded LRU cache. Entries are allocated by `A`, which is the global allocator
+/// by default.
+pub struct ShardLRUCache<K: Eq + Hash + Send + Sync, V: Send + Sync, A: Allocator = Global> {
+    caches: [Mutex<LRUCache<K, V, A>>; NUM_SHARD],
+    /// Maximum number of entries of each shard.
+    shard_capacity: usize,
+    /// Number of [LRUEntry] allocations that have not been released yet,
+    /// shared by all the shards.
+    live_entries: Arc<AtomicUsize>,
     _k: PhantomData<K>,
     _v: PhantomData<V>,
 }
 
 impl<K: Eq + Hash + Send + Sync, V: Send + Sync> Default for ShardLRUCache<K, V> {
     fn default() -> Self {
+        Self::new_in(Global)
+    }
+}
+
+impl<K: Eq + Hash + Send + Sync, V: Send + Sync> ShardLRUCache<K, V> {
+    /// Create a cache of at most `capacity` entries, which is rounded down to a multiple
+    /// of the number of shards, and up to one entry per shard.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_shard_capacity_in((capacity / NUM_SHARD).max(1), Global)
+    }
+}
+
+impl<K: Eq + Hash + Send + Sync, V: Send + Sync, A: Allocator + Clone> ShardLRUCache<K, V, A> {
+    /// Create a cache whose entries are allocated by `alloc`.
+    pub fn new_in(alloc: A) -> Self {
+        Self::with_shard_capacity_in(CACHE_CAP, alloc)
+    }
+
+    fn with_shard_capacity_in(shard_capacity: usize, alloc: A) -> Self {
+        let live_entries = Arc::new(AtomicUsize::default());
+        let new_shard = || {
+            Mutex::new(LRUCache::with_live_entries(
+                shard_capacity,
+                live_entries.clone(),
+                alloc.clone(),
+            ))
+        };
         ShardLRUCache {
             caches: [
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
-                Mutex::new(LRUCache::new()),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
+                new_shard(),
             ],
+            shard_capacity,
+            live_entries,
             _k: PhantomData,
             _v: PhantomData,
         }
     }
-}
 
-impl<K: Eq + Hash + Send + Sync, V: Send + Sync> ShardLRUCache<K, V> {
     pub fn insert_no_exists(&self, key: K, value: V, hash: u32) {
-        let mut guard: MutexGuard<LRUCache<K, V>> = self.caches[shard(hash)].lock().unwrap();
+        let mut guard: MutexGuard<LRUCache<K, V, A>> = self.caches[shard(hash)].lock().unwrap();
         guard.insert_no_exists(key, value, hash);
     }
 
-    pub fn look_up(&self, key: &K, hash: u32) -> EntryTracker<K, V> {
-        let mut guard: MutexGuard<LRUCache<K, V>> = self.caches[shard(hash)].lock().unwrap();
+    /// Look up `key`, returning a null [EntryTracker] if it is not found.
+    ///
+    /// Prefer [ShardLRUCache::get], which cannot be dereferenced on a miss by mistake.
+    /// This raw form is kept for the existing callers and will be removed.
+    pub fn look_up(&self, key: &K, hash: u32) -> EntryTracker<K, V, A> {
+        let mut guard: MutexGuard<LRUCache<K, V, A>> = self.caches[shard(hash)].lock().unwrap();
         guard.look_up(key, hash)
     }
 
+    /// Look up `key`, returning `None` if it is not found. The returned guard keeps the
+    /// entry alive like an [EntryTracker] and dereferences to the value.
+    pub fn get(&self, key: &K, hash: u32) -> Option<EntryGuard<K, V, A>> {
+        let tracker = self.look_up(key, hash);
+        if tracker.is_null() {
+            None
+        } else {
+            Some(EntryGuard(tracker))
+        }
+    }
+
+    /// Insert key-value, or replace the value if `key` already exists. The entry
+    /// is moved to the most recently used position.
+    ///
+    /// An [EntryTracker] obtained before the call keeps pointing to the old value, which is
+    /// released after the last tracker is dropped. Lookups after the call see the new value.
+    pub fn upsert(&self, key: K, value: V, hash: u32) {
+        let mut guard: MutexGuard<LRUCache<K, V, A>> = self.caches[shard(hash)].lock().unwrap();
+        guard.upsert(key, value, hash);
+    }
+
+    /// Whether an entry of `hash` can be inserted without evicting another entry.
+    pub fn has_room(&self, hash: u32) -> bool {
+        self.caches[shard(hash)].lock().unwrap().has_room()
+    }
+
+    /// Whether an entry can be inserted into any shard without evicting another entry.
+    pub fn has_room_in_all_shards(&self) -> bool {
+        self.caches
+            .iter()
+            .all(|cache| cache.lock().unwrap().has_room())
+    }
+
     pub fn erase(&self, key: &K, hash: u32) {
-        let mut guard: MutexGuard<LRUCache<K, V>> = self.caches[shard(hash)].lock().unwrap();
+        let mut guard: MutexGuard<LRUCache<K, V, A>> = self.caches[shard(hash)].lock().unwrap();
         guard.erase(key, hash);
     }
+
+    /// Insert key-value if `key` is not found, and pin the entry so that it is never
+    /// evicted until it is unpinned as many times as it is pinned. The pins survive
+    /// replacing the entry by [ShardLRUCache::upsert], but not erasing it.
+    ///
+    /// Return false without pinning if the shard of `hash` would have no unpinned entry
+    /// left to evict. The entry is inserted unpinned in that case.
+    pub fn pin(&self, key: K, value: V, hash: u32) -> bool {
+        let mut guard: MutexGuard<LRUCache<K, V, A>> = self.caches[shard(hash)].lock().unwrap();
+        guard.pin(key, value, hash)
+    }
+
+    /// Undo a [ShardLRUCache::pin] of `key`. Return false if `key` is not found or
+    /// not pinned.
+    pub fn unpin(&self, key: &K, hash: u32) -> bool {
+        let mut guard: MutexGuard<LRUCache<K, V, A>> = self.caches[shard(hash)].lock().unwrap();
+        guard.unpin(key, hash)
+    }
+
+    /// Number of pinned entries.
+    pub fn pinned(&self) -> usize {
+        self.caches
+            .iter()
+            .map(|cache| cache.lock().unwrap().pinned)
+            .sum()
+    }
+
+    /// Upsert all the `entries` of `(key, value, hash)`. Shards are locked in ascending
+    /// order and held until all the entries are inserted, so concurrent batches never
+    /// deadlock and a lookup never sees part of a batch within one shard.
+    pub fn multi_insert(&self, entries: Vec<(K, V, u32)>) {
+        let mut shards: Vec<Vec<(K, V, u32)>> = (0..NUM_SHARD).map(|_| vec![]).collect();
+        for entry in entries {
+            shards[shard(entry.2)].push(entry);
+        }
+        let mut guards = self.lock_shards(shards.iter().map(|s| !s.is_empty()));
+        for (guard, entries) in guards
+            .iter_mut()
+            .zip(shards.into_iter().filter(|s| !s.is_empty()))
+        {
+            for (key, value, hash) in entries {
+                guard.upsert(key, value, hash);
+            }
+        }
+    }
+
+    /// Erase all the `keys` of `(key, hash)`, locking shards in the same order as
+    /// [ShardLRUCache::multi_insert].
+    pub fn multi_erase(&self, keys: &[(K, u32)]) {
+        let mut shards: Vec<Vec<&(K, u32)>> = (0..NUM_SHARD).map(|_| vec![]).collect();
+        for key in keys {
+            shards[shard(key.1)].push(key);
+        }
+        let mut guards = self.lock_shards(shards.iter().map(|s| !s.is_empty()));
+        for (guard, keys) in guards
+            .iter_mut()
+            .zip(shards.iter().filter(|s| !s.is_empty()))
+        {
+            for (key, hash) in keys {
+                guard.erase(key, *hash);
+            }
+        }
+    }
+
+    /// Lock the shards whose flag is true in ascending shard index order.
+    fn lock_shards(
+        &self,
+        to_lock: impl Iterator<Item = bool>,
+    ) -> Vec<MutexGuard<LRUCache<K, V, A>>> {
+        self.caches
+            .iter()
+            .zip(to_lock)
+            .filter(|(_, lock)| *lock)
+            .map(|(cache, _)| cache.lock().unwrap())
+            .collect()
+    }
+
+    /// Number of cache entries currently allocated, including the entries
+    /// already evicted or erased but still held by an [EntryTracker].
+    pub fn live_entries(&self) -> usize {
+        self.live_entries.load(Ordering::Acquire)
+    }
+
+    /// Sum of `weigher` over the entries in the cache. Entries already evicted or
+    /// erased but still held by an [EntryTracker] are not counted.
+    pub fn total_charge(&self, weigher: impl Fn(&K, &V) -> usize) -> usize {
+        self.caches
+            .iter()
+            .map(|cache| cache.lock().unwrap().total_charge(&weigher))
+            .sum()
+    }
 }
 
-unsafe impl<K: Eq + Hash + Send + Sync, V: Send + Sync> Send for ShardLRUCache<K, V> {}
-unsafe impl<K: Eq + Hash + Send + Sync, V: Send + Sync> Sync for ShardLRUCache<K, V> {}
+impl<K: Eq + Hash + Send + Sync, V: Send + Sync, A: Allocator + Clone> fmt::Debug
+    for ShardLRUCache<K, V, A>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardLRUCache")
+            .field("shards", &NUM_SHARD)
+            .field("shard_capacity", &self.shard_capacity)
+            .field("live_entries", &self.live_entries())
+            .field("pinned", &self.pinned())
+            .finish()
+    }
+}
+
+unsafe impl<K: Eq + Hash + Send + Sync, V: Send + Sync, A: Allocator + Send + Sync> Send
+    for ShardLRUCache<K, V, A>
+{
+}
+unsafe impl<K: Eq + Hash + Send + Sync, V: Send + Sync, A: Allocator + Send + Sync> Sync
+    for ShardLRUCache<K, V, A>
+{
+}
 
 #[inline]
 fn shard(hash: u32) -> usize {
     (hash >> (32 - NUM_SHARD_BITS)) as usize
 }
 
-struct LRUCache<K: Eq, V> {
-    table: HashTable<K, V>,
+struct LRUCache<K: Eq, V, A: Allocator = Global> {
+    table: HashTable<K, V, A>,
     // dummy head, tail.next is the oldest entry
-    head: NonNull<LRUEntry<K, V>>,
+    head: NonNull<LRUEntry<K, V, A>>,
     // dummy tail, tail.prev is the oldest entry
-    tail: NonNull<LRUEntry<K, V>>,
+    tail: NonNull<LRUEntry<K, V, A>>,
+    capacity: usize,
+    live_entries: Arc<AtomicUsize>,
+    /// Number of the entries in `table` whose `pins` is not 0.
+    pinned: usize,
+    alloc: A,
 }
 
-unsafe impl<K: Eq, V> Send for LRUCache<K, V> {}
-unsafe impl<K: Eq, V> Sync for LRUCache<K, V> {}
+unsafe impl<K: Eq, V, A: Allocator + Send> Send for LRUCache<K, V, A> {}
+unsafe impl<K: Eq, V, A: Allocator + Sync> Sync for LRUCache<K, V, A> {}
 
+#[cfg(test)]
 impl<K: Eq, V> LRUCache<K, V> {
     fn new() -> LRUCache<K, V> {
-        let head = LRUEntry::new_empty();
-        let tail = LRUEntry::new_empty();
+        Self::with_live_entries(CACHE_CAP, Arc::default(), Global)
+    }
+}
+
+impl<K: Eq, V, A: Allocator + Clone> LRUCache<K, V, A> {
+    fn with_live_entries(
+        capacity: usize,
+        live_entries: Arc<AtomicUsize>,
+        alloc: A,
+    ) -> LRUCache<K, V, A> {
+        let head = LRUEntry::new_empty(alloc.clone());
+        let tail = LRUEntry::new_empty(alloc.clone());
         unsafe {
             (*head).next = tail;
             (*tail).prev = head;
@@ -92,11 +291,15 @@ impl<K: Eq, V> LRUCache<K, V> {
                 table: HashTable::default(),
                 head: NonNull::new_unchecked(head),
                 tail: NonNull::new_unchecked(tail),
+                capacity,
+                live_entries,
+                pinned: 0,
+                alloc,
             }
         }
     }
 
-    fn attach(&mut self, n: *mut LRUEntry<K, V>) {
+    fn attach(&mut self, n: *mut LRUEntry<K, V, A>) {
         unsafe {
             (*n).next = (self.head.as_ref()).next;
             (*n).prev = self.head.as_ptr();
@@ -105,7 +308,7 @@ impl<K: Eq, V> LRUCache<K, V> {
         }
     }
 
-    fn detach(n: *mut LRUEntry<K, V>) {
+    fn detach(n: *mut LRUEntry<K, V, A>) {
         debug_assert!(!n.is_null());
         unsafe {
             (*(*n).next).prev = (*n).prev;
@@ -113,7 +316,7 @@ impl<K: Eq, V> LRUCache<K, V> {
         }
     }
 
-    fn look_up(&mut self, key: &K, hash: u32) -> EntryTracker<K, V> {
+    fn look_up(&mut self, key: &K, hash: u32) -> EntryTracker<K, V, A> {
         let n = self.table.look_up(key, hash);
         if !n.is_null() {
             Self::detach(n);
@@ -125,27 +328,129 @@ impl<K: Eq, V> LRUCache<K, V> {
         EntryTracker(n)
     }
 
+    #[inline]
+    fn has_room(&self) -> bool {
+        self.table.len < self.capacity
+    }
+
     /// Insert key-value when key is not found.
     fn insert_no_exists(&mut self, key: K, value: V, hash: u32) {
         let entry = self.table.look_up(&key, hash);
         if entry.is_null() {
-            if self.table.len >= CACHE_CAP {
-                unsafe {
-                    let old = (self.tail.as_ref()).prev;
-                    debug_assert_ne!(self.tail.as_ptr(), old);
-                    Self::detach(old);
-                    self.table.remove(old);
+            if self.table.len >= self.capacity {
+                self.evict_oldest();
+            }
+            self.insert_new_entry(key, value, hash);
+        }
+    }
+
+    /// Insert key-value, replace the old entry if key is found.
+    /// The old entry is removed from cache and released after all its trackers are dropped.
+    fn upsert(&mut self, key: K, value: V, hash: u32) {
+        let entry = self.table.look_up(&key, hash);
+        if entry.is_null() {
+            if self.table.len >= self.capacity {
+                self.evict_oldest();
+            }
+            self.insert_new_entry(key, value, hash);
+        } else {
+            // the new entry takes over the pins of the old one
+            let pins = unsafe { (*entry).pins };
+            Self::detach(entry);
+            unsafe {
+                self.table.remove(entry);
+            }
+            let new_entry = self.insert_new_entry(key, value, hash);
+            unsafe {
+                (*new_entry).pins = pins;
+            }
+        }
+    }
+
+    fn insert_new_entry(&mut self, key: K, value: V, hash: u32) -> *mut LRUEntry<K, V, A> {
+        let new_entry = LRUEntry::new_in(key, value, hash, self.alloc.clone());
+        self.track(new_entry);
+        self.attach(new_entry);
+        self.table.insert(new_entry);
+        new_entry
+    }
+
+    /// Evict the least recently used entry which is not pinned. Nothing is evicted if
+    /// all the entries are pinned, which [LRUCache::pin] never lets happen.
+    fn evict_oldest(&mut self) {
+        unsafe {
+            let mut old = (self.tail.as_ref()).prev;
+            while old != self.head.as_ptr() && (*old).pins > 0 {
+                old = (*old).prev;
+            }
+            if old != self.head.as_ptr() {
+                Self::detach(old);
+                self.table.remove(old);
+            }
+        }
+    }
+
+    fn pin(&mut self, key: K, value: V, hash: u32) -> bool {
+        let mut entry = self.table.look_up(&key, hash);
+        if entry.is_null() {
+            if self.table.len >= self.capacity {
+                self.evict_oldest();
+            }
+            entry = self.insert_new_entry(key, value, hash);
+        }
+        unsafe {
+            if (*entry).pins == 0 {
+                if self.pinned + 1 >= self.capacity {
+                    return false;
                 }
+                self.pinned += 1;
+            }
+            (*entry).pins += 1;
+        }
+        true
+    }
+
+    fn unpin(&mut self, key: &K, hash: u32) -> bool {
+        let entry = self.table.look_up(key, hash);
+        unsafe {
+            if entry.is_null() || (*entry).pins == 0 {
+                return false;
             }
-            let new_entry = LRUEntry::new(key, value, hash);
-            self.attach(new_entry);
-            self.table.insert(new_entry);
+            (*entry).pins -= 1;
+            if (*entry).pins == 0 {
+                self.pinned -= 1;
+            }
+        }
+        true
+    }
+
+    /// Count `entry` in `live_entries` until it is released.
+    fn track(&self, entry: *mut LRUEntry<K, V, A>) {
+        self.live_entries.fetch_add(1, Ordering::Release);
+        unsafe {
+            (*entry).live_entries = Some(self.live_entries.clone());
         }
     }
 
+    fn total_charge(&self, weigher: &impl Fn(&K, &V) -> usize) -> usize {
+        let mut total = 0;
+        unsafe {
+            let mut node = (self.head.as_ref()).next;
+            for _ in 0..self.table.len {
+                debug_assert!(!node.is_null());
+                total += weigher((*node).key.assume_init_ref(), (*node).value());
+                node = (*node).next;
+            }
+        }
+        total
+    }
+
     fn erase(&mut self, key: &K, hash: u32) {
         let n = self.table.look_up(key, hash);
         if !n.is_null() {
+            if unsafe { (*n).pins } > 0 {
+                self.pinned -= 1;
+            }
             Self::detach(n);
             unsafe {
                 self.table.remove(n);
@@ -154,7 +459,7 @@ impl<K: Eq, V> LRUCache<K, V> {
     }
 }
 
-impl<K: Eq, V> Drop for LRUCache<K, V> {
+impl<K: Eq, V, A: Allocator> Drop for LRUCache<K, V, A> {
     fn drop(&mut self) {
         unsafe {
             let mut node = (self.head.as_ref()).next;
@@ -164,99 +469,160 @@ impl<K: Eq, V> Drop for LRUCache<K, V> {
                 node = (*node).next;
                 release(prev);
             }
-            let _head = *Box::from_raw(self.head.as_ptr());
-            let _tail = *Box::from_raw(self.tail.as_ptr());
+            dealloc_entry(self.head.as_ptr());
+            dealloc_entry(self.tail.as_ptr());
         }
     }
 }
 
-pub struct EntryTracker<K: Eq, V>(pub *const LRUEntry<K, V>);
+/// A reference to a cache entry returned by lookups, which is null if the key is not found.
+///
+/// The tracker holds a reference count of the entry, so the entry stays alive until the
+/// tracker is dropped, even if it is evicted, erased or replaced in the meantime.
+pub struct EntryTracker<K: Eq, V, A: Allocator = Global>(*const LRUEntry<K, V, A>);
+
+impl<K: Eq, V, A: Allocator> EntryTracker<K, V, A> {
+    /// Return true if the key is not found.
+    #[inline]
+    pub fn is_null(&self) -> bool {
+        self.0.is_null()
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the tracker is null.
+    #[inline]
+    pub fn key(&self) -> &K {
+        assert!(!self.is_null(), "key of a null EntryTracker");
+        // SAFETY: the entry is not released while the tracker holds its reference count,
+        // and the key of an entry is never mutated.
+        unsafe { (*self.0).key.assume_init_ref() }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the tracker is null.
+    #[inline]
+    pub fn value(&self) -> &V {
+        assert!(!self.is_null(), "value of a null EntryTracker");
+        // SAFETY: same as `key`. Entries are immutable once inserted, upsert replaces
+        // the whole entry instead of updating the value in place.
+        unsafe { (*self.0).value() }
+    }
+}
 
-impl<K: Eq, V> Drop for EntryTracker<K, V> {
+impl<K: Eq, V, A: Allocator> Drop for EntryTracker<K, V, A> {
     fn drop(&mut self) {
         if !self.0.is_null() {
-            release(self.0 as *mut LRUEntry<K, V>);
+            release(self.0 as *mut LRUEntry<K, V, A>);
         }
     }
 }
 
-pub struct LRUEntry<K: Eq, V> {
+/// A non-null [EntryTracker] returned by [ShardLRUCache::get], which dereferences to
+/// the value of the entry.
+pub struct EntryGuard<K: Eq, V, A: Allocator = Global>(EntryTracker<K, V, A>);
+
+impl<K: Eq, V, A: Allocator> EntryGuard<K, V, A> {
+    #[inline]
+    pub fn key(&self) -> &K {
+        self.0.key()
+    }
+}
+
+impl<K: Eq, V, A: Allocator> Deref for EntryGuard<K, V, A> {
+    type Target = V;
+
+    #[inline]
+    fn deref(&self) -> &V {
+        self.0.value()
+    }
+}
+
+pub struct LRUEntry<K: Eq, V, A: Allocator = Global> {
     key: MaybeUninit<K>,
     value: MaybeUninit<V>,
     hash: u32,
-    next_hash: *mut LRUEntry<K, V>,
-    prev: *mut LRUEntry<K, V>,
-    next: *mut LRUEntry<K, V>,
+    next_hash: *mut LRUEntry<K, V, A>,
+    prev: *mut LRUEntry<K, V, A>,
+    next: *mut LRUEntry<K, V, A>,
     ref_count: AtomicUsize,
+    live_entries: Option<Arc<AtomicUsize>>,
+    /// Count of [LRUCache::pin] not undone yet. Guarded by the lock of the shard.
+    pins: usize,
+    /// The allocator which allocated the entry and deallocates it on release.
+    alloc: A,
 }
 
+#[cfg(test)]
 impl<K: Eq, V> LRUEntry<K, V> {
     fn new(key: K, value: V, hash: u32) -> *mut Self {
-        let layout = Layout::new::<LRUEntry<K, V>>();
-        unsafe {
-            let node_ptr = std::alloc::alloc(layout) as *mut Self;
-            let node = &mut *node_ptr;
-            std::ptr::write(
-                node,
-                LRUEntry {
-                    key: MaybeUninit::new(key),
-                    value: MaybeUninit::new(value),
-                    hash,
-                    next_hash: ptr::null_mut(),
-                    prev: ptr::null_mut(),
-                    next: ptr::null_mut(),
-                    ref_count: AtomicUsize::new(1),
-                },
-            );
-            node
-        }
+        Self::new_in(key, value, hash, Global)
     }
+}
 
-    fn new_empty() -> *mut Self {
-        unsafe {
-            let layout = Layout::new::<LRUEntry<K, V>>();
-
-            let node_ptr = std::alloc::alloc(layout) as *mut Self;
-            let node = &mut *node_ptr;
-            std::ptr::write(
-                node,
-                LRUEntry {
-                    key: MaybeUninit::uninit(),
-                    value: MaybeUninit::uninit(),
-                    hash: 0,
-                    next_hash: ptr::null_mut(),
-                    prev: ptr::null_mut(),
-                    next: ptr::null_mut(),
-                    ref_count: AtomicUsize::new(1),
-                },
-            );
-            node
-        }
+impl<K: Eq, V, A: Allocator> LRUEntry<K, V, A> {
+    fn new_in(key: K, value: V, hash: u32, alloc: A) -> *mut Self {
+        Self::allocate(LRUEntry {
+            key: MaybeUninit::new(key),
+            value: MaybeUninit::new(value),
+            hash,
+            next_hash: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            ref_count: AtomicUsize::new(1),
+            live_entries: None,
+            pins: 0,
+            alloc,
+        })
     }
 
-    #[inline]
-    pub fn value(&self) -> &V {
-        unsafe { self.value.assume_init_ref() }
+    fn new_empty(alloc: A) -> *mut Self {
+        Self::allocate(LRUEntry {
+            key: MaybeUninit::uninit(),
+            value: MaybeUninit::uninit(),
+            hash: 0,
+            next_hash: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            ref_count: AtomicUsize::new(1),
+            live_entries: None,
+            pins: 0,
+            alloc,
+        })
+    }
+
+    /// Move `entry` into memory allocated by its own allocator.
+    fn allocate(entry: Self) -> *mut Self {
+        let layout = Layout::new::<LRUEntry<K, V, A>>();
+        match entry.alloc.allocate(layout) {
+            Ok(ptr) => {
+                let node_ptr = ptr.as_ptr() as *mut Self;
+                unsafe { std::ptr::write(node_ptr, entry) };
+                node_ptr
+            }
+            Err(_) => handle_alloc_error(layout),
+        }
     }
 
     #[inline]
-    pub fn value_mut(&mut self) -> &mut V {
-        unsafe { self.value.assume_init_mut() }
+    fn value(&self) -> &V {
+        unsafe { self.value.assume_init_ref() }
     }
 }
 
-unsafe impl<K: Eq, V> Send for LRUEntry<K, V> {}
+unsafe impl<K: Eq, V, A: Allocator + Send> Send for LRUEntry<K, V, A> {}
 
 const TABLE_SIZE: usize = 256;
 
-struct HashTable<K: Eq, V> {
-    table: [*mut LRUEntry<K, V>; TABLE_SIZE],
+struct HashTable<K: Eq, V, A: Allocator = Global> {
+    table: [*mut LRUEntry<K, V, A>; TABLE_SIZE],
     len: usize,
     _k: PhantomData<K>,
     _v: PhantomData<V>,
 }
 
-impl<K: Eq, V> Default for HashTable<K, V> {
+impl<K: Eq, V, A: Allocator> Default for HashTable<K, V, A> {
     fn default() -> Self {
         unsafe {
             HashTable {
@@ -269,8 +635,8 @@ impl<K: Eq, V> Default for HashTable<K, V> {
     }
 }
 
-impl<K: Eq, V> HashTable<K, V> {
-    fn look_up(&mut self, key: &K, hash: u32) -> *mut LRUEntry<K, V> {
+impl<K: Eq, V, A: Allocator> HashTable<K, V, A> {
+    fn look_up(&mut self, key: &K, hash: u32) -> *mut LRUEntry<K, V, A> {
         let idx = hash as usize & (TABLE_SIZE - 1);
         unsafe {
             let p = self.table.get_unchecked_mut(idx);
@@ -280,7 +646,7 @@ impl<K: Eq, V> HashTable<K, V> {
         }
     }
 
-    fn insert(&mut self, entry: *mut LRUEntry<K, V>) {
+    fn insert(&mut self, entry: *mut LRUEntry<K, V, A>) {
         unsafe {
             let idx = (*entry).hash as usize & (TABLE_SIZE - 1);
             let p = self.table.get_unchecked_mut(idx);
@@ -294,7 +660,7 @@ impl<K: Eq, V> HashTable<K, V> {
     /// # Safety:
     ///
     /// `entry` should not be null
-    unsafe fn remove(&mut self, entry: *mut LRUEntry<K, V>) {
+    unsafe fn remove(&mut self, entry: *mut LRUEntry<K, V, A>) {
         debug_assert!(!entry.is_null());
 
         let hash = (*entry).hash;
@@ -310,7 +676,7 @@ impl<K: Eq, V> HashTable<K, V> {
         release(entry);
     }
 
-    fn find_ptr(node: &mut *mut LRUEntry<K, V>, hash: u32, key: &K) {
+    fn find_ptr(node: &mut *mut LRUEntry<K, V, A>, hash: u32, key: &K) {
         unsafe {
             while !((*node).is_null()
                 || (**node).hash == hash && key.eq((**node).key.assume_init_ref()))
@@ -321,9 +687,9 @@ impl<K: Eq, V> HashTable<K, V> {
     }
 
     fn find_ptr_by_ptr(
-        mut node: &mut *mut LRUEntry<K, V>,
-        entry: *mut LRUEntry<K, V>,
-    ) -> *mut *mut LRUEntry<K, V> {
+        mut node: &mut *mut LRUEntry<K, V, A>,
+        entry: *mut LRUEntry<K, V, A>,
+    ) -> *mut *mut LRUEntry<K, V, A> {
         unsafe {
             while !((*node).is_null() || (*node) == entry) {
                 node = &mut (**node).next_hash;
@@ -333,22 +699,39 @@ impl<K: Eq, V> HashTable<K, V> {
     }
 }
 
-fn release<K: Eq, V>(n: *mut LRUEntry<K, V>) {
+fn release<K: Eq, V, A: Allocator>(n: *mut LRUEntry<K, V, A>) {
     unsafe {
         let count = (*n).ref_count.fetch_sub(1, Ordering::Release);
         if count == 1 {
-            let layout = Layout::new::<LRUEntry<K, V>>();
             std::ptr::drop_in_place((*n).key.as_mut_ptr());
             std::ptr::drop_in_place((*n).value.as_mut_ptr());
-            std::alloc::dealloc(n as *mut u8, layout);
+            if let Some(live_entries) = (*n).live_entries.take() {
+                live_entries.fetch_sub(1, Ordering::Release);
+            }
+            dealloc_entry(n);
         }
     }
 }
 
+/// Deallocate `n` with its own allocator, without dropping its key and value.
+///
+/// # Safety
+///
+/// `n` must be allocated by [LRUEntry::allocate] and not used afterwards.
+unsafe fn dealloc_entry<K: Eq, V, A: Allocator>(n: *mut LRUEntry<K, V, A>) {
+    let layout = Layout::new::<LRUEntry<K, V, A>>();
+    std::ptr::drop_in_place(&mut (*n).live_entries);
+    let alloc = std::ptr::read(&(*n).alloc);
+    alloc.deallocate(NonNull::new_unchecked(n as *mut u8), layout);
+}
+
 #[cfg(test)]
 mod tests {
     use crate::cache::{HashTable, LRUCache, LRUEntry, ShardLRUCache, CACHE_CAP, TABLE_SIZE};
     use crate::hash::murmur_hash;
+    use std::alloc::{AllocError, Allocator, Global, Layout};
+    use std::ptr::NonNull;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::{Arc, Barrier};
 
     fn make_entry(i: usize) -> *mut LRUEntry<String, String> {
@@ -412,10 +795,9 @@ mod tests {
             let h = murmur_hash(key.as_bytes(), 0x87654321);
             let tracker = lru_cache.look_up(&key, h);
             let tracker2 = lru_cache.look_up(&key, h);
-            unsafe {
-                assert_eq!((*tracker.0).value.assume_init_ref(), &key);
-                assert_eq!((*tracker2.0).value.assume_init_ref(), &key);
-            }
+            assert_eq!(tracker.key(), &key);
+            assert_eq!(tracker.value(), &key);
+            assert_eq!(tracker2.value(), &key);
         }
 
         for i in CACHE_CAP..CACHE_CAP + 20 {
@@ -430,7 +812,7 @@ mod tests {
         for i in 0..500 {
             let h = murmur_hash(i.to_string().as_bytes(), 0x87654321);
             let tracker = lru_cache.look_up(&hh, h);
-            assert!(tracker.0.is_null());
+            assert!(tracker.is_null());
         }
     }
 
@@ -455,12 +837,10 @@ mod tests {
             let h = murmur_hash(key.as_bytes(), 0x87654321);
             let tracker = lru_cache.look_up(&key, h);
             if (i & 1) == 0 || i < CACHE_CAP {
-                assert!(tracker.0.is_null());
+                assert!(tracker.is_null());
             } else {
-                assert!(!tracker.0.is_null());
-                unsafe {
-                    assert_eq!((*tracker.0).value.assume_init_ref(), &key);
-                }
+                assert!(!tracker.is_null());
+                assert_eq!(tracker.value(), &key);
             }
         }
     }
@@ -483,9 +863,7 @@ mod tests {
                 let key = i.to_string();
                 let h = murmur_hash(key.as_bytes(), 0x87654321);
                 let tracker = lru_cache.look_up(&key, h);
-                unsafe {
-                    assert_eq!((*(tracker.0)).value.assume_init_ref(), &i.to_string());
-                }
+                assert_eq!(tracker.value(), &i.to_string());
             }
         }
 
@@ -511,7 +889,357 @@ mod tests {
         for i in 0..500 {
             let h = murmur_hash(i.to_string().as_bytes(), 0x87654321);
             let tracker = lru_cache.look_up(&"hello".to_string(), h);
-            assert!(tracker.0.is_null());
+            assert!(tracker.is_null());
+        }
+    }
+
+    #[test]
+    fn test_live_entries() {
+        let lru_cache = ShardLRUCache::default();
+        assert_eq!(lru_cache.live_entries(), 0);
+
+        let hash = |key: &String| murmur_hash(key.as_bytes(), 0x87654321);
+        for i in 0..100 {
+            let key = i.to_string();
+            let h = hash(&key);
+            lru_cache.insert_no_exists(key, i.to_string(), h);
+        }
+        assert_eq!(lru_cache.live_entries(), 100);
+
+        let mut trackers = vec![];
+        for i in 0..10 {
+            let key = i.to_string();
+            trackers.push(lru_cache.look_up(&key, hash(&key)));
+        }
+        // erased entries stay alive until their trackers are dropped
+        for i in 0..10 {
+            let key = i.to_string();
+            lru_cache.erase(&key, hash(&key));
+        }
+        assert_eq!(lru_cache.live_entries(), 100);
+        drop(trackers);
+        assert_eq!(lru_cache.live_entries(), 90);
+
+        for i in 10..20 {
+            let key = i.to_string();
+            let tracker = lru_cache.look_up(&key, hash(&key));
+            assert!(!tracker.is_null());
+        }
+        assert_eq!(lru_cache.live_entries(), 90);
+
+        let live_entries = lru_cache.live_entries.clone();
+        drop(lru_cache);
+        assert_eq!(live_entries.load(std::sync::atomic::Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_upsert() {
+        let lru_cache = ShardLRUCache::default();
+        let key = String::from("key");
+        let h = murmur_hash(key.as_bytes(), 0x87654321);
+
+        lru_cache.upsert(key.clone(), String::from("value1"), h);
+        let tracker1 = lru_cache.look_up(&key, h);
+        assert_eq!(tracker1.value(), "value1");
+
+        lru_cache.upsert(key.clone(), String::from("value2"), h);
+        let tracker2 = lru_cache.look_up(&key, h);
+        assert_eq!(tracker2.value(), "value2");
+        // tracker obtained before upsert still sees the old value
+        assert_eq!(tracker1.value(), "value1");
+        assert_eq!(lru_cache.live_entries(), 2);
+        drop(tracker1);
+        assert_eq!(lru_cache.live_entries(), 1);
+        drop(tracker2);
+
+        for i in 0..CACHE_CAP * 2 {
+            let key = i.to_string();
+            let h = murmur_hash(key.as_bytes(), 0x87654321);
+            lru_cache.upsert(key, i.to_string(), h);
+        }
+        for i in 0..CACHE_CAP * 2 {
+            let key = i.to_string();
+            let h = murmur_hash(key.as_bytes(), 0x87654321);
+            lru_cache.upsert(key.clone(), format!("new{}", i), h);
+            let tracker = lru_cache.look_up(&key, h);
+            assert_eq!(tracker.value(), &format!("new{}", i));
+        }
+    }
+
+    #[test]
+    fn test_multi_insert() {
+        const NUM_KEYS: usize = 100;
+        let lru_cache = Arc::new(ShardLRUCache::default());
+        let hash = |key: &String| murmur_hash(key.as_bytes(), 0x87654321);
+
+        let barrier = Arc::new(Barrier::new(2));
+        let handles: Vec<_> = (0..2)
+            .map(|t| {
+                let lru_cache = lru_cache.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    for round in 0..1000 {
+                        let mut entries: Vec<_> = (0..NUM_KEYS)
+                            .map(|i| {
+                                let key = i.to_string();
+                                let h = hash(&key);
+                                (key, format!("{}_{}", t, round), h)
+                            })
+                            .collect();
+                        // the threads insert keys in opposite orders
+                        if t == 1 {
+                            entries.reverse();
+                        }
+                        lru_cache.multi_insert(entries);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // all the keys are from the last batch
+        let values: Vec<String> = (0..NUM_KEYS)
+            .map(|i| {
+                let key = i.to_string();
+                let tracker = lru_cache.look_up(&key, hash(&key));
+                tracker.value().clone()
+            })
+            .collect();
+        assert!(values.iter().all(|v| v == &values[0]));
+        assert!(values[0].ends_with("_999"));
+        assert_eq!(lru_cache.live_entries(), NUM_KEYS);
+
+        let keys: Vec<_> = (0..NUM_KEYS / 2)
+            .map(|i| {
+                let key = i.to_string();
+                let h = hash(&key);
+                (key, h)
+            })
+            .collect();
+        lru_cache.multi_erase(&keys);
+        for i in 0..NUM_KEYS {
+            let key = i.to_string();
+            let tracker = lru_cache.look_up(&key, hash(&key));
+            assert_eq!(tracker.is_null(), i < NUM_KEYS / 2);
+        }
+        assert_eq!(lru_cache.live_entries(), NUM_KEYS / 2);
+    }
+
+    #[test]
+    fn test_tracker_outlives_eviction() {
+        let lru_cache = Arc::new(ShardLRUCache::default());
+        let key = String::from("key");
+        let h = murmur_hash(key.as_bytes(), 0x87654321);
+        lru_cache.insert_no_exists(key.clone(), String::from("value"), h);
+        let tracker = lru_cache.look_up(&key, h);
+        assert_eq!(tracker.key(), &key);
+
+        // evict the key by filling its shard from another thread
+        let lru_cache2 = lru_cache.clone();
+        std::thread::spawn(move || {
+            for i in 0..CACHE_CAP * 2 {
+                let key = i.to_string();
+                lru_cache2.upsert(key, i.to_string(), h);
+            }
+        })
+        .join()
+        .unwrap();
+
+        assert!(lru_cache.look_up(&key, h).is_null());
+        assert_eq!(tracker.key(), &key);
+        assert_eq!(tracker.value(), "value");
+        assert_eq!(lru_cache.live_entries(), CACHE_CAP + 1);
+        drop(tracker);
+        assert_eq!(lru_cache.live_entries(), CACHE_CAP);
+    }
+
+    #[test]
+    fn test_total_charge() {
+        let lru_cache: ShardLRUCache<String, String> = ShardLRUCache::default();
+        assert_eq!(lru_cache.total_charge(|_, v| v.len()), 0);
+        for i in 0..10 {
+            let key = i.to_string();
+            let h = murmur_hash(key.as_bytes(), 0x87654321);
+            lru_cache.upsert(key, "v".repeat(i), h);
+        }
+        assert_eq!(lru_cache.total_charge(|_, v| v.len()), 45);
+        assert_eq!(lru_cache.total_charge(|k, _| k.len()), 10);
+
+        let key = String::from("9");
+        let h = murmur_hash(key.as_bytes(), 0x87654321);
+        let tracker = lru_cache.look_up(&key, h);
+        lru_cache.erase(&key, h);
+        // the erased entry is still alive, but no longer in the cache
+        assert_eq!(lru_cache.total_charge(|_, v| v.len()), 36);
+        drop(tracker);
+    }
+
+    #[test]
+    fn test_get() {
+        let lru_cache: ShardLRUCache<String, String> = ShardLRUCache::default();
+        let key = String::from("key");
+        let h = murmur_hash(key.as_bytes(), 0x87654321);
+        assert!(lru_cache.get(&key, h).is_none());
+
+        lru_cache.upsert(key.clone(), String::from("value"), h);
+        let entry = lru_cache.get(&key, h).unwrap();
+        assert_eq!(entry.key(), "key");
+        assert_eq!(*entry, "value");
+        assert_eq!(entry.len(), 5);
+
+        // the guard keeps the entry alive after it is erased
+        lru_cache.erase(&key, h);
+        assert!(lru_cache.get(&key, h).is_none());
+        assert_eq!(*entry, "value");
+        assert_eq!(lru_cache.live_entries(), 1);
+        drop(entry);
+        assert_eq!(lru_cache.live_entries(), 0);
+    }
+
+    #[test]
+    fn test_pin() {
+        let hash = |key: &String| murmur_hash(key.as_bytes(), 0x87654321);
+        let mut lru_cache = LRUCache::new();
+        for i in 0..10 {
+            let key = format!("pinned{}", i);
+            let h = hash(&key);
+            assert!(lru_cache.pin(key, i.to_string(), h));
+        }
+        // pin twice, unpin once
+        let key = String::from("pinned0");
+        assert!(lru_cache.pin(key.clone(), String::new(), hash(&key)));
+        assert!(lru_cache.unpin(&key, hash(&key)));
+        assert_eq!(lru_cache.pinned, 10);
+
+        // flood the cache with other entries
+        for i in 0..CACHE_CAP * 4 {
+            let key = i.to_string();
+            let h = hash(&key);
+            lru_cache.insert_no_exists(key, i.to_string(), h);
+        }
+        assert_eq!(lru_cache.table.len, CACHE_CAP);
+        for i in 0..10 {
+            let key = format!("pinned{}", i);
+            assert_eq!(lru_cache.look_up(&key, hash(&key)).value(), &i.to_string());
+        }
+
+        // the pin survives upsert, but not erase
+        lru_cache.upsert(key.clone(), String::from("new"), hash(&key));
+        let key1 = String::from("pinned1");
+        lru_cache.erase(&key1, hash(&key1));
+        assert_eq!(lru_cache.pinned, 9);
+        assert!(!lru_cache.unpin(&key1, hash(&key1)));
+        for i in 0..CACHE_CAP * 4 {
+            let key = format!("other{}", i);
+            let h = hash(&key);
+            lru_cache.upsert(key, i.to_string(), h);
+        }
+        assert_eq!(lru_cache.look_up(&key, hash(&key)).value(), "new");
+
+        // at least one entry is left unpinned
+        let mut i = 0;
+        while lru_cache.pinned + 1 < CACHE_CAP {
+            let key = format!("more{}", i);
+            let h = hash(&key);
+            assert!(lru_cache.pin(key, String::new(), h));
+            i += 1;
+        }
+        let key = String::from("rejected");
+        assert!(!lru_cache.pin(key.clone(), String::new(), hash(&key)));
+        assert!(!lru_cache.unpin(&key, hash(&key)));
+        assert!(!lru_cache.look_up(&key, hash(&key)).is_null());
+
+        // unpinned entries are evicted again
+        assert!(lru_cache.unpin(&String::from("pinned0"), hash(&String::from("pinned0"))));
+        assert!(!lru_cache.unpin(&String::from("pinned0"), hash(&String::from("pinned0"))));
+        for i in 0..CACHE_CAP {
+            let key = format!("last{}", i);
+            let h = hash(&key);
+            lru_cache.insert_no_exists(key, String::new(), h);
+        }
+        let key = String::from("pinned0");
+        assert!(lru_cache.look_up(&key, hash(&key)).is_null());
+        assert_eq!(lru_cache.table.len, CACHE_CAP);
+    }
+
+    #[test]
+    fn test_debug() {
+        let lru_cache = ShardLRUCache::<String, String>::default();
+        lru_cache.insert_no_exists("k1".to_string(), "v1".to_string(), 1);
+        lru_cache.insert_no_exists("k2".to_string(), "v2".to_string(), 2);
+        let debug = format!("{:?}", lru_cache);
+        assert!(debug.contains("shards: 16"));
+        assert!(debug.contains("live_entries: 2"));
+        assert!(debug.contains("pinned: 0"));
+        assert!(!debug.contains("v1"));
+    }
+
+    #[derive(Default)]
+    struct CountingAllocator {
+        allocated: AtomicUsize,
+        deallocated: AtomicUsize,
+    }
+
+    unsafe impl Allocator for CountingAllocator {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.deallocated.fetch_add(1, Ordering::Relaxed);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn test_custom_allocator() {
+        let alloc = CountingAllocator::default();
+        {
+            let lru_cache: ShardLRUCache<String, String, &CountingAllocator> =
+                ShardLRUCache::new_in(&alloc);
+            for i in 0..CACHE_CAP * 20 {
+                let key = i.to_string();
+                let h = murmur_hash(key.as_bytes(), 0x87654321);
+                lru_cache.insert_no_exists(key, i.to_string(), h);
+            }
+            let key = String::from("0");
+            let h = murmur_hash(key.as_bytes(), 0x87654321);
+            lru_cache.upsert(key.clone(), String::from("new"), h);
+            let tracker = lru_cache.look_up(&key, h);
+            lru_cache.erase(&key, h);
+            assert_eq!(tracker.value(), "new");
+            drop(tracker);
+            assert!(alloc.allocated.load(Ordering::Relaxed) > CACHE_CAP * 20);
+        }
+        // every entry, including the dummy heads and tails of the shards, is returned
+        assert_eq!(
+            alloc.allocated.load(Ordering::Relaxed),
+            alloc.deallocated.load(Ordering::Relaxed)
+        );
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let lru_cache: ShardLRUCache<String, String> = ShardLRUCache::with_capacity(64);
+        for i in 0..1000 {
+            let key = i.to_string();
+            let h = murmur_hash(key.as_bytes(), 0x87654321);
+            lru_cache.insert_no_exists(key, i.to_string(), h);
+            assert!(lru_cache.live_entries() <= 64);
+        }
+        assert_eq!(lru_cache.live_entries(), 64);
+
+        // at least one entry per shard
+        let lru_cache: ShardLRUCache<String, String> = ShardLRUCache::with_capacity(1);
+        for i in 0..1000 {
+            let key = i.to_string();
+            let h = murmur_hash(key.as_bytes(), 0x87654321);
+            lru_cache.insert_no_exists(key, i.to_string(), h);
         }
+        assert_eq!(lru_cache.live_entries(), 16);
     }
 }
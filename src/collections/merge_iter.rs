@@ -0,0 +1,227 @@
+//! K-way merge of sorted sources.
+
+use std::cmp::Ordering;
+
+/// Merge several iterators of `(key, value, sequence)`, each sorted by key in the order
+/// of the comparator, into one sorted iterator without duplicated keys.
+///
+/// Among the entries of equal keys, from the same source or not, the one with the
+/// highest sequence is yielded and the others are dropped. If two of them have the
+/// same sequence, the one from the source with the smaller index wins.
+///
+/// The smallest key is found by scanning the heads of all the sources, which is
+/// cheaper than a heap for the few sources a database merges.
+pub struct MergeIterator<K, V, S, I, C = fn(&K, &K) -> Ordering>
+where
+    S: Ord,
+    I: Iterator<Item = (K, V, S)>,
+    C: Fn(&K, &K) -> Ordering,
+{
+    sources: Vec<I>,
+    /// Next entry of each source.
+    heads: Vec<Option<(K, V, S)>>,
+    cmp: C,
+    is_tombstone: Option<fn(&V) -> bool>,
+    /// Count of the entries dropped because of an entry of the same key.
+    duplicates: usize,
+    /// Count of the tombstones dropped.
+    tombstones: usize,
+}
+
+impl<K: Ord, V, S: Ord, I: Iterator<Item = (K, V, S)>> MergeIterator<K, V, S, I> {
+    /// Merge `sources` sorted by [Ord] of the keys.
+    pub fn new(sources: Vec<I>) -> Self {
+        Self::with_comparator(sources, K::cmp)
+    }
+}
+
+impl<K, V, S, I, C> MergeIterator<K, V, S, I, C>
+where
+    S: Ord,
+    I: Iterator<Item = (K, V, S)>,
+    C: Fn(&K, &K) -> Ordering,
+{
+    /// Merge `sources` sorted by `cmp`.
+    pub fn with_comparator(mut sources: Vec<I>, cmp: C) -> Self {
+        let heads = sources.iter_mut().map(|source| source.next()).collect();
+        MergeIterator {
+            sources,
+            heads,
+            cmp,
+            is_tombstone: None,
+            duplicates: 0,
+            tombstones: 0,
+        }
+    }
+
+    /// Skip the keys whose newest values are tombstones, judged by `is_tombstone`.
+    /// The older values of such keys are dropped as well.
+    pub fn drop_tombstones(mut self, is_tombstone: fn(&V) -> bool) -> Self {
+        self.is_tombstone = Some(is_tombstone);
+        self
+    }
+
+    /// Count of the entries dropped so far because of a newer entry of the same key.
+    #[inline]
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
+
+    /// Count of the tombstones dropped so far, see [MergeIterator::drop_tombstones].
+    #[inline]
+    pub fn tombstones(&self) -> usize {
+        self.tombstones
+    }
+
+    fn advance(&mut self, idx: usize) -> (K, V, S) {
+        let next = self.sources[idx].next();
+        std::mem::replace(&mut self.heads[idx], next).unwrap()
+    }
+
+    /// Pop the newest entry of the smallest key, and drop the other entries of the key.
+    fn pop_newest(&mut self) -> Option<(K, V, S)> {
+        let mut min_idx: Option<usize> = None;
+        for (idx, head) in self.heads.iter().enumerate() {
+            if let Some((key, ..)) = head {
+                let is_less = match min_idx {
+                    Some(min_idx) => {
+                        let min_key = &self.heads[min_idx].as_ref().unwrap().0;
+                        (self.cmp)(key, min_key) == Ordering::Less
+                    }
+                    None => true,
+                };
+                if is_less {
+                    min_idx = Some(idx);
+                }
+            }
+        }
+
+        let mut newest = self.advance(min_idx?);
+        for idx in 0..self.heads.len() {
+            while matches!(&self.heads[idx], Some((key, ..)) if (self.cmp)(key, &newest.0) == Ordering::Equal)
+            {
+                let entry = self.advance(idx);
+                if entry.2 > newest.2 {
+                    newest = entry;
+                }
+                self.duplicates += 1;
+            }
+        }
+        Some(newest)
+    }
+}
+
+impl<K, V, S, I, C> Iterator for MergeIterator<K, V, S, I, C>
+where
+    S: Ord,
+    I: Iterator<Item = (K, V, S)>,
+    C: Fn(&K, &K) -> Ordering,
+{
+    type Item = (K, V, S);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.pop_newest()?;
+            match self.is_tombstone {
+                Some(is_tombstone) if is_tombstone(&entry.1) => self.tombstones += 1,
+                _ => return Some(entry),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::merge_iter::MergeIterator;
+
+    type Entries = Vec<(u32, &'static str, u64)>;
+
+    #[test]
+    fn test_overlapping() {
+        let sources: Vec<Entries> = vec![
+            vec![(1, "a", 0), (4, "d", 0), (7, "g", 0)],
+            vec![(2, "b", 0), (5, "e", 0)],
+            vec![],
+            vec![(3, "c", 0), (6, "f", 0), (8, "h", 0), (9, "i", 0)],
+        ];
+        let merged: Entries =
+            MergeIterator::new(sources.into_iter().map(Vec::into_iter).collect()).collect();
+        let keys: Vec<u32> = merged.iter().map(|e| e.0).collect();
+        assert_eq!(keys, (1..=9).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_duplicated_keys() {
+        let sources: Vec<Entries> = vec![
+            vec![(1, "old1", 1), (2, "new2", 5), (3, "old3", 1)],
+            vec![(1, "new1", 3), (3, "mid3", 2)],
+            vec![(2, "old2", 4), (3, "new3", 7), (4, "only4", 1)],
+            // versions of the same key in one source
+            vec![(4, "older4", 0), (4, "newest4", 9)],
+        ];
+        let mut merged = MergeIterator::new(sources.into_iter().map(Vec::into_iter).collect());
+        let entries: Entries = (&mut merged).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (1, "new1", 3),
+                (2, "new2", 5),
+                (3, "new3", 7),
+                (4, "newest4", 9)
+            ]
+        );
+        assert_eq!(merged.duplicates(), 6);
+
+        // the first source wins on equal sequences
+        let sources: Vec<Entries> = vec![vec![(1, "first", 1)], vec![(1, "second", 1)]];
+        let entries: Entries =
+            MergeIterator::new(sources.into_iter().map(Vec::into_iter).collect()).collect();
+        assert_eq!(entries, vec![(1, "first", 1)]);
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let sources: Vec<Entries> = vec![
+            vec![(1, "", 2), (2, "b", 2), (3, "", 1)],
+            vec![(1, "a", 1), (2, "", 1), (3, "c", 2), (4, "", 1)],
+        ];
+        let sources = || -> Vec<std::vec::IntoIter<(u32, &'static str, u64)>> {
+            sources.clone().into_iter().map(Vec::into_iter).collect()
+        };
+
+        let entries: Entries = MergeIterator::new(sources()).collect();
+        assert_eq!(
+            entries,
+            vec![(1, "", 2), (2, "b", 2), (3, "c", 2), (4, "", 1)]
+        );
+
+        let mut merged = MergeIterator::new(sources()).drop_tombstones(|v| v.is_empty());
+        let entries: Entries = (&mut merged).collect();
+        assert_eq!(entries, vec![(2, "b", 2), (3, "c", 2)]);
+        assert_eq!(merged.tombstones(), 2);
+    }
+
+    #[test]
+    fn test_comparator() {
+        let reversed = |a: &u32, b: &u32| b.cmp(a);
+        let sources: Vec<Entries> = vec![
+            vec![(9, "i", 0), (5, "e", 1), (1, "a", 0)],
+            vec![(8, "h", 0), (5, "old", 0), (2, "b", 0)],
+        ];
+        let entries: Entries = MergeIterator::with_comparator(
+            sources.into_iter().map(Vec::into_iter).collect(),
+            reversed,
+        )
+        .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (9, "i", 0),
+                (8, "h", 0),
+                (5, "e", 1),
+                (2, "b", 0),
+                (1, "a", 0)
+            ]
+        );
+    }
+}
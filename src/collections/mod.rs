@@ -1,3 +1,6 @@
+pub use merge_iter::MergeIterator;
+
+pub mod merge_iter;
 pub mod skip_list;
 pub mod treap;
 
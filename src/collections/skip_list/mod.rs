@@ -1,19 +1,46 @@
 pub mod skipmap;
 
 use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 pub const MAX_LEVEL: usize = 12;
 
 fn rand_level() -> usize {
-    let mut rng = rand::thread_rng();
+    level_from_bits(rand::thread_rng().gen())
+}
+
+/// Each level is kept with probability 1/4, which consumes two bits of `bits`.
+fn level_from_bits(mut bits: u64) -> usize {
     let mut level = 0;
-    while level < MAX_LEVEL {
-        let number = rng.gen_range(1..=4);
-        if number == 1 {
-            level += 1;
-        } else {
-            break;
-        }
+    while level < MAX_LEVEL && bits & 3 == 0 {
+        level += 1;
+        bits >>= 2;
     }
     level
 }
+
+/// SplitMix64 generator shared by the writers of a skip map,
+/// which yields the same sequence for the same seed.
+struct SeededRng {
+    state: AtomicU64,
+}
+
+impl SeededRng {
+    const GAMMA: u64 = 0x9e3779b97f4a7c15;
+
+    fn new(seed: u64) -> SeededRng {
+        SeededRng {
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    fn rand_level(&self) -> usize {
+        let mut z = self
+            .state
+            .fetch_add(Self::GAMMA, Ordering::Relaxed)
+            .wrapping_add(Self::GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        level_from_bits(z ^ (z >> 31))
+    }
+}
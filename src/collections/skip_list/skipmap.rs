@@ -1,7 +1,8 @@
-use crate::collections::skip_list::{rand_level, MAX_LEVEL};
+use crate::collections::skip_list::{rand_level, SeededRng, MAX_LEVEL};
 use crate::collections::Entry;
-use std::alloc::Layout;
+use std::alloc::{handle_alloc_error, Allocator, Global, Layout};
 use std::marker::PhantomData;
+use std::ptr::NonNull;
 use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 
 pub type SrSwSkipMap<K, V> = SkipMap<K, V, { SrSw }>;
@@ -34,19 +35,27 @@ pub struct Node<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> {
 }
 
 impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Node<K, V, { RW_MODE }> {
-    fn head() -> *mut Node<K, V, { RW_MODE }> {
-        Self::new_with_level(K::default(), V::default(), MAX_LEVEL)
+    fn head<A: Allocator>(alloc: &A) -> *mut Node<K, V, { RW_MODE }> {
+        Self::new_with_level(K::default(), V::default(), MAX_LEVEL, alloc)
     }
 
-    fn new_with_level(key: K, value: V, level: usize) -> *mut Node<K, V, { RW_MODE }> {
+    fn new_with_level<A: Allocator>(
+        key: K,
+        value: V,
+        level: usize,
+        alloc: &A,
+    ) -> *mut Node<K, V, { RW_MODE }> {
         let pointers_size = (level + 1) * std::mem::size_of::<*mut Self>();
         let layout = Layout::from_size_align(
             std::mem::size_of::<Self>() + pointers_size,
             std::mem::align_of::<Self>(),
         )
         .unwrap();
+        let node_ptr = match alloc.allocate(layout) {
+            Ok(ptr) => ptr.as_ptr() as *mut Self,
+            Err(_) => handle_alloc_error(layout),
+        };
         unsafe {
-            let node_ptr = std::alloc::alloc(layout) as *mut Self;
             let node = &mut *node_ptr;
             std::ptr::write(&mut node.entry, Entry { key, value });
             std::ptr::write(&mut node.bit_field, level);
@@ -128,44 +137,73 @@ impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Node<K, V, { RW
     }
 }
 
-unsafe fn drop_node<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode>(
+/// Drop `node` and return its memory to `alloc`, which must be the allocator of the node.
+unsafe fn drop_node<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator>(
     node: *mut Node<K, V, RW_MODE>,
+    alloc: &A,
 ) {
     let layout = (*node).get_layout();
     std::ptr::drop_in_place(node as *mut Node<K, V, RW_MODE>);
-    std::alloc::dealloc(node as *mut u8, layout);
+    alloc.deallocate(NonNull::new_unchecked(node as *mut u8), layout);
 }
 
-/// Map that allows duplicate keys, based on skip list
+/// Result of [SkipMap::search].
+pub struct SearchResult<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> {
+    /// The first node whose key is greater or equal than the searched key, or nullptr.
+    pub node: *mut Node<K, V, RW_MODE>,
+    /// `prev_nodes[i]` is the last node at level `i` whose key is less than the searched
+    /// key, or the dummy head if there is no such node.
+    pub prev_nodes: [*mut Node<K, V, RW_MODE>; MAX_LEVEL + 1],
+}
+
+/// Ordered map based on skip list.
+///
+/// Keys are unique: inserting an existing key replaces its value, so [SkipMap::len]
+/// is both the number of nodes and the number of distinct keys.
+///
+/// Nodes are allocated by `A`, which is the global allocator by default.
 ///
 /// # NOTICE:
 ///
 /// SkipMap is not thread-safe.
-pub struct SkipMap<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> {
+pub struct SkipMap<
+    K: Ord + Default,
+    V: Default,
+    const RW_MODE: ReadWriteMode,
+    A: Allocator = Global,
+> {
     dummy_head: *const Node<K, V, { RW_MODE }>,
     tail_lock: AtomicBool,
     tail: AtomicPtr<Node<K, V, { RW_MODE }>>,
     cur_max_level: AtomicUsize,
     len: AtomicUsize,
+    /// Generator of the levels of new nodes, or the thread RNG if `None`.
+    rng: Option<SeededRng>,
+    alloc: A,
     _key: PhantomData<K>,
     _value: PhantomData<V>,
 }
 
-unsafe impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Send
-    for SkipMap<K, V, RW_MODE>
+unsafe impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator + Send> Send
+    for SkipMap<K, V, RW_MODE, A>
 {
 }
 
-unsafe impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Sync
-    for SkipMap<K, V, RW_MODE>
+unsafe impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator + Sync> Sync
+    for SkipMap<K, V, RW_MODE, A>
 {
 }
 
-impl<SK: Ord + Default, V: Default> SkipMap<SK, V, { SrSw }> {
-    /// Remove all the `key` in map, return whether `key` exists
+impl<SK: Ord + Default, V: Default, A: Allocator> SkipMap<SK, V, { SrSw }, A> {
+    /// Remove `key` from map, return whether `key` exists.
+    ///
+    /// `len` is decreased after each node is unlinked, so it never exceeds the number
+    /// of nodes that a reader can observe.
     pub fn remove(&mut self, key: SK) -> bool {
-        let mut prev_nodes = [self.dummy_head as *mut _; MAX_LEVEL + 1];
-        let mut node = self.find_first_ge(&key, Some(&mut prev_nodes));
+        let SearchResult {
+            mut node,
+            prev_nodes,
+        } = self.search(&key);
         let has_key = unsafe { Self::node_eq_key(node, &key) };
         if has_key {
             unsafe {
@@ -179,7 +217,7 @@ impl<SK: Ord + Default, V: Default> SkipMap<SK, V, { SrSw }> {
                         self.tail
                             .store(*prev_nodes.get_unchecked(0) as *mut _, Ordering::SeqCst);
                     }
-                    drop_node(node);
+                    drop_node(node, &self.alloc);
                     node = next_node;
                 }
             }
@@ -190,7 +228,7 @@ impl<SK: Ord + Default, V: Default> SkipMap<SK, V, { SrSw }> {
     }
 }
 
-impl<SK: Ord + Default, V: Default> SkipMap<SK, V, { MrMw }> {
+impl<SK: Ord + Default, V: Default, A: Allocator> SkipMap<SK, V, { MrMw }, A> {
     /// return whether `key` has already exist.
     #[inline]
     pub fn insert_single_writer(&self, key: SK, mut value: V) -> Option<V> {
@@ -205,17 +243,41 @@ impl<SK: Ord + Default, V: Default> SkipMap<SK, V, { MrMw }> {
 
 impl<SK: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> SkipMap<SK, V, RW_MODE> {
     pub fn new() -> SkipMap<SK, V, RW_MODE> {
+        Self::new_in(Global)
+    }
+
+    /// Create a map whose node levels are generated from `seed`, so that the same
+    /// insertion order always builds the same structure.
+    pub fn with_rng_seed(seed: u64) -> SkipMap<SK, V, RW_MODE> {
+        Self::with_rng_seed_in(seed, Global)
+    }
+}
+
+impl<SK: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator>
+    SkipMap<SK, V, RW_MODE, A>
+{
+    /// Create a map whose nodes are allocated by `alloc`.
+    pub fn new_in(alloc: A) -> SkipMap<SK, V, RW_MODE, A> {
         SkipMap {
-            dummy_head: Node::head(),
+            dummy_head: Node::head(&alloc),
             tail_lock: AtomicBool::new(false),
             tail: AtomicPtr::default(),
             cur_max_level: AtomicUsize::default(),
             len: AtomicUsize::default(),
+            rng: None,
+            alloc,
             _key: PhantomData,
             _value: PhantomData,
         }
     }
 
+    /// Same as [SkipMap::with_rng_seed], but nodes are allocated by `alloc`.
+    pub fn with_rng_seed_in(seed: u64, alloc: A) -> SkipMap<SK, V, RW_MODE, A> {
+        let mut skip_map = Self::new_in(alloc);
+        skip_map.rng = Some(SeededRng::new(seed));
+        skip_map
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
         self.len.load(Ordering::Acquire)
@@ -377,6 +439,30 @@ impl<SK: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> SkipMap<SK, V,
         }
     }
 
+    /// Return the first node whose key is greater or equal than `key`, together with
+    /// its previous node at every level, so that the caller can check the existence of
+    /// `key` and insert after `prev_nodes` with a single descent.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use kvlite::collections::skip_list::skipmap::SrSwSkipMap;
+    /// let skip_map: SrSwSkipMap<i32, i32> = SrSwSkipMap::new();
+    /// skip_map.insert(3, 30);
+    /// skip_map.insert(7, 70);
+    ///
+    /// let result = skip_map.search(&5);
+    /// unsafe {
+    ///     assert_eq!((*result.node).entry.key, 7);
+    ///     assert_eq!((*result.prev_nodes[0]).entry.key, 3);
+    /// }
+    /// ```
+    pub fn search(&self, key: &SK) -> SearchResult<SK, V, RW_MODE> {
+        let mut prev_nodes = [self.dummy_head as *mut _; MAX_LEVEL + 1];
+        let node = self.find_first_ge(key, Some(&mut prev_nodes));
+        SearchResult { node, prev_nodes }
+    }
+
     fn update_first_ge(
         &self,
         key: &SK,
@@ -443,26 +529,75 @@ impl<SK: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> SkipMap<SK, V,
         }
     }
 
-    pub fn get_clone(&self, key: &SK) -> Option<V>
-    where
-        V: Clone,
-    {
+    /// Return the entry whose key is equal to `key`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use kvlite::collections::skip_list::skipmap::SrSwSkipMap;
+    /// let skip_map: SrSwSkipMap<i32, i32> = SrSwSkipMap::new();
+    /// assert!(skip_map.get(&3).is_none());
+    /// skip_map.insert(3, 30);
+    /// skip_map.insert(7, 70);
+    ///
+    /// let entry = skip_map.get(&3).unwrap();
+    /// assert_eq!((entry.key, entry.value), (3, 30));
+    /// assert!(skip_map.get(&5).is_none());
+    /// assert!(skip_map.get(&8).is_none());
+    /// ```
+    pub fn get(&self, key: &SK) -> Option<&Entry<SK, V>> {
         let node = self.find_first_ge(key, None);
         unsafe {
             if node.is_null() || (*node).entry.key.ne(key) {
                 None
             } else {
-                Some((*node).entry.value.clone())
+                Some(&(*node).entry)
             }
         }
     }
 
+    #[inline]
+    pub fn get_clone(&self, key: &SK) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.get(key).map(|entry| entry.value.clone())
+    }
+
+    /// Insert clones of the key-values whose keys are in `[key_start, key_end]`
+    /// (both inclusive) into `kvs`, converting the keys into `UK`.
+    ///
+    /// The range may extend beyond the keys of the map on either side, in which case only
+    /// the keys inside the map are inserted. Nothing is inserted if `key_start > key_end`.
+    /// Existing values of `kvs` with the same keys are overwritten, which is how the
+    /// writes of a transaction shadow the ones read from the database.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use kvlite::collections::skip_list::skipmap::SrSwSkipMap;
+    /// let skip_map: SrSwSkipMap<i32, i32> = SrSwSkipMap::new();
+    /// for i in 1..=5 {
+    ///     skip_map.insert(i * 10, i);
+    /// }
+    ///
+    /// let mut kvs = SrSwSkipMap::new();
+    /// skip_map.range_get(&20, &40, &mut kvs);
+    /// assert_eq!(kvs.into_iter().collect::<Vec<_>>(), vec![(20, 2), (30, 3), (40, 4)]);
+    ///
+    /// let mut kvs: SrSwSkipMap<i32, i32> = SrSwSkipMap::new();
+    /// skip_map.range_get(&40, &20, &mut kvs);
+    /// assert!(kvs.is_empty());
+    /// ```
     pub fn range_get<UK>(&self, key_start: &SK, key_end: &SK, kvs: &mut SkipMap<UK, V, { SrSw }>)
     where
         SK: Clone + Into<UK>,
         UK: Ord + Default,
         V: Clone,
     {
+        if key_start > key_end {
+            return;
+        }
         let mut node = self.find_first_ge(key_start, None);
         unsafe {
             while !node.is_null() && (*node).entry.key.le(key_end) {
@@ -520,8 +655,10 @@ impl<SK: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> SkipMap<SK, V,
 
     /// return whether `key` has already exist.
     fn insert_inner<const INSURE_SINGLE_WRITER: bool>(&self, key: SK, mut value: V) -> Option<V> {
-        let mut prev_nodes = [self.dummy_head as *mut _; MAX_LEVEL + 1];
-        let node = self.find_first_ge(&key, Some(&mut prev_nodes));
+        let SearchResult {
+            node,
+            mut prev_nodes,
+        } = self.search(&key);
 
         if let MrMw = RW_MODE {
             if !INSURE_SINGLE_WRITER {
@@ -585,12 +722,15 @@ impl<SK: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> SkipMap<SK, V,
             }
         }
 
-        let level = rand_level();
+        let level = match &self.rng {
+            Some(rng) => rng.rand_level(),
+            None => rand_level(),
+        };
         if level > self.cur_max_level.load(Ordering::Acquire) {
             self.cur_max_level.store(level, Ordering::Release);
         }
 
-        let new_node = Node::new_with_level(key, value, level);
+        let new_node = Node::new_with_level(key, value, level, &self.alloc);
         unsafe {
             if (*(*prev_nodes.get_unchecked(0))).get_next(0).is_null() {
                 self.tail.store(new_node, Ordering::Release);
@@ -620,6 +760,15 @@ impl<SK: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> SkipMap<SK, V,
         }
     }
 
+    /// Like [SkipMap::iter_ptr], but start from the first node whose key is greater or
+    /// equal than `key`. The iterator is empty if `key` is greater than all the keys.
+    pub fn iter_ptr_from<'a>(&self, key: &SK) -> IterPtr<'a, SK, V, RW_MODE> {
+        IterPtr {
+            node: self.find_first_ge(key, None),
+            _marker: PhantomData,
+        }
+    }
+
     pub fn iter<'a>(&self) -> Iter<'a, SK, V, RW_MODE> {
         unsafe {
             Iter {
@@ -675,7 +824,7 @@ impl<SK: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> SkipMap<SK, V,
         }
     }
 
-    pub fn into_ptr_iter(self) -> IntoPtrIter<SK, V, RW_MODE> {
+    pub fn into_ptr_iter(self) -> IntoPtrIter<SK, V, RW_MODE, A> {
         unsafe {
             let node = (*self.dummy_head).get_next(0);
             IntoPtrIter { _inner: self, node }
@@ -683,22 +832,42 @@ impl<SK: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> SkipMap<SK, V,
     }
 }
 
-impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Default
-    for SkipMap<K, V, RW_MODE>
+impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator + Default> Default
+    for SkipMap<K, V, RW_MODE, A>
 {
     fn default() -> Self {
-        Self::new()
+        Self::new_in(A::default())
     }
 }
 
-impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Drop for SkipMap<K, V, RW_MODE> {
+/// Deep copy the key-values into new nodes. The keys are inserted in order,
+/// so the levels of the nodes are randomized again.
+impl<
+        K: Ord + Default + Clone,
+        V: Default + Clone,
+        const RW_MODE: ReadWriteMode,
+        A: Allocator + Clone,
+    > Clone for SkipMap<K, V, RW_MODE, A>
+{
+    fn clone(&self) -> Self {
+        let skip_map = Self::new_in(self.alloc.clone());
+        for (key, value) in self.iter() {
+            skip_map.insert(key.clone(), value.clone());
+        }
+        skip_map
+    }
+}
+
+impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator> Drop
+    for SkipMap<K, V, RW_MODE, A>
+{
     fn drop(&mut self) {
         let mut node = self.dummy_head;
 
         unsafe {
             while !node.is_null() {
                 let next_node = (*node).get_next(0);
-                drop_node(node as *mut Node<K, V, RW_MODE>);
+                drop_node(node as *mut Node<K, V, RW_MODE>, &self.alloc);
                 node = next_node;
             }
         }
@@ -769,12 +938,19 @@ impl<'a, K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Iterator
     }
 }
 
-pub struct IntoPtrIter<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> {
-    _inner: SkipMap<K, V, RW_MODE>,
+pub struct IntoPtrIter<
+    K: Ord + Default,
+    V: Default,
+    const RW_MODE: ReadWriteMode,
+    A: Allocator = Global,
+> {
+    _inner: SkipMap<K, V, RW_MODE, A>,
     node: *mut Node<K, V, RW_MODE>,
 }
 
-impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> IntoPtrIter<K, V, RW_MODE> {
+impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator>
+    IntoPtrIter<K, V, RW_MODE, A>
+{
     pub fn current_mut_no_consume(&self) -> *mut Node<K, V, RW_MODE> {
         self.node as *mut _
     }
@@ -791,8 +967,8 @@ impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> IntoPtrIter<K,
     }
 }
 
-impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Iterator
-    for IntoPtrIter<K, V, RW_MODE>
+impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator> Iterator
+    for IntoPtrIter<K, V, RW_MODE, A>
 {
     type Item = *mut Node<K, V, RW_MODE>;
 
@@ -809,13 +985,19 @@ impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Iterator
     }
 }
 
-pub struct IntoIter<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> {
-    _inner: SkipMap<K, V, RW_MODE>,
+/// Owning iterator of [SkipMap]. Each node is freed as soon as its entry is yielded.
+pub struct IntoIter<
+    K: Ord + Default,
+    V: Default,
+    const RW_MODE: ReadWriteMode,
+    A: Allocator = Global,
+> {
+    inner: SkipMap<K, V, RW_MODE, A>,
     node: *mut Node<K, V, RW_MODE>,
 }
 
-impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Iterator
-    for IntoIter<K, V, RW_MODE>
+impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator> Iterator
+    for IntoIter<K, V, RW_MODE, A>
 {
     type Item = (K, V);
 
@@ -825,34 +1007,84 @@ impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> Iterator
         } else {
             let n = self.node;
             unsafe {
-                self.node = (*self.node).get_next(0);
+                self.node = (*n).get_next(0);
+                // Unlink `n` so that it will not be visited when dropping `inner`.
+                (*(self.inner.dummy_head as *mut Node<K, V, RW_MODE>)).set_next(0, self.node);
+                self.inner.len.fetch_sub(1, Ordering::Release);
+
                 let entry = std::mem::take(&mut (*n).entry);
+                drop_node(n, &self.inner.alloc);
                 Some(entry.key_value())
             }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.inner.len();
+        (len, Some(len))
+    }
 }
 
-impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode> IntoIterator
-    for SkipMap<K, V, RW_MODE>
+impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator> ExactSizeIterator
+    for IntoIter<K, V, RW_MODE, A>
+{
+}
+
+impl<K: Ord + Default, V: Default, const RW_MODE: ReadWriteMode, A: Allocator> IntoIterator
+    for SkipMap<K, V, RW_MODE, A>
 {
     type Item = (K, V);
-    type IntoIter = IntoIter<K, V, RW_MODE>;
+    type IntoIter = IntoIter<K, V, RW_MODE, A>;
 
     fn into_iter(self) -> Self::IntoIter {
         unsafe {
             let node = (*self.dummy_head).get_next(0);
-            IntoIter { _inner: self, node }
+            IntoIter { inner: self, node }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::collections::skip_list::skipmap::ReadWriteMode::{MrSw, SrSw};
-    use crate::collections::skip_list::skipmap::SrSwSkipMap;
+    use crate::collections::skip_list::skipmap::ReadWriteMode::{MrMw, MrSw, SrSw};
+    use crate::collections::skip_list::skipmap::{
+        MrMwSkipMap, ReadWriteMode, SkipMap, SrSwSkipMap,
+    };
     use crate::db::no_transaction_db::tests::create_random_map;
     use rand::Rng;
+    use std::alloc::{AllocError, Allocator, Global, Layout};
+    use std::ptr::NonNull;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_search() {
+        let skip_map: SrSwSkipMap<i32, i32> = SrSwSkipMap::new();
+        for i in 0..1000 {
+            skip_map.insert(i * 2, i);
+        }
+
+        for key in [500, 501] {
+            let result = skip_map.search(&key);
+            let expected = if key % 2 == 0 { key } else { key + 1 };
+            unsafe {
+                assert_eq!((*result.node).entry.key, expected);
+                for (level, prev) in result.prev_nodes.iter().enumerate() {
+                    if *prev != skip_map.dummy_head as *mut _ {
+                        assert!((**prev).entry.key < key);
+                        assert!((**prev).get_level() >= level);
+                    }
+                    let next = (**prev).get_next(level);
+                    assert!(next.is_null() || (*next).entry.key >= key);
+                }
+            }
+        }
+        let result = skip_map.search(&2000);
+        assert!(result.node.is_null());
+        unsafe {
+            assert_eq!((*result.prev_nodes[0]).entry.key, 1998);
+        }
+    }
 
     #[test]
     fn test_key() {
@@ -920,6 +1152,62 @@ mod tests {
         assert_eq!("b".to_string(), map1.get_clone(&"a".to_string()).unwrap());
     }
 
+    #[test]
+    fn test_into_iter() {
+        let value = Rc::new(0);
+        let skip_map: SrSwSkipMap<i32, Rc<i32>> = SrSwSkipMap::new();
+        for i in (0..100).rev() {
+            skip_map.insert(i, value.clone());
+        }
+        assert_eq!(Rc::strong_count(&value), 101);
+
+        let mut iter = skip_map.into_iter();
+        let first_half: Vec<(i32, Rc<i32>)> = iter.by_ref().take(50).collect();
+        assert_eq!(iter.len(), 50);
+        drop(first_half);
+        assert_eq!(Rc::strong_count(&value), 51);
+
+        let second_half: Vec<(i32, Rc<i32>)> = iter.collect();
+        for (i, (k, _)) in second_half.iter().enumerate() {
+            assert_eq!(*k, i as i32 + 50);
+        }
+        drop(second_half);
+        assert_eq!(Rc::strong_count(&value), 1);
+
+        // drop the iterator before consuming all the entries
+        let skip_map: SrSwSkipMap<i32, Rc<i32>> = SrSwSkipMap::new();
+        for i in 0..100 {
+            skip_map.insert(i, value.clone());
+        }
+        let mut iter = skip_map.into_iter();
+        assert_eq!(iter.next().unwrap().0, 0);
+        drop(iter);
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn test_len() {
+        let mut skip_map: SrSwSkipMap<i32, i32> = SrSwSkipMap::new();
+        for i in 0..100 {
+            for j in 0..3 {
+                skip_map.insert(i % 10, j);
+            }
+        }
+        assert_eq!(skip_map.len(), 10);
+        assert_eq!(skip_map.iter().count(), 10);
+
+        for i in (0..10).step_by(3) {
+            assert!(skip_map.remove(i));
+        }
+        assert_eq!(skip_map.len(), 6);
+        assert_eq!(skip_map.iter().count(), 6);
+        assert_eq!(skip_map.last_key_value().unwrap().key, 8);
+
+        assert!(skip_map.remove(8));
+        assert_eq!(skip_map.last_key_value().unwrap().key, 7);
+        assert_eq!(skip_map.len(), skip_map.iter().count());
+    }
+
     #[test]
     fn test_remove() {
         let mut skip_map: SrSwSkipMap<i32, String> = SrSwSkipMap::new();
@@ -1010,4 +1298,169 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_clone() {
+        let mut skip_map: SrSwSkipMap<i32, String> = SrSwSkipMap::new();
+        for i in 0..100 {
+            skip_map.insert(i, format!("value{}", i));
+        }
+        let mut cloned = skip_map.clone();
+        assert_eq!(cloned.len(), 100);
+        assert!(skip_map.iter().eq(cloned.iter()));
+
+        // nodes are not shared
+        for ((_, v1), (_, v2)) in skip_map.iter().zip(cloned.iter()) {
+            assert_ne!(v1 as *const String, v2 as *const String);
+        }
+
+        for i in 0..50 {
+            cloned.remove(i * 2);
+        }
+        cloned.insert(1, String::from("new"));
+        cloned.insert(1000, String::from("value1000"));
+        assert_eq!(cloned.len(), 51);
+        assert!(cloned
+            .iter()
+            .map(|(k, _)| *k)
+            .eq((0..50).map(|i| i * 2 + 1).chain([1000])));
+        assert_eq!(cloned.get_clone(&1), Some(String::from("new")));
+
+        // the original map is unaffected
+        skip_map.remove(3);
+        assert_eq!(skip_map.len(), 99);
+        assert!(skip_map.iter().map(|(k, v)| (*k, v.clone())).eq((0..100)
+            .filter(|i| *i != 3)
+            .map(|i| (i, format!("value{}", i)))));
+        assert_eq!(cloned.get_clone(&3), Some(String::from("value3")));
+    }
+
+    #[test]
+    fn test_rng_seed() {
+        fn levels<const RW_MODE: ReadWriteMode>(
+            skip_map: &SkipMap<i32, i32, RW_MODE>,
+        ) -> Vec<usize> {
+            skip_map
+                .iter_ptr()
+                .map(|node| unsafe { (*node).get_level() })
+                .collect()
+        }
+
+        let keys: Vec<i32> = {
+            let mut rng = rand::thread_rng();
+            (0..1000).map(|_| rng.gen_range(0..10000)).collect()
+        };
+        let build = |seed| {
+            let skip_map: SrSwSkipMap<i32, i32> = SrSwSkipMap::with_rng_seed(seed);
+            for key in &keys {
+                skip_map.insert(*key, *key);
+            }
+            skip_map
+        };
+        let (map1, map2, map3) = (build(42), build(42), build(43));
+        assert_eq!(levels(&map1), levels(&map2));
+        assert_ne!(levels(&map1), levels(&map3));
+        assert!(levels(&map1).iter().any(|level| *level > 0));
+
+        let map4: MrMwSkipMap<i32, i32> = MrMwSkipMap::with_rng_seed(42);
+        for key in &keys {
+            map4.insert(*key, *key);
+        }
+        assert_eq!(levels(&map1), levels(&map4));
+    }
+
+    #[test]
+    fn test_range_get() {
+        let range_get = |skip_map: &SrSwSkipMap<i32, i32>, start: i32, end: i32| {
+            let mut kvs = SrSwSkipMap::new();
+            skip_map.range_get(&start, &end, &mut kvs);
+            kvs.into_iter().map(|(k, _)| k).collect::<Vec<i32>>()
+        };
+
+        let empty: SrSwSkipMap<i32, i32> = SrSwSkipMap::new();
+        assert!(range_get(&empty, 0, 100).is_empty());
+
+        let skip_map: SrSwSkipMap<i32, i32> = SrSwSkipMap::new();
+        for i in 1..=10 {
+            skip_map.insert(i * 10, i);
+        }
+        // both ends are inclusive
+        assert_eq!(range_get(&skip_map, 30, 50), vec![30, 40, 50]);
+        assert_eq!(range_get(&skip_map, 31, 49), vec![40]);
+        assert_eq!(range_get(&skip_map, 40, 40), vec![40]);
+        // empty ranges
+        assert!(range_get(&skip_map, 41, 49).is_empty());
+        assert!(range_get(&skip_map, 50, 30).is_empty());
+        // partially or totally outside the map
+        assert_eq!(range_get(&skip_map, -5, 25), vec![10, 20]);
+        assert_eq!(range_get(&skip_map, 85, 200), vec![90, 100]);
+        assert_eq!(range_get(&skip_map, i32::MIN, i32::MAX).len(), 10);
+        assert!(range_get(&skip_map, -100, 9).is_empty());
+        assert!(range_get(&skip_map, 101, 200).is_empty());
+
+        // existing values of the output are overwritten
+        let mut kvs: SrSwSkipMap<i32, i32> = SrSwSkipMap::new();
+        kvs.insert(20, -1);
+        kvs.insert(5, -1);
+        skip_map.range_get(&10, &20, &mut kvs);
+        assert_eq!(
+            kvs.into_iter().collect::<Vec<_>>(),
+            vec![(5, -1), (10, 1), (20, 2)]
+        );
+    }
+
+    #[derive(Default)]
+    struct CountingAllocator {
+        allocated: AtomicUsize,
+        deallocated: AtomicUsize,
+    }
+
+    unsafe impl Allocator for CountingAllocator {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.deallocated.fetch_add(1, Ordering::Relaxed);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn test_custom_allocator() {
+        let alloc = CountingAllocator::default();
+        {
+            let mut skip_map: SkipMap<i32, String, { SrSw }, &CountingAllocator> =
+                SkipMap::new_in(&alloc);
+            for i in 0..1000 {
+                skip_map.insert(i, i.to_string());
+            }
+            // dummy head and 1000 nodes
+            assert_eq!(alloc.allocated.load(Ordering::Relaxed), 1001);
+            skip_map.insert(0, String::from("new"));
+            for i in 0..100 {
+                assert!(skip_map.remove(i * 2));
+            }
+            assert_eq!(alloc.deallocated.load(Ordering::Relaxed), 100);
+
+            let cloned = skip_map.clone();
+            assert_eq!(cloned.len(), 900);
+            let mut iter = cloned.into_iter();
+            assert_eq!(iter.next(), Some((1, String::from("1"))));
+
+            let mr_mw: SkipMap<i32, String, { MrMw }, &CountingAllocator> =
+                SkipMap::with_rng_seed_in(42, &alloc);
+            let other = SrSwSkipMap::new();
+            for i in 0..100 {
+                other.insert(i, i.to_string());
+            }
+            mr_mw.merge(other);
+            assert_eq!(mr_mw.len(), 100);
+        }
+        assert_eq!(
+            alloc.allocated.load(Ordering::Relaxed),
+            alloc.deallocated.load(Ordering::Relaxed)
+        );
+    }
 }
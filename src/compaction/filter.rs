@@ -0,0 +1,50 @@
+//! User-defined hook that runs on every key-value pair written by compaction.
+
+use crate::db::key_types::InternalKey;
+use crate::db::Value;
+use crate::sstable::value_log::{encode_inline, load_value};
+
+/// What to do with a key-value pair during compaction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decision {
+    Keep,
+    /// Replace the value with a tombstone, so that older versions in deeper levels stay hidden.
+    Remove,
+    ChangeValue(Value),
+}
+
+/// Filter invoked for every live key-value pair while compacting sstables.
+///
+/// It never runs on the read path, so keys that should be filtered stay readable
+/// until the sstable containing them is compacted. Tombstones are not passed to the filter.
+pub trait CompactionFilter: Send + Sync + std::fmt::Debug {
+    fn filter(&self, key: &[u8], value: &[u8]) -> Decision;
+}
+
+/// Apply `filter` to the output key-value pairs of compaction. If `value_log_dir` is
+/// given, the values are stored as in [crate::sstable::value_log], and the filter is
+/// passed the values they stand for.
+pub(crate) fn filter_kvs(
+    filter: Option<&dyn CompactionFilter>,
+    value_log_dir: Option<&str>,
+    kvs: &mut Vec<(InternalKey, Value)>,
+) -> crate::Result<()> {
+    if let Some(filter) = filter {
+        for (key, value) in kvs.iter_mut() {
+            if value.is_empty() {
+                continue;
+            }
+            let decision = match value_log_dir {
+                Some(dir) => filter.filter(key, &load_value(dir, value.clone())?),
+                None => filter.filter(key, value),
+            };
+            match decision {
+                Decision::Keep => {}
+                Decision::Remove => value.clear(),
+                Decision::ChangeValue(v) if value_log_dir.is_some() => *value = encode_inline(v),
+                Decision::ChangeValue(v) => *value = v,
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,19 +1,27 @@
-use crate::collections::skip_list::skipmap::{IntoIter, IntoPtrIter, ReadWriteMode, SrSwSkipMap};
+use crate::collections::skip_list::skipmap::SrSwSkipMap;
+use crate::collections::MergeIterator;
+use crate::compaction::filter::filter_kvs;
+use crate::compaction::CompactionOutputs;
 use crate::db::key_types::{InternalKey, MemKey};
 use crate::db::Value;
 use crate::memory::MemTable;
 use crate::sstable::manager::level_0::Level0Manager;
 use crate::sstable::manager::level_n::LevelNManager;
 use crate::sstable::table_handle::TableReadHandle;
+use crate::sstable::TableID;
 use crate::wal::WAL;
-use std::cmp::Ordering;
 use std::collections::VecDeque;
 use std::marker::PhantomData;
 use std::num::NonZeroUsize;
 use std::sync::Arc;
 
+/// Default of [crate::db::options::Options::level0_files_threshold].
 pub const LEVEL0_FILES_THRESHOLD: usize = 4;
 
+/// Seed of the merge of level 0 if [crate::db::options::Options::deterministic_compaction]
+/// is true.
+const DETERMINISTIC_SEED: u64 = 0x5eed;
+
 /// Merge all the `level0_table_handles` and `level1_tables` to `new_table`,
 /// then insert `new_table` to `TableManager`.
 /// In `level0_manager`, oldest table is at first
@@ -37,6 +45,28 @@ pub(crate) fn compact_and_insert<
     compactor.run();
 }
 
+/// Merge all the `level0_table_handles` to the level 0 table `table_id`, which replaces
+/// them. See [crate::db::options::Options::level0_intra_compaction_bytes].
+pub(crate) fn compact_intra_level0<
+    SK: 'static + MemKey,
+    UK: 'static + MemKey,
+    M: 'static + MemTable<SK, UK>,
+    L: 'static + WAL<SK, UK>,
+>(
+    level0_manager: &Arc<Level0Manager<SK, UK, M, L>>,
+    leveln_manager: &Arc<LevelNManager>,
+    level0_table_handles: Vec<Arc<TableReadHandle>>,
+    table_id: TableID,
+) {
+    let compactor = Compactor::new(
+        level0_manager.clone(),
+        leveln_manager.clone(),
+        level0_table_handles,
+        VecDeque::new(),
+    );
+    compactor.run_intra_level0(table_id);
+}
+
 struct Compactor<SK: MemKey, UK: MemKey, M: MemTable<SK, UK>, L: WAL<SK, UK>> {
     level0_manager: Arc<Level0Manager<SK, UK, M, L>>,
     leveln_manager: Arc<LevelNManager>,
@@ -78,124 +108,42 @@ where
 
         let level0_skip_map: SrSwSkipMap<InternalKey, Value> = self.merge_level0_tables();
         let mut kv_total = level0_skip_map.len();
+        for table in &self.level1_table_handles {
+            kv_total += table.kv_total() as usize;
+        }
+        let target_table_size = self.leveln_manager.options().target_table_size_bytes;
 
-        if self.level1_table_handles.is_empty() {
-            let level1_table_size = (kv_total + 1) / self.level0_table_handles.len();
-            debug_assert!(level1_table_size >= LEVEL0_FILES_THRESHOLD);
-
-            let mut temp_kvs: Vec<(InternalKey, Value)> = vec![];
-            let iter: IntoIter<InternalKey, Value, { ReadWriteMode::SrSw }> =
-                level0_skip_map.into_iter();
-            for (k, v) in iter {
-                temp_kvs.push((k, v));
-                #[cfg(debug_assertions)]
-                {
-                    self.kv_count += 1;
-                }
-
-                if temp_kvs.len() >= level1_table_size {
-                    self.add_table_handle_from_vec(temp_kvs);
-                    temp_kvs = vec![];
-                }
-            }
-            if !temp_kvs.is_empty() {
-                self.add_table_handle_from_vec(temp_kvs);
-            }
-        } else {
-            for table in &self.level1_table_handles {
-                kv_total += table.kv_total() as usize;
-            }
-
-            let level1_table_size = kv_total / self.level1_table_handles.len();
-            debug_assert!(level1_table_size > 0);
-
-            let mut temp_kvs = vec![];
-
-            macro_rules! add_kv {
-                ($key:expr, $value:expr) => {
-                    temp_kvs.push(($key, $value));
-
-                    #[cfg(debug_assertions)]
-                    {
-                        self.kv_count += 1;
-                    }
-
-                    if temp_kvs.len() >= level1_table_size {
-                        self.add_table_handle_from_vec(temp_kvs);
-                        temp_kvs = vec![];
-                    }
-                };
-            }
-
-            let mut level0_iter: IntoPtrIter<InternalKey, Value, { ReadWriteMode::SrSw }> =
-                level0_skip_map.into_ptr_iter();
-            let mut kv = level0_iter.current_mut_no_consume();
-
-            for level1_table_handle in self.level1_table_handles.iter() {
-                for (level1_key, level1_value) in TableReadHandle::iter(level1_table_handle.clone())
-                {
-                    if kv.is_null() {
-                        // write all the remain key-values in level1 tables.
-                        add_kv!(level1_key, level1_value);
-                        continue;
-                    }
-
-                    loop {
-                        let level0_key = unsafe { &(*kv).entry.key };
-                        debug_assert!(!level0_key.is_empty());
-                        match level0_key.cmp(&level1_key) {
-                            // set to level0_value
-                            // drop level1_value
-                            Ordering::Equal => {
-                                let level0_entry = unsafe { std::mem::take(&mut (*kv).entry) };
-                                let (level0_key, level0_value) = level0_entry.key_value();
-                                add_kv!(level0_key, level0_value);
-                                #[cfg(debug_assertions)]
-                                {
-                                    self.kv_count += 1;
-                                }
-                                kv = level0_iter.next_node();
-                                break;
-                            }
-                            // insert level1_value
-                            Ordering::Greater => {
-                                add_kv!(level1_key, level1_value);
-                                break;
-                            }
-                            // insert level0_value
-                            Ordering::Less => {
-                                let level0_entry = unsafe { std::mem::take(&mut (*kv).entry) };
-                                let (level0_key, level0_value) = level0_entry.key_value();
-                                add_kv!(level0_key, level0_value);
-                                kv = level0_iter.next_node();
-                                if kv.is_null() {
-                                    add_kv!(level1_key, level1_value);
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-
-            // write all the remain kv in level0 tables.
-            while !kv.is_null() {
-                unsafe {
-                    let entry = std::mem::take(&mut (*kv).entry);
-                    add_kv!(entry.key, entry.value);
-                }
-                kv = level0_iter.next_node();
-            }
+        let level1_table_handles: Vec<Arc<TableReadHandle>> =
+            self.level1_table_handles.iter().cloned().collect();
+        let leveln_manager = self.leveln_manager.clone();
+        let mut merged = MergeSorted::new(
+            level0_skip_map.into_iter(),
+            level1_table_handles
+                .into_iter()
+                .flat_map(move |table| leveln_manager.compaction_iter(table)),
+        );
 
-            if !temp_kvs.is_empty() {
-                self.add_table_handle_from_vec(temp_kvs);
+        let mut outputs =
+            CompactionOutputs::new(self.leveln_manager.clone(), NonZeroUsize::new(1).unwrap());
+        split_tables(&mut merged, target_table_size, |kvs| {
+            #[cfg(debug_assertions)]
+            {
+                self.kv_count += kvs.len();
             }
-        }
+            outputs.add(kvs);
+        });
+        outputs.finish();
 
         #[cfg(debug_assertions)]
         {
-            if self.kv_count != kv_total {
-                error!("self.kv_count: {}, kv_total: {}", self.kv_count, kv_total);
+            // values of level1 overwritten by level0 are dropped
+            if self.kv_count + merged.overwritten() != kv_total {
+                error!(
+                    "self.kv_count: {}, overwritten: {}, kv_total: {}",
+                    self.kv_count,
+                    merged.overwritten(),
+                    kv_total
+                );
             }
         }
 
@@ -209,24 +157,170 @@ where
             .may_compact(unsafe { NonZeroUsize::new_unchecked(1) });
     }
 
+    fn run_intra_level0(&self, table_id: TableID) {
+        debug_assert!(self.level1_table_handles.is_empty());
+
+        // the tombstones are kept, since the older levels may have the keys
+        let mut kvs: Vec<(InternalKey, Value)> = self.merge_level0_tables().into_iter().collect();
+        filter_kvs(
+            self.leveln_manager.options().compaction_filter.as_deref(),
+            self.leveln_manager.compaction_value_log_dir().as_deref(),
+            &mut kvs,
+        )
+        .unwrap();
+        self.level0_manager
+            .write_compacted_table(table_id, kvs)
+            .unwrap();
+
+        for table in &self.level0_table_handles {
+            self.level0_manager.ready_to_delete(table.table_id());
+        }
+    }
+
     fn merge_level0_tables(&self) -> SrSwSkipMap<InternalKey, Value> {
-        let skip_map = SrSwSkipMap::new();
+        let skip_map = if self.leveln_manager.options().deterministic_compaction {
+            SrSwSkipMap::with_rng_seed(DETERMINISTIC_SEED)
+        } else {
+            SrSwSkipMap::new()
+        };
         for table in &self.level0_table_handles {
-            for (key, value) in TableReadHandle::iter(table.clone()) {
+            for (key, value) in self.leveln_manager.compaction_iter(table.clone()) {
                 skip_map.insert(key, value);
             }
         }
         skip_map
     }
+}
+
+/// Split the sorted `kvs` into tables of about `target_table_size` bytes, and pass each
+/// of them to `add`. A table is only cut between two different keys, so that the tables
+/// never overlap, even if a key is yielded more than once.
+fn split_tables(
+    kvs: impl Iterator<Item = (InternalKey, Value)>,
+    target_table_size: u64,
+    mut add: impl FnMut(Vec<(InternalKey, Value)>),
+) {
+    let mut temp_kvs: Vec<(InternalKey, Value)> = vec![];
+    let mut temp_kvs_size = 0;
+    for (key, value) in kvs {
+        if temp_kvs_size >= target_table_size
+            && matches!(temp_kvs.last(), Some((last_key, _)) if *last_key != key)
+        {
+            add(std::mem::take(&mut temp_kvs));
+            temp_kvs_size = 0;
+        }
+        temp_kvs_size += (key.len() + value.len()) as u64;
+        temp_kvs.push((key, value));
+    }
+    add(temp_kvs);
+}
+
+/// Key-values tagged with the sequence of the iterator they come from.
+type SequencedIterator<K, V> = Box<dyn Iterator<Item = (K, V, u8)>>;
+
+/// Merge two iterators sorted by key, each of which has no duplicate keys.
+/// If both of them yield the same key, the value of `newer` is kept.
+struct MergeSorted<K: Ord, V>(MergeIterator<K, V, u8, SequencedIterator<K, V>>);
+
+impl<K: Ord + 'static, V: 'static> MergeSorted<K, V> {
+    fn new(
+        newer: impl Iterator<Item = (K, V)> + 'static,
+        older: impl Iterator<Item = (K, V)> + 'static,
+    ) -> MergeSorted<K, V> {
+        let newer: SequencedIterator<K, V> = Box::new(newer.map(|(k, v)| (k, v, 1)));
+        let older: SequencedIterator<K, V> = Box::new(older.map(|(k, v)| (k, v, 0)));
+        MergeSorted(MergeIterator::new(vec![newer, older]))
+    }
 
-    fn add_table_handle_from_vec(&self, temp_kvs: Vec<(InternalKey, Value)>) {
-        if !temp_kvs.is_empty() {
-            let mut new_table = self.leveln_manager.create_table_write_handle(
-                unsafe { NonZeroUsize::new_unchecked(1) },
-                temp_kvs.len() as u32,
+    /// Count of the key-values of `older` dropped because of the same keys in `newer`.
+    #[cfg(debug_assertions)]
+    fn overwritten(&self) -> usize {
+        self.0.duplicates()
+    }
+}
+
+impl<K: Ord, V> Iterator for MergeSorted<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(k, v, _)| (k, v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::skip_list::skipmap::SrSwSkipMap;
+    use crate::compaction::level_0::{split_tables, MergeSorted};
+    use crate::db::key_types::InternalKey;
+    use crate::db::Value;
+
+    #[test]
+    fn test_merge_sorted() {
+        let level0: SrSwSkipMap<InternalKey, Value> = SrSwSkipMap::new();
+        for i in (0..300).step_by(3) {
+            level0.insert(
+                format!("key{:03}", i).into_bytes(),
+                format!("level0_{}", i).into_bytes(),
             );
-            new_table.write_sstable_from_vec(temp_kvs).unwrap();
-            self.leveln_manager.upsert_table_handle(new_table);
+        }
+        let level1: Vec<(InternalKey, Value)> = (0..300)
+            .step_by(2)
+            .map(|i| {
+                (
+                    format!("key{:03}", i).into_bytes(),
+                    format!("level1_{}", i).into_bytes(),
+                )
+            })
+            .collect();
+
+        let mut merged = MergeSorted::new(level0.into_iter(), level1.into_iter());
+        let kvs: Vec<(InternalKey, Value)> = (&mut merged).collect();
+        #[cfg(debug_assertions)]
+        assert_eq!(merged.overwritten(), 50);
+
+        let expected: Vec<(InternalKey, Value)> = (0..300)
+            .filter(|i| i % 2 == 0 || i % 3 == 0)
+            .map(|i| {
+                let value = if i % 3 == 0 {
+                    format!("level0_{}", i)
+                } else {
+                    format!("level1_{}", i)
+                };
+                (format!("key{:03}", i).into_bytes(), value.into_bytes())
+            })
+            .collect();
+        assert_eq!(expected, kvs);
+        assert!(kvs.windows(2).all(|w| w[0].0 < w[1].0));
+
+        // one of the inputs is empty
+        let level1: Vec<(InternalKey, Value)> = vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"2".to_vec()),
+        ];
+        let kvs: Vec<(InternalKey, Value)> = MergeSorted::new(
+            SrSwSkipMap::<InternalKey, Value>::new().into_iter(),
+            level1.clone().into_iter(),
+        )
+        .collect();
+        assert_eq!(level1, kvs);
+    }
+
+    #[test]
+    fn test_split_tables_on_key_transitions() {
+        // each key-value is 4 bytes, and "k2" is duplicated at the boundary of 8 bytes
+        let kvs: Vec<(InternalKey, Value)> = ["k1", "k2", "k2", "k2", "k3", "k4", "k4"]
+            .iter()
+            .map(|k| (k.as_bytes().to_vec(), b"vv".to_vec()))
+            .collect();
+        let mut tables: Vec<Vec<(InternalKey, Value)>> = vec![];
+        split_tables(kvs.clone().into_iter(), 8, |table| tables.push(table));
+
+        assert_eq!(tables.concat(), kvs);
+        assert!(tables.len() > 1);
+        for w in tables.windows(2) {
+            let (prev_max, _) = w[0].last().unwrap();
+            let (next_min, _) = w[1].first().unwrap();
+            assert!(prev_max < next_min, "{:?} overlaps {:?}", w[0], w[1]);
         }
     }
 }
@@ -1,9 +1,11 @@
 use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::num::NonZeroUsize;
 use std::sync::Arc;
 
+use crate::compaction::CompactionOutputs;
 use crate::db::key_types::InternalKey;
-use crate::db::Value;
+use crate::db::{Value, MAX_LEVEL};
 use crate::sstable::manager::level_n::LevelNManager;
 use crate::sstable::table_handle::TableReadHandle;
 
@@ -12,13 +14,68 @@ pub(crate) fn start_compact(
     handle_to_compact: Arc<TableReadHandle>,
     leveln_manager: Arc<LevelNManager>,
 ) {
-    let mut compactor = Compactor::new(compact_level, handle_to_compact, leveln_manager);
+    let next_level_table_handles = leveln_manager.get_overlap_tables(
+        unsafe { NonZeroUsize::new_unchecked(compact_level.get() + 1) },
+        handle_to_compact.min_key(),
+        handle_to_compact.max_key(),
+    );
+    compact(
+        compact_level,
+        handle_to_compact,
+        next_level_table_handles,
+        leveln_manager,
+    );
+}
+
+/// Merge `handle_to_compact` into `next_level_table_handles`, the tables in the next
+/// level overlapping with it, which are already marked as compacting.
+pub(crate) fn compact(
+    compact_level: NonZeroUsize,
+    handle_to_compact: Arc<TableReadHandle>,
+    next_level_table_handles: VecDeque<Arc<TableReadHandle>>,
+    leveln_manager: Arc<LevelNManager>,
+) {
+    let mut compactor = Compactor::new(
+        compact_level,
+        handle_to_compact,
+        next_level_table_handles,
+        leveln_manager,
+    );
     compactor.run();
 }
 
+/// Rewrite `handle`, a table of [MAX_LEVEL] already marked as compacting, in place. The
+/// compaction filter runs on its key-values, and its tombstones are dropped.
+pub(crate) fn rewrite_bottom_table(
+    handle: Arc<TableReadHandle>,
+    leveln_manager: Arc<LevelNManager>,
+) {
+    debug_assert_eq!(handle.level(), MAX_LEVEL);
+    let target_table_size = leveln_manager.options().target_table_size_bytes;
+    let mut outputs = CompactionOutputs::new(
+        leveln_manager.clone(),
+        NonZeroUsize::new(MAX_LEVEL).unwrap(),
+    )
+    .drop_tombstones();
+    let mut kvs: Vec<(InternalKey, Value)> = vec![];
+    let mut kvs_size = 0;
+    for (key, value) in leveln_manager.compaction_iter(handle.clone()) {
+        kvs_size += (key.len() + value.len()) as u64;
+        kvs.push((key, value));
+        if kvs_size >= target_table_size {
+            outputs.add(std::mem::take(&mut kvs));
+            kvs_size = 0;
+        }
+    }
+    outputs.add(kvs);
+    outputs.finish();
+    leveln_manager.ready_to_delete(handle);
+}
+
 struct Compactor {
     compact_level: NonZeroUsize,
     handle_to_compact: Arc<TableReadHandle>,
+    next_level_table_handles: VecDeque<Arc<TableReadHandle>>,
     leveln_manager: Arc<LevelNManager>,
     #[cfg(debug_assertions)]
     kv_count: usize,
@@ -28,12 +85,14 @@ impl Compactor {
     fn new(
         compact_level: NonZeroUsize,
         handle_to_compact: Arc<TableReadHandle>,
+        next_level_table_handles: VecDeque<Arc<TableReadHandle>>,
         leveln_manager: Arc<LevelNManager>,
     ) -> Compactor {
         debug_assert_eq!(handle_to_compact.level(), compact_level.get());
         Compactor {
             compact_level,
             handle_to_compact,
+            next_level_table_handles,
             leveln_manager,
             #[cfg(debug_assertions)]
             kv_count: 0,
@@ -41,31 +100,37 @@ impl Compactor {
     }
 
     fn run(&mut self) {
-        let next_level_table_handles = self.leveln_manager.get_overlap_tables(
-            unsafe { NonZeroUsize::new_unchecked(self.compact_level.get() + 1) },
-            self.handle_to_compact.min_key(),
-            self.handle_to_compact.max_key(),
-        );
+        let next_level_table_handles = std::mem::take(&mut self.next_level_table_handles);
         let mut total = self.handle_to_compact.kv_total() as usize;
         for handle in next_level_table_handles.iter() {
             total += handle.kv_total() as usize;
         }
 
-        let new_table_size = total / next_level_table_handles.len().max(2) + 1;
+        let target_table_size = self.leveln_manager.options().target_table_size_bytes;
 
+        let mut outputs = CompactionOutputs::new(
+            self.leveln_manager.clone(),
+            NonZeroUsize::new(self.compact_level.get() + 1).unwrap(),
+        );
         let mut temp_kvs: Vec<(InternalKey, Value)> = vec![];
-        let mut table_to_compact_iter = TableReadHandle::iter(self.handle_to_compact.clone());
+        let mut temp_kvs_size = 0;
+        let mut table_to_compact_iter = self
+            .leveln_manager
+            .compaction_iter(self.handle_to_compact.clone());
 
         macro_rules! add_kv {
             ($key:expr, $value:expr) => {
-                temp_kvs.push(($key, $value));
+                let (key, value): (InternalKey, Value) = ($key, $value);
+                temp_kvs_size += (key.len() + value.len()) as u64;
+                temp_kvs.push((key, value));
                 #[cfg(debug_assertions)]
                 {
                     self.kv_count += 1;
                 }
-                if temp_kvs.len() >= new_table_size {
-                    self.add_table_handle(temp_kvs);
+                if temp_kvs_size >= target_table_size {
+                    outputs.add(temp_kvs);
                     temp_kvs = vec![];
+                    temp_kvs_size = 0;
                 }
             };
         }
@@ -84,8 +149,9 @@ impl Compactor {
             let mut cur_level_state = CurLevelState::Start;
 
             for next_level_table_handle in next_level_table_handles.iter() {
-                for (next_level_key, next_level_value) in
-                    TableReadHandle::iter(next_level_table_handle.clone())
+                for (next_level_key, next_level_value) in self
+                    .leveln_manager
+                    .compaction_iter(next_level_table_handle.clone())
                 {
                     match cur_level_state {
                         CurLevelState::Start => loop {
@@ -175,9 +241,8 @@ impl Compactor {
             }
         }
 
-        if !temp_kvs.is_empty() {
-            self.add_table_handle(temp_kvs);
-        }
+        outputs.add(temp_kvs);
+        outputs.finish();
 
         self.leveln_manager
             .ready_to_delete(self.handle_to_compact.clone());
@@ -187,25 +252,21 @@ impl Compactor {
         self.leveln_manager
             .may_compact(unsafe { NonZeroUsize::new_unchecked(self.compact_level.get() + 1) });
     }
-
-    fn add_table_handle(&self, temp_kvs: Vec<(InternalKey, Value)>) {
-        debug_assert!(!temp_kvs.is_empty());
-        let mut new_table = self.leveln_manager.create_table_write_handle(
-            unsafe { NonZeroUsize::new_unchecked(self.compact_level.get() + 1) },
-            temp_kvs.len() as u32,
-        );
-        new_table.write_sstable_from_vec(temp_kvs).unwrap();
-        self.leveln_manager.upsert_table_handle(new_table);
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::num::NonZeroUsize;
+    use std::sync::Arc;
 
+    use crate::cache::ShardLRUCache;
+    use crate::compaction::filter::{CompactionFilter, Decision};
     use crate::compaction::level_n::start_compact;
+    use crate::db::options::Options;
     use crate::sstable::manager::level_n::tests::create_manager;
-    use crate::sstable::table_handle::temp_file_name;
+    use crate::sstable::manager::level_n::LevelNManager;
+    use crate::sstable::sstable_file;
+    use crate::sstable::table_handle::{temp_file_name, TableReadHandle};
 
     #[test]
     fn test_compact() {
@@ -246,4 +307,193 @@ mod tests {
         start_compact(one, handle_to_compact, manager.clone());
         assert_eq!(manager.level_size(1), 0);
     }
+
+    #[derive(Debug)]
+    struct PrefixFilter(&'static str);
+
+    impl CompactionFilter for PrefixFilter {
+        fn filter(&self, key: &[u8], _value: &[u8]) -> Decision {
+            if key.starts_with(self.0.as_bytes()) {
+                Decision::Remove
+            } else {
+                Decision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let path = tempfile::TempDir::new().unwrap();
+        let db_path = path.path().to_str().unwrap();
+        let options = Options {
+            compaction_filter: Some(Arc::new(PrefixFilter("key11"))),
+            ..Options::default()
+        };
+        let manager = LevelNManager::open_tables(
+            db_path.to_string(),
+            options,
+            Arc::new(ShardLRUCache::default()),
+        );
+
+        for (level, range) in vec![(1, 100..120), (2, 110..130)] {
+            let mut handle = manager.create_table_write_handle(
+                NonZeroUsize::new(level).unwrap(),
+                (range.end - range.start) as u32,
+            );
+            let kvs = range
+                .map(|i| {
+                    (
+                        format!("key{}", i).into_bytes(),
+                        format!("value{}_{}", i, level).into_bytes(),
+                    )
+                })
+                .collect();
+            handle.write_sstable_from_vec(kvs).unwrap();
+            manager.upsert_table_handle(handle);
+        }
+
+        // the filter does not run on the read path
+        assert_eq!(
+            manager.query(&Vec::from("key115")).unwrap(),
+            Some(Vec::from("value115_1"))
+        );
+
+        let one = NonZeroUsize::new(1).unwrap();
+        let handle_to_compact = manager.get_handle_to_compact(one).unwrap();
+        start_compact(one, handle_to_compact, manager.clone());
+        assert_eq!(manager.level_size(1), 0);
+
+        for i in 100..130 {
+            let value = manager.query(&format!("key{}", i).into_bytes()).unwrap();
+            if (110..120).contains(&i) {
+                assert!(value.unwrap().is_empty(), "key{}", i);
+            } else {
+                let level = if i < 110 { 1 } else { 2 };
+                assert_eq!(value, Some(format!("value{}_{}", i, level).into_bytes()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_target_table_size() {
+        let path = tempfile::TempDir::new().unwrap();
+        let db_path = path.path().to_str().unwrap();
+        let target_table_size_bytes = 1024;
+        let options = Options {
+            target_table_size_bytes,
+            ..Options::default()
+        };
+        let manager = LevelNManager::open_tables(
+            db_path.to_string(),
+            options,
+            Arc::new(ShardLRUCache::default()),
+        );
+
+        // every key-value pair is 18 bytes
+        for (level, range) in vec![(1, 1000..1200), (2, 1100..1300)] {
+            let mut handle = manager.create_table_write_handle(
+                NonZeroUsize::new(level).unwrap(),
+                (range.end - range.start) as u32,
+            );
+            let kvs = range
+                .map(|i| {
+                    (
+                        format!("key{}", i).into_bytes(),
+                        format!("value{}_{}", i, level).into_bytes(),
+                    )
+                })
+                .collect();
+            handle.write_sstable_from_vec(kvs).unwrap();
+            manager.upsert_table_handle(handle);
+        }
+
+        let one = NonZeroUsize::new(1).unwrap();
+        let handle_to_compact = manager.get_handle_to_compact(one).unwrap();
+        start_compact(one, handle_to_compact, manager.clone());
+        assert_eq!(manager.level_size(1), 0);
+
+        let tables: Vec<_> = manager
+            .get_level_tables_lock(NonZeroUsize::new(2).unwrap())
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        assert!(tables.len() > 1);
+
+        let mut kv_total = 0;
+        for (i, table) in tables.iter().enumerate() {
+            kv_total += table.kv_total();
+            let size: u64 = TableReadHandle::iter(table.clone())
+                .map(|(k, v)| (k.len() + v.len()) as u64)
+                .sum();
+            assert!(size < target_table_size_bytes + 18);
+            if i + 1 < tables.len() {
+                assert!(size >= target_table_size_bytes);
+            }
+        }
+        assert_eq!(kv_total, 300);
+    }
+
+    #[test]
+    fn test_deterministic_compaction() {
+        let options = Options {
+            target_table_size_bytes: 1024,
+            deterministic_compaction: true,
+            ..Options::default()
+        };
+        let compact_once = |skipped_ids: u64| {
+            let path = tempfile::TempDir::new().unwrap();
+            let db_path = path.path().to_str().unwrap();
+            let manager = LevelNManager::open_tables(
+                db_path.to_string(),
+                options.clone(),
+                Arc::new(ShardLRUCache::default()),
+            );
+            for (level, range) in vec![(1, 1000..1200), (2, 1100..1300)] {
+                let mut handle = manager.create_table_write_handle(
+                    NonZeroUsize::new(level).unwrap(),
+                    (range.end - range.start) as u32,
+                );
+                let kvs = range
+                    .map(|i| {
+                        (
+                            format!("key{}", i).into_bytes(),
+                            format!("value{}_{}", i, level).into_bytes(),
+                        )
+                    })
+                    .collect();
+                handle.write_sstable_from_vec(kvs).unwrap();
+                manager.upsert_table_handle(handle);
+            }
+            // the ids allocated before the compaction differ
+            manager.table_ids().allocate_many(skipped_ids);
+
+            let one = NonZeroUsize::new(1).unwrap();
+            let handle_to_compact = manager.get_handle_to_compact(one).unwrap();
+            start_compact(one, handle_to_compact, manager.clone());
+            let tables: Vec<Arc<TableReadHandle>> = manager
+                .get_level_tables_lock(NonZeroUsize::new(2).unwrap())
+                .read()
+                .unwrap()
+                .values()
+                .cloned()
+                .collect();
+            manager.close();
+
+            let ids: Vec<u64> = tables.iter().map(|table| table.table_id()).collect();
+            assert!(ids.len() > 1);
+            assert!(ids.windows(2).all(|w| w[0] + 1 == w[1]), "{:?}", ids);
+            let files: Vec<Vec<u8>> = tables
+                .iter()
+                .map(|table| std::fs::read(sstable_file(db_path, 2, table.table_id())).unwrap())
+                .collect();
+            (ids, files)
+        };
+
+        let (ids1, files1) = compact_once(0);
+        let (ids2, files2) = compact_once(7);
+        assert_eq!(ids1.iter().map(|id| id + 7).collect::<Vec<_>>(), ids2);
+        assert_eq!(files1, files2);
+    }
 }
@@ -1,2 +1,96 @@
+use crate::compaction::filter::filter_kvs;
+use crate::db::key_types::InternalKey;
+use crate::db::{Value, MAX_LEVEL};
+use crate::sstable::manager::level_n::LevelNManager;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+pub mod filter;
 pub mod level_0;
 pub(crate) mod level_n;
+pub mod plan;
+
+/// Output sstables of one compaction into `level`.
+struct CompactionOutputs {
+    leveln_manager: Arc<LevelNManager>,
+    level: NonZeroUsize,
+    /// Key-values of the output tables buffered until [CompactionOutputs::finish], if
+    /// [crate::db::options::Options::deterministic_compaction] is true.
+    buffered: Vec<Vec<(InternalKey, Value)>>,
+    /// Whether the tombstones are dropped instead of written, see
+    /// [CompactionOutputs::drop_tombstones].
+    drop_tombstones: bool,
+}
+
+impl CompactionOutputs {
+    fn new(leveln_manager: Arc<LevelNManager>, level: NonZeroUsize) -> CompactionOutputs {
+        CompactionOutputs {
+            leveln_manager,
+            level,
+            buffered: vec![],
+            drop_tombstones: false,
+        }
+    }
+
+    /// Drop the tombstones, including those written by the compaction filter. Only the
+    /// tables of [MAX_LEVEL] can do without them, as there is no older version below.
+    fn drop_tombstones(mut self) -> CompactionOutputs {
+        debug_assert_eq!(self.level.get(), MAX_LEVEL);
+        self.drop_tombstones = true;
+        self
+    }
+
+    /// Filter `kvs` and write them as an output table.
+    fn add(&mut self, mut kvs: Vec<(InternalKey, Value)>) {
+        if kvs.is_empty() {
+            return;
+        }
+        filter_kvs(
+            self.leveln_manager.options().compaction_filter.as_deref(),
+            self.leveln_manager.compaction_value_log_dir().as_deref(),
+            &mut kvs,
+        )
+        .unwrap();
+        if self.drop_tombstones {
+            kvs.retain(|(_, value)| !value.is_empty());
+            if kvs.is_empty() {
+                return;
+            }
+        }
+        if self.leveln_manager.options().deterministic_compaction {
+            self.buffered.push(kvs);
+            return;
+        }
+        let mut new_table = self
+            .leveln_manager
+            .create_compaction_table_write_handle(self.level, kvs.len() as u32)
+            .unwrap();
+        new_table.write_sstable_from_stored_vec(kvs).unwrap();
+        self.leveln_manager.upsert_table_handle(new_table);
+    }
+
+    /// Write the buffered output tables with consecutive table ids in key order.
+    fn finish(self) {
+        if self.buffered.is_empty() {
+            return;
+        }
+        let table_ids = self
+            .leveln_manager
+            .table_ids()
+            .allocate_many(self.buffered.len() as u64);
+        let mut new_tables = Vec::with_capacity(self.buffered.len());
+        for (table_id, kvs) in table_ids.zip(self.buffered) {
+            let mut new_table = self
+                .leveln_manager
+                .create_compaction_table_write_handle_with_id(
+                    self.level,
+                    table_id,
+                    kvs.len() as u32,
+                )
+                .unwrap();
+            new_table.write_sstable_from_stored_vec(kvs).unwrap();
+            new_tables.push(new_table);
+        }
+        self.leveln_manager.install_table_handles(new_tables);
+    }
+}
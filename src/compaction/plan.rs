@@ -0,0 +1,125 @@
+use crate::db::key_types::MemKey;
+use crate::db::MAX_LEVEL;
+use crate::memory::MemTable;
+use crate::sstable::manager::level_0::Level0Manager;
+use crate::sstable::manager::level_n::LevelNManager;
+use crate::sstable::table_handle::TableReadHandle;
+use crate::sstable::TableID;
+use crate::wal::WAL;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+/// Compactions which would be performed on the current tables. See [crate::db::DB::plan_compaction].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionPlan {
+    /// One task for each level that reaches its compaction trigger, in level order.
+    pub tasks: Vec<CompactionTask>,
+    /// Estimated file count of every level after all the tasks finish, indexed by level.
+    pub level_file_counts: Vec<usize>,
+}
+
+/// Compaction of the tables in `level` into `output_level`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionTask {
+    pub level: usize,
+    /// `level + 1`, or 0 if level 0 is compacted into itself, see
+    /// [crate::db::options::Options::level0_intra_compaction_bytes].
+    pub output_level: usize,
+    /// `(level, table id)` of the input tables, including the overlapping
+    /// tables in `output_level`.
+    pub input_tables: Vec<(usize, TableID)>,
+    /// Total file size of the input tables.
+    pub input_bytes: u64,
+    /// Overwritten and filtered key-value pairs are dropped by compaction, so the
+    /// input size is used as an upper bound of the output size.
+    pub estimated_output_bytes: u64,
+    pub estimated_output_files: usize,
+}
+
+impl CompactionTask {
+    fn new(
+        level: usize,
+        output_level: usize,
+        inputs: impl Iterator<Item = Arc<TableReadHandle>>,
+        target_table_size: u64,
+    ) -> CompactionTask {
+        let mut input_tables = vec![];
+        let mut input_bytes = 0;
+        for table in inputs {
+            input_tables.push((table.level(), table.table_id()));
+            input_bytes += table.file_size();
+        }
+        let estimated_output_files =
+            ((input_bytes + target_table_size - 1) / target_table_size).max(1) as usize;
+        CompactionTask {
+            level,
+            output_level,
+            input_tables,
+            input_bytes,
+            estimated_output_bytes: input_bytes,
+            estimated_output_files,
+        }
+    }
+}
+
+/// Run the pickers of every level without claiming any table or doing any I/O.
+/// Each level is planned against the current tables, ignoring the output of the
+/// tasks of lower levels.
+pub(crate) fn plan_compaction<SK, UK, M, L>(
+    level0_manager: &Level0Manager<SK, UK, M, L>,
+    leveln_manager: &LevelNManager,
+) -> CompactionPlan
+where
+    SK: MemKey + 'static,
+    UK: MemKey + 'static,
+    M: MemTable<SK, UK> + 'static,
+    L: WAL<SK, UK> + 'static,
+{
+    let target_table_size = leveln_manager.options().target_table_size_bytes.max(1);
+    let mut level_file_counts = vec![level0_manager.file_count()];
+    level_file_counts.extend((1..=MAX_LEVEL).map(|level| leveln_manager.file_count(level)));
+
+    let mut tasks = vec![];
+    let level1 = unsafe { NonZeroUsize::new_unchecked(1) };
+    if let Some(level0_tables) = level0_manager.peek_intra_level0_tables() {
+        let mut task = CompactionTask::new(0, 0, level0_tables.into_iter(), target_table_size);
+        // the tables are always merged into one
+        task.estimated_output_files = 1;
+        tasks.push(task);
+    } else if let Some((level0_tables, min_key, max_key)) =
+        level0_manager.peek_level0_tables_to_compact()
+    {
+        let level1_tables = leveln_manager.peek_overlap_tables(level1, &min_key, &max_key);
+        tasks.push(CompactionTask::new(
+            0,
+            1,
+            level0_tables.into_iter().chain(level1_tables),
+            target_table_size,
+        ));
+    }
+    for level in 1..MAX_LEVEL {
+        let level = unsafe { NonZeroUsize::new_unchecked(level) };
+        if let Some(handle) = leveln_manager.peek_handle_to_compact(level) {
+            let next_level = unsafe { NonZeroUsize::new_unchecked(level.get() + 1) };
+            let next_level_tables =
+                leveln_manager.peek_overlap_tables(next_level, handle.min_key(), handle.max_key());
+            tasks.push(CompactionTask::new(
+                level.get(),
+                next_level.get(),
+                std::iter::once(handle).chain(next_level_tables),
+                target_table_size,
+            ));
+        }
+    }
+
+    for task in &tasks {
+        for (level, _) in &task.input_tables {
+            level_file_counts[*level] = level_file_counts[*level].saturating_sub(1);
+        }
+        level_file_counts[task.output_level] += task.estimated_output_files;
+    }
+    CompactionPlan {
+        tasks,
+        level_file_counts,
+    }
+}
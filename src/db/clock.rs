@@ -0,0 +1,27 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the wall-clock time the database measures the age of sstables with,
+/// see [crate::db::options::Options::periodic_compaction_seconds]. Tests may replace
+/// it to advance the time at will.
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// Seconds since the UNIX epoch.
+    fn now_secs(&self) -> u64;
+}
+
+/// The clock of the system.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_secs(&self) -> u64 {
+        unix_secs(SystemTime::now())
+    }
+}
+
+/// Seconds from the UNIX epoch to `time`, 0 if `time` is earlier.
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -1,49 +1,48 @@
 use crate::collections::skip_list::skipmap::ReadWriteMode;
+use crate::collections::MergeIterator;
 use crate::db::key_types::InternalKey;
 use crate::db::Value;
+use crate::error::KVLiteError;
 use crate::memory::{MemTableCloneIterator, SkipMapMemTable};
 use crate::sstable::manager::level_iter::{Level0Iterator, MergingIterator};
-use std::cmp::Ordering;
+use crate::Result;
+use std::sync::{Arc, Mutex};
 
 pub type InternalKeyValue = (InternalKey, Value);
 
-#[derive(PartialEq, Eq)]
-pub(crate) struct KeyValueIterItem {
-    pub(crate) key: InternalKey,
-    pub(crate) value: Value,
-    pub(crate) iter_id: usize,
-}
+/// Where the sstable iterators under a [DBIterator] record the first error they meet,
+/// ending their own iteration instead of panicking.
+#[derive(Clone, Default)]
+pub(crate) struct IterErrors(Arc<Mutex<Option<KVLiteError>>>);
 
-impl PartialOrd for KeyValueIterItem {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+impl IterErrors {
+    /// Record `e` unless an earlier error is recorded.
+    pub(crate) fn record(&self, e: KVLiteError) {
+        let mut guard = self.0.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(e);
+        }
     }
-}
 
-impl Ord for KeyValueIterItem {
-    /// Larger iter idx is larger.
-    /// Smaller key is larger.
-    /// [std::collections::BinaryHeap] returns the greatest item
-    fn cmp(&self, other: &Self) -> Ordering {
-        other
-            .key
-            .cmp(&self.key)
-            .then(self.iter_id.cmp(&other.iter_id))
+    fn take(&self) -> Option<KVLiteError> {
+        self.0.lock().unwrap().take()
     }
 }
 
-impl KeyValueIterItem {
-    pub(crate) fn new(key: InternalKey, value: Value, iter_id: usize) -> KeyValueIterItem {
-        KeyValueIterItem {
-            key,
-            value,
-            iter_id,
-        }
-    }
+/// Iterator of all the key-values of the database, see
+/// [crate::db::no_transaction_db::NoTransactionDB::get_db_iterator].
+///
+/// An error of reading an sstable, e.g. a corrupted data block, is yielded in place of
+/// the key-values after it and ends the iteration. The key-values yielded before the
+/// error are correct.
+pub struct DBIterator {
+    inner: MergingIterator<Box<dyn Iterator<Item = InternalKeyValue>>>,
+    errors: IterErrors,
+    failed: bool,
 }
-pub type DBIterator = MergingIterator<Box<dyn Iterator<Item = InternalKeyValue>>>;
 
 impl DBIterator {
+    /// `errors` is where the iterators of the sstables record their errors.
     pub(crate) fn new<
         const RW_MODE: ReadWriteMode,
         M: SkipMapMemTable<InternalKey, Value, { RW_MODE }> + 'static,
@@ -52,12 +51,54 @@ impl DBIterator {
         mut_mem_iterator: MemTableCloneIterator<InternalKey, Value, { RW_MODE }, M>,
         level0_iterator: Level0Iterator,
         mut leveln_iterators: Vec<Box<dyn Iterator<Item = InternalKeyValue>>>,
+        errors: IterErrors,
     ) -> DBIterator {
         leveln_iterators.reverse();
         leveln_iterators.reserve(3);
         leveln_iterators.push(Box::new(level0_iterator));
         leveln_iterators.push(Box::new(imm_mem_iterator));
         leveln_iterators.push(Box::new(mut_mem_iterator));
-        Self::from_iterators(leveln_iterators)
+        DBIterator {
+            inner: MergingIterator::from_iterators(leveln_iterators),
+            errors,
+            failed: false,
+        }
+    }
+}
+
+impl Iterator for DBIterator {
+    type Item = Result<InternalKeyValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        // A source is advanced right after its key-value is yielded, so the error of a
+        // source is recorded after the key-values before it are yielded.
+        let e = match self.errors.take() {
+            Some(e) => e,
+            None => match self.inner.next() {
+                Some(kv) => return Some(Ok(kv)),
+                None => self.errors.take()?,
+            },
+        };
+        self.failed = true;
+        Some(Err(e))
+    }
+}
+
+/// A source of [PinnedIterator], whose key-values are tagged with the priority of the
+/// source. The newer source has the greater priority.
+pub(crate) type PinnedSource = Box<dyn Iterator<Item = (InternalKey, Value, usize)>>;
+
+/// Iterator of the live key-values as of a sequence, see
+/// [crate::db::no_transaction_db::NoTransactionDB::pinned_iter].
+pub struct PinnedIterator(pub(crate) MergeIterator<InternalKey, Value, usize, PinnedSource>);
+
+impl Iterator for PinnedIterator {
+    type Item = InternalKeyValue;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, value, _)| (key, value))
     }
 }
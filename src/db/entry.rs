@@ -0,0 +1,112 @@
+use crate::db::Value;
+use crate::Result;
+use std::any::Any;
+use std::cell::Cell;
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
+
+/// Given the current value of the key, `None` if it is absent or removed, return the
+/// value to write, `None` if nothing is written, and the final value of the key.
+pub(crate) type EntryUpdate<'a> =
+    Box<dyn FnOnce(Option<Value>) -> (Option<Value>, Option<Value>) + 'a>;
+
+enum Step<'a> {
+    OrInsertWith(Box<dyn FnOnce() -> Value + 'a>),
+    AndModify(Box<dyn FnOnce(&mut Value) + 'a>),
+}
+
+/// A key of the database to read and conditionally modify, returned by
+/// [crate::db::DB::entry].
+///
+/// Like [std::collections::hash_map::Entry], but the steps are only collected until
+/// [Entry::apply], which runs all of them on the current value of the key and writes
+/// the result atomically: no other writer interleaves between the read and the write.
+///
+/// The steps run while the other writers of the database are blocked, so they should
+/// be quick and must not block, e.g. on I/O or on a lock held by a writer, and must
+/// not write to the database, which deadlocks.
+pub struct Entry<'a> {
+    write: Box<dyn FnOnce(EntryUpdate<'a>) -> Result<Option<Value>> + 'a>,
+    steps: Vec<Step<'a>>,
+}
+
+impl<'a> Entry<'a> {
+    /// `write` passes the current value of the key to the update, and writes the value it
+    /// returns while blocking the other writers.
+    pub(crate) fn new(
+        write: impl FnOnce(EntryUpdate<'a>) -> Result<Option<Value>> + 'a,
+    ) -> Entry<'a> {
+        Entry {
+            write: Box::new(write),
+            steps: vec![],
+        }
+    }
+
+    /// Insert the value returned by `default` if the key is absent at this step.
+    pub fn or_insert_with(mut self, default: impl FnOnce() -> Value + 'a) -> Entry<'a> {
+        self.steps.push(Step::OrInsertWith(Box::new(default)));
+        self
+    }
+
+    /// Insert `default` if the key is absent at this step.
+    pub fn or_insert(self, default: Value) -> Entry<'a> {
+        self.or_insert_with(move || default)
+    }
+
+    /// Modify the value with `f` if the key is present at this step, which includes
+    /// a value inserted by an earlier step.
+    pub fn and_modify(mut self, f: impl FnOnce(&mut Value) + 'a) -> Entry<'a> {
+        self.steps.push(Step::AndModify(Box::new(f)));
+        self
+    }
+
+    /// Run the steps in order and write the result if it differs from the current value.
+    /// Return the final value of the key, `None` if it is absent.
+    ///
+    /// An empty value is a tombstone, so modifying the value to be empty removes the key.
+    /// If a step panics, nothing is written, and the panic is resumed once the other
+    /// writers are unblocked.
+    pub fn apply(self) -> Result<Option<Value>> {
+        let steps = self.steps;
+        let panicked: Rc<Cell<Option<Box<dyn Any + Send>>>> = Rc::default();
+        let panicked_in_update = panicked.clone();
+        let result = (self.write)(Box::new(move |current| {
+            let run_steps = AssertUnwindSafe(|| {
+                let mut value = current.clone();
+                for step in steps {
+                    match step {
+                        Step::OrInsertWith(default) => {
+                            if value.is_none() {
+                                value = Some(default());
+                            }
+                        }
+                        Step::AndModify(f) => {
+                            if let Some(v) = value.as_mut() {
+                                f(v);
+                            }
+                        }
+                    }
+                }
+                value
+            });
+            // a panic must not poison the lock blocking the other writers
+            let value = match std::panic::catch_unwind(run_steps) {
+                Ok(value) => value,
+                Err(payload) => {
+                    panicked_in_update.set(Some(payload));
+                    return (None, None);
+                }
+            };
+            let new = if value == current {
+                None
+            } else {
+                value.clone()
+            };
+            (new, value.filter(|v| !v.is_empty()))
+        }));
+        if let Some(payload) = panicked.take() {
+            std::panic::resume_unwind(payload);
+        }
+        result
+    }
+}
@@ -83,6 +83,11 @@ impl MemKey for I32UserKey {
 pub type LSN = u64;
 
 /// User key with log sequence number(LSN)
+///
+/// Ordered by the user key, then by the LSN ascending, so the newest version of a user key
+/// visible at `lsn` is the last one less than or equal to `LSNKey(user_key, lsn)`. Only the
+/// memory tables hold LSN keys; the LSN is dropped when a key is written to sstables, which
+/// keep only the latest version of each user key.
 #[derive(PartialEq, Eq, Default, Clone)]
 pub struct LSNKey<UK: MemKey> {
     user_key: UK,
@@ -1,15 +1,53 @@
+//! # Directory layout
+//!
+//! ```text
+//! <db_path>/
+//! +-- LOCK          locked while the database is opened, see [crate::ioutils::LOCK_FILE]
+//! +-- TABLE_ID      upper bound of the allocated table ids, see [crate::sstable::manager::table_id]
+//! +-- SEQUENCE      upper bound of the allocated sequence numbers, see [crate::db::sequence]
+//! +-- DICTIONARY    dictionary of the compressed values, see [crate::sstable::dictionary]
+//! +-- log/          write-ahead logs, see [crate::wal::LOG_DIR]
+//! |   +-- 0.log     log of the immutable memory table
+//! |   +-- 1.log     log of the mutable memory table
+//! +-- 0/            sstables of level 0, see [crate::sstable::level_dir]
+//! |   +-- <id>      sstable named by its table id, see [crate::sstable::sstable_file]
+//! |   +-- <id>_write  sstable being written
+//! +-- 1/ ... 7/     sstables of level 1 to [MAX_LEVEL]
+//! +-- lost/         unreadable sstables moved away by [DB::repair]
+//! +-- vlog/         values moved out of the sstables, see [crate::sstable::value_log]
+//! |   +-- <id>      value log of the sstable of the same id
+//! ```
+//!
+//! Table ids are allocated from one increasing counter shared by all the levels,
+//! so they are unique in the database and never reused.
+
 use crate::collections::skip_list::skipmap::SrSwSkipMap;
+use crate::compaction::plan::CompactionPlan;
+use crate::db::entry::Entry;
 use crate::db::key_types::MemKey;
-use crate::db::options::WriteOptions;
+use crate::db::latency::LatencyStats;
+use crate::db::options::{Options, WriteOptions};
+use crate::db::stats::{LevelInfo, MemoryUsage, Stats};
+use crate::error::KVLiteError;
 use crate::memory::MemTable;
+use crate::sstable::verify::VerifyReport;
 use crate::Result;
+use crossbeam_channel::Receiver;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+pub mod clock;
 pub mod db_iter;
+pub mod entry;
 pub mod key_types;
+pub mod latency;
 pub mod no_transaction_db;
 pub mod options;
+pub mod sequence;
+pub mod stats;
 pub mod transaction;
+pub mod write_batch;
 
 pub const WRITE_BUFFER_SIZE: u64 = 4 * 1024 * 1024;
 pub const MAX_LEVEL: usize = 7;
@@ -26,6 +64,55 @@ pub(crate) const fn max_level_shift() -> usize {
 
 pub type Value = Vec<u8>;
 
+/// Drop the keys whose newest version in `kvs` is a tombstone (an empty value),
+/// after all the layers of a range query are merged into `kvs`.
+pub(crate) fn remove_tombstones<K: Ord + Default + Clone>(kvs: &mut SrSwSkipMap<K, Value>) {
+    let deleted: Vec<K> = kvs
+        .iter()
+        .filter(|(_, v)| v.is_empty())
+        .map(|(k, _)| k.clone())
+        .collect();
+    for key in deleted {
+        kvs.remove(key);
+    }
+}
+
+/// Set when a background task of the database panics.
+/// Once set, the database rejects all the reads and writes.
+#[derive(Default)]
+pub(crate) struct FailureFlag(AtomicBool);
+
+impl FailureFlag {
+    #[inline]
+    pub(crate) fn set(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    #[inline]
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.0.load(Ordering::Acquire) {
+            Err(KVLiteError::BackgroundFailure)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Set the [FailureFlag] if the thread holding it is unwinding.
+pub(crate) struct PanicGuard(pub(crate) Arc<FailureFlag>);
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            error!(
+                "background thread `{}` panicked",
+                std::thread::current().name().unwrap_or("unnamed")
+            );
+            self.0.set();
+        }
+    }
+}
+
 pub trait DBCommand<SK: MemKey, UK: MemKey> {
     fn range_get(&self, key_start: &SK, key_end: &SK, kvs: &mut SrSwSkipMap<UK, Value>)
     where
@@ -33,16 +120,164 @@ pub trait DBCommand<SK: MemKey, UK: MemKey> {
         UK: From<SK>;
     fn get(&self, key: &SK) -> crate::Result<Option<Value>>;
     fn set(&self, key: SK, value: Value) -> crate::Result<()>;
+    /// Insert a tombstone (empty value) of `key`.
+    /// Removing an absent key is not an error, because the key may exist in sstables.
     fn remove(&self, key: SK) -> crate::Result<()>;
 }
 
 pub trait DB<SK: MemKey, UK: MemKey, M: MemTable<SK, UK>>: Sized {
-    fn open(db_path: impl AsRef<Path>) -> Result<Self>;
+    fn open(db_path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_options(db_path, Options::default())
+    }
+    fn open_with_options(db_path: impl AsRef<Path>, options: Options) -> Result<Self>;
     fn get(&self, key: &SK) -> Result<Option<Value>>;
     fn set(&self, write_options: &WriteOptions, key: SK, value: Value) -> Result<()>;
+    /// Like [DB::set], but return `Ok(false)` without writing, instead of waiting, if
+    /// the writes are stalled by level 0 or another writer holds the write lock.
+    /// It lets latency-sensitive callers shed load rather than block.
+    fn try_set(&self, write_options: &WriteOptions, key: SK, value: Value) -> Result<bool>;
+    /// Remove `key` from database. Return `Ok(())` whether `key` exists or not.
     fn remove(&self, write_options: &WriteOptions, key: SK) -> Result<()>;
+    /// Write `new` to `key` only if its current value equals `expected`, where `None`
+    /// means the key is absent or removed. Return whether `new` is written.
+    ///
+    /// The check and the write are atomic with respect to the other writers.
+    fn compare_and_swap(
+        &self,
+        write_options: &WriteOptions,
+        key: SK,
+        expected: Option<&Value>,
+        new: Value,
+    ) -> Result<bool>;
+    /// Add `delta` to the value of `key`, which is a little-endian `i64`, or 0 if the key
+    /// is absent or removed. Return the new value, which wraps around on overflow.
+    ///
+    /// Like [DB::compare_and_swap], it is atomic with respect to the other writers.
+    /// Fail if the current value is not 8 bytes long.
+    fn fetch_add(&self, write_options: &WriteOptions, key: SK, delta: i64) -> Result<i64>;
+    /// Get the [Entry] of `key` to insert a default value if it is absent, or modify its
+    /// value, e.g. for counters. Like [DB::compare_and_swap], the steps of the entry are
+    /// atomic with respect to the other writers, without retrying.
+    fn entry<'a>(&'a self, write_options: &'a WriteOptions, key: SK) -> Entry<'a>;
     fn range_get(&self, key_start: &SK, key_end: &SK) -> Result<SrSwSkipMap<UK, Value>>
     where
         UK: From<SK>;
+    /// Like [DB::range_get], but the key-values are in descending order of keys.
+    fn range_get_rev(&self, key_start: &SK, key_end: &SK) -> Result<Vec<(UK, Value)>>
+    where
+        UK: From<SK>,
+    {
+        let mut kvs: Vec<(UK, Value)> = self.range_get(key_start, key_end)?.into_iter().collect();
+        kvs.reverse();
+        Ok(kvs)
+    }
+    /// Approximate count of the keys in [`key_start`, `key_end`), e.g. for query planning.
+    ///
+    /// The keys of the memory tables are counted, while the keys of the sstables are
+    /// estimated from their index blocks without reading any data block. A key is counted
+    /// once for each layer holding a version of it, and tombstones are counted too, so
+    /// the estimate exceeds the live count after overwrites and removals.
+    fn estimate_count_in_range(&self, key_start: &SK, key_end: &SK) -> Result<u64>;
     fn db_path(&self) -> &String;
+    /// Subscribe to the writes whose keys start with `prefix`.
+    ///
+    /// Each committed `set` is delivered as `(key, Some(value))` and each `remove`
+    /// as `(key, None)`, in commit order. The channel is bounded, and the writers never
+    /// wait for a subscriber: if it lags so far behind that the channel is full, it is
+    /// unsubscribed, and its receiver disconnects once the queued writes are received.
+    /// Subscribe again to resume from the current writes. Drop the receiver to unsubscribe.
+    fn subscribe(&self, prefix: &[u8]) -> Receiver<(SK, Option<Value>)>;
+    /// Write key-value pairs directly into sstables of the last level,
+    /// bypassing WAL and memory tables. If the key range of any of the sstables overlaps
+    /// with a table of the last level, they are all written into level 0 instead.
+    ///
+    /// Keys of `sorted` should be strictly increasing and must not overlap with
+    /// any existing key of the database, or else nothing is ingested and an error is returned.
+    fn bulk_ingest(&self, sorted: impl Iterator<Item = (SK, Value)>) -> Result<()>;
+    /// Return the compactions which would be performed on the current sstables,
+    /// without performing any of them.
+    fn plan_compaction(&self) -> CompactionPlan;
+    /// Write the memory tables to sstables. When it returns, the written sstables and
+    /// their directory entries are synced to disk, and the flushed writes are no longer
+    /// recovered from WAL.
+    fn flush(&self) -> Result<()>;
+    /// Remove all the key-values, leaving the database empty and usable: the memory
+    /// tables are emptied, the sstables and the value log files are removed, and the
+    /// write-ahead logs are truncated.
+    ///
+    /// Writers are blocked until it returns. It waits for the running flush and
+    /// compactions to finish, and the reads meanwhile see the database either before
+    /// or after it is cleared. Table ids keep increasing, so that the files of the
+    /// removed sstables still read by pinned iterators are never reused.
+    fn clear(&self) -> Result<()>;
+    /// Bytes read and written since the database is opened, used for measuring
+    /// read and write amplification.
+    fn stats(&self) -> Stats;
+    /// Latency percentiles of `get`, `set`, `range_get` and compaction since the database
+    /// is opened. Unlike [DB::stats], they are only recorded with the `latency_stats`
+    /// feature, which is enabled by default.
+    fn latency_stats(&self) -> LatencyStats;
+    /// Stop or resume appending the writes to the write-ahead log, e.g. to save half of
+    /// the I/O of an initial bulk load which can simply be run again.
+    ///
+    /// While it is disabled, the writes go straight to the memory table, and a crash
+    /// loses all of them that are not flushed yet. Re-enabling it makes the following
+    /// writes durable again, but not the ones written meanwhile, so call [DB::flush]
+    /// before re-enabling it to keep them.
+    fn set_wal_enabled(&self, enabled: bool);
+    /// Load the filters, the indexes and the data blocks that reading `keys` needs into
+    /// the caches, so that the following reads of them do not wait for the disk, e.g.
+    /// right after opening the database. Return how many of `keys` are warmed.
+    ///
+    /// Warming never evicts a cached entry: it stops at the first key whose sstables
+    /// the caches have no room for. Keys still in the memory tables need no warming.
+    fn warm_cache(&self, keys: &[SK]) -> Result<usize>;
+    /// Pin the data blocks of the sstables which may hold keys in [`key_start`, `key_end`]
+    /// in [Options::block_cache], so that they stay cached however much else is read,
+    /// e.g. for hot metadata. The blocks not cached yet are read first. Pins are counted,
+    /// so overlapping ranges can be pinned and unpinned independently.
+    ///
+    /// Only the blocks of the sstables existing now are pinned, not the ones flush and
+    /// compaction write later, and the pins of an sstable go away when it is deleted.
+    /// Return [crate::error::KVLiteError::CacheBudgetExceeded] without pinning anything
+    /// if the cache has no room for more pinned blocks, or an error if there is no
+    /// block cache.
+    fn pin_range(&self, key_start: &SK, key_end: &SK) -> Result<()>;
+    /// Undo the oldest [DB::pin_range] of the same range not undone yet. Does nothing if
+    /// there is none.
+    fn unpin_range(&self, key_start: &SK, key_end: &SK) -> Result<()>;
+    /// Bytes held in memory by the memory tables, the table cache and the filters,
+    /// for enforcing a memory budget of the host.
+    fn memory_usage(&self) -> MemoryUsage;
+    /// Number of the sstable files kept open for reading, which is at most
+    /// [crate::db::options::Options::max_open_files]. Reads in progress may hold a few
+    /// more files, which are closed once they are done. Always 0 if the number of open
+    /// files is not capped, since every read opens and closes its own file then.
+    fn open_table_files(&self) -> usize;
+    /// The sstables of every level, indexed by level from 0 to [MAX_LEVEL]. Tables
+    /// being written are not listed, while the inputs of a running compaction are
+    /// listed until the compaction installs its output.
+    fn levels(&self) -> Vec<LevelInfo>;
+    /// Read every live sstable and check that it is well-formed, that its keys are
+    /// sorted and within the range recorded in its index, and that the sstables of each
+    /// level other than level 0 do not overlap. All the anomalies found are reported
+    /// rather than only the first.
+    ///
+    /// Sstables have no checksums yet, so corruptions that keep the blocks parsable
+    /// and the keys sorted are not detected.
+    fn verify(&self) -> Result<VerifyReport>;
+    /// Rebuild the metadata of a closed database from the sstables that survive, if
+    /// [crate::sstable::manager::table_id::TABLE_ID_FILE] is lost or the sstables of a
+    /// level overlap, e.g. after files are restored by hand.
+    ///
+    /// The footer and the index of every sstable are read. The unreadable sstables are
+    /// moved to [crate::sstable::repair::LOST_DIR]. The sstables that overlap with others
+    /// in the same level are moved to level 0, where the newer table id wins, together
+    /// with the sstables of shallower levels overlapping with them. The sstables in
+    /// level 0 are renumbered so that a shallower sstable still wins, and are
+    /// reorganized by the compaction after the database is opened. The write-ahead
+    /// logs are left for opening to replay.
+    fn repair(db_path: impl AsRef<Path>) -> Result<()> {
+        crate::sstable::repair::repair(db_path.as_ref())
+    }
 }
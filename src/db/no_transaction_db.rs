@@ -1,20 +1,49 @@
-use crate::cache::ShardLRUCache;
+use crate::cache::{ShardLRUCache, NUM_SHARD};
 use crate::collections::skip_list::skipmap::{ReadWriteMode, SrSwSkipMap};
-use crate::db::db_iter::DBIterator;
+use crate::collections::MergeIterator;
+use crate::compaction::plan::{plan_compaction, CompactionPlan};
+use crate::db::db_iter::{DBIterator, InternalKeyValue, IterErrors, PinnedIterator, PinnedSource};
+use crate::db::entry::Entry;
 use crate::db::key_types::{InternalKey, MemKey};
-use crate::db::options::WriteOptions;
-use crate::db::{Value, DB, WRITE_BUFFER_SIZE};
+use crate::db::latency::{LatencyStats, LatencyTimer};
+use crate::db::options::{Options, WalSync, WriteOptions, WRITE_SLOWDOWN_DELAY};
+use crate::db::stats::{LevelInfo, MemoryUsage, Stats, StatsCounters, TableInfo};
+use crate::db::write_batch::WriteBatch;
+use crate::db::{remove_tombstones, PanicGuard, Value, DB, MAX_LEVEL, WRITE_BUFFER_SIZE};
+use crate::error::KVLiteError;
+use crate::ioutils::lock_dir;
 use crate::memory::{MemTable, MemTableCloneIterator, SkipMapMemTable};
 use crate::sstable::manager::level_0::Level0Manager;
 use crate::sstable::manager::level_n::LevelNManager;
-use crate::wal::WAL;
+use crate::sstable::table_handle::{TableReadHandle, TableWriteHandle};
+use crate::sstable::value_log::{list_value_logs, value_log_dir, value_log_file, StoredValue};
+use crate::sstable::verify::{verify, VerifyReport};
+use crate::sstable::TableID;
+use crate::wal::{ReplayDigest, WAL};
 use crate::Result;
 use arc_swap::ArcSwap;
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use fs2::FileExt;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::num::NonZeroUsize;
 use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, TryLockError};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Where a tombstone yielded by [NoTransactionDB::debug_tombstones] is found.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TombstoneSource {
+    /// The mutable or the immutable memory table.
+    MemTable,
+    /// An sstable of the level.
+    Level(usize),
+}
 
 pub struct NoTransactionDB<
     SK: MemKey + 'static,
@@ -33,8 +62,44 @@ pub struct NoTransactionDB<
     level0_writer_handle: Option<JoinHandle<()>>,
     write_level0_channel: Option<Sender<()>>,
     background_task_write_to_level0_is_running: Arc<AtomicBool>,
+
+    wal_syncer_handle: Option<JoinHandle<()>>,
+    wal_syncer_channel: Option<Sender<()>>,
+
+    /// Sequence of the last write applied to the memory table, advanced under the WAL
+    /// lock. See [NoTransactionDB::pinned_iter].
+    last_sequence: AtomicU64,
+
+    /// Count of the snapshots and transactions of a
+    /// [crate::db::transaction::write_committed::WriteCommittedDB] reading the memory
+    /// tables, or of the prepared transactions of a
+    /// [crate::db::transaction::write_prepared::WritePreparedDB], which keep them from
+    /// being frozen, see [NoTransactionDB::may_freeze].
+    pub(crate) num_lsn_acquired: AtomicU64,
+
+    /// Whether the writes are appended to the WAL, see [DB::set_wal_enabled].
+    wal_enabled: AtomicBool,
+
+    /// Held for reading by point and range reads, and for writing by [DB::clear] while
+    /// it removes the sstables and empties the memory tables, so that no read sees a
+    /// partly cleared database.
+    clear_lock: RwLock<()>,
+
+    /// Ranges pinned by [DB::pin_range], with the `(table id, offset)` of their blocks.
+    pinned_ranges: Mutex<Vec<(InternalKey, InternalKey, Vec<(TableID, u32)>)>>,
+
+    /// Key prefixes and channels of subscribers.
+    subscribers: Mutex<Vec<(Vec<u8>, Sender<(SK, Option<Value>)>)>>,
+
+    /// Exclusive lock of the database directory, released after the background
+    /// tasks are stopped when the database is dropped.
+    /// `None` if the database is in memory, see [Options::in_memory].
+    lock_file: Option<File>,
 }
 
+/// Capacity of the channel of each subscriber.
+const SUBSCRIBER_CHANNEL_CAPACITY: usize = 1024;
+
 impl<SK, UK, M, L> DB<SK, UK, M> for NoTransactionDB<SK, UK, M, L>
 where
     SK: MemKey + 'static,
@@ -42,17 +107,39 @@ where
     M: MemTable<SK, UK> + 'static,
     L: WAL<SK, UK> + 'static,
 {
-    fn open(db_path: impl AsRef<Path>) -> Result<Self> {
+    fn open_with_options(db_path: impl AsRef<Path>, options: Options) -> Result<Self> {
+        // each shard of the file cache keeps at least one file open
+        if options.max_open_files != 0 && options.max_open_files < NUM_SHARD {
+            return Err(KVLiteError::Custom(format!(
+                "max_open_files of {} is less than the {} shards of the file cache",
+                options.max_open_files, NUM_SHARD
+            )));
+        }
+        let lock_file = if options.in_memory {
+            None
+        } else {
+            Some(Self::lock_db_path(db_path.as_ref(), &options)?)
+        };
         let db_path = db_path.as_ref().as_os_str().to_str().unwrap().to_string();
 
+        let warm_cache_on_open = options.warm_cache_on_open;
+        let verify_recovery = options.verify_recovery;
         let index_cache = Arc::new(ShardLRUCache::default());
-        let leveln_manager = LevelNManager::open_tables(db_path.clone(), index_cache.clone());
+        let leveln_manager =
+            LevelNManager::open_tables(db_path.clone(), options.clone(), index_cache.clone());
 
         let mut mut_mem_table = M::default();
 
-        let wal = Arc::new(Mutex::new(
-            L::open_and_load_logs(&db_path, &mut mut_mem_table).unwrap(),
-        ));
+        let wal = if verify_recovery {
+            let mut digest = ReplayDigest::default();
+            let wal =
+                L::open_and_replay_logs(&db_path, &mut mut_mem_table, Some(&mut digest)).unwrap();
+            digest.verify(&mut_mem_table)?;
+            wal
+        } else {
+            L::open_and_load_logs(&db_path, &mut mut_mem_table).unwrap()
+        };
+        let wal = Arc::new(Mutex::new(wal));
 
         let imm_mem_table = Arc::new(ArcSwap::new(Arc::new(M::default())));
         let channel = crossbeam_channel::unbounded();
@@ -69,7 +156,21 @@ where
                 background_task_write_to_level0_is_running.clone(),
             );
 
-        Ok(NoTransactionDB {
+        let (wal_syncer_handle, wal_syncer_channel) = match options.wal_sync {
+            WalSync::PerWrite => (None, None),
+            WalSync::Interval(interval) => {
+                let (sender, receiver) = crossbeam_channel::bounded(0);
+                let handle = Self::start_task_sync_wal(
+                    wal.clone(),
+                    interval,
+                    receiver,
+                    PanicGuard(leveln_manager.failure_flag().clone()),
+                );
+                (Some(handle), Some(sender))
+            }
+        };
+
+        let db = NoTransactionDB {
             db_path,
             wal,
             mut_mem_table: ArcSwap::new(Arc::new(mut_mem_table)),
@@ -79,655 +180,4742 @@ where
             level0_writer_handle: Some(level0_writer_handle),
             write_level0_channel: Some(channel.0),
             background_task_write_to_level0_is_running,
-        })
+            wal_syncer_handle,
+            wal_syncer_channel,
+            last_sequence: AtomicU64::default(),
+            num_lsn_acquired: AtomicU64::default(),
+            clear_lock: RwLock::new(()),
+            wal_enabled: AtomicBool::new(true),
+            pinned_ranges: Mutex::default(),
+            subscribers: Mutex::default(),
+            lock_file,
+        };
+        if warm_cache_on_open {
+            db.warm_table_caches();
+        }
+        Ok(db)
     }
 
     fn get(&self, key: &SK) -> Result<Option<Value>> {
-        match self.query(key)? {
+        self.leveln_manager.failure_flag().check()?;
+        let timer = LatencyTimer::start();
+        let clear_guard = self.clear_lock.read().unwrap();
+        let value = self.query(key)?;
+        drop(clear_guard);
+        let result = match value {
             Some(v) => {
                 if v.is_empty() {
-                    Ok(None)
+                    None
                 } else {
-                    Ok(Some(v))
+                    StatsCounters::add(
+                        &self.leveln_manager.stats().user_bytes_read,
+                        key.internal_key().len() + v.len(),
+                    );
+                    Some(v)
                 }
             }
-            None => Ok(None),
-        }
+            None => None,
+        };
+        timer.record(&self.leveln_manager.stats().latency.get);
+        Ok(result)
     }
 
     fn set(&self, write_options: &WriteOptions, key: SK, value: Value) -> Result<()> {
-        {
-            let mut wal_guard = self.wal.lock().unwrap();
-            wal_guard.append(write_options, &key, Some(&value))?;
-        }
-
-        let mut_mem_table = self.get_mut_mem_table();
-        mut_mem_table.set(key, value)?;
-        if self.should_freeze(mut_mem_table.approximate_memory_usage()) {
-            self.freeze();
-        }
+        self.leveln_manager.failure_flag().check()?;
+        self.check_key_size(key.internal_key())?;
+        self.check_value_size(&value)?;
+        let timer = LatencyTimer::start();
+        self.stall_writes()?;
+        self.set_locked(self.lock_wal()?, write_options, key, value)?;
+        timer.record(&self.leveln_manager.stats().latency.set);
         Ok(())
     }
 
-    fn remove(&self, write_options: &WriteOptions, key: SK) -> Result<()> {
-        {
-            let mut wal_guard = self.wal.lock().unwrap();
-            wal_guard.append(write_options, &key, None)?;
+    fn try_set(&self, write_options: &WriteOptions, key: SK, value: Value) -> Result<bool> {
+        self.leveln_manager.failure_flag().check()?;
+        self.check_key_size(key.internal_key())?;
+        self.check_value_size(&value)?;
+        if self.write_stalled() {
+            return Ok(false);
         }
+        let wal_guard = match self.wal.try_lock() {
+            Ok(wal_guard) => wal_guard,
+            Err(TryLockError::WouldBlock) => return Ok(false),
+            Err(TryLockError::Poisoned(_)) => return Err(KVLiteError::BackgroundFailure),
+        };
+        self.set_locked(wal_guard, write_options, key, value)?;
+        Ok(true)
+    }
 
-        let mut_mem_table = self.get_mut_mem_table();
-        mut_mem_table.remove(key)?;
+    fn remove(&self, write_options: &WriteOptions, key: SK) -> Result<()> {
+        self.leveln_manager.failure_flag().check()?;
+        self.check_key_size(key.internal_key())?;
+        self.stall_writes()?;
+        let bytes_written = key.internal_key().len();
+        let mut_mem_table = {
+            let mut wal_guard = self.lock_wal()?;
+            if self.wal_enabled() {
+                wal_guard.append(write_options, &key, None)?;
+            }
+            self.publish(&key, None);
+            let mut_mem_table = self.get_mut_mem_table();
+            mut_mem_table.remove(key)?;
+            self.advance_sequence();
+            mut_mem_table
+        };
+        self.add_user_bytes_written(bytes_written);
 
-        if self.should_freeze(mut_mem_table.approximate_memory_usage()) {
-            self.freeze();
-        }
+        self.may_freeze(&mut_mem_table);
         Ok(())
     }
 
-    fn range_get(&self, key_start: &SK, key_end: &SK) -> Result<SrSwSkipMap<UK, Value>> {
-        let mut skip_map = SrSwSkipMap::new();
-        self.leveln_manager.range_query(
-            key_start.internal_key(),
-            key_end.internal_key(),
-            &mut skip_map,
-        );
-        self.level0_manager.range_query(
-            key_start.internal_key(),
-            key_end.internal_key(),
-            &mut skip_map,
-        );
+    fn compare_and_swap(
+        &self,
+        write_options: &WriteOptions,
+        key: SK,
+        expected: Option<&Value>,
+        new: Value,
+    ) -> Result<bool> {
+        self.compare_and_swap_with(write_options, || key, expected, new)
+    }
 
-        let imm_mem_table = self.get_imm_mem_table();
-        imm_mem_table.range_get(key_start, key_end, &mut skip_map);
+    fn fetch_add(&self, write_options: &WriteOptions, key: SK, delta: i64) -> Result<i64> {
+        self.fetch_add_with(write_options, || key, delta)
+    }
 
-        let mut_mem_table = self.get_mut_mem_table();
-        mut_mem_table.range_get(key_start, key_end, &mut skip_map);
+    fn entry<'a>(&'a self, write_options: &'a WriteOptions, key: SK) -> Entry<'a> {
+        self.entry_with(write_options, || key)
+    }
+
+    fn range_get(&self, key_start: &SK, key_end: &SK) -> Result<SrSwSkipMap<UK, Value>> {
+        let timer = LatencyTimer::start();
+        let mut skip_map = self.range_get_with_tombstones(key_start, key_end, &[])?;
+        remove_tombstones(&mut skip_map);
+        self.add_user_bytes_read(&skip_map);
+        timer.record(&self.leveln_manager.stats().latency.range_get);
         Ok(skip_map)
     }
 
+    fn estimate_count_in_range(&self, key_start: &SK, key_end: &SK) -> Result<u64> {
+        let (key_start, key_end) = (key_start.internal_key(), key_end.internal_key());
+        let mut count = 0;
+        for mem_table in [self.get_imm_mem_table(), self.get_mut_mem_table()] {
+            count += mem_table
+                .kv_iter_from(key_start)
+                .take_while(|(k, _)| *k < key_end)
+                .count() as u64;
+        }
+        for table in self.list_tables() {
+            count += table.estimate_count_in_range(
+                key_start,
+                key_end,
+                &self.leveln_manager.index_cache,
+            )?;
+        }
+        Ok(count)
+    }
+
     fn db_path(&self) -> &String {
         &self.db_path
     }
-}
 
-impl<SK, UK, M, L: 'static> NoTransactionDB<SK, UK, M, L>
-where
-    SK: MemKey + 'static,
-    UK: MemKey,
-    M: MemTable<SK, UK> + 'static,
-    L: WAL<SK, UK>,
-{
-    pub(crate) fn should_freeze(&self, table_size: u64) -> bool {
-        table_size >= WRITE_BUFFER_SIZE
-            && !self
-                .background_task_write_to_level0_is_running
-                .load(Ordering::Acquire)
-    }
+    fn bulk_ingest(&self, sorted: impl Iterator<Item = (SK, Value)>) -> Result<()> {
+        self.leveln_manager.failure_flag().check()?;
 
-    pub(crate) fn freeze(&self) {
-        self.background_task_write_to_level0_is_running
-            .store(true, Ordering::Release);
-        {
-            // new log before writing to level0 sstable
-            let mut wal_guard = self.wal.lock().unwrap();
-            wal_guard.freeze_mut_log().unwrap();
+        let mut handles = vec![];
+        let result = match self.write_ingested_tables(sorted, &mut handles) {
+            Ok(Some((min_key, max_key))) => {
+                // a newer tombstone would hide the ingested value
+                let existing =
+                    self.range_get_with_tombstones(&SK::from(min_key), &SK::from(max_key), &[])?;
+                if existing.is_empty() {
+                    Ok(())
+                } else {
+                    Err(KVLiteError::Custom(
+                        "ingested keys overlap with existing keys".into(),
+                    ))
+                }
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        if result.is_ok() {
+            if let Err(handles) = self.leveln_manager.install_disjoint_table_handles(handles) {
+                // the ingested keys fall in the gaps of the tables of the last level
+                return self.level0_manager.ingest_table_handles(handles);
+            }
+        } else {
+            handles.into_iter().for_each(TableWriteHandle::discard);
         }
+        result
+    }
 
-        let imm = self.mut_mem_table.swap(Arc::new(M::default()));
-        self.imm_mem_table.store(imm);
+    fn subscribe(&self, prefix: &[u8]) -> Receiver<(SK, Option<Value>)> {
+        let (sender, receiver) = crossbeam_channel::bounded(SUBSCRIBER_CHANNEL_CAPACITY);
+        let mut guard = self.subscribers.lock().unwrap();
+        guard.push((prefix.to_vec(), sender));
+        receiver
+    }
 
-        if let Some(chan) = &self.write_level0_channel {
-            if let Err(e) = chan.send(()) {
-                warn!("{}", e);
+    fn plan_compaction(&self) -> CompactionPlan {
+        plan_compaction(&self.level0_manager, &self.leveln_manager)
+    }
+
+    fn flush(&self) -> Result<()> {
+        // The immutable memory table may be being written, and a writer may freeze the
+        // mutable one before this does.
+        loop {
+            self.wait_for_level0_writer()?;
+            if self.freeze() {
+                break;
             }
         }
+        self.wait_for_level0_writer()
     }
 
-    pub(crate) fn get_mut_mem_table(&self) -> Arc<M> {
-        let guard = self.mut_mem_table.load();
-        guard.clone()
+    fn clear(&self) -> Result<()> {
+        self.leveln_manager.failure_flag().check()?;
+        // A freeze sets the flag under the WAL lock, and the level 0 writer clears it
+        // after removing the log of the immutable memory table.
+        let mut wal_guard = loop {
+            self.wait_for_level0_writer()?;
+            let wal_guard = self.lock_wal()?;
+            if !self
+                .background_task_write_to_level0_is_running
+                .load(Ordering::Acquire)
+            {
+                break wal_guard;
+            }
+        };
+        let tables = self.claim_all_tables()?;
+        {
+            let _clear_guard = self.clear_lock.write().unwrap();
+            for table in tables {
+                if table.level() == 0 {
+                    self.level0_manager.ready_to_delete(table.table_id());
+                } else {
+                    self.leveln_manager.ready_to_delete(table);
+                }
+            }
+            self.imm_mem_table.store(Arc::new(M::default()));
+            self.mut_mem_table.store(Arc::new(M::default()));
+            wal_guard.freeze_mut_log()?;
+            wal_guard.clear_imm_log()?;
+            self.last_sequence.store(0, Ordering::Release);
+        }
+        drop(wal_guard);
+        self.collect_value_log_garbage()?;
+        Ok(())
     }
 
-    pub(crate) fn get_imm_mem_table(&self) -> Arc<M> {
-        let guard = self.imm_mem_table.load();
-        guard.clone()
+    fn stats(&self) -> Stats {
+        let wal_bytes_written = {
+            let wal_guard = self.wal.lock().unwrap_or_else(PoisonError::into_inner);
+            wal_guard.bytes_written()
+        };
+        self.leveln_manager.stats().snapshot(wal_bytes_written)
     }
 
-    fn query(&self, key: &SK) -> Result<Option<Value>> {
-        // query mutable memory table
-        {
-            let mut_mem = self.get_mut_mem_table();
-            let option = mut_mem.get(key)?;
-            if option.is_some() {
-                return Ok(option);
+    fn latency_stats(&self) -> LatencyStats {
+        self.leveln_manager.stats().latency.snapshot()
+    }
+
+    fn set_wal_enabled(&self, enabled: bool) {
+        // switched under the WAL lock, so no write is half logged
+        let _wal_guard = self.wal.lock().unwrap_or_else(PoisonError::into_inner);
+        self.wal_enabled.store(enabled, Ordering::Release);
+    }
+
+    fn warm_cache(&self, keys: &[SK]) -> Result<usize> {
+        self.leveln_manager.failure_flag().check()?;
+        for (i, key) in keys.iter().enumerate() {
+            let key = key.internal_key();
+            let mut tables = self.level0_manager.tables_may_contain(key);
+            tables.extend(self.leveln_manager.tables_may_contain(key));
+            if !tables
+                .iter()
+                .all(|table| self.leveln_manager.cache_has_room(table))
+            {
+                return Ok(i);
+            }
+            // read like a query, which stops at the newest sstable containing the key
+            if self.level0_manager.query(key)?.is_none() {
+                self.leveln_manager.query(key)?;
             }
         }
+        Ok(keys.len())
+    }
 
-        // query immutable memory table
-        {
-            let imm_mem = self.get_imm_mem_table();
-            let option = imm_mem.get(key)?;
-            if option.is_some() {
-                return Ok(option);
+    fn pin_range(&self, key_start: &SK, key_end: &SK) -> Result<()> {
+        self.leveln_manager.failure_flag().check()?;
+        let block_cache = self
+            .leveln_manager
+            .block_cache()
+            .ok_or_else(|| KVLiteError::Custom("pin_range needs Options::block_cache".into()))?;
+        let (key_start, key_end) = (key_start.internal_key(), key_end.internal_key());
+        let mut pinned = vec![];
+        let mut pin_tables = || -> Result<()> {
+            for table in self.list_tables() {
+                let blocks = table.data_blocks_in_range(
+                    key_start,
+                    key_end,
+                    &self.leveln_manager.index_cache,
+                    block_cache,
+                )?;
+                for (offset, block) in blocks {
+                    // the blocks of a table being deleted are not pinned, like they are
+                    // not inserted by the queries
+                    let mut fits = true;
+                    table.insert_if_readable(|| {
+                        fits = block_cache.pin(table.table_id(), offset, block);
+                        if fits {
+                            pinned.push((table.table_id(), offset));
+                        }
+                    });
+                    if !fits {
+                        return Err(KVLiteError::CacheBudgetExceeded);
+                    }
+                }
+            }
+            Ok(())
+        };
+        if let Err(e) = pin_tables() {
+            for (table_id, offset) in pinned {
+                block_cache.unpin(table_id, offset);
             }
+            return Err(e);
         }
+        self.pinned_ranges
+            .lock()
+            .unwrap()
+            .push((key_start.clone(), key_end.clone(), pinned));
+        Ok(())
+    }
 
-        // query level0 sstables
-        let option = self.level0_manager.query(key.internal_key()).unwrap();
-        if option.is_some() {
-            return Ok(option);
+    fn unpin_range(&self, key_start: &SK, key_end: &SK) -> Result<()> {
+        let (key_start, key_end) = (key_start.internal_key(), key_end.internal_key());
+        let mut pinned_ranges = self.pinned_ranges.lock().unwrap();
+        if let Some(i) = pinned_ranges
+            .iter()
+            .position(|(start, end, _)| start == key_start && end == key_end)
+        {
+            let (_, _, blocks) = pinned_ranges.remove(i);
+            if let Some(block_cache) = self.leveln_manager.block_cache() {
+                for (table_id, offset) in blocks {
+                    block_cache.unpin(table_id, offset);
+                }
+            }
         }
+        Ok(())
+    }
 
-        // query sstables
-        let option = self.leveln_manager.query(key.internal_key()).unwrap();
-        Ok(option)
+    fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = self.leveln_manager.memory_usage();
+        usage.filters += self.level0_manager.prefix_filters_size() as u64;
+        usage.mut_mem_table = self.get_mut_mem_table().approximate_memory_usage();
+        usage.imm_mem_table = self.get_imm_mem_table().approximate_memory_usage();
+        usage
     }
 
-    /// Get an iterator for all the valid key-value pairs in databases.
-    pub fn get_db_iterator<const RW_MODE: ReadWriteMode>(&self) -> DBIterator
-    where
-        M: SkipMapMemTable<InternalKey, InternalKey, { RW_MODE }>,
-    {
-        let imm_mem = self.get_imm_mem_table();
-        let imm_mem_iterator = MemTableCloneIterator::new(imm_mem.clone());
+    #[inline]
+    fn open_table_files(&self) -> usize {
+        self.leveln_manager.open_table_files()
+    }
 
-        let mut_mem = self.get_mut_mem_table();
-        let mut_mem_iterator = MemTableCloneIterator::new(mut_mem.clone());
+    fn levels(&self) -> Vec<LevelInfo> {
+        let mut levels = Vec::with_capacity(MAX_LEVEL + 1);
+        let guard = self.level0_manager.get_level0_tables_lock().read().unwrap();
+        levels.push(LevelInfo {
+            level: 0,
+            tables: guard
+                .values()
+                .map(|table| TableInfo::from(&**table))
+                .collect(),
+        });
+        drop(guard);
+        for level in 1..=MAX_LEVEL {
+            let guard = self
+                .leveln_manager
+                .get_level_tables_lock(NonZeroUsize::new(level).unwrap())
+                .read()
+                .unwrap();
+            levels.push(LevelInfo {
+                level,
+                tables: guard
+                    .values()
+                    .map(|table| TableInfo::from(&**table))
+                    .collect(),
+            });
+        }
+        levels
+    }
 
-        let level0_iterator = self.level0_manager.get_level0_iterator();
-        let leveln_iterators = self.leveln_manager.get_iterators();
-        DBIterator::new(
-            imm_mem_iterator,
-            mut_mem_iterator,
-            level0_iterator,
-            leveln_iterators,
-        )
+    fn verify(&self) -> Result<VerifyReport> {
+        self.leveln_manager.failure_flag().check()?;
+        Ok(verify(&self.level0_manager, &self.leveln_manager))
     }
 }
 
-impl<SK, UK, M, L> Drop for NoTransactionDB<SK, UK, M, L>
+impl<SK, UK, M, L: 'static> NoTransactionDB<SK, UK, M, L>
 where
     SK: MemKey + 'static,
     UK: MemKey,
     M: MemTable<SK, UK> + 'static,
-    L: WAL<SK, UK> + 'static,
+    L: WAL<SK, UK>,
 {
-    fn drop(&mut self) {
-        self.write_level0_channel.take();
-        if let Some(handle) = self.level0_writer_handle.take() {
-            handle.join().unwrap();
+    /// Check that `db_path` is a directory, creating it if it is missing and
+    /// `options.create_if_missing` is true, then lock it.
+    fn lock_db_path(db_path: &Path, options: &Options) -> Result<File> {
+        match std::fs::metadata(db_path) {
+            Ok(metadata) if !metadata.is_dir() => {
+                return Err(KVLiteError::DbPathNotDirectory(db_path.to_path_buf()));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if !options.create_if_missing {
+                    return Err(KVLiteError::DbPathNotFound(db_path.to_path_buf()));
+                }
+                std::fs::create_dir_all(db_path)?;
+            }
+            Err(e) => return Err(e.into()),
         }
-        self.level0_manager.close();
-        self.leveln_manager.close();
+        lock_dir(db_path)
     }
-}
-
-#[cfg(test)]
-pub(crate) mod tests {
-    use crate::db::key_types::InternalKey;
-    use crate::db::no_transaction_db::NoTransactionDB;
-    use crate::db::options::WriteOptions;
-    use crate::db::{DB, MAX_LEVEL};
-    use crate::memory::{
-        BTreeMemTable, MemTable, MrMwSkipMapMemTable, MrSwSkipMapMemTable, MutexSkipMapMemTable,
-        SkipMapMemTable,
-    };
-    use crate::sstable::manager::level_n::tests::create_manager;
-    use crate::wal::simple_wal::SimpleWriteAheadLog;
-    use log::info;
-    use rand::Rng;
-    use std::collections::HashMap;
-    use std::convert::TryInto;
-    use std::num::NonZeroUsize;
-    use std::path::{Path, PathBuf};
-    use std::sync::{Arc, Barrier};
-    use std::time::Duration;
 
-    const NUM_KEYS: u64 = 100000;
+    /// Create an empty [WriteBatch] to be applied by [NoTransactionDB::write].
+    pub fn batch(&self) -> WriteBatch<SK> {
+        WriteBatch::default()
+    }
 
-    #[test]
-    fn test_command() {
-        let _ = env_logger::try_init();
+    /// Apply all the writes of `batch` with one WAL append and one memory table merge.
+    pub fn write(&self, write_options: &WriteOptions, batch: WriteBatch<SK>) -> Result<()> {
+        self.leveln_manager.failure_flag().check()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
 
-        for j in 0..2 {
-            let temp_dir = tempfile::Builder::new()
-                .prefix("test_command")
-                .tempdir()
-                .unwrap();
-            let path = temp_dir.path();
-            // let path_buf = PathBuf::from(format!("test_command_{}", j));
-            // let path = path_buf.as_path();
-            info!("{:?}", path);
-            _test_command::<MrMwSkipMapMemTable<InternalKey>>(path, 5);
-            check(path);
-            for i in 0..2 {
-                _test_command::<BTreeMemTable<InternalKey>>(path, i);
-                check(path);
-                _test_command::<MutexSkipMapMemTable<InternalKey>>(path, i);
-                check(path);
-                _test_command::<MrSwSkipMapMemTable<InternalKey>>(path, i);
-                check(path);
-            }
-            _test_command::<MrMwSkipMapMemTable<InternalKey>>(path, 5);
-            check(path);
+        for (key, value) in batch.iter() {
+            self.check_key_size(key.internal_key())?;
+            self.check_value_size(value)?;
         }
-    }
+        self.stall_writes()?;
 
-    fn query(
-        db1: Arc<
-            NoTransactionDB<
-                InternalKey,
-                InternalKey,
-                impl MemTable<InternalKey, InternalKey>,
-                SimpleWriteAheadLog,
-            >,
-        >,
-        value_prefix: u32,
-    ) {
-        let mut not_found_key = vec![];
-        for i in 0..NUM_KEYS {
-            let v = db1.get(&format!("key{}", i).into_bytes());
-            let value = v.unwrap();
-            if let Some(value) = value {
-                if format!("value{}_{}", i, value_prefix).as_bytes().ne(&value) {
-                    not_found_key.push(i);
-                }
+        let (table, mem_usage) = batch.into_inner();
+        fn value_of(v: &Value) -> Option<&Value> {
+            if v.is_empty() {
+                None
             } else {
-                not_found_key.push(i);
+                Some(v)
             }
         }
-
-        if !not_found_key.is_empty() {
-            let mut count = 0;
-            let length = not_found_key.len();
-            warn!("{} keys not found", length);
-            std::thread::sleep(Duration::from_secs(5));
-            for key in not_found_key {
-                println!("{}", key);
-                let v = db1.get(&format!("key{}", key).into_bytes());
-                let value = v.unwrap();
-                if let Some(value) = value {
-                    assert_eq!(format!("value{}_{}", key, value_prefix).into_bytes(), value);
-                } else {
-                    count += 1;
-                }
+        let bytes_written = table
+            .iter()
+            .map(|(k, v)| k.internal_key().len() + v.len())
+            .sum();
+        let mut_mem_table = {
+            let mut wal_guard = self.lock_wal()?;
+            if self.wal_enabled() {
+                wal_guard
+                    .append_batch(write_options, table.iter().map(|(k, v)| (k, value_of(v))))?;
             }
-            if count > 0 {
-                panic!("{} keys still not found", count);
-            } else {
-                info!("{} keys now found", length);
+            for (key, value) in table.iter() {
+                self.publish(key, value_of(value));
             }
-        }
+            // the whole batch takes one sequence
+            let mut_mem_table = self.get_mut_mem_table();
+            mut_mem_table.merge(table, mem_usage);
+            self.advance_sequence();
+            mut_mem_table
+        };
+        self.add_user_bytes_written(bytes_written);
+
+        self.may_freeze(&mut_mem_table);
+        Ok(())
     }
 
-    fn _test_command<M: 'static + MemTable<InternalKey, InternalKey>>(
-        path: &Path,
-        value_prefix: u32,
-    ) {
-        let wo = WriteOptions { sync: false };
-        let db = NoTransactionDB::<InternalKey, InternalKey, M, SimpleWriteAheadLog>::open(path)
-            .unwrap();
+    /// Sequence of the last write applied. Each `set`, `remove`, swap and batch write
+    /// takes the next sequence, starting from 1 after the database is opened.
+    /// Bulk ingestion takes none.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence.load(Ordering::Acquire)
+    }
+
+    /// Whether the writes are appended to the WAL, see [DB::set_wal_enabled].
+    #[inline]
+    pub(crate) fn wal_enabled(&self) -> bool {
+        self.wal_enabled.load(Ordering::Acquire)
+    }
+
+    /// Must be called with the WAL lock held, after the write is applied to the memory table.
+    #[inline]
+    pub(crate) fn advance_sequence(&self) {
+        self.last_sequence.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Iterate the live key-values as of the returned sequence: the writes of greater
+    /// sequences are not seen, however long the iteration takes.
+    ///
+    /// The keys of this database carry no sequences, so the key-values are not filtered
+    /// by sequence. The state at the sequence is captured under the WAL lock instead,
+    /// which is also held while [NoTransactionDB::freeze] moves the mutable memory table
+    /// to the immutable one.
+    ///
+    /// Writers are blocked only while the mutable memory table is copied and the
+    /// sstables are listed. The immutable memory table and the sstables are pinned
+    /// rather than copied, so flushes and compactions running during the iteration do
+    /// not change what it yields, and the files of the compacted sstables are removed
+    /// after the iterator is dropped. Until then,
+    /// [NoTransactionDB::collect_value_log_garbage] keeps the value log files they
+    /// point to.
+    pub fn pinned_iter(&self) -> Result<(u64, PinnedIterator)> {
+        self.leveln_manager.failure_flag().check()?;
+        // Nothing newer than the sequence reaches the sstables while the WAL lock is held.
+        // The layers are listed from the newest to the oldest, so that the key-values
+        // moved to older layers by flushes and compactions meanwhile are seen at least once.
+        let (sequence, mut_kvs, imm_mem_table, tables) = {
+            let _wal_guard = self.lock_wal()?;
+            let mut_kvs: Vec<InternalKeyValue> = self
+                .get_mut_mem_table()
+                .kv_iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            let imm_mem_table = self.get_imm_mem_table();
+            let mut tables: Vec<Vec<Arc<TableReadHandle>>> = Vec::with_capacity(MAX_LEVEL + 1);
+            {
+                let guard = self.level0_manager.get_level0_tables_lock().read().unwrap();
+                tables.push(guard.values().cloned().collect());
+            }
+            for level in 1..=MAX_LEVEL {
+                let guard = self
+                    .leveln_manager
+                    .get_level_tables_lock(NonZeroUsize::new(level).unwrap())
+                    .read()
+                    .unwrap();
+                tables.push(guard.values().cloned().collect());
+            }
+            (self.last_sequence(), mut_kvs, imm_mem_table, tables)
+        };
+
+        // The newer source has the greater priority. A level may briefly hold both the
+        // input and the output tables of a compaction, whose keys overlap, so every
+        // sstable is a source of its own, and the newer table of a level wins.
+        let mut sources: Vec<PinnedSource> = vec![];
+        for mut level_tables in tables.into_iter().rev() {
+            level_tables.sort_by_key(|table| table.table_id());
+            for table in level_tables {
+                let priority = sources.len();
+                sources.push(Box::new(
+                    TableReadHandle::iter(table).map(move |(k, v)| (k, v, priority)),
+                ));
+            }
+        }
+        let imm_kvs: Vec<InternalKeyValue> = imm_mem_table
+            .kv_iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        drop(imm_mem_table);
+        for kvs in [imm_kvs, mut_kvs] {
+            let priority = sources.len();
+            sources.push(Box::new(
+                kvs.into_iter().map(move |(k, v)| (k, v, priority)),
+            ));
+        }
+        let iter = MergeIterator::new(sources).drop_tombstones(Value::is_empty);
+        Ok((sequence, PinnedIterator(iter)))
+    }
+
+    /// Remove the value log files which no sstable points to any more, see
+    /// [crate::sstable::value_log]. The values of a file stay until all of them are
+    /// overwritten or removed and compacted away. Returns the bytes reclaimed.
+    pub fn collect_value_log_garbage(&self) -> Result<u64> {
+        self.leveln_manager.failure_flag().check()?;
+        if self.leveln_manager.options().in_memory {
+            return Ok(0);
+        }
+        let dir = value_log_dir(&self.db_path);
+        // The tables are listed from the newest level to the oldest. Compaction installs
+        // its outputs before removing its inputs, so every pointer is seen at least once.
+        // The removed tables still read by iterators and snapshots are listed afterwards.
+        let (file_ids, tables) = {
+            let _value_log_guard = self.leveln_manager.value_log_lock().lock().unwrap();
+            let file_ids = list_value_logs(Path::new(&dir))?;
+            let mut tables = self.list_tables();
+            tables.extend(self.leveln_manager.deleted_tables());
+            (file_ids, tables)
+        };
+
+        let mut referenced = HashSet::new();
+        for table in tables {
+            if !table.separated_values() {
+                continue;
+            }
+            for (_, stored) in TableReadHandle::stored_iter(table) {
+                if let StoredValue::Pointer(pointer) = StoredValue::decode(&stored)? {
+                    referenced.insert(pointer.file_id);
+                }
+            }
+        }
+
+        let mut reclaimed = 0;
+        for file_id in file_ids {
+            if referenced.contains(&file_id) {
+                continue;
+            }
+            let path = value_log_file(&dir, file_id);
+            reclaimed += std::fs::metadata(&path)?.len();
+            info!("remove value log {}", path);
+            std::fs::remove_file(&path)?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// The sstables of all the levels, from level 0 to [MAX_LEVEL].
+    /// Load the filters and the indexes of the sstables into the table cache, from the
+    /// newest sstable to the oldest, skipping the ones the cache has no room for.
+    /// See [Options::warm_cache_on_open].
+    fn warm_table_caches(&self) {
+        let mut tables = self.list_tables();
+        tables.sort_by_key(|table| (table.level(), std::cmp::Reverse(table.table_id())));
+        for table in tables {
+            self.leveln_manager.warm_table_cache(&table);
+        }
+    }
+
+    /// Claim all the sstables for [DB::clear], waiting for the running compactions,
+    /// whose outputs are claimed once they are installed. It is called with the WAL
+    /// lock held and the level 0 writer idle, so no flush adds sstables meanwhile.
+    fn claim_all_tables(&self) -> Result<Vec<Arc<TableReadHandle>>> {
+        let mut claimed = vec![];
+        let mut claimed_ids = HashSet::new();
+        loop {
+            let mut tables = self.level0_manager.claim_idle_tables();
+            tables.extend(self.leveln_manager.claim_idle_tables());
+            for table in tables {
+                claimed_ids.insert(table.table_id());
+                claimed.push(table);
+            }
+            // a compaction removes its inputs after installing its outputs
+            if self
+                .list_tables()
+                .iter()
+                .all(|table| claimed_ids.contains(&table.table_id()))
+            {
+                return Ok(claimed);
+            }
+            self.leveln_manager.failure_flag().check()?;
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn list_tables(&self) -> Vec<Arc<TableReadHandle>> {
+        let mut tables: Vec<Arc<TableReadHandle>> = {
+            let guard = self.level0_manager.get_level0_tables_lock().read().unwrap();
+            guard.values().cloned().collect()
+        };
+        for level in 1..=MAX_LEVEL {
+            let guard = self
+                .leveln_manager
+                .get_level_tables_lock(NonZeroUsize::new(level).unwrap())
+                .read()
+                .unwrap();
+            tables.extend(guard.values().cloned());
+        }
+        tables
+    }
+
+    /// Iterate the tombstones of all the layers together with the levels they are found
+    /// at, to see how far the deletions have been compacted. The tombstones of the
+    /// memory tables are yielded first, followed by the ones of the sstables from
+    /// level 0 to [MAX_LEVEL]. A key removed several times may be
+    /// yielded once for each layer holding its tombstone.
+    ///
+    /// The memory tables are copied and the sstables are listed when it is called, and
+    /// the sstables are read lazily.
+    pub fn debug_tombstones(&self) -> impl Iterator<Item = (SK, TombstoneSource)> {
+        let mem_tombstones = |mem_table: Arc<M>| -> Vec<(SK, TombstoneSource)> {
+            mem_table
+                .kv_iter()
+                .filter(|(_, v)| v.is_empty())
+                .map(|(k, _)| (SK::from(k.clone()), TombstoneSource::MemTable))
+                .collect()
+        };
+        let mut tombstones = mem_tombstones(self.get_mut_mem_table());
+        // The immutable memory table is kept after it is written to level 0, and only
+        // holds tombstones not yet in the sstables while the level 0 writer is running.
+        // The sstables are listed afterwards, so that none of them is missed.
+        if self
+            .background_task_write_to_level0_is_running
+            .load(Ordering::Acquire)
+        {
+            tombstones.extend(mem_tombstones(self.get_imm_mem_table()));
+        }
+        let table_tombstones = self.list_tables().into_iter().flat_map(|table| {
+            let level = table.level();
+            TableReadHandle::iter(table)
+                .filter(|(_, v)| v.is_empty())
+                .map(move |(k, _)| (SK::from(k), TombstoneSource::Level(level)))
+        });
+        tombstones.into_iter().chain(table_tombstones)
+    }
+
+    /// Every version of the keys in [`key_start`, `key_end`] held by the layers, from
+    /// the oldest layer to the newest. The versions of the sstables, from [MAX_LEVEL]
+    /// up to level 0 and from the older tables to the newer ones in level 0, are
+    /// returned apart from the versions of the memory tables, which are newer.
+    ///
+    /// Like [NoTransactionDB::debug_tombstones], the memory tables are read before the
+    /// sstables are listed, so a version being written to level 0 may be returned twice.
+    pub(crate) fn versions_in_range(
+        &self,
+        key_start: &SK,
+        key_end: &SK,
+    ) -> (Vec<(InternalKey, Value)>, Vec<(SK, Value)>) {
+        let mut mem_versions = vec![];
+        if self
+            .background_task_write_to_level0_is_running
+            .load(Ordering::Acquire)
+        {
+            mem_versions = self
+                .get_imm_mem_table()
+                .versions_in_range(key_start, key_end);
+        }
+        mem_versions.extend(
+            self.get_mut_mem_table()
+                .versions_in_range(key_start, key_end),
+        );
+
+        let (start, end) = (key_start.internal_key(), key_end.internal_key());
+        let mut tables = self.list_tables();
+        // the sort is stable, so the level 0 tables remain in ascending order of ids
+        tables.sort_by_key(|table| std::cmp::Reverse(table.level()));
+        let mut table_versions = vec![];
+        for table in tables {
+            if !table.is_overlapping(start, end) {
+                continue;
+            }
+            table_versions.extend(
+                TableReadHandle::iter(table)
+                    .skip_while(|(k, _)| k < start)
+                    .take_while(|(k, _)| k <= end),
+            );
+        }
+        (table_versions, mem_versions)
+    }
+
+    /// Get the live key-values whose keys start with `prefix`.
+    ///
+    /// If [Options::prefix_bloom_length] is set and not longer than `prefix`,
+    /// the sstables whose prefix filters do not contain `prefix` are not read.
+    pub fn prefix_scan(&self, prefix: &[u8]) -> Result<SrSwSkipMap<UK, Value>>
+    where
+        UK: From<SK>,
+    {
+        let key_end = match prefix_successor(prefix) {
+            Some(key_end) => key_end,
+            // all the keys not less than `prefix` start with it
+            None => match self.max_key() {
+                Some(max_key) if max_key.as_slice() >= prefix => max_key,
+                _ => return Ok(SrSwSkipMap::new()),
+            },
+        };
+        let mut skip_map =
+            self.range_get_with_tombstones(&SK::from(prefix.to_vec()), &SK::from(key_end), prefix)?;
+        let unmatched: Vec<UK> = skip_map
+            .iter()
+            .filter(|(k, v)| v.is_empty() || !k.internal_key().starts_with(prefix))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in unmatched {
+            skip_map.remove(key);
+        }
+        self.add_user_bytes_read(&skip_map);
+        Ok(skip_map)
+    }
+
+    /// Get the max key of all the layers, including the tombstones.
+    pub(crate) fn max_key(&self) -> Option<InternalKey> {
+        let mut max_key = self.level0_manager.max_key();
+        for level in 1..=MAX_LEVEL {
+            max_key = max_key.max(
+                self.leveln_manager
+                    .max_key(NonZeroUsize::new(level).unwrap()),
+            );
+        }
+        for mem_table in [self.get_imm_mem_table(), self.get_mut_mem_table()] {
+            max_key = max_key.max(mem_table.kv_iter().last().map(|(k, _)| k.clone()));
+        }
+        max_key
+    }
+
+    /// Merge the key-values in [`key_start`, `key_end`] of all the layers,
+    /// keeping the tombstones. See [NoTransactionDB::prefix_scan] for `prefix`.
+    fn range_get_with_tombstones(
+        &self,
+        key_start: &SK,
+        key_end: &SK,
+        prefix: &[u8],
+    ) -> Result<SrSwSkipMap<UK, Value>>
+    where
+        UK: From<SK>,
+    {
+        self.leveln_manager.failure_flag().check()?;
+        // The memory tables are swapped by freezes under the WAL lock, so both of them are
+        // taken at once, before the sstables which the frozen key-values are written to.
+        let (_clear_guard, imm_mem_table, mut_mem_table) = {
+            let _wal_guard = self.lock_wal()?;
+            (
+                self.clear_lock.read().unwrap(),
+                self.get_imm_mem_table(),
+                self.get_mut_mem_table(),
+            )
+        };
+        let mut skip_map = SrSwSkipMap::new();
+        self.leveln_manager.range_query(
+            key_start.internal_key(),
+            key_end.internal_key(),
+            prefix,
+            &mut skip_map,
+        );
+        self.level0_manager.range_query(
+            key_start.internal_key(),
+            key_end.internal_key(),
+            prefix,
+            &mut skip_map,
+        );
+
+        imm_mem_table.range_get(key_start, key_end, &mut skip_map);
+        mut_mem_table.range_get(key_start, key_end, &mut skip_map);
+        Ok(skip_map)
+    }
+
+    /// Wait until the immutable memory table is written to sstable.
+    pub(crate) fn wait_for_level0_writer(&self) -> Result<()> {
+        while self
+            .background_task_write_to_level0_is_running
+            .load(Ordering::Acquire)
+        {
+            self.leveln_manager.failure_flag().check()?;
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        self.leveln_manager.failure_flag().check()
+    }
+
+    /// Delay the write while level 0 has [Options::level0_slowdown_writes_trigger] sstables,
+    /// or block it while level 0 has [Options::level0_stop_writes_trigger] sstables.
+    /// Write `key` with the WAL locked by `wal_guard`, which is released before the
+    /// memory table is frozen.
+    fn set_locked(
+        &self,
+        mut wal_guard: MutexGuard<L>,
+        write_options: &WriteOptions,
+        key: SK,
+        value: Value,
+    ) -> Result<()> {
+        let bytes_written = key.internal_key().len() + value.len();
+        let mut_mem_table = {
+            if self.wal_enabled() {
+                wal_guard.append(write_options, &key, Some(&value))?;
+            }
+            self.publish(&key, Some(&value));
+            // applied under the WAL lock, so that compare_and_swap never misses it
+            let mut_mem_table = self.get_mut_mem_table();
+            mut_mem_table.set(key, value)?;
+            self.advance_sequence();
+            drop(wal_guard);
+            mut_mem_table
+        };
+        self.add_user_bytes_written(bytes_written);
+        self.may_freeze(&mut_mem_table);
+        Ok(())
+    }
+
+    /// Whether the writes are delayed or blocked by [NoTransactionDB::stall_writes] now.
+    fn write_stalled(&self) -> bool {
+        let options = self.leveln_manager.options();
+        let file_count = self.level0_manager.file_count();
+        [
+            options.level0_slowdown_writes_trigger,
+            options.level0_stop_writes_trigger,
+        ]
+        .iter()
+        .any(|trigger| *trigger != 0 && file_count >= *trigger)
+    }
+
+    pub(crate) fn stall_writes(&self) -> Result<()> {
+        let options = self.leveln_manager.options();
+        let slowdown = options.level0_slowdown_writes_trigger;
+        let stop = options.level0_stop_writes_trigger;
+        let mut start: Option<Instant> = None;
+        loop {
+            let file_count = self.level0_manager.file_count();
+            if stop != 0 && file_count >= stop {
+                start.get_or_insert_with(Instant::now);
+                self.leveln_manager.failure_flag().check()?;
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            if slowdown != 0 && file_count >= slowdown {
+                start.get_or_insert_with(Instant::now);
+                std::thread::sleep(WRITE_SLOWDOWN_DELAY);
+            }
+            break;
+        }
+        if let Some(start) = start {
+            StatsCounters::add(
+                &self.leveln_manager.stats().write_stall_micros,
+                start.elapsed().as_micros() as usize,
+            );
+        }
+        Ok(())
+    }
+
+    /// Reject `key` if it is larger than [Options::max_key_bytes].
+    #[allow(clippy::ptr_arg)]
+    pub(crate) fn check_key_size(&self, key: &InternalKey) -> Result<()> {
+        let limit = self.leveln_manager.options().max_key_bytes;
+        if limit != 0 && key.len() > limit {
+            return Err(KVLiteError::KeyTooLarge {
+                size: key.len(),
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reject `value` if it is larger than [Options::max_value_bytes].
+    pub(crate) fn check_value_size(&self, value: &Value) -> Result<()> {
+        let limit = self.leveln_manager.options().max_value_bytes;
+        if limit != 0 && value.len() > limit {
+            return Err(KVLiteError::ValueTooLarge {
+                size: value.len(),
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn add_user_bytes_written(&self, bytes: usize) {
+        StatsCounters::add(&self.leveln_manager.stats().user_bytes_written, bytes);
+    }
+
+    fn add_user_bytes_read(&self, kvs: &SrSwSkipMap<UK, Value>) {
+        let bytes: usize = kvs
+            .iter()
+            .map(|(k, v)| k.internal_key().len() + v.len())
+            .sum();
+        StatsCounters::add(&self.leveln_manager.stats().user_bytes_read, bytes);
+    }
+
+    /// Freeze the mutable memory table when it is large enough or holds too many tombstones.
+    pub(crate) fn should_freeze(&self, mem_table: &M) -> bool {
+        let max_tombstones = self.leveln_manager.options().max_memtable_tombstones;
+        (mem_table.approximate_memory_usage() >= WRITE_BUFFER_SIZE
+            || (max_tombstones != 0 && mem_table.tombstone_count() >= max_tombstones))
+            && !self
+                .background_task_write_to_level0_is_running
+                .load(Ordering::Acquire)
+    }
+
+    /// Freeze the mutable memory table if [NoTransactionDB::should_freeze], unless
+    /// snapshots or transactions read it, see [NoTransactionDB::num_lsn_acquired]. The
+    /// frozen table is written to sstables, which keep only the newest version of each
+    /// key, so the versions they read would be lost. If the memory table grows to
+    /// [Options::snapshot_freeze_multiple] times [WRITE_BUFFER_SIZE] meanwhile, the write
+    /// is stalled until they all end.
+    pub(crate) fn may_freeze(&self, mem_table: &M) {
+        if !self.should_freeze(mem_table) {
+            return;
+        }
+        if self.exceeds_snapshot_freeze_limit(mem_table) {
+            let num_lsn_acquired = self.num_lsn_acquired.load(Ordering::Acquire);
+            if num_lsn_acquired != 0 {
+                warn!(
+                    "stall the write until {} snapshots or transactions end",
+                    num_lsn_acquired
+                );
+            }
+            while self.num_lsn_acquired.load(Ordering::Acquire) != 0 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        // checked under the WAL lock, under which the snapshots are counted
+        self.freeze_unless(|| self.num_lsn_acquired.load(Ordering::Acquire) != 0);
+    }
+
+    /// Whether `mem_table` has grown large enough to stall the writes until the snapshots
+    /// end, see [Options::snapshot_freeze_multiple].
+    fn exceeds_snapshot_freeze_limit(&self, mem_table: &M) -> bool {
+        let multiple = self.leveln_manager.options().snapshot_freeze_multiple;
+        multiple != 0
+            && mem_table.approximate_memory_usage() >= WRITE_BUFFER_SIZE.saturating_mul(multiple)
+    }
+
+    /// Write `sorted` into new sstables of the last level without installing them.
+    /// Return the min key and max key written.
+    fn write_ingested_tables(
+        &self,
+        sorted: impl Iterator<Item = (SK, Value)>,
+        handles: &mut Vec<TableWriteHandle>,
+    ) -> Result<Option<(InternalKey, InternalKey)>> {
+        let level = NonZeroUsize::new(MAX_LEVEL).unwrap();
+        let mut min_key: Option<InternalKey> = None;
+        let mut max_key: Option<InternalKey> = None;
+        let mut kvs: Vec<(InternalKey, Value)> = vec![];
+        let mut kvs_size = 0;
+
+        for (key, value) in sorted {
+            self.check_key_size(key.internal_key())?;
+            let key: InternalKey = key.internal_key().clone();
+            match &max_key {
+                Some(max_key) if max_key >= &key => {
+                    return Err(KVLiteError::Custom("ingested keys are not sorted".into()));
+                }
+                Some(_) => {}
+                None => min_key = Some(key.clone()),
+            }
+            max_key = Some(key.clone());
+
+            kvs_size += (key.len() + value.len()) as u64;
+            self.add_user_bytes_written(key.len() + value.len());
+            kvs.push((key, value));
+            if kvs_size >= WRITE_BUFFER_SIZE {
+                handles.push(self.write_ingested_table(level, std::mem::take(&mut kvs))?);
+                kvs_size = 0;
+            }
+        }
+
+        if !kvs.is_empty() {
+            handles.push(self.write_ingested_table(level, kvs)?);
+        }
+        Ok(min_key.zip(max_key))
+    }
+
+    fn write_ingested_table(
+        &self,
+        level: NonZeroUsize,
+        kvs: Vec<(InternalKey, Value)>,
+    ) -> Result<TableWriteHandle> {
+        let mut handle = self
+            .leveln_manager
+            .create_table_write_handle(level, kvs.len() as u32);
+        handle.write_sstable_from_vec(kvs)?;
+        Ok(handle)
+    }
+
+    /// Send the write to subscribers. Called with WAL lock held, so that
+    /// subscribers receive the writes in commit order. It never blocks the writers:
+    /// a subscriber whose channel is full is dropped, see [DB::subscribe].
+    pub(crate) fn publish(&self, key: &SK, value: Option<&Value>) {
+        let mut guard = self.subscribers.lock().unwrap();
+        // remove the subscribers whose receivers are dropped or lag behind
+        guard.retain(|(prefix, sender)| {
+            !key.internal_key().starts_with(prefix)
+                || sender.try_send((key.clone(), value.cloned())).is_ok()
+        });
+    }
+
+    /// [DB::compare_and_swap] whose key is made by `make_key` after the other writers
+    /// are blocked, so that a key carrying a sequence number is newer than all the
+    /// writes it is compared with.
+    pub(crate) fn compare_and_swap_with(
+        &self,
+        write_options: &WriteOptions,
+        make_key: impl FnOnce() -> SK,
+        expected: Option<&Value>,
+        new: Value,
+    ) -> Result<bool> {
+        self.read_modify_write(write_options, make_key, |current| {
+            if current.as_ref() == expected {
+                Ok((Some(new), true))
+            } else {
+                Ok((None, false))
+            }
+        })
+    }
+
+    /// [DB::fetch_add] whose key is made by `make_key` after the other writers are
+    /// blocked, like [NoTransactionDB::compare_and_swap_with].
+    pub(crate) fn fetch_add_with(
+        &self,
+        write_options: &WriteOptions,
+        make_key: impl FnOnce() -> SK,
+        delta: i64,
+    ) -> Result<i64> {
+        self.read_modify_write(write_options, make_key, |current| {
+            let value = match current {
+                Some(bytes) => {
+                    let bytes: [u8; 8] = bytes.as_slice().try_into().map_err(|_| {
+                        KVLiteError::Custom(format!("value of {} bytes is not an i64", bytes.len()))
+                    })?;
+                    i64::from_le_bytes(bytes)
+                }
+                None => 0,
+            };
+            let value = value.wrapping_add(delta);
+            Ok((Some(value.to_le_bytes().to_vec()), value))
+        })
+    }
+
+    /// [DB::entry] whose key is made by `make_key` after the other writers are blocked,
+    /// like [NoTransactionDB::compare_and_swap_with].
+    pub(crate) fn entry_with<'a>(
+        &'a self,
+        write_options: &'a WriteOptions,
+        make_key: impl FnOnce() -> SK + 'a,
+    ) -> Entry<'a> {
+        Entry::new(move |update| {
+            self.read_modify_write(write_options, make_key, |current| Ok(update(current)))
+        })
+    }
+
+    /// Pass the current value of the key made by `make_key`, `None` if it is absent or
+    /// removed, to `update`, and write the new value it returns, if any. The other writers
+    /// are blocked from making the key until the new value is applied.
+    fn read_modify_write<T>(
+        &self,
+        write_options: &WriteOptions,
+        make_key: impl FnOnce() -> SK,
+        update: impl FnOnce(Option<Value>) -> Result<(Option<Value>, T)>,
+    ) -> Result<T> {
+        self.leveln_manager.failure_flag().check()?;
+        self.stall_writes()?;
+        let (mut_mem_table, result) = {
+            // set and remove apply their writes to the memory table under the WAL lock,
+            // so the current value cannot change until the new value is applied
+            let mut wal_guard = self.lock_wal()?;
+            let key = make_key();
+            self.check_key_size(key.internal_key())?;
+            let current = self.query(&key)?.filter(|v| !v.is_empty());
+            let (new, result) = match update(current)? {
+                (Some(new), result) => (new, result),
+                (None, result) => return Ok(result),
+            };
+            self.check_value_size(&new)?;
+            if self.wal_enabled() {
+                wal_guard.append(write_options, &key, Some(&new))?;
+            }
+            self.publish(&key, Some(&new));
+            self.add_user_bytes_written(key.internal_key().len() + new.len());
+            let mut_mem_table = self.get_mut_mem_table();
+            mut_mem_table.set(key, new)?;
+            self.advance_sequence();
+            (mut_mem_table, result)
+        };
+        self.may_freeze(&mut_mem_table);
+        Ok(result)
+    }
+
+    /// WAL lock is poisoned only if a background task panicked while holding it.
+    fn lock_wal(&self) -> Result<MutexGuard<L>> {
+        self.wal.lock().map_err(|_| KVLiteError::BackgroundFailure)
+    }
+
+    /// Move the mutable memory table to the immutable one, and wake up the level 0 writer.
+    /// Nothing is frozen if the level 0 writer is still writing the immutable memory table,
+    /// in which case false is returned.
+    pub(crate) fn freeze(&self) -> bool {
+        self.freeze_unless(|| false)
+    }
+
+    /// Like [NoTransactionDB::freeze], but nothing is frozen if `is_pinned` returns true.
+    /// It is called under the WAL lock, so the callers can keep the memory table from
+    /// being frozen by what is only changed under the lock.
+    pub(crate) fn freeze_unless(&self, is_pinned: impl FnOnce() -> bool) -> bool {
+        {
+            // new log before writing to level0 sstable
+            let mut wal_guard = self.wal.lock().unwrap();
+            // emptied by DB::clear since the caller decided to freeze it
+            if self.get_mut_mem_table().is_empty() {
+                return true;
+            }
+            if is_pinned() {
+                return false;
+            }
+            // another freeze may have taken the level 0 writer since the caller checked it
+            if self
+                .background_task_write_to_level0_is_running
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                return false;
+            }
+            wal_guard.freeze_mut_log().unwrap();
+            // Swapped under the WAL lock, so that the writers reading the mutable memory
+            // table under it, e.g. read_modify_write, never miss a write being frozen.
+            let imm = self.mut_mem_table.swap(Arc::new(M::default()));
+            self.imm_mem_table.store(imm);
+        }
+
+        if let Some(chan) = &self.write_level0_channel {
+            if let Err(e) = chan.send(()) {
+                warn!("{}", e);
+            }
+        }
+        true
+    }
+
+    /// Start a thread which syncs the un-synced writes of WAL every `interval`.
+    /// The thread exits when `receiver` is disconnected.
+    fn start_task_sync_wal(
+        wal: Arc<Mutex<L>>,
+        interval: Duration,
+        receiver: crossbeam_channel::Receiver<()>,
+        panic_guard: PanicGuard,
+    ) -> JoinHandle<()> {
+        std::thread::Builder::new()
+            .name("wal syncer".to_owned())
+            .spawn(move || {
+                let _panic_guard = panic_guard;
+                info!("thread `wal syncer` start!");
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                    let mut wal_guard = wal.lock().unwrap();
+                    if let Err(e) = wal_guard.sync() {
+                        error!("failed to sync WAL: {:?}", e);
+                    }
+                }
+                info!("thread `wal syncer` exit!");
+            })
+            .unwrap()
+    }
+
+    pub(crate) fn get_mut_mem_table(&self) -> Arc<M> {
+        let guard = self.mut_mem_table.load();
+        guard.clone()
+    }
+
+    pub(crate) fn get_imm_mem_table(&self) -> Arc<M> {
+        let guard = self.imm_mem_table.load();
+        guard.clone()
+    }
+
+    /// Smallest and largest keys of the memory tables and sstables, tombstones included,
+    /// or `None` if the database is empty.
+    pub(crate) fn key_range(&self) -> Option<(InternalKey, InternalKey)> {
+        let mut range: Option<(InternalKey, InternalKey)> = None;
+        let mut extend = |min_key: &InternalKey, max_key: &InternalKey| {
+            range = Some(match range.take() {
+                Some((min, max)) => (
+                    std::cmp::min(min, min_key.clone()),
+                    std::cmp::max(max, max_key.clone()),
+                ),
+                None => (min_key.clone(), max_key.clone()),
+            });
+        };
+
+        for mem_table in [self.get_mut_mem_table(), self.get_imm_mem_table()].iter() {
+            let mut iter = mem_table.kv_iter();
+            if let Some((first, _)) = iter.next() {
+                let last = iter.last().map_or(first, |(k, _)| k);
+                extend(first, last);
+            }
+        }
+        {
+            let guard = self.level0_manager.get_level0_tables_lock().read().unwrap();
+            for table in guard.values() {
+                extend(table.min_key(), table.max_key());
+            }
+        }
+        for level in 1..=MAX_LEVEL {
+            let lock = self
+                .leveln_manager
+                .get_level_tables_lock(unsafe { NonZeroUsize::new_unchecked(level) });
+            let guard = lock.read().unwrap();
+            // sorted by max key
+            if let (Some(first), Some(last)) = (guard.values().next(), guard.values().last()) {
+                extend(first.min_key(), last.max_key());
+            }
+        }
+        range
+    }
+
+    fn query(&self, key: &SK) -> Result<Option<Value>> {
+        // query mutable memory table
+        {
+            let mut_mem = self.get_mut_mem_table();
+            let option = mut_mem.get(key)?;
+            if option.is_some() {
+                return Ok(option);
+            }
+        }
+
+        // query immutable memory table
+        {
+            let imm_mem = self.get_imm_mem_table();
+            let option = imm_mem.get(key)?;
+            if option.is_some() {
+                return Ok(option);
+            }
+        }
+
+        // query level0 sstables
+        let option = self.level0_manager.query(key.internal_key())?;
+        if option.is_some() {
+            return Ok(option);
+        }
+
+        // query sstables
+        let option = self.leveln_manager.query(key.internal_key())?;
+        Ok(option)
+    }
+
+    /// Get an iterator for all the valid key-value pairs in databases. An error of reading
+    /// an sstable is yielded instead of panicking, see [DBIterator].
+    pub fn get_db_iterator<const RW_MODE: ReadWriteMode>(&self) -> DBIterator
+    where
+        M: SkipMapMemTable<InternalKey, InternalKey, { RW_MODE }>,
+    {
+        let imm_mem = self.get_imm_mem_table();
+        let imm_mem_iterator = MemTableCloneIterator::new(imm_mem.clone());
+
+        let mut_mem = self.get_mut_mem_table();
+        let mut_mem_iterator = MemTableCloneIterator::new(mut_mem.clone());
+
+        let errors = IterErrors::default();
+        let level0_iterator = self.level0_manager.get_level0_iterator(&errors);
+        let leveln_iterators = self.leveln_manager.get_iterators(&errors);
+        DBIterator::new(
+            imm_mem_iterator,
+            mut_mem_iterator,
+            level0_iterator,
+            leveln_iterators,
+            errors,
+        )
+    }
+}
+
+/// Get the smallest key greater than all the keys starting with `prefix`,
+/// or `None` if `prefix` is empty or consists of `0xff` only.
+pub(crate) fn prefix_successor(prefix: &[u8]) -> Option<InternalKey> {
+    let end = prefix.iter().rposition(|b| *b != 0xff)?;
+    let mut successor = prefix[..=end].to_vec();
+    successor[end] += 1;
+    Some(successor)
+}
+
+/// Shows the path, the sstable count of every level and the size of the mutable memory table.
+impl<SK, UK, M, L> fmt::Debug for NoTransactionDB<SK, UK, M, L>
+where
+    SK: MemKey + 'static,
+    UK: MemKey,
+    M: MemTable<SK, UK> + 'static,
+    L: WAL<SK, UK> + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut level_file_counts = vec![self.level0_manager.file_count()];
+        level_file_counts.extend((1..=MAX_LEVEL).map(|level| {
+            self.leveln_manager
+                .get_level_tables_lock(NonZeroUsize::new(level).unwrap())
+                .read()
+                .unwrap()
+                .len()
+        }));
+        f.debug_struct("NoTransactionDB")
+            .field("db_path", &self.db_path)
+            .field("level_file_counts", &level_file_counts)
+            .field(
+                "mut_mem_table_size",
+                &self.get_mut_mem_table().approximate_memory_usage(),
+            )
+            .finish()
+    }
+}
+
+impl<SK, UK, M, L> Drop for NoTransactionDB<SK, UK, M, L>
+where
+    SK: MemKey + 'static,
+    UK: MemKey,
+    M: MemTable<SK, UK> + 'static,
+    L: WAL<SK, UK> + 'static,
+{
+    fn drop(&mut self) {
+        self.wal_syncer_channel.take();
+        if let Some(handle) = self.wal_syncer_handle.take() {
+            if handle.join().is_err() {
+                error!("thread `wal syncer` panicked");
+            }
+        }
+        self.write_level0_channel.take();
+        if let Some(handle) = self.level0_writer_handle.take() {
+            if handle.join().is_err() {
+                error!("thread `level0 writer` panicked");
+            }
+        }
+        self.level0_manager.close();
+        self.leveln_manager.close();
+        // no background task writes to the directory any more
+        if let Some(lock_file) = &self.lock_file {
+            if let Err(e) = lock_file.unlock() {
+                warn!("failed to unlock {}: {}", self.db_path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::collections::skip_list::skipmap::SrSwSkipMap;
+    use crate::compaction::filter::{CompactionFilter, Decision};
+    use crate::compaction::level_0::LEVEL0_FILES_THRESHOLD;
+    use crate::db::db_iter::InternalKeyValue;
+    use crate::db::key_types::InternalKey;
+    use crate::db::no_transaction_db::{
+        NoTransactionDB, TombstoneSource, SUBSCRIBER_CHANNEL_CAPACITY,
+    };
+    use crate::db::options::{BloomMode, Options, WalSync, WriteOptions};
+    use crate::db::stats::Stats;
+    use crate::db::{DBCommand, Value, DB, MAX_LEVEL};
+    use crate::error::KVLiteError;
+    use crate::memory::{
+        BTreeMemTable, InternalKeyValueIterator, MemTable, MrMwSkipMapMemTable,
+        MrSwSkipMapMemTable, MutexSkipMapMemTable, SkipMapMemTable,
+    };
+    use crate::sstable::block_cache::{BlockCache, BlockKey, LRUBlockCache};
+    use crate::sstable::dictionary::DICTIONARY_FILE;
+    use crate::sstable::manager::level_n::tests::create_manager;
+    use crate::sstable::manager::table_id::TABLE_ID_FILE;
+    use crate::sstable::repair::LOST_DIR;
+    use crate::sstable::value_log::value_log_dir;
+    use crate::sstable::{level_dir, sstable_file, CompressionType};
+    use crate::wal::null_wal::NullWriteAheadLog;
+    use crate::wal::simple_wal::SimpleWriteAheadLog;
+    use crate::wal::{LOG_DIR, WAL};
+    use fs2::FileExt;
+    use log::info;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+    use std::num::NonZeroUsize;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::time::Duration;
+
+    const NUM_KEYS: u64 = 100000;
+
+    #[test]
+    fn test_command() {
+        let _ = env_logger::try_init();
+
+        for j in 0..2 {
+            let temp_dir = tempfile::Builder::new()
+                .prefix("test_command")
+                .tempdir()
+                .unwrap();
+            let path = temp_dir.path();
+            // let path_buf = PathBuf::from(format!("test_command_{}", j));
+            // let path = path_buf.as_path();
+            info!("{:?}", path);
+            _test_command::<MrMwSkipMapMemTable<InternalKey>>(path, 5);
+            check(path);
+            for i in 0..2 {
+                _test_command::<BTreeMemTable<InternalKey>>(path, i);
+                check(path);
+                _test_command::<MutexSkipMapMemTable<InternalKey>>(path, i);
+                check(path);
+                _test_command::<MrSwSkipMapMemTable<InternalKey>>(path, i);
+                check(path);
+            }
+            _test_command::<MrMwSkipMapMemTable<InternalKey>>(path, 5);
+            check(path);
+        }
+    }
+
+    fn query(
+        db1: Arc<
+            NoTransactionDB<
+                InternalKey,
+                InternalKey,
+                impl MemTable<InternalKey, InternalKey>,
+                SimpleWriteAheadLog,
+            >,
+        >,
+        value_prefix: u32,
+    ) {
+        let mut not_found_key = vec![];
+        for i in 0..NUM_KEYS {
+            let v = db1.get(&format!("key{}", i).into_bytes());
+            let value = v.unwrap();
+            if let Some(value) = value {
+                if format!("value{}_{}", i, value_prefix).as_bytes().ne(&value) {
+                    not_found_key.push(i);
+                }
+            } else {
+                not_found_key.push(i);
+            }
+        }
+
+        if !not_found_key.is_empty() {
+            let mut count = 0;
+            let length = not_found_key.len();
+            warn!("{} keys not found", length);
+            std::thread::sleep(Duration::from_secs(5));
+            for key in not_found_key {
+                println!("{}", key);
+                let v = db1.get(&format!("key{}", key).into_bytes());
+                let value = v.unwrap();
+                if let Some(value) = value {
+                    assert_eq!(format!("value{}_{}", key, value_prefix).into_bytes(), value);
+                } else {
+                    count += 1;
+                }
+            }
+            if count > 0 {
+                panic!("{} keys still not found", count);
+            } else {
+                info!("{} keys now found", length);
+            }
+        }
+    }
+
+    fn _test_command<M: 'static + MemTable<InternalKey, InternalKey>>(
+        path: &Path,
+        value_prefix: u32,
+    ) {
+        let wo = WriteOptions { sync: false };
+        let db = NoTransactionDB::<InternalKey, InternalKey, M, SimpleWriteAheadLog>::open(path)
+            .unwrap();
         db.set(
             &wo,
             "hello".into(),
             format!("world_{}", value_prefix).into_bytes(),
         )
         .unwrap();
-        db.remove(&wo, "no_exist".into()).unwrap();
-        let hello = Vec::from("hello");
-        assert_eq!(
-            format!("world_{}", value_prefix).into_bytes(),
-            db.get(&hello).unwrap().unwrap()
-        );
-        db.remove(&wo, "hello".into()).unwrap();
+        db.remove(&wo, "no_exist".into()).unwrap();
+        let hello = Vec::from("hello");
+        assert_eq!(
+            format!("world_{}", value_prefix).into_bytes(),
+            db.get(&hello).unwrap().unwrap()
+        );
+        db.remove(&wo, "hello".into()).unwrap();
+
+        let v = db.get(&hello).unwrap();
+        assert!(v.is_none(), "{:?}", v);
+
+        let num_keys_zero = NUM_KEYS / 10;
+        for i in 0..NUM_KEYS {
+            db.set(
+                &wo,
+                format!("key{}", if i < num_keys_zero { 0 } else { i }).into_bytes(),
+                format!("value{}_{}", i, value_prefix).into_bytes(),
+            )
+            .unwrap();
+        }
+        for i in 0..num_keys_zero {
+            db.set(
+                &wo,
+                format!("key{}", i).into_bytes(),
+                format!("value{}_{}", i, value_prefix).into_bytes(),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            db.get(&Vec::from("key3")).unwrap().unwrap(),
+            format!("value3_{}", value_prefix).as_bytes()
+        );
+
+        info!("start query");
+
+        let db1 = Arc::new(db);
+        let db2 = db1.clone();
+        let handle1 = std::thread::spawn(move || {
+            query(db1, value_prefix);
+        });
+
+        let handle2 = std::thread::spawn(move || {
+            query(db2, value_prefix);
+        });
+
+        handle1.join().unwrap();
+        handle2.join().unwrap();
+
+        info!("db done");
+    }
+
+    fn check(path: &Path) {
+        let min = InternalKey::default();
+
+        let db_path = path.to_str().unwrap();
+        let leveln_manager = create_manager(db_path);
+        for i in 1..=MAX_LEVEL {
+            let lock =
+                leveln_manager.get_level_tables_lock(unsafe { NonZeroUsize::new_unchecked(i) });
+            let read_guard = lock.read().unwrap();
+            let mut last_min_key;
+            let mut last_max_key: &InternalKey = &min;
+            for (_, table) in read_guard.iter() {
+                let (min_key, max_key) = table.min_max_key();
+                assert!(last_max_key.lt(min_key));
+                assert!(min_key <= max_key);
+                last_min_key = min_key;
+                last_max_key = max_key;
+            }
+        }
+        leveln_manager.close();
+    }
+
+    #[test]
+    fn test_range_query() {
+        let wo = WriteOptions { sync: false };
+        let temp_dir = tempfile::Builder::new()
+            .prefix("range_query")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path();
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MrSwSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(path)
+        .unwrap();
+        for i in 1i32..NUM_KEYS as i32 {
+            db.set(
+                &wo,
+                Vec::from(i.to_be_bytes()),
+                Vec::from((i + 1).to_be_bytes()),
+            )
+            .unwrap();
+        }
+
+        for start in [32, 2203, 1234, 123, 1234, 121, 9982] {
+            let skip_map = db
+                .range_get(
+                    &Vec::from((start + 1i32).to_be_bytes()),
+                    &Vec::from((start + 100i32).to_be_bytes()),
+                )
+                .unwrap();
+            assert_eq!(100, skip_map.len());
+            for node in skip_map.iter_ptr() {
+                unsafe {
+                    assert_eq!(
+                        i32::from_be_bytes((*node).entry.key.clone().try_into().unwrap()) + 1,
+                        i32::from_be_bytes((*node).entry.value.clone().try_into().unwrap())
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_range_query_sstables() {
+        let wo = WriteOptions { sync: false };
+        let temp_dir = tempfile::Builder::new()
+            .prefix("range_query_sstables")
+            .tempdir()
+            .unwrap();
+        let open = || {
+            NoTransactionDB::<
+                InternalKey,
+                InternalKey,
+                MutexSkipMapMemTable<InternalKey>,
+                SimpleWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap()
+        };
+        {
+            let db = open();
+            for (low, high) in [("00", "10"), ("01", "11"), ("02", "12")] {
+                db.set(&wo, Vec::from(low), Vec::from(low)).unwrap();
+                db.set(&wo, Vec::from(high), Vec::from(high)).unwrap();
+                db.flush().unwrap();
+            }
+        }
+
+        // read from the sstables only, whose last blocks hold the ends of the ranges
+        let db = open();
+        let keys = |kvs: SrSwSkipMap<InternalKey, Value>| -> Vec<InternalKey> {
+            kvs.iter().map(|(k, _)| k.clone()).collect()
+        };
+        assert_eq!(keys(db.prefix_scan(b"00").unwrap()), vec![Vec::from("00")]);
+        assert_eq!(keys(db.prefix_scan(b"01").unwrap()), vec![Vec::from("01")]);
+        assert_eq!(
+            keys(db.range_get(&Vec::from("01"), &Vec::from("02")).unwrap()),
+            vec![Vec::from("01"), Vec::from("02")]
+        );
+        assert_eq!(
+            keys(db.range_get(&Vec::from("02"), &Vec::from("11")).unwrap()),
+            vec![Vec::from("02"), Vec::from("10"), Vec::from("11")]
+        );
+        assert_eq!(
+            db.range_get(&Vec::from("00"), &Vec::from("12"))
+                .unwrap()
+                .len(),
+            6
+        );
+    }
+
+    #[test]
+    fn test_read_log() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("read_log")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path();
+
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MrSwSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(path)
+        .unwrap();
+        let wo = WriteOptions { sync: false };
+        for i in 0..NUM_KEYS - 1 {
+            db.set(
+                &wo,
+                format!("{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .unwrap();
+        }
+        drop(db);
+
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            BTreeMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(path)
+        .unwrap();
+
+        for i in 0..NUM_KEYS - 1 {
+            assert_eq!(
+                Some(format!("value{}", i).into_bytes()),
+                db.get(&format!("{}", i).into_bytes()).unwrap()
+            );
+        }
+        for i in NUM_KEYS..NUM_KEYS + 30 {
+            db.set(
+                &wo,
+                format!("{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .unwrap();
+            assert_eq!(
+                Some(format!("value{}", i).into_bytes()),
+                db.get(&format!("{}", i).into_bytes()).unwrap()
+            );
+        }
+
+        drop(db);
+        let db = Arc::new(
+            NoTransactionDB::<
+                InternalKey,
+                InternalKey,
+                MrMwSkipMapMemTable<InternalKey>,
+                SimpleWriteAheadLog,
+            >::open(path)
+            .unwrap(),
+        );
+        for _ in 0..4 {
+            test_log(db.clone());
+        }
+
+        let thread_cnt = 4;
+
+        for _ in 0..10 {
+            let barrier = Arc::new(Barrier::new(thread_cnt));
+            let mut handles = vec![];
+            for _ in 0..thread_cnt {
+                let db = db.clone();
+                let barrier = barrier.clone();
+                let handle = std::thread::spawn(move || {
+                    barrier.wait();
+                    test_log(db);
+                });
+                handles.push(handle);
+            }
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        }
+    }
+
+    fn test_log<M: MemTable<InternalKey, InternalKey> + 'static>(
+        db: Arc<NoTransactionDB<InternalKey, InternalKey, M, SimpleWriteAheadLog>>,
+    ) {
+        for _ in 0..3 {
+            for i in NUM_KEYS..NUM_KEYS + 30 {
+                assert_eq!(
+                    Some(format!("value{}", i).into_bytes()),
+                    db.get(&format!("{}", i).into_bytes())
+                        .expect("error in read thread1")
+                );
+            }
+        }
+    }
+
+    pub(crate) fn create_random_map(size: usize) -> HashMap<i32, usize> {
+        let mut map = HashMap::new();
+        let rng = rand::thread_rng();
+        let distribution = rand::distributions::uniform::Uniform::new(0, i32::MAX);
+        for (cnt, i) in rng.sample_iter(distribution).enumerate() {
+            map.insert(i, cnt);
+            if cnt >= size {
+                break;
+            }
+        }
+        map
+    }
+
+    #[test]
+    fn test_random() {
+        let _ = env_logger::try_init();
+        let temp_dir = tempfile::Builder::new()
+            .prefix("random_test")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path();
+        let write_option = WriteOptions { sync: false };
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MrMwSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(path)
+        .unwrap();
+
+        let map = create_random_map(20000);
+        for (k, v) in map.iter() {
+            db.set(
+                &write_option,
+                Vec::from(k.to_le_bytes()),
+                Vec::from(v.to_le_bytes()),
+            )
+            .unwrap();
+        }
+        info!("start query");
+        let mut not_found_map = HashMap::new();
+        for (i, (k, v)) in map.iter().enumerate() {
+            if let Some(s) = db.get(&Vec::from(k.to_le_bytes())).unwrap() {
+                assert_eq!(s, v.to_le_bytes());
+            } else {
+                not_found_map.insert(*k, *v);
+            }
+            if i % 10000 == 0 {
+                info!("{}", i);
+            }
+        }
+        if !not_found_map.is_empty() {
+            warn!("{} keys not found", not_found_map.len());
+            std::thread::sleep(Duration::from_secs(5));
+            for (k, v) in not_found_map {
+                if let Some(s) = db.get(&Vec::from(k.to_le_bytes())).unwrap() {
+                    assert_eq!(s, v.to_le_bytes());
+                } else {
+                    panic!("{} {}", k, v);
+                }
+            }
+        }
+    }
+
+    fn setup_iterate1(
+        db: &NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MrMwSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >,
+        write_option: &WriteOptions,
+    ) -> usize {
+        for _ in 0..3 {
+            for i in 0..10000u128 {
+                db.set(
+                    write_option,
+                    Vec::from(i.to_be_bytes()),
+                    Vec::from((i + 1).to_be_bytes()),
+                )
+                .unwrap();
+            }
+        }
+        10000
+    }
+
+    fn setup_iterate2(
+        db: &NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MrMwSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >,
+        write_option: &WriteOptions,
+    ) -> usize {
+        for i in 0..1000000u128 {
+            db.set(
+                write_option,
+                Vec::from(i.to_be_bytes()),
+                Vec::from((i + 1).to_be_bytes()),
+            )
+            .unwrap();
+        }
+        1000000
+    }
+
+    #[test]
+    fn test_iterate() {
+        for f in [setup_iterate1, setup_iterate2] {
+            let temp_dir = tempfile::Builder::new()
+                .prefix("iterate")
+                .tempdir()
+                .unwrap();
+            let path = temp_dir.path();
+            let write_option = WriteOptions { sync: false };
+            let db = NoTransactionDB::<
+                InternalKey,
+                InternalKey,
+                MrMwSkipMapMemTable<InternalKey>,
+                SimpleWriteAheadLog,
+            >::open(path)
+            .unwrap();
+
+            let expected_count = f(&db, &write_option);
+            let iterator = db.get_db_iterator();
+            let mut count = 0;
+            for kv in iterator {
+                kv.unwrap();
+                count += 1;
+            }
+            assert_eq!(expected_count, count);
+
+            let iterator = db.get_db_iterator();
+            for (i, kv) in iterator.enumerate() {
+                let (k, v) = kv.unwrap();
+                let i = i as u128;
+                assert_eq!(Vec::from(i.to_be_bytes()), k);
+                assert_eq!(Vec::from((i + 1).to_be_bytes()), v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_wal_sync_interval() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("wal_sync")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path();
+        let interval = Duration::from_millis(50);
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open_with_options(
+            path,
+            Options {
+                wal_sync: WalSync::Interval(interval),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        let wo = WriteOptions { sync: false };
+        for i in 0..100 {
+            db.set(
+                &wo,
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .unwrap();
+        }
+        std::thread::sleep(interval * 2);
+
+        let synced_len = {
+            let wal_guard = db.wal.lock().unwrap();
+            <SimpleWriteAheadLog as WAL<InternalKey, InternalKey>>::synced_len(&wal_guard)
+        };
+        let log_file = std::fs::File::open(path.join("log").join("1.log")).unwrap();
+        assert!(synced_len > 0);
+        assert_eq!(synced_len, log_file.metadata().unwrap().len());
+
+        // read the logs from disk without closing db
+        let mut mem_table = MutexSkipMapMemTable::<InternalKey>::default();
+        <SimpleWriteAheadLog as WAL<InternalKey, InternalKey>>::load_log(&log_file, &mut mem_table)
+            .unwrap();
+        for i in 0..100 {
+            assert_eq!(
+                mem_table.get(&format!("key{}", i).into_bytes()).unwrap(),
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+    }
+
+    #[derive(Debug)]
+    struct PanicFilter;
+
+    impl CompactionFilter for PanicFilter {
+        fn filter(&self, _key: &[u8], _value: &[u8]) -> Decision {
+            panic!("injected panic in compaction");
+        }
+    }
+
+    #[test]
+    fn test_background_failure() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("background_failure")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            compaction_filter: Some(Arc::new(PanicFilter)),
+            ..Options::default()
+        };
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open_with_options(temp_dir.path(), options)
+        .unwrap();
+
+        // write until the level0 compaction starts and panics
+        let wo = WriteOptions { sync: false };
+        let mut result = Ok(());
+        for i in 0..200000 {
+            result = db.set(&wo, format!("key{}", i).into_bytes(), vec![1u8; 1024]);
+            if result.is_err() {
+                break;
+            }
+        }
+        for _ in 0..100 {
+            if result.is_err() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+            result = db.get(&Vec::from("key0")).map(|_| ());
+        }
+
+        assert_eq!(result, Err(KVLiteError::BackgroundFailure));
+        assert_eq!(
+            db.get(&Vec::from("key0")),
+            Err(KVLiteError::BackgroundFailure)
+        );
+        assert_eq!(
+            db.remove(&wo, Vec::from("key0")),
+            Err(KVLiteError::BackgroundFailure)
+        );
+        assert!(db
+            .range_get(&Vec::from("key0"), &Vec::from("key1"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("subscribe")
+            .tempdir()
+            .unwrap();
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        let receiver = db.subscribe(b"user:");
+        let dropped_receiver = db.subscribe(b"user:");
+        drop(dropped_receiver);
+
+        let wo = WriteOptions { sync: false };
+        db.set(&wo, Vec::from("user:1"), Vec::from("a")).unwrap();
+        db.set(&wo, Vec::from("order:1"), Vec::from("b")).unwrap();
+        db.remove(&wo, Vec::from("user:1")).unwrap();
+        db.set(&wo, Vec::from("user:2"), Vec::from("c")).unwrap();
+        db.remove(&wo, Vec::from("order:1")).unwrap();
+
+        let events: Vec<(InternalKey, Option<Vec<u8>>)> = receiver.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                (Vec::from("user:1"), Some(Vec::from("a"))),
+                (Vec::from("user:1"), None),
+                (Vec::from("user:2"), Some(Vec::from("c"))),
+            ]
+        );
+        assert_eq!(db.subscribers.lock().unwrap().len(), 1);
+
+        // a subscriber lagging behind does not block the writers but is dropped
+        for i in 0..SUBSCRIBER_CHANNEL_CAPACITY + 10 {
+            db.set(&wo, format!("user:{}", i).into_bytes(), Vec::from("v"))
+                .unwrap();
+        }
+        assert!(db.subscribers.lock().unwrap().is_empty());
+        assert_eq!(receiver.iter().count(), SUBSCRIBER_CHANNEL_CAPACITY);
+    }
+
+    #[test]
+    fn test_bulk_ingest() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("bulk_ingest")
+            .tempdir()
+            .unwrap();
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+
+        let wo = WriteOptions { sync: false };
+        db.set(&wo, Vec::from("a"), Vec::from("a")).unwrap();
+
+        let kvs = (0..100000u32).map(|i| {
+            (
+                format!("key{:06}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+        });
+        db.bulk_ingest(kvs).unwrap();
+
+        // key-values are written to sstables directly
+        assert_eq!(db.get_mut_mem_table().len(), 1);
+        assert!(db.leveln_manager.file_count(MAX_LEVEL) > 0);
+        for i in 0..100000u32 {
+            assert_eq!(
+                db.get(&format!("key{:06}", i).into_bytes()).unwrap(),
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+
+        // overlapping with existing keys
+        let file_count = db.leveln_manager.file_count(MAX_LEVEL);
+        let kvs = vec![
+            (Vec::from("0"), Vec::from("0")),
+            (Vec::from("b"), Vec::from("b")),
+        ];
+        assert!(db.bulk_ingest(kvs.into_iter()).is_err());
+        // not sorted
+        let kvs = vec![
+            (Vec::from("d"), Vec::from("d")),
+            (Vec::from("c"), Vec::from("c")),
+        ];
+        assert!(db.bulk_ingest(kvs.into_iter()).is_err());
+        assert_eq!(db.leveln_manager.file_count(MAX_LEVEL), file_count);
+        assert!(db.get(&Vec::from("0")).unwrap().is_none());
+        assert!(db.get(&Vec::from("c")).unwrap().is_none());
+
+        // within the key range of a table of the last level, between two of its keys
+        let kvs = vec![
+            (Vec::from("key000100a"), Vec::from("a")),
+            (Vec::from("key000100b"), Vec::from("b")),
+        ];
+        db.bulk_ingest(kvs.into_iter()).unwrap();
+        assert_eq!(db.leveln_manager.file_count(MAX_LEVEL), file_count);
+        assert_eq!(db.level0_manager.file_count(), 1);
+        assert_eq!(
+            db.get(&Vec::from("key000100a")).unwrap(),
+            Some(Vec::from("a"))
+        );
+        assert_eq!(
+            db.get(&Vec::from("key000100b")).unwrap(),
+            Some(Vec::from("b"))
+        );
+        assert_eq!(
+            db.get(&Vec::from("key000101")).unwrap(),
+            Some(Vec::from("value101"))
+        );
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("empty_key")
+            .tempdir()
+            .unwrap();
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+
+        let wo = WriteOptions { sync: false };
+
+        // the first table is appended to level1, the others are written to level0
+        // and compacted with it.
+        for i in 0..=LEVEL0_FILES_THRESHOLD + 1 {
+            db.set(&wo, InternalKey::new(), format!("value{}", i).into_bytes())
+                .unwrap();
+            db.set(&wo, Vec::from(" "), format!("space{}", i).into_bytes())
+                .unwrap();
+            db.set(&wo, Vec::from("key"), format!("key{}", i).into_bytes())
+                .unwrap();
+            db.freeze();
+            db.wait_for_level0_writer().unwrap();
+        }
+        while db.level0_manager.file_count() > LEVEL0_FILES_THRESHOLD {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(db.leveln_manager.file_count(1) > 0);
+
+        let last = LEVEL0_FILES_THRESHOLD + 1;
+        assert_eq!(
+            db.get(&InternalKey::new()).unwrap(),
+            Some(format!("value{}", last).into_bytes())
+        );
+        assert_eq!(
+            db.get(&Vec::from(" ")).unwrap(),
+            Some(format!("space{}", last).into_bytes())
+        );
+
+        // the empty key is the smallest key
+        let kvs = db.range_get(&InternalKey::new(), &Vec::from("z")).unwrap();
+        let keys: Vec<InternalKey> = kvs.iter().map(|(k, _v)| k.clone()).collect();
+        assert_eq!(keys, vec![vec![], Vec::from(" "), Vec::from("key")]);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("write_batch")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: true };
+        {
+            let db = NoTransactionDB::<
+                InternalKey,
+                InternalKey,
+                MutexSkipMapMemTable<InternalKey>,
+                SimpleWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap();
+            db.set(&wo, Vec::from("k1"), Vec::from("v1")).unwrap();
+            db.set(&wo, Vec::from("k2"), Vec::from("v2")).unwrap();
+
+            let mut batch = db.batch();
+            batch.put(Vec::from("k3"), Vec::from("v3"));
+            batch.delete(Vec::from("k1"));
+            batch.put(Vec::from("k2"), Vec::from("v2_old"));
+            batch.put(Vec::from("k2"), Vec::from("v2_new"));
+            assert_eq!(batch.len(), 3);
+
+            // nothing is applied before `write`
+            assert_eq!(db.get(&Vec::from("k1")).unwrap(), Some(Vec::from("v1")));
+            assert_eq!(db.get(&Vec::from("k2")).unwrap(), Some(Vec::from("v2")));
+            assert!(db.get(&Vec::from("k3")).unwrap().is_none());
+
+            db.write(&wo, batch).unwrap();
+            assert!(db.get(&Vec::from("k1")).unwrap().is_none());
+            assert_eq!(db.get(&Vec::from("k2")).unwrap(), Some(Vec::from("v2_new")));
+            assert_eq!(db.get(&Vec::from("k3")).unwrap(), Some(Vec::from("v3")));
+        }
+
+        // the batch is recovered from WAL
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        assert!(db.get(&Vec::from("k1")).unwrap().is_none());
+        assert_eq!(db.get(&Vec::from("k2")).unwrap(), Some(Vec::from("v2_new")));
+        assert_eq!(db.get(&Vec::from("k3")).unwrap(), Some(Vec::from("v3")));
+    }
+
+    #[test]
+    fn test_max_value_size() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("max_value_size")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            max_value_bytes: 10,
+            ..Options::default()
+        };
+        let wo = WriteOptions { sync: true };
+        let too_large = Err(KVLiteError::ValueTooLarge {
+            size: 11,
+            limit: 10,
+        });
+        {
+            let db = NoTransactionDB::<
+                InternalKey,
+                InternalKey,
+                MutexSkipMapMemTable<InternalKey>,
+                SimpleWriteAheadLog,
+            >::open_with_options(temp_dir.path(), options.clone())
+            .unwrap();
+            let synced_len = || {
+                <SimpleWriteAheadLog as WAL<InternalKey, InternalKey>>::synced_len(
+                    &db.wal.lock().unwrap(),
+                )
+            };
+            let synced_before = synced_len();
+
+            assert_eq!(db.set(&wo, Vec::from("k1"), vec![b'v'; 11]), too_large);
+            let mut batch = db.batch();
+            batch.put(Vec::from("k2"), vec![b'v'; 10]);
+            batch.put(Vec::from("k3"), vec![b'v'; 11]);
+            assert_eq!(db.write(&wo, batch), too_large);
+            // nothing is appended to WAL
+            assert_eq!(synced_len(), synced_before);
+
+            db.set(&wo, Vec::from("k4"), vec![b'v'; 10]).unwrap();
+        }
+
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open_with_options(temp_dir.path(), options)
+        .unwrap();
+        for key in ["k1", "k2", "k3"] {
+            assert!(db.get(&Vec::from(key)).unwrap().is_none());
+        }
+        assert_eq!(db.get(&Vec::from("k4")).unwrap(), Some(vec![b'v'; 10]));
+    }
+
+    #[test]
+    fn test_flush() {
+        let temp_dir = tempfile::Builder::new().prefix("flush").tempdir().unwrap();
+        let wo = WriteOptions { sync: false };
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        for i in 0..1000 {
+            db.set(
+                &wo,
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .unwrap();
+        }
+        db.flush().unwrap();
+        assert!(db.get_mut_mem_table().is_empty());
+        // exit without closing the database, the lock is released as if the process exits
+        db.lock_file.as_ref().unwrap().unlock().unwrap();
+        std::mem::forget(db);
+
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        // nothing is recovered from WAL
+        assert!(db.get_mut_mem_table().is_empty());
+        assert!(db.level0_manager.file_count() + db.leveln_manager.file_count(1) > 0);
+        for i in 0..1000 {
+            assert_eq!(
+                db.get(&format!("key{}", i).into_bytes()).unwrap(),
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let temp_dir = tempfile::Builder::new().prefix("stats").tempdir().unwrap();
+        let wo = WriteOptions { sync: false };
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        assert_eq!(db.stats(), Stats::default());
+        assert_eq!(db.stats().write_amplification(), 0.0);
+
+        // every flush creates a level0 sstable, which triggers compacting level0
+        for round in 0..=LEVEL0_FILES_THRESHOLD {
+            for i in 0..1000 {
+                db.set(
+                    &wo,
+                    format!("key{}", i).into_bytes(),
+                    format!("value{}_{}", i, round).into_bytes(),
+                )
+                .unwrap();
+            }
+            db.flush().unwrap();
+        }
+        // the immutable memory table kept after a flush would serve the reads below
+        db.set(&wo, Vec::from("other"), Vec::from("value")).unwrap();
+        db.flush().unwrap();
+
+        let stats = db.stats();
+        assert!(stats.user_bytes_written > 0);
+        assert!(stats.wal_bytes_written > stats.user_bytes_written);
+        assert!(stats.sstable_bytes_written > 0);
+        assert!(stats.write_amplification() > 1.0);
+        assert_eq!(stats.user_bytes_read, 0);
+
+        for i in 0..1000 {
+            assert_eq!(
+                db.get(&format!("key{}", i).into_bytes()).unwrap(),
+                Some(format!("value{}_{}", i, LEVEL0_FILES_THRESHOLD).into_bytes())
+            );
+        }
+        let stats = db.stats();
+        assert!(stats.user_bytes_read > 0);
+        assert!(stats.disk_bytes_read > 0);
+        assert!(stats.read_amplification() > 0.0);
+    }
+
+    #[test]
+    fn test_stats_diff() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("stats_diff")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        let key = |i: usize| format!("key{:03}", i).into_bytes();
+        let value = |i: usize| format!("value{:03}", i).into_bytes();
+        // every key-value pair takes 14 bytes
+        let bytes = 100 * 14;
+
+        let start = db.stats();
+        for i in 0..100 {
+            db.set(&wo, key(i), value(i)).unwrap();
+        }
+        let written = db.stats();
+        let delta = written - start;
+        assert_eq!(delta.user_bytes_written, bytes);
+        assert!(delta.wal_bytes_written > bytes);
+        assert_eq!(delta.user_bytes_read, 0);
+        assert_eq!(start + delta, written);
+
+        for i in 0..100 {
+            assert_eq!(db.get(&key(i)).unwrap(), Some(value(i)));
+        }
+        let read = db.stats();
+        let delta = read - written;
+        assert_eq!(delta.user_bytes_written, 0);
+        assert_eq!(delta.wal_bytes_written, 0);
+        assert_eq!(delta.user_bytes_read, bytes);
+        assert_eq!(read - start, (written - start) + delta);
+
+        assert_eq!(read - read, Stats::default());
+        // counters never go below 0
+        assert_eq!(start - read, Stats::default());
+    }
+
+    #[test]
+    fn test_open_errors() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("open_errors")
+            .tempdir()
+            .unwrap();
+
+        let missing = temp_dir.path().join("missing");
+        let options = Options {
+            create_if_missing: false,
+            ..Options::default()
+        };
+        assert_eq!(
+            TestDB::open_with_options(&missing, options).err(),
+            Some(KVLiteError::DbPathNotFound(missing.clone()))
+        );
+        assert!(!missing.exists());
+        // created by default
+        drop(TestDB::open(&missing).unwrap());
+        assert!(missing.is_dir());
+
+        let file_path = temp_dir.path().join("file");
+        std::fs::write(&file_path, b"not a directory").unwrap();
+        assert_eq!(
+            TestDB::open(&file_path).err(),
+            Some(KVLiteError::DbPathNotDirectory(file_path.clone()))
+        );
+
+        let db_path = temp_dir.path().join("db");
+        let db = TestDB::open(&db_path).unwrap();
+        assert_eq!(
+            TestDB::open(&db_path).err(),
+            Some(KVLiteError::DbLocked(db_path.clone()))
+        );
+        drop(db);
+    }
+
+    #[test]
+    fn test_lock_released_on_drop() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new().prefix("lock").tempdir().unwrap();
+        let wo = WriteOptions { sync: true };
+
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        db.set(&wo, Vec::from("k1"), Vec::from("v1")).unwrap();
+        assert_eq!(
+            TestDB::open(temp_dir.path()).err(),
+            Some(KVLiteError::DbLocked(temp_dir.path().to_path_buf()))
+        );
+        // the failed open does not disturb the opened database
+        assert_eq!(db.get(&Vec::from("k1")).unwrap(), Some(Vec::from("v1")));
+        drop(db);
+
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get(&Vec::from("k1")).unwrap(), Some(Vec::from("v1")));
+    }
+
+    #[test]
+    fn test_prefix_bloom() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        fn fill(db: &TestDB) {
+            let wo = WriteOptions { sync: false };
+            // sstable `t` contains prefixes `t` and `t + 10`, so the key ranges of
+            // the sstables overlap with each other.
+            for t in 0..3 {
+                for prefix in [t, t + 10] {
+                    for i in 0..100 {
+                        db.set(
+                            &wo,
+                            format!("{:02}_{:03}", prefix, i).into_bytes(),
+                            format!("value{}", i).into_bytes(),
+                        )
+                        .unwrap();
+                    }
+                }
+                db.flush().unwrap();
+            }
+        }
+
+        let temp_dir = tempfile::Builder::new()
+            .prefix("prefix_bloom")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            prefix_bloom_length: 2,
+            ..Options::default()
+        };
+        let db = TestDB::open_with_options(temp_dir.path().join("bloom"), options).unwrap();
+        fill(&db);
+        assert!(db.get_mut_mem_table().is_empty());
+
+        let kvs = db.prefix_scan(b"01").unwrap();
+        assert_eq!(kvs.len(), 100);
+        for (i, (k, v)) in kvs.iter().enumerate() {
+            assert_eq!(&format!("01_{:03}", i).into_bytes(), k);
+            assert_eq!(&format!("value{}", i).into_bytes(), v);
+        }
+        let matched_bytes_read = db.stats().disk_bytes_read;
+        assert!(matched_bytes_read > 0);
+
+        // prefix `05` is in the key ranges of all the sstables, but none of them contains it
+        assert!(db.prefix_scan(b"05").unwrap().is_empty());
+        assert_eq!(db.stats().disk_bytes_read, matched_bytes_read);
+
+        // shorter prefixes cannot be checked by the filters
+        assert_eq!(db.prefix_scan(b"1").unwrap().len(), 300);
+        assert_eq!(db.prefix_scan(b"").unwrap().len(), 600);
+
+        let db = TestDB::open(temp_dir.path().join("no_bloom")).unwrap();
+        fill(&db);
+        assert!(db.prefix_scan(b"05").unwrap().is_empty());
+        assert!(db.stats().disk_bytes_read > 0);
+    }
+
+    #[test]
+    fn test_bloom_mode() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let wo = WriteOptions { sync: false };
+        let key = |prefix: usize, i: usize| format!("{:02}_{:03}", prefix, i).into_bytes();
+        let fill = |db: &TestDB, prefixes: &[usize]| {
+            for prefix in prefixes {
+                for i in 0..100 {
+                    db.set(&wo, key(*prefix, i), format!("value{}", i).into_bytes())
+                        .unwrap();
+                }
+            }
+            db.flush().unwrap();
+        };
+        let temp_dir = tempfile::Builder::new()
+            .prefix("bloom_mode")
+            .tempdir()
+            .unwrap();
+
+        {
+            let options = Options {
+                bloom_mode: BloomMode::Prefix(2),
+                ..Options::default()
+            };
+            let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+            // the key ranges of the sstables overlap with each other
+            fill(&db, &[0, 10]);
+            fill(&db, &[1, 11]);
+            assert!(db.get_mut_mem_table().is_empty());
+
+            assert_eq!(db.prefix_scan(b"01").unwrap().len(), 100);
+            let bytes_read = db.stats().disk_bytes_read;
+            assert!(db.prefix_scan(b"05").unwrap().is_empty());
+            assert_eq!(db.stats().disk_bytes_read, bytes_read);
+            for prefix in [0, 1, 10, 11] {
+                assert_eq!(
+                    db.get(&key(prefix, 42)).unwrap(),
+                    Some(Vec::from("value42"))
+                );
+            }
+            assert_eq!(db.get(&key(5, 42)).unwrap(), None);
+        }
+
+        // the prefix-mode sstables are still probed with prefixes
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        fill(&db, &[2, 12]);
+        // the first table is appended to the empty level 1, and the others overlap it
+        assert_eq!(db.leveln_manager.file_count(1), 1);
+        assert_eq!(db.level0_manager.file_count(), 2);
+        for prefix in [0, 1, 2, 10, 11, 12] {
+            for i in [0, 42, 99] {
+                assert_eq!(
+                    db.get(&key(prefix, i)).unwrap(),
+                    Some(format!("value{}", i).into_bytes())
+                );
+            }
+        }
+        assert_eq!(db.get(&key(2, 100)).unwrap(), None);
+        assert_eq!(db.prefix_scan(b"12").unwrap().len(), 100);
+    }
+
+    #[test]
+    fn test_freeze_on_tombstones() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("freeze_on_tombstones")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            max_memtable_tombstones: 100,
+            ..Options::default()
+        };
+        let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+        let wo = WriteOptions { sync: false };
+
+        db.set(&wo, Vec::from("key"), Vec::from("value")).unwrap();
+        for i in 0..99 {
+            db.remove(&wo, format!("key{}", i).into_bytes()).unwrap();
+        }
+        // removing a removed key again does not add a tombstone
+        db.remove(&wo, Vec::from("key0")).unwrap();
+        assert_eq!(db.get_mut_mem_table().tombstone_count(), 99);
+        assert_eq!(db.leveln_manager.file_count(1), 0);
+
+        // the frozen table is appended to the empty level 1
+        db.remove(&wo, Vec::from("key")).unwrap();
+        db.wait_for_level0_writer().unwrap();
+        assert!(db.get_mut_mem_table().is_empty());
+        assert_eq!(db.get_mut_mem_table().tombstone_count(), 0);
+        assert_eq!(db.leveln_manager.file_count(1), 1);
+        assert!(db.get(&Vec::from("key")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_in_memory() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            NullWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("in_memory")
+            .tempdir()
+            .unwrap();
+        let db_path = temp_dir.path().join("db");
+        let db = TestDB::open_with_options(&db_path, Options::in_memory()).unwrap();
+        let wo = WriteOptions { sync: true };
+
+        // level0 tables are compacted to level1 after the threshold is exceeded
+        for round in 0..=LEVEL0_FILES_THRESHOLD + 1 {
+            for i in 0..100 {
+                db.set(
+                    &wo,
+                    format!("key{:03}", i).into_bytes(),
+                    format!("value{}_{}", round, i).into_bytes(),
+                )
+                .unwrap();
+            }
+            db.remove(&wo, format!("key{:03}", round).into_bytes())
+                .unwrap();
+            db.flush().unwrap();
+        }
+        while db.level0_manager.file_count() > LEVEL0_FILES_THRESHOLD {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(db.leveln_manager.file_count(1) > 0);
+        assert!(db.get_mut_mem_table().is_empty());
+
+        let last = LEVEL0_FILES_THRESHOLD + 1;
+        assert!(db
+            .get(&format!("key{:03}", last).into_bytes())
+            .unwrap()
+            .is_none());
+        for i in last + 1..100 {
+            assert_eq!(
+                db.get(&format!("key{:03}", i).into_bytes()).unwrap(),
+                Some(format!("value{}_{}", last, i).into_bytes())
+            );
+        }
+        let kvs = db.range_get(&Vec::from("key"), &Vec::from("kez")).unwrap();
+        assert_eq!(kvs.len(), 99);
+
+        // nothing is written under the database path
+        assert!(!db_path.exists());
+        drop(db);
+        assert!(!db_path.exists());
+    }
+
+    #[test]
+    fn test_max_key_size() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("max_key_size")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            max_key_bytes: 4,
+            ..Options::default()
+        };
+        let wo = WriteOptions { sync: true };
+        let too_large = Err(KVLiteError::KeyTooLarge { size: 5, limit: 4 });
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open_with_options(temp_dir.path(), options)
+        .unwrap();
+
+        assert_eq!(db.set(&wo, Vec::from("key12"), Vec::from("v")), too_large);
+        assert_eq!(db.remove(&wo, Vec::from("key12")), too_large);
+        let mut batch = db.batch();
+        batch.put(Vec::from("key1"), Vec::from("v"));
+        batch.put(Vec::from("key12"), Vec::from("v"));
+        assert_eq!(db.write(&wo, batch), too_large);
+        assert!(db.get(&Vec::from("key1")).unwrap().is_none());
+
+        db.set(&wo, Vec::from("key1"), Vec::from("v")).unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.get(&Vec::from("key1")).unwrap(), Some(Vec::from("v")));
+    }
+
+    #[test]
+    fn test_range_get_rev() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("range_get_rev")
+            .tempdir()
+            .unwrap();
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        let wo = WriteOptions { sync: false };
+
+        // keys are spread over an sstable and the memory table
+        for i in (0..100).step_by(2) {
+            db.set(
+                &wo,
+                format!("key{:03}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .unwrap();
+        }
+        db.flush().unwrap();
+        for i in (1..100).step_by(2) {
+            db.set(
+                &wo,
+                format!("key{:03}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .unwrap();
+        }
+        for i in (0..100).step_by(10) {
+            db.remove(&wo, format!("key{:03}", i).into_bytes()).unwrap();
+        }
+
+        let key_start = Vec::from("key020");
+        let key_end = Vec::from("key079");
+        let ascending: Vec<(InternalKey, Value)> = db
+            .range_get(&key_start, &key_end)
+            .unwrap()
+            .into_iter()
+            .collect();
+        let mut descending = db.range_get_rev(&key_start, &key_end).unwrap();
+        assert_eq!(descending.len(), 54);
+        assert!(descending.windows(2).all(|w| w[0].0 > w[1].0));
+        assert!(descending.iter().all(|(_, v)| !v.is_empty()));
+        descending.reverse();
+        assert_eq!(ascending, descending);
+    }
+
+    #[test]
+    fn test_verify() {
+        let temp_dir = tempfile::Builder::new().prefix("verify").tempdir().unwrap();
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        let wo = WriteOptions { sync: false };
+        for i in 0..1000 {
+            db.set(
+                &wo,
+                format!("key{:04}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .unwrap();
+        }
+        db.remove(&wo, Vec::from("key0000")).unwrap();
+        db.flush().unwrap();
+
+        let report = db.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(report.tables_checked >= 1);
+        assert!(report.kvs_checked >= 1000);
+
+        // overwrite the key length of the first entry in the first data block of the
+        // table, which is appended to the empty level 1
+        let (level, table_id) = {
+            let tables = db.list_tables();
+            assert_eq!(tables.len(), 1);
+            (tables[0].level(), tables[0].table_id())
+        };
+        assert_eq!(level, 1);
+        let path = crate::sstable::sstable_file(db.db_path(), level, table_id);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[0..4].copy_from_slice(&[0xff; 4]);
+        std::fs::write(&path, bytes).unwrap();
+
+        let report = db.verify().unwrap();
+        assert!(!report.is_ok());
+        let anomaly = report
+            .anomalies
+            .iter()
+            .find(|a| a.table_id == table_id)
+            .unwrap();
+        assert_eq!(anomaly.level, level);
+        assert_eq!(anomaly.block_offset, Some(0));
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("memory_usage")
+            .tempdir()
+            .unwrap();
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        let wo = WriteOptions { sync: false };
+        let write = |prefix: &str| {
+            for i in 0..500 {
+                db.set(
+                    &wo,
+                    format!("{}{:03}", prefix, i).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                )
+                .unwrap();
+            }
+        };
+
+        let usage0 = db.memory_usage();
+        assert_eq!(usage0.block_cache, 0);
+        write("a");
+        let usage1 = db.memory_usage();
+        assert!(usage1.mut_mem_table > usage0.mut_mem_table);
+        write("b");
+        let usage2 = db.memory_usage();
+        assert!(usage2.mut_mem_table > usage1.mut_mem_table);
+
+        // the immutable memory table keeps the last flushed writes, so flush twice
+        // to make the reads of the first writes go to the sstables
+        db.flush().unwrap();
+        write("c");
+        db.flush().unwrap();
+        assert_eq!(db.memory_usage().block_cache, 0);
+        for i in 0..500 {
+            assert!(db
+                .get(&format!("a{:03}", i).into_bytes())
+                .unwrap()
+                .is_some());
+        }
+
+        let usage3 = db.memory_usage();
+        assert!(usage3.block_cache > 0);
+        assert!(usage3.index_blocks > 0);
+        assert!(usage3.filters > 0);
+        assert_eq!(
+            usage3.total(),
+            usage3.mut_mem_table
+                + usage3.imm_mem_table
+                + usage3.block_cache
+                + usage3.filters
+                + usage3.index_blocks
+        );
+    }
+
+    #[test]
+    fn test_reduce_level0_files_threshold() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("level0_threshold")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        let last = LEVEL0_FILES_THRESHOLD;
+        {
+            let db = TestDB::open(temp_dir.path()).unwrap();
+            // the first table is appended to level1, the others overlap with it
+            for round in 0..=last {
+                for i in 0..100 {
+                    db.set(
+                        &wo,
+                        format!("key{:02}", i).into_bytes(),
+                        format!("value{}_{}", i, round).into_bytes(),
+                    )
+                    .unwrap();
+                }
+                db.flush().unwrap();
+            }
+            assert_eq!(db.level0_manager.file_count(), LEVEL0_FILES_THRESHOLD);
+        }
+
+        let options = Options {
+            level0_files_threshold: 2,
+            ..Options::default()
+        };
+        let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+        for _ in 0..500 {
+            if db.level0_manager.file_count() <= 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(db.level0_manager.file_count() <= 2);
+        assert!(db.leveln_manager.file_count(1) > 0);
+        for i in 0..100 {
+            assert_eq!(
+                db.get(&format!("key{:02}", i).into_bytes()).unwrap(),
+                Some(format!("value{}_{}", i, last).into_bytes())
+            );
+        }
+        assert!(db.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_level0_file_size_threshold() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("level0_size_threshold")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        // each table takes about 100 KB
+        let options = Options {
+            level0_file_size_threshold: 150 * 1024,
+            ..Options::default()
+        };
+        let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+        let value = |round: usize| vec![b'a' + round as u8; 1024];
+        let write_round = |round: usize| {
+            for i in 0..100 {
+                db.set(&wo, format!("key{:02}", i).into_bytes(), value(round))
+                    .unwrap();
+            }
+            db.flush().unwrap();
+        };
+
+        // the first table is appended to level1, the second one stays in level0
+        write_round(0);
+        write_round(1);
+        assert_eq!(db.level0_manager.file_count(), 1);
+        assert!(db.level0_manager.file_size() < 150 * 1024);
+        assert!(!db.level0_manager.needs_compaction());
+
+        // two tables are far fewer than LEVEL0_FILES_THRESHOLD, but large enough
+        write_round(2);
+        for _ in 0..500 {
+            if db.level0_manager.file_count() == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(db.level0_manager.file_count(), 0);
+        assert_eq!(db.level0_manager.file_size(), 0);
+        for i in 0..100 {
+            assert_eq!(
+                db.get(&format!("key{:02}", i).into_bytes()).unwrap(),
+                Some(value(2))
+            );
+        }
+        assert!(db.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_write_stall() {
+        /// Throttle compaction so that level 0 piles up.
+        #[derive(Debug)]
+        struct SlowFilter;
+        impl CompactionFilter for SlowFilter {
+            fn filter(&self, _key: &[u8], _value: &[u8]) -> Decision {
+                std::thread::sleep(Duration::from_millis(20));
+                Decision::Keep
+            }
+        }
+
+        let temp_dir = tempfile::Builder::new()
+            .prefix("write_stall")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            compaction_filter: Some(Arc::new(SlowFilter)),
+            level0_files_threshold: 2,
+            level0_slowdown_writes_trigger: 3,
+            level0_stop_writes_trigger: 4,
+            ..Options::default()
+        };
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open_with_options(temp_dir.path(), options)
+        .unwrap();
+        let wo = WriteOptions { sync: false };
+
+        // every round overwrites the same keys, so the tables overlap with level 1
+        let rounds = 12;
+        let mut max_file_count = 0;
+        for round in 0..rounds {
+            for i in 0..10 {
+                db.set(
+                    &wo,
+                    format!("key{}", i).into_bytes(),
+                    format!("value{}_{}", i, round).into_bytes(),
+                )
+                .unwrap();
+            }
+            db.freeze();
+            db.wait_for_level0_writer().unwrap();
+            max_file_count = max_file_count.max(db.level0_manager.file_count());
+        }
+
+        assert!(max_file_count <= 4, "{}", max_file_count);
+        assert!(db.stats().write_stall_micros > 0);
+        for i in 0..10 {
+            assert_eq!(
+                db.get(&format!("key{}", i).into_bytes()).unwrap(),
+                Some(format!("value{}_{}", i, rounds - 1).into_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn test_levels() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new().prefix("levels").tempdir().unwrap();
+        let wo = WriteOptions { sync: false };
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        let levels = db.levels();
+        assert_eq!(levels.len(), MAX_LEVEL + 1);
+        assert!(levels.iter().all(|level| level.tables.is_empty()));
+
+        // level0 is compacted once it has more than LEVEL0_FILES_THRESHOLD tables
+        for round in 0..LEVEL0_FILES_THRESHOLD + 2 {
+            for i in 0..100 {
+                db.set(
+                    &wo,
+                    format!("key{:03}", i * 3 + round % 3).into_bytes(),
+                    vec![b'a' + round as u8; 100],
+                )
+                .unwrap();
+            }
+            db.flush().unwrap();
+        }
+        // the first table is appended to level1, the others stay in level0 until the
+        // compaction brings it back under LEVEL0_FILES_THRESHOLD
+        for _ in 0..500 {
+            if !db.level0_manager.needs_compaction() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let levels = db.levels();
+        for (i, level) in levels.iter().enumerate() {
+            assert_eq!(level.level, i);
+        }
+        assert!(levels[0].tables.len() <= LEVEL0_FILES_THRESHOLD);
+        let level1 = &levels[1];
+        assert!(!level1.tables.is_empty());
+        assert_eq!(level1.tables[0].min_key, b"key000");
+        let max_key = levels[..=1]
+            .iter()
+            .flat_map(|level| level.tables.iter())
+            .map(|table| &table.max_key)
+            .max()
+            .unwrap();
+        assert_eq!(max_key, b"key299");
+        for table in levels[..=1].iter().flat_map(|level| level.tables.iter()) {
+            assert!(table.min_key <= table.max_key);
+            assert!(table.file_size > 0);
+        }
+        for w in level1.tables.windows(2) {
+            assert!(w[0].max_key < w[1].min_key);
+        }
+        assert!(levels[2..].iter().all(|level| level.tables.is_empty()));
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("compare_and_swap")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        let db = Arc::new(
+            NoTransactionDB::<
+                InternalKey,
+                InternalKey,
+                MutexSkipMapMemTable<InternalKey>,
+                SimpleWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap(),
+        );
+        let key = Vec::from("key");
+        assert!(db
+            .compare_and_swap(&wo, key.clone(), None, Vec::from("v1"))
+            .unwrap());
+        assert!(!db
+            .compare_and_swap(&wo, key.clone(), None, Vec::from("v2"))
+            .unwrap());
+        assert!(!db
+            .compare_and_swap(&wo, key.clone(), Some(&Vec::from("v0")), Vec::from("v2"))
+            .unwrap());
+        assert_eq!(db.get(&key).unwrap(), Some(Vec::from("v1")));
+
+        // the current value is found in sstables as well
+        db.flush().unwrap();
+        assert!(db
+            .compare_and_swap(&wo, key.clone(), Some(&Vec::from("v1")), Vec::from("v2"))
+            .unwrap());
+        assert_eq!(db.get(&key).unwrap(), Some(Vec::from("v2")));
+
+        // a removed key is absent
+        db.remove(&wo, key.clone()).unwrap();
+        assert!(!db
+            .compare_and_swap(&wo, key.clone(), Some(&Vec::from("v2")), Vec::from("v3"))
+            .unwrap());
+        assert!(db
+            .compare_and_swap(&wo, key.clone(), None, Vec::from("v3"))
+            .unwrap());
+        assert_eq!(db.get(&key).unwrap(), Some(Vec::from("v3")));
+
+        // exactly one of the racing threads takes each lock
+        for round in 0..100 {
+            let key = format!("lock{}", round).into_bytes();
+            let barrier = Arc::new(Barrier::new(2));
+            let handles: Vec<_> = (0..2)
+                .map(|thread| {
+                    let db = db.clone();
+                    let key = key.clone();
+                    let barrier = barrier.clone();
+                    std::thread::spawn(move || {
+                        let wo = WriteOptions { sync: false };
+                        barrier.wait();
+                        db.compare_and_swap(&wo, key, None, format!("{}", thread).into_bytes())
+                            .unwrap()
+                    })
+                })
+                .collect();
+            let results: Vec<bool> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            assert_eq!(results.iter().filter(|swapped| **swapped).count(), 1);
+            let winner = results.iter().position(|swapped| *swapped).unwrap();
+            assert_eq!(
+                db.get(&key).unwrap(),
+                Some(format!("{}", winner).into_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn test_fetch_add() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("fetch_add")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        let db = Arc::new(
+            NoTransactionDB::<
+                InternalKey,
+                InternalKey,
+                MutexSkipMapMemTable<InternalKey>,
+                SimpleWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap(),
+        );
+        assert_eq!(db.fetch_add(&wo, Vec::from("counter"), 5).unwrap(), 5);
+        assert_eq!(db.fetch_add(&wo, Vec::from("counter"), -7).unwrap(), -2);
+        assert_eq!(
+            db.get(&Vec::from("counter")).unwrap(),
+            Some((-2i64).to_le_bytes().to_vec())
+        );
+        db.set(&wo, Vec::from("text"), Vec::from("abc")).unwrap();
+        assert!(db.fetch_add(&wo, Vec::from("text"), 1).is_err());
+        assert_eq!(db.get(&Vec::from("text")).unwrap(), Some(Vec::from("abc")));
+
+        const THREADS: usize = 8;
+        const ADDS: usize = 500;
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    let wo = WriteOptions { sync: false };
+                    for _ in 0..ADDS {
+                        db.fetch_add(&wo, Vec::from("shared"), 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(
+            db.get(&Vec::from("shared")).unwrap(),
+            Some(((THREADS * ADDS) as i64).to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            db.fetch_add(&wo, Vec::from("shared"), 0).unwrap(),
+            (THREADS * ADDS) as i64
+        );
+    }
+
+    #[test]
+    fn test_entry() {
+        let temp_dir = tempfile::Builder::new().prefix("entry").tempdir().unwrap();
+        let wo = WriteOptions { sync: false };
+        let db = Arc::new(
+            NoTransactionDB::<
+                InternalKey,
+                InternalKey,
+                MutexSkipMapMemTable<InternalKey>,
+                SimpleWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap(),
+        );
+        // modifying an absent key writes nothing
+        let value = db
+            .entry(&wo, Vec::from("key"))
+            .and_modify(|v| v.push(b'!'))
+            .apply()
+            .unwrap();
+        assert_eq!(value, None);
+        assert_eq!(db.get(&Vec::from("key")).unwrap(), None);
+
+        let value = db
+            .entry(&wo, Vec::from("key"))
+            .or_insert(Vec::from("v"))
+            .and_modify(|v| v.push(b'1'))
+            .apply()
+            .unwrap();
+        assert_eq!(value, Some(Vec::from("v1")));
+        let value = db
+            .entry(&wo, Vec::from("key"))
+            .or_insert_with(|| unreachable!())
+            .apply()
+            .unwrap();
+        assert_eq!(value, Some(Vec::from("v1")));
+
+        // modifying the value to be empty removes the key
+        db.flush().unwrap();
+        let value = db
+            .entry(&wo, Vec::from("key"))
+            .and_modify(|v| v.clear())
+            .apply()
+            .unwrap();
+        assert_eq!(value, None);
+        assert_eq!(db.get(&Vec::from("key")).unwrap(), None);
+
+        const THREADS: usize = 8;
+        const INCREMENTS: usize = 500;
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    let wo = WriteOptions { sync: false };
+                    for _ in 0..INCREMENTS {
+                        db.entry(&wo, Vec::from("counter"))
+                            .or_insert_with(|| 0u64.to_le_bytes().to_vec())
+                            .and_modify(|v| {
+                                let count = u64::from_le_bytes(v.as_slice().try_into().unwrap());
+                                *v = (count + 1).to_le_bytes().to_vec();
+                            })
+                            .apply()
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(
+            db.get(&Vec::from("counter")).unwrap(),
+            Some(((THREADS * INCREMENTS) as u64).to_le_bytes().to_vec())
+        );
+
+        // a panicking step writes nothing and leaves the database writable
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.entry(&wo, Vec::from("counter"))
+                .and_modify(|v| v.clear())
+                .and_modify(|_| panic!("step failed"))
+                .apply()
+        }));
+        assert!(result.is_err());
+        assert_eq!(
+            db.get(&Vec::from("counter")).unwrap(),
+            Some(((THREADS * INCREMENTS) as u64).to_le_bytes().to_vec())
+        );
+        db.set(&wo, Vec::from("counter"), Vec::from("v")).unwrap();
+    }
+
+    #[test]
+    fn test_pinned_iter() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("pinned_iter")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        let db = Arc::new(
+            NoTransactionDB::<
+                InternalKey,
+                InternalKey,
+                MutexSkipMapMemTable<InternalKey>,
+                SimpleWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap(),
+        );
+        assert_eq!(db.last_sequence(), 0);
+        for i in 0..300 {
+            db.set(&wo, format!("key{:03}", i).into_bytes(), Vec::from("v0"))
+                .unwrap();
+        }
+        db.flush().unwrap();
+        for i in (0..300).step_by(3) {
+            db.remove(&wo, format!("key{:03}", i).into_bytes()).unwrap();
+        }
+        assert_eq!(db.last_sequence(), 400);
+
+        let (sequence, iter) = db.pinned_iter().unwrap();
+        assert_eq!(sequence, 400);
+        // overwrite everything and push the pinned sstables through compaction
+        for round in 0..LEVEL0_FILES_THRESHOLD + 2 {
+            for i in 0..300 {
+                db.set(
+                    &wo,
+                    format!("key{:03}", i).into_bytes(),
+                    format!("v{}", round + 1).into_bytes(),
+                )
+                .unwrap();
+            }
+            db.set(&wo, format!("new{}", round).into_bytes(), Vec::from("v"))
+                .unwrap();
+            db.flush().unwrap();
+        }
+        for _ in 0..500 {
+            if db.level0_manager.file_count() == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let expected: Vec<_> = (0..300)
+            .filter(|i| i % 3 != 0)
+            .map(|i| (format!("key{:03}", i).into_bytes(), Vec::from("v0")))
+            .collect();
+        assert_eq!(iter.collect::<Vec<_>>(), expected);
+
+        // a pinned iterator sees a prefix of the writes of a concurrent writer, which
+        // freezes the memory table from time to time
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                let wo = WriteOptions { sync: false };
+                for i in 0..2000 {
+                    db.set(&wo, format!("seq{:04}", i).into_bytes(), Vec::from("v"))
+                        .unwrap();
+                    if i % 200 == 199 {
+                        db.flush().unwrap();
+                    }
+                }
+            })
+        };
+        let base = db.last_sequence();
+        for _ in 0..20 {
+            let (sequence, iter) = db.pinned_iter().unwrap();
+            let visible: Vec<_> = iter
+                .filter(|(k, _)| k.starts_with(b"seq"))
+                .map(|(k, _)| k)
+                .collect();
+            let expected: Vec<_> = (0..sequence - base)
+                .map(|i| format!("seq{:04}", i).into_bytes())
+                .collect();
+            assert_eq!(visible, expected);
+        }
+        writer.join().unwrap();
+
+        let (sequence, iter) = db.pinned_iter().unwrap();
+        assert_eq!(sequence, base + 2000);
+        assert_eq!(iter.filter(|(k, _)| k.starts_with(b"seq")).count(), 2000);
+    }
+
+    #[test]
+    fn test_try_set() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("try_set")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        let db = Arc::new(
+            TestDB::open_with_options(
+                temp_dir.path(),
+                Options {
+                    level0_slowdown_writes_trigger: 1,
+                    ..Options::default()
+                },
+            )
+            .unwrap(),
+        );
+        assert!(db.try_set(&wo, Vec::from("k1"), Vec::from("v1")).unwrap());
+        assert_eq!(db.get(&Vec::from("k1")).unwrap(), Some(Vec::from("v1")));
+
+        // another writer holds the write lock
+        let barrier = Arc::new(Barrier::new(2));
+        let holder = {
+            let db = db.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let _wal_guard = db.wal.lock().unwrap();
+                barrier.wait();
+                barrier.wait();
+            })
+        };
+        barrier.wait();
+        assert!(!db.try_set(&wo, Vec::from("k2"), Vec::from("v2")).unwrap());
+        barrier.wait();
+        holder.join().unwrap();
+        assert_eq!(db.get(&Vec::from("k2")).unwrap(), None);
+        assert!(db.try_set(&wo, Vec::from("k2"), Vec::from("v2")).unwrap());
+
+        // the first table goes to the empty level 1, and the next one overlaps it and
+        // makes level 0 reach the slowdown trigger
+        db.flush().unwrap();
+        assert_eq!(db.leveln_manager.file_count(1), 1);
+        db.set(&wo, Vec::from("k1"), Vec::from("v1")).unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.level0_manager.file_count(), 1);
+        assert!(!db.try_set(&wo, Vec::from("k3"), Vec::from("v3")).unwrap());
+        assert_eq!(db.get(&Vec::from("k3")).unwrap(), None);
+        db.set(&wo, Vec::from("k3"), Vec::from("v3")).unwrap();
+        assert_eq!(db.get(&Vec::from("k3")).unwrap(), Some(Vec::from("v3")));
+    }
+
+    #[test]
+    fn test_debug() {
+        let temp_dir = tempfile::Builder::new().prefix("debug").tempdir().unwrap();
+        let wo = WriteOptions { sync: false };
+        let db = NoTransactionDB::<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        db.set(&wo, Vec::from("key"), Vec::from("value")).unwrap();
+        db.flush().unwrap();
+        db.set(&wo, Vec::from("key"), Vec::from("value")).unwrap();
+
+        let debug = format!("{:?}", db);
+        assert!(debug.contains(temp_dir.path().to_str().unwrap()));
+        // the table is appended to the empty level 1
+        assert!(debug.contains("level_file_counts: [0, 1, 0, 0, 0, 0, 0, 0]"));
+        assert!(debug.contains("mut_mem_table_size: "));
+        assert!(!debug.contains("mut_mem_table_size: 0"));
+
+        let debug = format!("{:?}", Options::default());
+        assert!(debug.contains("wal_sync: PerWrite"));
+        assert!(debug.contains("compression: "));
+        let error = KVLiteError::KeyTooLarge { size: 10, limit: 5 };
+        assert!(format!("{:?}", error).contains("KeyTooLarge"));
+        assert_eq!(
+            error.to_string(),
+            "key of 10 bytes exceeds the limit of 5 bytes"
+        );
+    }
+
+    #[test]
+    fn test_repair() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new().prefix("repair").tempdir().unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let wo = WriteOptions { sync: false };
+        {
+            let db = TestDB::open(path).unwrap();
+            for round in 0..LEVEL0_FILES_THRESHOLD + 2 {
+                for i in 0..300 {
+                    db.set(
+                        &wo,
+                        format!("key{:03}", i).into_bytes(),
+                        format!("value{}", round).into_bytes(),
+                    )
+                    .unwrap();
+                }
+                db.flush().unwrap();
+            }
+            for _ in 0..500 {
+                if db.level0_manager.file_count() == 0 {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            for i in 0..100 {
+                db.set(
+                    &wo,
+                    format!("key{:03}", i).into_bytes(),
+                    Vec::from("newest"),
+                )
+                .unwrap();
+            }
+            db.flush().unwrap();
+            assert!(!db.levels()[1].tables.is_empty());
+        }
+
+        // lose the table id bound, restore a stale copy of a level 1 table under an
+        // unused id, and leave a broken table in level 2
+        std::fs::remove_file(temp_dir.path().join(TABLE_ID_FILE)).unwrap();
+        let level1_table = std::fs::read_dir(level_dir(path, 1))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .parse::<u64>()
+                    .is_ok()
+            })
+            .unwrap();
+        std::fs::copy(&level1_table, sstable_file(path, 1, 0)).unwrap();
+        std::fs::write(sstable_file(path, 2, 999_999), b"broken").unwrap();
+
+        TestDB::repair(path).unwrap();
+        assert!(temp_dir.path().join(LOST_DIR).join("2_999999").exists());
+        assert!(!Path::new(&sstable_file(path, 1, 0)).exists());
+        // the tables are renumbered after the broken one
+        assert!(Path::new(&sstable_file(path, 0, 1_000_000)).exists());
+        let table_id_bound = std::fs::read(temp_dir.path().join(TABLE_ID_FILE)).unwrap();
+        assert!(u64::from_le_bytes(table_id_bound.try_into().unwrap()) > 1_000_001);
+
+        let db = TestDB::open(path).unwrap();
+        for i in 0..300 {
+            let expected = if i < 100 {
+                Vec::from("newest")
+            } else {
+                format!("value{}", LEVEL0_FILES_THRESHOLD + 1).into_bytes()
+            };
+            assert_eq!(
+                db.get(&format!("key{:03}", i).into_bytes()).unwrap(),
+                Some(expected)
+            );
+        }
+        assert!(db.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_repair_keeps_shallower_tables() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("repair_shallower")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let wo = WriteOptions { sync: false };
+        let table_ids = |level: usize| -> Vec<u64> {
+            std::fs::read_dir(level_dir(path, level))
+                .unwrap()
+                .filter_map(|entry| entry.unwrap().file_name().to_str()?.parse().ok())
+                .collect()
+        };
+        let write_to_level1 = |value: &str| {
+            let db = TestDB::open(path).unwrap();
+            for _ in 0..LEVEL0_FILES_THRESHOLD + 2 {
+                for i in 0..300 {
+                    db.set(
+                        &wo,
+                        format!("key{:03}", i).into_bytes(),
+                        value.as_bytes().to_vec(),
+                    )
+                    .unwrap();
+                }
+                db.flush().unwrap();
+            }
+            for _ in 0..500 {
+                if !db.level0_manager.needs_compaction() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert!(!db.level0_manager.needs_compaction());
+        };
+
+        // the older values compacted to level 1 end up in level 2, the newer ones in
+        // level 1
+        write_to_level1("older");
+        for table_id in table_ids(1) {
+            std::fs::rename(
+                sstable_file(path, 1, table_id),
+                sstable_file(path, 2, table_id),
+            )
+            .unwrap();
+        }
+        write_to_level1("newer");
+        assert!(!table_ids(1).is_empty());
+
+        // make the tables of level 2 overlap, so that they are moved to level 0
+        let level2_table = table_ids(2)[0];
+        std::fs::copy(
+            sstable_file(path, 2, level2_table),
+            sstable_file(path, 2, 0),
+        )
+        .unwrap();
 
-        let v = db.get(&hello).unwrap();
-        assert!(v.is_none(), "{:?}", v);
+        TestDB::repair(path).unwrap();
+        assert!(table_ids(0).len() >= 3);
+        let db = TestDB::open(path).unwrap();
+        for i in 0..300 {
+            assert_eq!(
+                db.get(&format!("key{:03}", i).into_bytes()).unwrap(),
+                Some(Vec::from("newer"))
+            );
+        }
+    }
 
-        let num_keys_zero = NUM_KEYS / 10;
-        for i in 0..NUM_KEYS {
-            db.set(
-                &wo,
-                format!("key{}", if i < num_keys_zero { 0 } else { i }).into_bytes(),
-                format!("value{}_{}", i, value_prefix).into_bytes(),
-            )
+    #[test]
+    fn test_value_log() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let wo = WriteOptions { sync: false };
+        let rounds = LEVEL0_FILES_THRESHOLD + 1;
+        let fill = |db: &TestDB| {
+            for round in 0..rounds {
+                for i in 0..50 {
+                    db.set(
+                        &wo,
+                        format!("key{:02}", i).into_bytes(),
+                        vec![round as u8; 4096],
+                    )
+                    .unwrap();
+                }
+                db.flush().unwrap();
+            }
+            for _ in 0..500 {
+                if !db.level0_manager.needs_compaction() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(db.level0_manager.file_count(), 0);
+        };
+        let check = |db: &TestDB| {
+            for i in 0..50 {
+                assert_eq!(
+                    db.get(&format!("key{:02}", i).into_bytes()).unwrap(),
+                    Some(vec![(rounds - 1) as u8; 4096])
+                );
+            }
+        };
+
+        // level0 is compacted down to no tables, so that only the values of the last
+        // round are still pointed to
+        let plain_options = Options {
+            level0_files_threshold: 0,
+            ..Options::default()
+        };
+        let plain_dir = tempfile::Builder::new().prefix("plain").tempdir().unwrap();
+        let plain_db = TestDB::open_with_options(plain_dir.path(), plain_options).unwrap();
+        fill(&plain_db);
+        assert_eq!(plain_db.collect_value_log_garbage().unwrap(), 0);
+
+        let temp_dir = tempfile::Builder::new()
+            .prefix("value_log")
+            .tempdir()
             .unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let options = Options {
+            value_log_threshold: 1024,
+            level0_files_threshold: 0,
+            ..Options::default()
+        };
+        let value_log_files = || std::fs::read_dir(value_log_dir(path)).unwrap().count();
+        {
+            let db = TestDB::open_with_options(path, options.clone()).unwrap();
+            fill(&db);
+            check(&db);
+            assert_eq!(value_log_files(), rounds);
+            let stats = db.stats();
+            assert!(stats.value_log_bytes_written >= (rounds * 50 * 4096) as u64);
+            assert!(stats.sstable_bytes_written * 10 < plain_db.stats().sstable_bytes_written);
+
+            // only the values of the last round are still pointed to
+            assert!(db.collect_value_log_garbage().unwrap() >= ((rounds - 1) * 50 * 4096) as u64);
+            assert_eq!(value_log_files(), 1);
+            check(&db);
+
+            // the tables compacted away are still read by the pinned iterator, and so are
+            // the value log files they point to
+            let (_, pinned) = db.pinned_iter().unwrap();
+            fill(&db);
+            db.collect_value_log_garbage().unwrap();
+            let kvs: Vec<InternalKeyValue> = pinned.collect();
+            assert_eq!(kvs.len(), 50);
+            assert!(kvs
+                .iter()
+                .all(|(_, value)| *value == vec![(rounds - 1) as u8; 4096]));
+            assert!(db.collect_value_log_garbage().unwrap() >= (50 * 4096) as u64);
+            assert_eq!(value_log_files(), 1);
+            check(&db);
         }
-        for i in 0..num_keys_zero {
+
+        let db = TestDB::open_with_options(path, options).unwrap();
+        check(&db);
+        assert!(db.verify().unwrap().is_ok());
+    }
+
+    #[cfg(feature = "latency_stats")]
+    #[test]
+    fn test_latency_stats() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("latency")
+            .tempdir()
+            .unwrap();
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        let wo = WriteOptions { sync: false };
+        assert_eq!(db.latency_stats(), Default::default());
+
+        // the first table is appended to level1, so it takes one more round for level0
+        // to exceed LEVEL0_FILES_THRESHOLD
+        let rounds = LEVEL0_FILES_THRESHOLD + 2;
+        for round in 0..rounds {
+            for i in 0..100 {
+                db.set(
+                    &wo,
+                    format!("key{:03}", i).into_bytes(),
+                    format!("value{}", round).into_bytes(),
+                )
+                .unwrap();
+            }
+            db.flush().unwrap();
+        }
+        for i in 0..100 {
+            db.get(&format!("key{:03}", i).into_bytes()).unwrap();
+        }
+        for _ in 0..10 {
+            db.range_get(&Vec::from("key000"), &Vec::from("key099"))
+                .unwrap();
+        }
+        for _ in 0..500 {
+            if db.latency_stats().compaction.count > 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let stats = db.latency_stats();
+        assert_eq!(stats.set.count, 100 * rounds as u64);
+        assert_eq!(stats.get.count, 100);
+        assert_eq!(stats.range_get.count, 10);
+        assert!(stats.compaction.count > 0);
+        for op in [stats.get, stats.set, stats.range_get, stats.compaction] {
+            assert!(op.p50_micros <= op.p95_micros);
+            assert!(op.p95_micros <= op.p99_micros);
+            // nothing takes anywhere near a minute
+            assert!(op.p99_micros < 60_000_000);
+        }
+        // compaction reads and writes files, which takes more than a microsecond
+        assert!(stats.compaction.p50_micros > 0);
+    }
+
+    #[test]
+    fn test_estimate_count_in_range() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("estimate")
+            .tempdir()
+            .unwrap();
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        let wo = WriteOptions { sync: false };
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+
+        // keys in [0, 10000) are flushed, every third of them in [10000, 10300) is not
+        for i in 0..10000 {
+            db.set(&wo, key(i), vec![b'v'; 100]).unwrap();
+        }
+        db.flush().unwrap();
+        for i in (10000..10300).step_by(3) {
+            db.set(&wo, key(i), vec![b'v'; 100]).unwrap();
+        }
+
+        let estimate =
+            |start: usize, end: usize| db.estimate_count_in_range(&key(start), &key(end)).unwrap();
+        assert_eq!(estimate(10000, 10300), 100);
+        assert_eq!(estimate(20000, 30000), 0);
+        assert_eq!(estimate(5000, 5000), 0);
+        // the same with the index cached by a query
+        let uncached = estimate(2000, 5000);
+        db.get(&key(3000)).unwrap();
+        assert_eq!(estimate(2000, 5000), uncached);
+        for (start, end) in [(0, 10000), (2000, 5000), (1234, 1434), (9990, 10300)] {
+            let expected = (start..end).filter(|i| *i < 10000 || i % 3 == 1).count() as u64;
+            let estimated = estimate(start, end);
+            assert!(
+                estimated * 2 >= expected && estimated <= expected * 2,
+                "[{}, {}): estimate {}, expected {}",
+                start,
+                end,
+                estimated,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_disable_wal() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("disable_wal")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        let log_size = || -> u64 {
+            std::fs::read_dir(temp_dir.path().join(LOG_DIR))
+                .unwrap()
+                .map(|entry| entry.unwrap().metadata().unwrap().len())
+                .sum()
+        };
+
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        db.set_wal_enabled(false);
+        let wal_bytes_written = db.stats().wal_bytes_written;
+        let size = log_size();
+        for i in 0..1000 {
             db.set(
                 &wo,
                 format!("key{}", i).into_bytes(),
-                format!("value{}_{}", i, value_prefix).into_bytes(),
+                format!("value{}", i).into_bytes(),
             )
             .unwrap();
         }
+        db.remove(&wo, Vec::from("key0")).unwrap();
+        let mut batch = db.batch();
+        batch.put(Vec::from("batch"), Vec::from("value"));
+        db.write(&wo, batch).unwrap();
+        assert_eq!(db.stats().wal_bytes_written, wal_bytes_written);
+        assert_eq!(log_size(), size);
 
+        db.flush().unwrap();
+        db.set_wal_enabled(true);
+        db.set(&wo, Vec::from("logged"), Vec::from("value"))
+            .unwrap();
+        assert!(db.stats().wal_bytes_written > wal_bytes_written);
+        // crash without closing the database
+        db.lock_file.as_ref().unwrap().unlock().unwrap();
+        std::mem::forget(db);
+
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get(&Vec::from("key0")).unwrap(), None);
+        for i in 1..1000 {
+            assert_eq!(
+                db.get(&format!("key{}", i).into_bytes()).unwrap(),
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
         assert_eq!(
-            db.get(&Vec::from("key3")).unwrap().unwrap(),
-            format!("value3_{}", value_prefix).as_bytes()
+            db.get(&Vec::from("batch")).unwrap(),
+            Some(Vec::from("value"))
         );
+        assert_eq!(
+            db.get(&Vec::from("logged")).unwrap(),
+            Some(Vec::from("value"))
+        );
+    }
 
-        info!("start query");
-
-        let db1 = Arc::new(db);
-        let db2 = db1.clone();
-        let handle1 = std::thread::spawn(move || {
-            query(db1, value_prefix);
-        });
+    #[test]
+    fn test_custom_block_cache() {
+        #[derive(Debug, Default)]
+        struct RecordingCache {
+            blocks: Mutex<HashMap<BlockKey, Arc<Vec<u8>>>>,
+            hits: AtomicUsize,
+            misses: AtomicUsize,
+            inserts: AtomicUsize,
+        }
 
-        let handle2 = std::thread::spawn(move || {
-            query(db2, value_prefix);
-        });
+        impl BlockCache for RecordingCache {
+            fn get(&self, key: &BlockKey) -> Option<Arc<Vec<u8>>> {
+                let block = self.blocks.lock().unwrap().get(key).cloned();
+                match block {
+                    Some(_) => self.hits.fetch_add(1, Ordering::SeqCst),
+                    None => self.misses.fetch_add(1, Ordering::SeqCst),
+                };
+                block
+            }
 
-        handle1.join().unwrap();
-        handle2.join().unwrap();
+            fn insert(&self, key: BlockKey, block: Arc<Vec<u8>>) {
+                self.inserts.fetch_add(1, Ordering::SeqCst);
+                self.blocks.lock().unwrap().insert(key, block);
+            }
+        }
 
-        info!("db done");
-    }
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let cache = Arc::new(RecordingCache::default());
+        let options = Options {
+            block_cache: Some(cache.clone() as Arc<dyn BlockCache>),
+            ..Options::default()
+        };
+        let wo = WriteOptions { sync: false };
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        let value = |db: usize, i: usize| format!("value{}-{:04}", db, i).into_bytes();
 
-    fn check(path: &Path) {
-        let min = InternalKey::default();
+        // two databases share the cache, and both of them have table 1
+        let temp_dirs: Vec<_> = (0..2)
+            .map(|_| {
+                tempfile::Builder::new()
+                    .prefix("block_cache")
+                    .tempdir()
+                    .unwrap()
+            })
+            .collect();
+        let dbs: Vec<TestDB> = temp_dirs
+            .iter()
+            .map(|dir| TestDB::open_with_options(dir.path(), options.clone()).unwrap())
+            .collect();
+        for (d, db) in dbs.iter().enumerate() {
+            for i in 0..1000 {
+                db.set(&wo, key(i), value(d, i)).unwrap();
+            }
+            db.flush().unwrap();
+            // the immutable memory table kept after a flush would serve the reads below
+            db.set(&wo, Vec::from("other"), Vec::from("value")).unwrap();
+            db.flush().unwrap();
+        }
+        assert_eq!(cache.inserts.load(Ordering::SeqCst), 0);
 
-        let db_path = path.to_str().unwrap();
-        let leveln_manager = create_manager(db_path);
-        for i in 1..=MAX_LEVEL {
-            let lock =
-                leveln_manager.get_level_tables_lock(unsafe { NonZeroUsize::new_unchecked(i) });
-            let read_guard = lock.read().unwrap();
-            let mut last_min_key;
-            let mut last_max_key: &InternalKey = &min;
-            for (_, table) in read_guard.iter() {
-                let (min_key, max_key) = table.min_max_key();
-                assert!(last_max_key.lt(min_key));
-                assert!(min_key <= max_key);
-                last_min_key = min_key;
-                last_max_key = max_key;
+        for _ in 0..2 {
+            for (d, db) in dbs.iter().enumerate() {
+                for i in (0..1000).step_by(100) {
+                    assert_eq!(db.get(&key(i)).unwrap(), Some(value(d, i)));
+                }
             }
         }
-        leveln_manager.close();
+        let misses = cache.misses.load(Ordering::SeqCst);
+        assert!(misses > 0);
+        // every block missed is read from the sstable and inserted
+        assert_eq!(cache.inserts.load(Ordering::SeqCst), misses);
+        // the second round reads every block from the cache
+        assert!(cache.hits.load(Ordering::SeqCst) >= 20);
+        let blocks = cache.blocks.lock().unwrap();
+        assert_eq!(blocks.len(), misses);
+        let mut cache_ids: Vec<u64> = blocks.keys().map(|key| key.cache_id).collect();
+        cache_ids.sort_unstable();
+        cache_ids.dedup();
+        assert_eq!(cache_ids.len(), 2);
     }
 
     #[test]
-    fn test_range_query() {
+    fn test_evict_deleted_table_blocks() {
+        #[derive(Debug, Default)]
+        struct MapCache {
+            blocks: Mutex<HashMap<BlockKey, Arc<Vec<u8>>>>,
+        }
+
+        impl BlockCache for MapCache {
+            fn get(&self, key: &BlockKey) -> Option<Arc<Vec<u8>>> {
+                self.blocks.lock().unwrap().get(key).cloned()
+            }
+
+            fn insert(&self, key: BlockKey, block: Arc<Vec<u8>>) {
+                self.blocks.lock().unwrap().insert(key, block);
+            }
+
+            fn erase(&self, key: &BlockKey) {
+                self.blocks.lock().unwrap().remove(key);
+            }
+        }
+
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let cache = Arc::new(MapCache::default());
+        let options = Options {
+            block_cache: Some(cache.clone() as Arc<dyn BlockCache>),
+            ..Options::default()
+        };
         let wo = WriteOptions { sync: false };
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
         let temp_dir = tempfile::Builder::new()
-            .prefix("range_query")
+            .prefix("evict_blocks")
             .tempdir()
             .unwrap();
-        let path = temp_dir.path();
-        let db = NoTransactionDB::<
+        {
+            let db = TestDB::open_with_options(temp_dir.path(), options.clone()).unwrap();
+            for i in 0..1000 {
+                db.set(&wo, key(i), Vec::from("old")).unwrap();
+            }
+            db.flush().unwrap();
+        }
+
+        // reopened, so that the reads are not served by the immutable memory table
+        let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+        let tables = db.list_tables();
+        assert_eq!(tables.len(), 1);
+        let table_id = tables[0].table_id();
+        drop(tables);
+        for i in (0..1000).step_by(100) {
+            assert_eq!(db.get(&key(i)).unwrap(), Some(Vec::from("old")));
+        }
+        let cached_blocks = |table_id: u64| {
+            cache
+                .blocks
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|block_key| block_key.table_id == table_id)
+                .count()
+        };
+        assert!(cached_blocks(table_id) > 0);
+
+        // the table is compacted with the overlapping tables of level 0, and deleted
+        for round in 0..=LEVEL0_FILES_THRESHOLD {
+            for i in 0..1000 {
+                db.set(&wo, key(i), format!("new{}", round).into_bytes())
+                    .unwrap();
+            }
+            db.flush().unwrap();
+        }
+        let deleted = || {
+            db.list_tables().iter().all(|t| t.table_id() != table_id)
+                && cached_blocks(table_id) == 0
+        };
+        for _ in 0..500 {
+            if deleted() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(deleted());
+        assert_eq!(db.get(&key(0)).unwrap(), Some(Vec::from("new4")));
+    }
+
+    #[test]
+    fn test_pin_range() {
+        type TestDB = NoTransactionDB<
             InternalKey,
             InternalKey,
-            MrSwSkipMapMemTable<InternalKey>,
+            MutexSkipMapMemTable<InternalKey>,
             SimpleWriteAheadLog,
-        >::open(path)
-        .unwrap();
-        for i in 1i32..NUM_KEYS as i32 {
-            db.set(
-                &wo,
-                Vec::from(i.to_be_bytes()),
-                Vec::from((i + 1).to_be_bytes()),
-            )
+        >;
+        let cache = Arc::new(LRUBlockCache::default());
+        let options = Options {
+            block_cache: Some(cache.clone() as Arc<dyn BlockCache>),
+            ..Options::default()
+        };
+        let wo = WriteOptions { sync: false };
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        let temp_dir = tempfile::Builder::new()
+            .prefix("pin_range")
+            .tempdir()
             .unwrap();
+        let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..2000 {
+            db.set(&wo, key(i), vec![i as u8; 100]).unwrap();
         }
+        db.flush().unwrap();
+        // the immutable memory table kept after a flush would serve the reads below
+        db.set(&wo, Vec::from("other"), Vec::from("value")).unwrap();
+        db.flush().unwrap();
+        db.pin_range(&key(100), &key(199)).unwrap();
 
-        for start in [32, 2203, 1234, 123, 1234, 121, 9982] {
-            let skip_map = db
-                .range_get(
-                    &Vec::from((start + 1i32).to_be_bytes()),
-                    &Vec::from((start + 100i32).to_be_bytes()),
-                )
-                .unwrap();
-            assert_eq!(100, skip_map.len());
-            for node in skip_map.iter_ptr() {
-                unsafe {
-                    assert_eq!(
-                        i32::from_be_bytes((*node).entry.key.clone().try_into().unwrap()) + 1,
-                        i32::from_be_bytes((*node).entry.value.clone().try_into().unwrap())
-                    );
-                }
+        // other reads of the database, and of another one sharing the cache
+        let flood = || {
+            for i in (0..2000).step_by(10) {
+                db.get(&key(i)).unwrap();
             }
+            for offset in 0..(16 * 256 * 2) {
+                let block_key = BlockKey {
+                    cache_id: u64::MAX,
+                    table_id: 0,
+                    offset,
+                };
+                cache.insert(block_key, Arc::new(vec![]));
+            }
+        };
+        flood();
+        let misses = || db.stats().block_cache_misses;
+        let before = misses();
+        for i in 100..200 {
+            assert_eq!(db.get(&key(i)).unwrap(), Some(vec![i as u8; 100]));
         }
+        assert_eq!(misses(), before);
+        db.get(&key(1999)).unwrap();
+        assert_eq!(misses(), before + 1);
+
+        // pinning twice needs unpinning twice
+        db.pin_range(&key(150), &key(150)).unwrap();
+        db.unpin_range(&key(100), &key(199)).unwrap();
+        flood();
+        let before = misses();
+        db.get(&key(150)).unwrap();
+        assert_eq!(misses(), before);
+        db.get(&key(100)).unwrap();
+        assert_eq!(misses(), before + 1);
+
+        db.unpin_range(&key(150), &key(150)).unwrap();
+        // unpinning a range not pinned does nothing
+        db.unpin_range(&key(150), &key(150)).unwrap();
+        flood();
+        let before = misses();
+        db.get(&key(150)).unwrap();
+        assert_eq!(misses(), before + 1);
     }
 
     #[test]
-    fn test_read_log() {
+    fn test_pin_range_over_budget() {
+        /// Pins at most 10 blocks.
+        #[derive(Debug, Default)]
+        struct SmallCache {
+            pinned: Mutex<HashMap<BlockKey, usize>>,
+        }
+
+        impl BlockCache for SmallCache {
+            fn get(&self, _key: &BlockKey) -> Option<Arc<Vec<u8>>> {
+                None
+            }
+
+            fn insert(&self, _key: BlockKey, _block: Arc<Vec<u8>>) {}
+
+            fn pin(&self, key: BlockKey, _block: Arc<Vec<u8>>) -> bool {
+                let mut pinned = self.pinned.lock().unwrap();
+                if pinned.len() >= 10 && !pinned.contains_key(&key) {
+                    return false;
+                }
+                *pinned.entry(key).or_default() += 1;
+                true
+            }
+
+            fn unpin(&self, key: &BlockKey) {
+                let mut pinned = self.pinned.lock().unwrap();
+                let pins = pinned.get_mut(key).unwrap();
+                *pins -= 1;
+                if *pins == 0 {
+                    pinned.remove(key);
+                }
+            }
+        }
+
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        let wo = WriteOptions { sync: false };
         let temp_dir = tempfile::Builder::new()
-            .prefix("read_log")
+            .prefix("pin_budget")
             .tempdir()
             .unwrap();
-        let path = temp_dir.path();
+        {
+            let db = TestDB::open(temp_dir.path()).unwrap();
+            for i in 0..2000 {
+                db.set(&wo, key(i), vec![i as u8; 100]).unwrap();
+            }
+            db.flush().unwrap();
+            // no block cache
+            assert!(matches!(
+                db.pin_range(&key(0), &key(10)),
+                Err(KVLiteError::Custom(_))
+            ));
+        }
+
+        let cache = Arc::new(SmallCache::default());
+        let options = Options {
+            block_cache: Some(cache.clone() as Arc<dyn BlockCache>),
+            ..Options::default()
+        };
+        let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+        db.pin_range(&key(0), &key(10)).unwrap();
+        let pinned = cache.pinned.lock().unwrap().len();
+        assert!(pinned > 0 && pinned < 10);
+
+        // the blocks pinned before running out of room are unpinned
+        assert_eq!(
+            db.pin_range(&key(0), &key(1999)),
+            Err(KVLiteError::CacheBudgetExceeded)
+        );
+        assert_eq!(cache.pinned.lock().unwrap().len(), pinned);
+        db.unpin_range(&key(0), &key(10)).unwrap();
+        assert!(cache.pinned.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_warm_cache() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new().prefix("warm").tempdir().unwrap();
+        let wo = WriteOptions { sync: false };
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        {
+            let db = TestDB::open(temp_dir.path()).unwrap();
+            for i in 0..2000 {
+                db.set(&wo, key(i), vec![b'v'; 100]).unwrap();
+            }
+            db.flush().unwrap();
+        }
+
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        let keys: Vec<InternalKey> = (0..2000).step_by(200).map(key).collect();
+        assert_eq!(db.warm_cache(&keys).unwrap(), keys.len());
+        let warmed = db.stats();
+        assert!(warmed.block_cache_misses > 0);
+        for k in &keys {
+            assert_eq!(db.get(k).unwrap(), Some(vec![b'v'; 100]));
+        }
+        let stats = db.stats();
+        assert_eq!(
+            stats.block_cache_hits - warmed.block_cache_hits,
+            keys.len() as u64
+        );
+        assert_eq!(stats.block_cache_misses, warmed.block_cache_misses);
+        assert_eq!(stats.disk_bytes_read, warmed.disk_bytes_read);
+        drop(db);
+
+        let options = Options {
+            warm_cache_on_open: true,
+            ..Options::default()
+        };
+        let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+        assert!(db.memory_usage().index_blocks > 0);
+        assert_eq!(db.get(&key(1000)).unwrap(), Some(vec![b'v'; 100]));
+        assert_eq!(db.stats().block_cache_misses, 1);
+    }
 
-        let db = NoTransactionDB::<
+    #[test]
+    fn test_debug_tombstones() {
+        type TestDB = NoTransactionDB<
             InternalKey,
             InternalKey,
-            MrSwSkipMapMemTable<InternalKey>,
+            MutexSkipMapMemTable<InternalKey>,
             SimpleWriteAheadLog,
-        >::open(path)
-        .unwrap();
+        >;
         let wo = WriteOptions { sync: false };
-        for i in 0..NUM_KEYS - 1 {
-            db.set(
-                &wo,
-                format!("{}", i).into_bytes(),
-                format!("value{}", i).into_bytes(),
-            )
+        let temp_dir = tempfile::Builder::new()
+            .prefix("debug_tombstones")
+            .tempdir()
             .unwrap();
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        for i in 0..100 {
+            db.set(&wo, format!("key{:03}", i).into_bytes(), Vec::from("value"))
+                .unwrap();
         }
-        drop(db);
+        db.flush().unwrap();
+        assert_eq!(db.debug_tombstones().count(), 0);
 
-        let db = NoTransactionDB::<
+        let removed: Vec<InternalKey> = (0..100)
+            .step_by(10)
+            .map(|i| format!("key{:03}", i).into_bytes())
+            .collect();
+        for key in &removed {
+            db.remove(&wo, key.clone()).unwrap();
+        }
+        let expected = |source: TombstoneSource| -> Vec<(InternalKey, TombstoneSource)> {
+            removed.iter().map(|key| (key.clone(), source)).collect()
+        };
+        assert_eq!(
+            db.debug_tombstones().collect::<Vec<_>>(),
+            expected(TombstoneSource::MemTable)
+        );
+
+        db.flush().unwrap();
+        assert!(db.get_mut_mem_table().is_empty());
+        // the first sstable was appended to level 1, and the tombstones are in level 0,
+        // not compacted yet
+        assert_eq!(db.level0_manager.file_count(), 1);
+        assert_eq!(
+            db.debug_tombstones().collect::<Vec<_>>(),
+            expected(TombstoneSource::Level(0))
+        );
+        for key in &removed {
+            assert_eq!(db.get(key).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_intra_level0_compaction() {
+        type TestDB = NoTransactionDB<
             InternalKey,
             InternalKey,
-            BTreeMemTable<InternalKey>,
+            MutexSkipMapMemTable<InternalKey>,
             SimpleWriteAheadLog,
-        >::open(path)
-        .unwrap();
+        >;
+        let wo = WriteOptions { sync: false };
+        let temp_dir = tempfile::Builder::new()
+            .prefix("intra_level0_compaction")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            level0_intra_compaction_bytes: 64 * 1024,
+            ..Options::default()
+        };
+        let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+        let level1_table_ids = || -> Vec<u64> {
+            let lock = db
+                .leveln_manager
+                .get_level_tables_lock(NonZeroUsize::new(1).unwrap());
+            let guard = lock.read().unwrap();
+            guard.values().map(|table| table.table_id()).collect()
+        };
 
-        for i in 0..NUM_KEYS - 1 {
-            assert_eq!(
-                Some(format!("value{}", i).into_bytes()),
-                db.get(&format!("{}", i).into_bytes()).unwrap()
-            );
+        // the first sstable is appended to level 1
+        for i in 0..1000 {
+            db.set(&wo, format!("key{:04}", i).into_bytes(), vec![b'v'; 100])
+                .unwrap();
         }
-        for i in NUM_KEYS..NUM_KEYS + 30 {
-            db.set(
-                &wo,
-                format!("{}", i).into_bytes(),
-                format!("value{}", i).into_bytes(),
-            )
-            .unwrap();
-            assert_eq!(
-                Some(format!("value{}", i).into_bytes()),
-                db.get(&format!("{}", i).into_bytes()).unwrap()
-            );
+        db.flush().unwrap();
+        let level1_tables = level1_table_ids();
+        assert!(!level1_tables.is_empty());
+        assert_eq!(db.level0_manager.file_count(), 0);
+
+        // tiny sstables overlapping level 1
+        for round in 0..=LEVEL0_FILES_THRESHOLD {
+            for i in (round..1000).step_by(100) {
+                db.set(
+                    &wo,
+                    format!("key{:04}", i).into_bytes(),
+                    format!("value{}", round).into_bytes(),
+                )
+                .unwrap();
+            }
+            db.flush().unwrap();
+        }
+        while db.level0_manager.file_count() > 1 {
+            std::thread::sleep(Duration::from_millis(10));
         }
+        assert_eq!(db.level0_manager.file_count(), 1);
+        assert_eq!(level1_table_ids(), level1_tables);
 
-        drop(db);
-        let db = Arc::new(
-            NoTransactionDB::<
-                InternalKey,
-                InternalKey,
-                MrMwSkipMapMemTable<InternalKey>,
-                SimpleWriteAheadLog,
-            >::open(path)
-            .unwrap(),
-        );
-        for _ in 0..4 {
-            test_log(db.clone());
+        for round in 0..=LEVEL0_FILES_THRESHOLD {
+            assert_eq!(
+                db.get(&format!("key{:04}", round).into_bytes()).unwrap(),
+                Some(format!("value{}", round).into_bytes())
+            );
         }
+        assert_eq!(db.get(&b"key0999".to_vec()).unwrap(), Some(vec![b'v'; 100]));
+    }
 
-        let thread_cnt = 4;
+    #[test]
+    fn test_clear() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let wo = WriteOptions { sync: false };
+        let temp_dir = tempfile::Builder::new().prefix("clear").tempdir().unwrap();
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
 
-        for _ in 0..10 {
-            let barrier = Arc::new(Barrier::new(thread_cnt));
-            let mut handles = vec![];
-            for _ in 0..thread_cnt {
-                let db = db.clone();
-                let barrier = barrier.clone();
-                let handle = std::thread::spawn(move || {
-                    barrier.wait();
-                    test_log(db);
-                });
-                handles.push(handle);
+        {
+            let db = TestDB::open(temp_dir.path()).unwrap();
+            // sstables in level 0 and level 1, and key-values in the memory table
+            for round in 0..=LEVEL0_FILES_THRESHOLD + 1 {
+                for i in 0..1000 {
+                    db.set(&wo, key(i), format!("value{}", round).into_bytes())
+                        .unwrap();
+                }
+                db.flush().unwrap();
             }
-            for handle in handles {
-                handle.join().unwrap();
+            for i in 0..100 {
+                db.set(&wo, key(i), Vec::from("unflushed")).unwrap();
             }
-        }
-    }
+            assert!(db.list_tables().len() > 1);
 
-    fn test_log<M: MemTable<InternalKey, InternalKey> + 'static>(
-        db: Arc<NoTransactionDB<InternalKey, InternalKey, M, SimpleWriteAheadLog>>,
-    ) {
-        for _ in 0..3 {
-            for i in NUM_KEYS..NUM_KEYS + 30 {
-                assert_eq!(
-                    Some(format!("value{}", i).into_bytes()),
-                    db.get(&format!("{}", i).into_bytes())
-                        .expect("error in read thread1")
-                );
+            db.clear().unwrap();
+            assert!(db.list_tables().is_empty());
+            assert!(db.get_mut_mem_table().is_empty());
+            assert_eq!(db.last_sequence(), 0);
+            for i in 0..1000 {
+                assert_eq!(db.get(&key(i)).unwrap(), None);
             }
-        }
-    }
+            assert!(db.range_get(&key(0), &key(1000)).unwrap().is_empty());
 
-    pub(crate) fn create_random_map(size: usize) -> HashMap<i32, usize> {
-        let mut map = HashMap::new();
-        let rng = rand::thread_rng();
-        let distribution = rand::distributions::uniform::Uniform::new(0, i32::MAX);
-        for (cnt, i) in rng.sample_iter(distribution).enumerate() {
-            map.insert(i, cnt);
-            if cnt >= size {
-                break;
+            for i in (0..1000).step_by(10) {
+                db.set(&wo, key(i), Vec::from("new")).unwrap();
             }
+            assert_eq!(db.last_sequence(), 100);
+            assert_eq!(db.range_get(&key(0), &key(1000)).unwrap().len(), 100);
+        }
+
+        // the cleared key-values are not recovered from the WAL
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        for i in 0..1000 {
+            let expected = if i % 10 == 0 {
+                Some(Vec::from("new"))
+            } else {
+                None
+            };
+            assert_eq!(db.get(&key(i)).unwrap(), expected);
         }
-        map
     }
 
     #[test]
-    fn test_random() {
-        let _ = env_logger::try_init();
+    fn test_dictionary_compression() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            SimpleWriteAheadLog,
+        >;
+        let wo = WriteOptions { sync: false };
+        // values of about 4000 bytes made of the same random chunks, so that they have
+        // much in common with each other but little within one data block
+        let mut rng = StdRng::seed_from_u64(0);
+        let chunks: Vec<Vec<u8>> = (0..16)
+            .map(|_| (0..500).map(|_| rng.gen()).collect())
+            .collect();
+        let values: Vec<Value> = (0..300)
+            .map(|i| {
+                let mut value = format!("value{:04}", i).into_bytes();
+                for _ in 0..8 {
+                    value.extend_from_slice(&chunks[rng.gen_range(0..chunks.len())]);
+                }
+                value
+            })
+            .collect();
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        let fill = |db: &TestDB| {
+            for (i, value) in values.iter().enumerate() {
+                db.set(&wo, key(i), value.clone()).unwrap();
+            }
+            db.flush().unwrap();
+        };
+        let check = |db: &TestDB| {
+            for (i, value) in values.iter().enumerate() {
+                assert_eq!(db.get(&key(i)).unwrap().as_ref(), Some(value));
+            }
+            let kvs = db.range_get(&key(0), &key(values.len())).unwrap();
+            assert_eq!(kvs.len(), values.len());
+        };
+
+        let plain_dir = tempfile::Builder::new().prefix("plain").tempdir().unwrap();
+        let plain_db = TestDB::open(plain_dir.path()).unwrap();
+        fill(&plain_db);
+        assert!(!plain_dir.path().join(DICTIONARY_FILE).exists());
+
         let temp_dir = tempfile::Builder::new()
-            .prefix("random_test")
+            .prefix("dictionary")
             .tempdir()
             .unwrap();
-        let path = temp_dir.path();
-        let write_option = WriteOptions { sync: false };
-        let db = NoTransactionDB::<
+        let options = Options {
+            dictionary_compression: true,
+            ..Options::default()
+        };
+        {
+            let db = TestDB::open_with_options(temp_dir.path(), options.clone()).unwrap();
+            fill(&db);
+            assert!(temp_dir.path().join(DICTIONARY_FILE).exists());
+            assert!(db.stats().sstable_bytes_written * 2 < plain_db.stats().sstable_bytes_written);
+            check(&db);
+        }
+
+        let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+        check(&db);
+        assert!(db.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_max_open_files() {
+        type TestDB = NoTransactionDB<
             InternalKey,
             InternalKey,
-            MrMwSkipMapMemTable<InternalKey>,
+            MutexSkipMapMemTable<InternalKey>,
             SimpleWriteAheadLog,
-        >::open(path)
-        .unwrap();
-
-        let map = create_random_map(20000);
-        for (k, v) in map.iter() {
-            db.set(
-                &write_option,
-                Vec::from(k.to_le_bytes()),
-                Vec::from(v.to_le_bytes()),
-            )
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("max_open_files")
+            .tempdir()
             .unwrap();
-        }
-        info!("start query");
-        let mut not_found_map = HashMap::new();
-        for (i, (k, v)) in map.iter().enumerate() {
-            if let Some(s) = db.get(&Vec::from(k.to_le_bytes())).unwrap() {
-                assert_eq!(s, v.to_le_bytes());
-            } else {
-                not_found_map.insert(*k, *v);
-            }
-            if i % 10000 == 0 {
-                info!("{}", i);
+        let options = Options {
+            max_open_files: 16,
+            level0_files_threshold: 100,
+            level0_slowdown_writes_trigger: 0,
+            level0_stop_writes_trigger: 0,
+            ..Options::default()
+        };
+        let wo = WriteOptions { sync: false };
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        let value = |i: usize| format!("value{:04}", i).into_bytes();
+        let tables = 40;
+
+        // the descriptors of the sstable files, which are in the level directories
+        let db_dir = temp_dir.path().canonicalize().unwrap();
+        let level_dirs: Vec<PathBuf> = (0..=MAX_LEVEL)
+            .map(|level| PathBuf::from(level_dir(db_dir.to_str().unwrap(), level)))
+            .collect();
+        let open_sstable_files = || {
+            std::fs::read_dir("/proc/self/fd")
+                .unwrap()
+                .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+                .filter(|target| {
+                    target
+                        .parent()
+                        .map_or(false, |dir| level_dirs.iter().any(|d| d == dir))
+                })
+                .count()
+        };
+        let check = |db: &TestDB| {
+            // the tables are appended to level 1, since their keys follow the previous ones
+            assert_eq!(db.levels()[1].tables.len(), tables);
+            for i in 0..tables * 10 {
+                assert_eq!(db.get(&key(i)).unwrap(), Some(value(i)));
+                assert!(db.open_table_files() <= 16);
+                if cfg!(target_os = "linux") {
+                    assert!(open_sstable_files() <= 16);
+                }
             }
-        }
-        if !not_found_map.is_empty() {
-            warn!("{} keys not found", not_found_map.len());
-            std::thread::sleep(Duration::from_secs(5));
-            for (k, v) in not_found_map {
-                if let Some(s) = db.get(&Vec::from(k.to_le_bytes())).unwrap() {
-                    assert_eq!(s, v.to_le_bytes());
-                } else {
-                    panic!("{} {}", k, v);
+            assert!(db.open_table_files() > 0);
+        };
+
+        let too_few = Options {
+            max_open_files: 8,
+            ..options.clone()
+        };
+        assert!(TestDB::open_with_options(temp_dir.path(), too_few).is_err());
+
+        {
+            let db = TestDB::open_with_options(temp_dir.path(), options.clone()).unwrap();
+            // each table holds its own keys, so every table is read
+            for t in 0..tables {
+                for i in t * 10..(t + 1) * 10 {
+                    db.set(&wo, key(i), value(i)).unwrap();
                 }
+                db.flush().unwrap();
             }
+            check(&db);
         }
+
+        let db = TestDB::open_with_options(temp_dir.path(), options).unwrap();
+        check(&db);
     }
 
-    fn setup_iterate1(
-        db: &NoTransactionDB<
+    #[test]
+    fn test_iterate_corrupted_table() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("iterate_corrupted")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            compression: CompressionType::NoCompression,
+            ..Options::default()
+        };
+        type CorruptedDB = NoTransactionDB<
             InternalKey,
             InternalKey,
             MrMwSkipMapMemTable<InternalKey>,
             SimpleWriteAheadLog,
-        >,
-        write_option: &WriteOptions,
-    ) -> usize {
-        for _ in 0..3 {
-            for i in 0..10000u128 {
-                db.set(
-                    write_option,
-                    Vec::from(i.to_be_bytes()),
-                    Vec::from((i + 1).to_be_bytes()),
-                )
+        >;
+        let db = CorruptedDB::open_with_options(temp_dir.path(), options.clone()).unwrap();
+        let wo = WriteOptions { sync: false };
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        for i in 0..1000 {
+            db.set(&wo, key(i), format!("value{}", i).into_bytes())
                 .unwrap();
-            }
         }
-        10000
+        db.flush().unwrap();
+
+        // overwrite the key length of the first entry in the second data block of the
+        // table, which is appended to the empty level 1
+        let (level, table_id) = {
+            let tables = db.list_tables();
+            assert_eq!(tables.len(), 1);
+            (tables[0].level(), tables[0].table_id())
+        };
+        assert_eq!(level, 1);
+        let path = sstable_file(db.db_path(), level, table_id);
+        let mut reader = crate::sstable::read_table(&path).unwrap();
+        let mut first_block_kvs = 0;
+        while reader.next().unwrap().is_ok() && reader.block_offset() == Some(0) {
+            first_block_kvs += 1;
+        }
+        let offset = reader.block_offset().unwrap() as usize;
+        drop(reader);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[offset..offset + 4].copy_from_slice(&[0xff; 4]);
+        std::fs::write(&path, bytes).unwrap();
+
+        // reopened, so that the iterator is not served by the immutable memory table
+        drop(db);
+        let db = CorruptedDB::open_with_options(temp_dir.path(), options).unwrap();
+        let mut iterator = db.get_db_iterator();
+        for i in 0..first_block_kvs {
+            let (k, v) = iterator.next().unwrap().unwrap();
+            assert_eq!(k, key(i));
+            assert_eq!(v, format!("value{}", i).into_bytes());
+        }
+        assert!(matches!(
+            iterator.next(),
+            Some(Err(KVLiteError::Corruption(_)))
+        ));
+        // the iteration ends after the error
+        assert!(iterator.next().is_none());
+        drop(iterator);
+        assert_eq!(db.get(&key(0)).unwrap(), Some(b"value0".to_vec()));
     }
 
-    fn setup_iterate2(
-        db: &NoTransactionDB<
+    #[test]
+    fn test_iterate_lost_value_log() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("iterate_lost_value_log")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            value_log_threshold: 16,
+            ..Options::default()
+        };
+        type LostDB = NoTransactionDB<
             InternalKey,
             InternalKey,
             MrMwSkipMapMemTable<InternalKey>,
             SimpleWriteAheadLog,
-        >,
-        write_option: &WriteOptions,
-    ) -> usize {
-        for i in 0..1000000u128 {
-            db.set(
-                write_option,
-                Vec::from(i.to_be_bytes()),
-                Vec::from((i + 1).to_be_bytes()),
-            )
-            .unwrap();
-        }
-        1000000
-    }
-
-    #[test]
-    fn test_iterate() {
-        for f in [setup_iterate1, setup_iterate2] {
-            let temp_dir = tempfile::Builder::new()
-                .prefix("iterate")
-                .tempdir()
+        >;
+        let db = LostDB::open_with_options(temp_dir.path(), options.clone()).unwrap();
+        let wo = WriteOptions { sync: false };
+        for i in 0..10 {
+            db.set(&wo, format!("key{}", i).into_bytes(), vec![i as u8; 100])
                 .unwrap();
-            let path = temp_dir.path();
-            let write_option = WriteOptions { sync: false };
-            let db = NoTransactionDB::<
-                InternalKey,
-                InternalKey,
-                MrMwSkipMapMemTable<InternalKey>,
-                SimpleWriteAheadLog,
-            >::open(path)
-            .unwrap();
-
-            let expected_count = f(&db, &write_option);
-            let iterator = db.get_db_iterator();
-            let mut count = 0;
-            for _ in iterator {
-                count += 1;
-            }
-            assert_eq!(expected_count, count);
-
-            let iterator = db.get_db_iterator();
-            for (i, (k, v)) in iterator.enumerate() {
-                let i = i as u128;
-                assert_eq!(Vec::from(i.to_be_bytes()), k);
-                assert_eq!(Vec::from((i + 1).to_be_bytes()), v);
-            }
         }
+        db.flush().unwrap();
+        for entry in std::fs::read_dir(value_log_dir(db.db_path())).unwrap() {
+            std::fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+
+        // reopened, so that the reads are not served by the immutable memory table
+        drop(db);
+        let db = LostDB::open_with_options(temp_dir.path(), options).unwrap();
+        let mut iterator = db.get_db_iterator();
+        assert!(matches!(iterator.next(), Some(Err(_))));
+        assert!(iterator.next().is_none());
+        assert!(db.get(&Vec::from("key0")).is_err());
     }
 }
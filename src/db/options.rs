@@ -1,3 +1,18 @@
+use crate::compaction::filter::CompactionFilter;
+use crate::compaction::level_0::LEVEL0_FILES_THRESHOLD;
+use crate::db::clock::{Clock, SystemClock};
+use crate::db::WRITE_BUFFER_SIZE;
+use crate::sstable::block_cache::BlockCache;
+use crate::sstable::CompressionType;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Delay of each write once [Options::level0_slowdown_writes_trigger] is reached.
+pub const WRITE_SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+
+/// Default of [Options::max_key_bytes].
+pub const DEFAULT_MAX_KEY_BYTES: usize = 64 * 1024;
+
 /// Options that control write operations
 pub struct WriteOptions {
     /// If true, the write will be flushed from the operating system
@@ -16,3 +31,223 @@ pub struct WriteOptions {
     /// system call followed by "fsync()".
     pub sync: bool,
 }
+
+/// Policy of syncing the write-ahead log to disk
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WalSync {
+    /// Only the writes whose [WriteOptions::sync] is true are synced.
+    PerWrite,
+    /// Besides [WalSync::PerWrite], a background thread wakes up every interval
+    /// and syncs the writes that are not synced yet.
+    Interval(Duration),
+}
+
+/// What the filter block of each sstable is built from. It is recorded in the footer
+/// when the sstable is written, so the sstables written before a change are still
+/// probed the way they were built.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BloomMode {
+    /// Whole keys, which suits point lookups.
+    WholeKey,
+    /// The first given bytes of the keys, or the whole keys if they are shorter. Point
+    /// lookups probe the filter with the prefixes of their keys, so keys sharing a prefix
+    /// are not told apart, while prefix scans whose prefix is at least this long skip the
+    /// sstables without the prefix. [Options::prefix_bloom_length] is ignored, as the
+    /// filter block already serves prefix scans.
+    Prefix(u16),
+}
+
+/// Options that control the behavior of a database
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub wal_sync: WalSync,
+    /// Compression of the newly written sstables. Existing sstables are read with
+    /// the compression type recorded in their own footers.
+    pub compression: CompressionType,
+    /// Invoked on every key-value pair written by compaction. See [CompactionFilter].
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Approximate size of the sstables produced by compaction, counted as the
+    /// total bytes of their keys and values. A table is cut once the size reaches
+    /// this value, so it may exceed it by at most one key-value pair.
+    pub target_table_size_bytes: u64,
+    /// Writes whose value is larger than this are rejected with
+    /// [crate::error::KVLiteError::ValueTooLarge]. 0 means unlimited.
+    pub max_value_bytes: usize,
+    /// Writes whose key is larger than this are rejected with
+    /// [crate::error::KVLiteError::KeyTooLarge]. An sstable whose index records a
+    /// longer key is reported as corrupted when it is opened, instead of allocating
+    /// whatever length a broken file claims. 0 means unlimited.
+    pub max_key_bytes: usize,
+    /// Create the database directory if it does not exist. If false, opening a
+    /// missing directory fails with [crate::error::KVLiteError::DbPathNotFound].
+    pub create_if_missing: bool,
+    /// Length of the key prefixes added to the prefix bloom filter of each sstable,
+    /// which lets [crate::db::no_transaction_db::NoTransactionDB::prefix_scan] skip
+    /// the sstables that cannot contain the prefix.
+    /// 0 means no prefix bloom filter is built.
+    pub prefix_bloom_length: u16,
+    /// What the filter block of each newly written sstable is built from.
+    pub bloom_mode: BloomMode,
+    /// The mutable memory table is also frozen once it holds this many tombstones,
+    /// so that a delete-heavy workload does not keep reads scanning over tombstones
+    /// until the table reaches [WRITE_BUFFER_SIZE]. 0 means no limit.
+    pub max_memtable_tombstones: u64,
+    /// Number of key-values between the restart points of a data block. A point lookup
+    /// binary searches the restart points, then scans at most this many key-values.
+    /// Larger intervals make the restart points smaller but lookups slower.
+    /// 0 is treated as 1, which makes every key-value a restart point.
+    pub block_restart_interval: usize,
+    /// If true, the sstables are kept in memory and nothing is written under the
+    /// database path, which does not even need to exist. Use it together with
+    /// [crate::wal::null_wal::NullWriteAheadLog], otherwise the write-ahead log
+    /// still writes files.
+    pub in_memory: bool,
+    /// Level 0 is compacted once it has more sstables than this. It only decides when
+    /// compaction is triggered, so it can be changed when reopening a database. If the
+    /// existing level 0 has more sstables than a reduced threshold, it is compacted
+    /// right after opening until the count is back under the threshold.
+    pub level0_files_threshold: usize,
+    /// Level 0 is also compacted once its sstables take more bytes than this, so that
+    /// a few large sstables do not wait for [Options::level0_files_threshold] to be
+    /// reached. Like the file count, it can be changed when reopening a database.
+    /// 0 means the size of level 0 never triggers compaction.
+    pub level0_file_size_threshold: u64,
+    /// If not 0, level 0 is compacted into itself while all its sstables take at most
+    /// this many bytes, and fewer than the sstables of level 1 they overlap: they are
+    /// merged into one level 0 sstable, so that reads check fewer sstables without
+    /// rewriting level 1. Otherwise level 0 is compacted into level 1 as usual.
+    pub level0_intra_compaction_bytes: u64,
+    /// Each write is delayed by [WRITE_SLOWDOWN_DELAY] while level 0 has at least this
+    /// many sstables, giving compaction time to catch up. 0 means writes are never delayed.
+    pub level0_slowdown_writes_trigger: usize,
+    /// Writes are blocked while level 0 has at least this many sstables, until compaction
+    /// brings the count down. It should be greater than [Options::level0_files_threshold],
+    /// otherwise level 0 may never be compacted. 0 means writes are never blocked.
+    pub level0_stop_writes_trigger: usize,
+    /// If true, the sstables written by compaction are opened with `O_DIRECT`, so that
+    /// compaction does not evict the pages cached for foreground reads. Buffered I/O is
+    /// used instead, with a warning, if the platform or the filesystem does not support it.
+    /// Only Linux supports it now.
+    pub use_direct_io_for_compaction: bool,
+    /// Number of background threads compacting each of the levels 1 to
+    /// [crate::db::MAX_LEVEL] - 1. The threads of a level compact different sstables at
+    /// the same time, and never two whose key ranges overlap in the next level.
+    /// 0 is treated as 1.
+    pub compaction_threads_per_level: usize,
+    /// The index of an sstable whose index block is longer than this many bytes is
+    /// cached as a two-level index: only the max key of every 4 KB of index entries is
+    /// kept, and the entries themselves are read on demand. It saves memory for large
+    /// sstables at the cost of an extra read on a cache miss. 0 means the whole index
+    /// block is always cached.
+    pub partitioned_index_threshold: u32,
+    /// Values longer than this many bytes are moved from the sstables written by flush
+    /// and bulk ingestion to a value log, see [crate::sstable::value_log]. Compaction then
+    /// copies small pointers instead of the values. Reading such a value takes an extra
+    /// read from the value log. 0 means values are always stored in the sstables.
+    /// It is ignored if [Options::in_memory] is true.
+    pub value_log_threshold: usize,
+    /// If true, a zstd dictionary is trained on the values of the first flush, and the
+    /// values of the sstables written from then on are compressed one by one against it,
+    /// see [crate::sstable::dictionary]. It suits many similar small values. Values
+    /// separated by [Options::value_log_threshold] are not compressed this way.
+    pub dictionary_compression: bool,
+    /// If true, compacting the same input sstables always writes byte-identical output
+    /// sstables with consecutive table ids in key order, e.g. for testing or content
+    /// hashing. The in-memory merge of level 0 uses a fixed random seed, and the outputs
+    /// of a compaction are buffered in memory until all of them are cut, so that their
+    /// ids are allocated at once instead of interleaving with flushes.
+    pub deterministic_compaction: bool,
+    /// The mutable memory table of a
+    /// [crate::db::transaction::write_committed::WriteCommittedDB] is not frozen while
+    /// snapshots or transactions are active, because the sstables it is written to keep
+    /// only the newest versions. Once it grows to this many times [WRITE_BUFFER_SIZE],
+    /// the writes are stalled until all of them end, and then it is frozen. A thread
+    /// holding a snapshot must not write meanwhile, or it waits for itself. 0 means the
+    /// writes are never stalled, and the memory table grows until the snapshots end.
+    pub snapshot_freeze_multiple: u64,
+    /// Cache of the data blocks read by point lookups. Several databases may share one
+    /// cache, and so one memory budget, e.g. a [crate::sstable::block_cache::LRUBlockCache]
+    /// or a cache of the embedding system. If `None`, each sstable keeps the data blocks
+    /// it has read in its own entry of the table cache.
+    pub block_cache: Option<Arc<dyn BlockCache>>,
+    /// If true, the filters and the indexes of the sstables are loaded into the table
+    /// cache when the database is opened, from the newest sstable to the oldest, as long
+    /// as the cache has room for them. See also [crate::db::DB::warm_cache].
+    pub warm_cache_on_open: bool,
+    /// If true, opening the database checks that the mutable memory table holds exactly
+    /// what is replayed from the WAL, comparing the count and a checksum of the replayed
+    /// records with the ones recomputed from the table, and fails with
+    /// [crate::error::KVLiteError::RecoveryMismatch] if they differ. The replayed keys are
+    /// kept in memory until the check.
+    pub verify_recovery: bool,
+    /// If not 0, an sstable of level 1 to [crate::db::MAX_LEVEL] - 1 created more than
+    /// this many seconds ago is compacted into the next level even if the size of its
+    /// level does not call for compaction, the oldest first, so that the deletions and
+    /// the values dropped by [Options::compaction_filter] in cold sstables do not linger.
+    /// Such an sstable of [crate::db::MAX_LEVEL] is rewritten in place instead, and its
+    /// tombstones are dropped. The compacting threads look for such sstables at least
+    /// once a minute.
+    ///
+    /// The creation time of an sstable is taken from [Options::clock] when it is written,
+    /// and from the modification time of its file when the database is opened.
+    pub periodic_compaction_seconds: u64,
+    /// Clock that [Options::periodic_compaction_seconds] is measured with.
+    pub clock: Arc<dyn Clock>,
+    /// If not 0, reads of the sstables share the files kept open by an LRU cache of at
+    /// most this many files, so that a database of many sstables does not run out of
+    /// file descriptors. The files of the least recently read sstables are closed, and
+    /// opened again when they are read. The cap is rounded down to a multiple of 16, the
+    /// number of shards of the cache, and opening fails if it is less than 16. A read in
+    /// progress keeps its file open even if it is evicted.
+    ///
+    /// If 0, every read opens the file of its sstable and closes it when it is done.
+    /// See [crate::db::DB::open_table_files].
+    pub max_open_files: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            wal_sync: WalSync::PerWrite,
+            compression: CompressionType::default(),
+            compaction_filter: None,
+            target_table_size_bytes: WRITE_BUFFER_SIZE,
+            max_value_bytes: 0,
+            max_key_bytes: DEFAULT_MAX_KEY_BYTES,
+            create_if_missing: true,
+            prefix_bloom_length: 0,
+            bloom_mode: BloomMode::WholeKey,
+            max_memtable_tombstones: 0,
+            block_restart_interval: 16,
+            in_memory: false,
+            level0_files_threshold: LEVEL0_FILES_THRESHOLD,
+            level0_file_size_threshold: 0,
+            level0_intra_compaction_bytes: 0,
+            level0_slowdown_writes_trigger: 20,
+            level0_stop_writes_trigger: 36,
+            use_direct_io_for_compaction: false,
+            compaction_threads_per_level: 1,
+            partitioned_index_threshold: 0,
+            value_log_threshold: 0,
+            dictionary_compression: false,
+            deterministic_compaction: false,
+            snapshot_freeze_multiple: 4,
+            block_cache: None,
+            warm_cache_on_open: false,
+            verify_recovery: false,
+            periodic_compaction_seconds: 0,
+            clock: Arc::new(SystemClock),
+            max_open_files: 0,
+        }
+    }
+}
+
+impl Options {
+    /// Options of a database that lives in memory only, see [Options::in_memory].
+    pub fn in_memory() -> Options {
+        Options {
+            in_memory: true,
+            ..Options::default()
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use crate::db::key_types::LSN;
+use crate::ioutils::replace_file;
+use crate::Result;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Name of the file in the database directory which records the upper bound of
+/// the allocated sequence numbers.
+pub const SEQUENCE_FILE: &str = "SEQUENCE";
+
+/// Count of sequence numbers reserved each time [SEQUENCE_FILE] is rewritten.
+const RESERVED_SEQUENCES: u64 = 4096;
+
+/// Sequence number of the writes, transactions and snapshots of a
+/// [crate::db::transaction::write_committed::WriteCommittedDB], which is used as
+/// the [LSN] of their keys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SequenceNumber(LSN);
+
+impl SequenceNumber {
+    #[inline]
+    pub const fn new(lsn: LSN) -> SequenceNumber {
+        SequenceNumber(lsn)
+    }
+
+    #[inline]
+    pub const fn get(self) -> LSN {
+        self.0
+    }
+}
+
+impl From<SequenceNumber> for LSN {
+    #[inline]
+    fn from(sequence: SequenceNumber) -> LSN {
+        sequence.0
+    }
+}
+
+impl fmt::Display for SequenceNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Allocator of strictly increasing sequence numbers.
+///
+/// A number is allocated by one atomic `fetch_add`, so a number allocated after
+/// another one returns is always greater, whichever threads they are allocated by.
+/// The upper bound of the allocated numbers is written to [SEQUENCE_FILE] before any
+/// number below it is returned, so the numbers allocated after reopening never collide
+/// with the ones already in WAL or sstables.
+pub(crate) struct SequenceAllocator {
+    next: AtomicU64,
+    /// Numbers less than it can be allocated without rewriting [SEQUENCE_FILE].
+    reserved: AtomicU64,
+    /// Held while rewriting [SEQUENCE_FILE].
+    persist_lock: Mutex<()>,
+    /// `None` if the database is in memory.
+    db_path: Option<PathBuf>,
+}
+
+impl SequenceAllocator {
+    /// Load the upper bound persisted in `db_path`, or start from 1 if there is none.
+    pub(crate) fn open(db_path: &Path) -> Result<SequenceAllocator> {
+        let next = match std::fs::read(db_path.join(SEQUENCE_FILE)) {
+            Ok(bytes) if bytes.len() == 8 => {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes);
+                u64::from_le_bytes(buf)
+            }
+            Ok(_) => {
+                warn!("ignore invalid {}", SEQUENCE_FILE);
+                1
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 1,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(SequenceAllocator {
+            next: AtomicU64::new(next),
+            reserved: AtomicU64::new(next),
+            persist_lock: Mutex::new(()),
+            db_path: Some(db_path.to_path_buf()),
+        })
+    }
+
+    /// Allocator of a database which is never persisted.
+    pub(crate) fn in_memory() -> SequenceAllocator {
+        SequenceAllocator {
+            next: AtomicU64::new(1),
+            reserved: AtomicU64::new(u64::MAX),
+            persist_lock: Mutex::new(()),
+            db_path: None,
+        }
+    }
+
+    pub(crate) fn allocate(&self) -> SequenceNumber {
+        let sequence = self.next.fetch_add(1, Ordering::SeqCst);
+        if sequence >= self.reserved.load(Ordering::SeqCst) {
+            let _guard = self.persist_lock.lock().unwrap();
+            // another thread may have reserved it while waiting for the lock
+            if sequence >= self.reserved.load(Ordering::SeqCst) {
+                let new_reserved = sequence + RESERVED_SEQUENCES;
+                if let Some(db_path) = &self.db_path {
+                    replace_file(db_path, SEQUENCE_FILE, &new_reserved.to_le_bytes()).unwrap();
+                }
+                self.reserved.store(new_reserved, Ordering::SeqCst);
+            }
+        }
+        SequenceNumber(sequence)
+    }
+
+    /// The number which the next [SequenceAllocator::allocate] returns, if no other
+    /// thread allocates in between.
+    pub(crate) fn peek_next(&self) -> SequenceNumber {
+        SequenceNumber(self.next.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::sequence::{SequenceAllocator, SequenceNumber, RESERVED_SEQUENCES};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Barrier};
+
+    #[test]
+    fn test_allocate_concurrently() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("sequence")
+            .tempdir()
+            .unwrap();
+        const THREADS: usize = 16;
+        const SEQUENCES_PER_THREAD: usize = 5000;
+
+        let allocator = Arc::new(SequenceAllocator::open(temp_dir.path()).unwrap());
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let allocator = allocator.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    (0..SEQUENCES_PER_THREAD)
+                        .map(|_| allocator.allocate())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut all = HashSet::new();
+        for handle in handles {
+            let sequences = handle.join().unwrap();
+            assert!(sequences.windows(2).all(|w| w[0] < w[1]));
+            for sequence in sequences {
+                assert!(all.insert(sequence));
+            }
+        }
+        assert_eq!(all.len(), THREADS * SEQUENCES_PER_THREAD);
+        let max = *all.iter().max().unwrap();
+        assert_eq!(
+            max,
+            SequenceNumber::new((THREADS * SEQUENCES_PER_THREAD) as u64)
+        );
+        assert!(allocator.peek_next() > max);
+
+        // numbers allocated before reopening are never allocated again
+        drop(allocator);
+        let allocator = SequenceAllocator::open(temp_dir.path()).unwrap();
+        let sequence = allocator.allocate();
+        assert!(sequence > max);
+        assert!(sequence.get() <= max.get() + RESERVED_SEQUENCES);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::db::key_types::InternalKey;
+use crate::db::latency::LatencyHistograms;
+use crate::sstable::table_handle::TableReadHandle;
+use crate::sstable::TableID;
+use std::ops::{Add, Sub};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bytes read and written since the database is opened. See [crate::db::DB::stats].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    /// Bytes of the keys and values written by users.
+    pub user_bytes_written: u64,
+    /// Bytes appended to WAL.
+    pub wal_bytes_written: u64,
+    /// Bytes of the sstables written by flush, compaction and bulk ingestion.
+    pub sstable_bytes_written: u64,
+    /// Bytes of the values moved to the value log, see
+    /// [crate::db::options::Options::value_log_threshold].
+    pub value_log_bytes_written: u64,
+    /// Bytes of the keys and values returned to users.
+    pub user_bytes_read: u64,
+    /// Bytes of the data blocks read from sstables to serve user reads.
+    pub disk_bytes_read: u64,
+    /// Data blocks of point lookups found in the block cache, either
+    /// [crate::db::options::Options::block_cache] or the table cache.
+    pub block_cache_hits: u64,
+    /// Data blocks of point lookups read from sstables.
+    pub block_cache_misses: u64,
+    /// Microseconds the writes are delayed or blocked because level 0 has too
+    /// many sstables, see [crate::db::options::Options::level0_slowdown_writes_trigger].
+    pub write_stall_micros: u64,
+}
+
+impl Stats {
+    /// Bytes written to disk per byte written by users, or 0 if nothing is written.
+    pub fn write_amplification(&self) -> f64 {
+        ratio(
+            self.wal_bytes_written + self.sstable_bytes_written + self.value_log_bytes_written,
+            self.user_bytes_written,
+        )
+    }
+
+    /// Bytes read from disk per byte returned to users, or 0 if nothing is read.
+    pub fn read_amplification(&self) -> f64 {
+        ratio(self.disk_bytes_read, self.user_bytes_read)
+    }
+
+    /// Combine each counter of `self` with the same counter of `other`.
+    fn zip_with(self, other: Stats, op: impl Fn(u64, u64) -> u64) -> Stats {
+        Stats {
+            user_bytes_written: op(self.user_bytes_written, other.user_bytes_written),
+            wal_bytes_written: op(self.wal_bytes_written, other.wal_bytes_written),
+            sstable_bytes_written: op(self.sstable_bytes_written, other.sstable_bytes_written),
+            value_log_bytes_written: op(
+                self.value_log_bytes_written,
+                other.value_log_bytes_written,
+            ),
+            user_bytes_read: op(self.user_bytes_read, other.user_bytes_read),
+            disk_bytes_read: op(self.disk_bytes_read, other.disk_bytes_read),
+            block_cache_hits: op(self.block_cache_hits, other.block_cache_hits),
+            block_cache_misses: op(self.block_cache_misses, other.block_cache_misses),
+            write_stall_micros: op(self.write_stall_micros, other.write_stall_micros),
+        }
+    }
+}
+
+/// The counters of `self` minus those of `earlier`, e.g. the work done between two calls
+/// of [crate::db::DB::stats], whose amplifications are those of the interval. A counter
+/// smaller than in `earlier`, e.g. of another opening of the database, yields 0.
+impl Sub for Stats {
+    type Output = Stats;
+
+    fn sub(self, earlier: Stats) -> Stats {
+        self.zip_with(earlier, u64::saturating_sub)
+    }
+}
+
+/// The sum of the counters, e.g. of several databases.
+impl Add for Stats {
+    type Output = Stats;
+
+    fn add(self, other: Stats) -> Stats {
+        self.zip_with(other, u64::saturating_add)
+    }
+}
+
+fn ratio(physical: u64, logical: u64) -> f64 {
+    if logical == 0 {
+        0.0
+    } else {
+        physical as f64 / logical as f64
+    }
+}
+
+/// Bytes held in memory by the database. See [crate::db::DB::memory_usage].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryUsage {
+    /// Approximate bytes of the mutable memory table.
+    pub mut_mem_table: u64,
+    /// Approximate bytes of the immutable memory table being written to level 0.
+    pub imm_mem_table: u64,
+    /// Bytes of the data blocks in the table cache.
+    pub block_cache: u64,
+    /// Bytes of the whole-key filters in the table cache, and the prefix filters
+    /// which are kept as long as their sstables are live.
+    pub filters: u64,
+    /// Bytes of the index blocks in the table cache.
+    pub index_blocks: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.mut_mem_table
+            + self.imm_mem_table
+            + self.block_cache
+            + self.filters
+            + self.index_blocks
+    }
+}
+
+/// The sstables of one level. See [crate::db::DB::levels].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LevelInfo {
+    pub level: usize,
+    /// Sorted by table id in level 0, whose tables may overlap, and by key range
+    /// in the other levels.
+    pub tables: Vec<TableInfo>,
+}
+
+impl LevelInfo {
+    /// Total file size of the sstables in the level.
+    pub fn size(&self) -> u64 {
+        self.tables.iter().map(|table| table.file_size).sum()
+    }
+}
+
+/// An sstable of a [LevelInfo].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableInfo {
+    pub table_id: TableID,
+    /// Min key of the sstable, which may be a tombstone.
+    pub min_key: InternalKey,
+    /// Max key of the sstable, which may be a tombstone.
+    pub max_key: InternalKey,
+    pub file_size: u64,
+}
+
+impl From<&TableReadHandle> for TableInfo {
+    fn from(table: &TableReadHandle) -> Self {
+        TableInfo {
+            table_id: table.table_id(),
+            min_key: table.min_key().clone(),
+            max_key: table.max_key().clone(),
+            file_size: table.file_size(),
+        }
+    }
+}
+
+/// Counters shared by the database and its background tasks.
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    pub(crate) user_bytes_written: AtomicU64,
+    pub(crate) sstable_bytes_written: AtomicU64,
+    pub(crate) value_log_bytes_written: AtomicU64,
+    pub(crate) user_bytes_read: AtomicU64,
+    pub(crate) disk_bytes_read: AtomicU64,
+    pub(crate) block_cache_hits: AtomicU64,
+    pub(crate) block_cache_misses: AtomicU64,
+    pub(crate) write_stall_micros: AtomicU64,
+    pub(crate) latency: LatencyHistograms,
+}
+
+impl StatsCounters {
+    #[inline]
+    pub(crate) fn add(counter: &AtomicU64, bytes: usize) {
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a data block of a point lookup found in the block cache.
+    #[inline]
+    pub(crate) fn add_block_cache_hit(&self) {
+        Self::add(&self.block_cache_hits, 1);
+    }
+
+    /// Count a data block of `length` bytes read from an sstable for a point lookup.
+    #[inline]
+    pub(crate) fn add_block_cache_miss(&self, length: usize) {
+        Self::add(&self.disk_bytes_read, length);
+        Self::add(&self.block_cache_misses, 1);
+    }
+
+    pub(crate) fn snapshot(&self, wal_bytes_written: u64) -> Stats {
+        Stats {
+            user_bytes_written: self.user_bytes_written.load(Ordering::Relaxed),
+            wal_bytes_written,
+            sstable_bytes_written: self.sstable_bytes_written.load(Ordering::Relaxed),
+            value_log_bytes_written: self.value_log_bytes_written.load(Ordering::Relaxed),
+            user_bytes_read: self.user_bytes_read.load(Ordering::Relaxed),
+            disk_bytes_read: self.disk_bytes_read.load(Ordering::Relaxed),
+            block_cache_hits: self.block_cache_hits.load(Ordering::Relaxed),
+            block_cache_misses: self.block_cache_misses.load(Ordering::Relaxed),
+            write_stall_micros: self.write_stall_micros.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1 +1,2 @@
 pub mod write_committed;
+pub mod write_prepared;
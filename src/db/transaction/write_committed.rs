@@ -1,13 +1,21 @@
 use crate::collections::skip_list::skipmap::SrSwSkipMap;
-use crate::db::key_types::{LSNKey, MemKey, LSN};
-use crate::db::no_transaction_db::NoTransactionDB;
-use crate::db::options::WriteOptions;
-use crate::db::{Value, DB};
+use crate::compaction::plan::CompactionPlan;
+use crate::db::entry::Entry;
+use crate::db::key_types::{InternalKey, LSNKey, MemKey, LSN};
+use crate::db::latency::LatencyStats;
+use crate::db::no_transaction_db::{prefix_successor, NoTransactionDB};
+use crate::db::options::{Options, WriteOptions};
+use crate::db::sequence::SequenceAllocator;
+use crate::db::stats::{LevelInfo, MemoryUsage, Stats};
+use crate::db::{remove_tombstones, Value, DB};
 use crate::memory::MemTable;
-use crate::wal::TransactionWAL;
+use crate::sstable::verify::VerifyReport;
+use crate::wal::{TransactionWAL, WALRecord};
 use crate::Result;
+use crossbeam_channel::Receiver;
+use std::collections::BTreeMap;
 use std::path::Path;
-use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::{Arc, MutexGuard};
 
 pub struct SnapShot<UK, M, L>
@@ -36,6 +44,34 @@ where
         let key = LSNKey::new(key, self.lsn);
         self.db.get(&key)
     }
+
+    /// Get the key-values visible to the snapshot whose keys start with `prefix`.
+    pub fn prefix_get(&self, prefix: UK) -> SrSwSkipMap<UK, Value> {
+        match self.db.prefix_end(prefix.internal_key(), None) {
+            Some(key_end) => {
+                let mut kvs = self.range_get(prefix.clone(), UK::from(key_end));
+                retain_prefix(&mut kvs, prefix.internal_key());
+                kvs
+            }
+            None => SrSwSkipMap::new(),
+        }
+    }
+
+    /// Like [SnapShot::prefix_get], but return an iterator of the key-values.
+    pub fn prefix_iter(&self, prefix: UK) -> impl Iterator<Item = (UK, Value)> {
+        self.prefix_get(prefix).into_iter()
+    }
+
+    /// Iterate all the key-value pairs visible to the snapshot in key order, tombstones
+    /// excluded. The pairs are collected when it is called, so the iterator is not
+    /// affected by the writes after the snapshot is taken.
+    pub fn iter(&self) -> impl Iterator<Item = (UK, Value)> + '_ {
+        let kvs = match self.db.inner.key_range() {
+            Some((min_key, max_key)) => self.range_get(UK::from(min_key), UK::from(max_key)),
+            None => SrSwSkipMap::new(),
+        };
+        kvs.into_iter()
+    }
 }
 
 impl<UK, M, L> Drop for SnapShot<UK, M, L>
@@ -45,7 +81,10 @@ where
     L: TransactionWAL<LSNKey<UK>, UK> + 'static,
 {
     fn drop(&mut self) {
-        self.db.num_lsn_acquired.fetch_sub(1, Ordering::Release);
+        self.db
+            .inner
+            .num_lsn_acquired
+            .fetch_sub(1, Ordering::Release);
     }
 }
 
@@ -73,9 +112,32 @@ where
         let key_end = LSNKey::new(key_end, self.lsn);
         let mut kvs = self.db.range_get(&key_start, &key_end).unwrap();
         self.table.range_get(&key_start, &key_end, &mut kvs);
+        remove_tombstones(&mut kvs);
         kvs
     }
 
+    /// Get the key-values whose keys start with `prefix`. Like [WriteBatch::range_get],
+    /// the pending writes of the batch are included.
+    pub fn prefix_get(&self, prefix: UK) -> SrSwSkipMap<UK, Value> {
+        let pending_max_key = self
+            .table
+            .last_key_value()
+            .map(|entry| entry.key.internal_key());
+        match self.db.prefix_end(prefix.internal_key(), pending_max_key) {
+            Some(key_end) => {
+                let mut kvs = self.range_get(prefix.clone(), UK::from(key_end));
+                retain_prefix(&mut kvs, prefix.internal_key());
+                kvs
+            }
+            None => SrSwSkipMap::new(),
+        }
+    }
+
+    /// Like [WriteBatch::prefix_get], but return an iterator of the key-values.
+    pub fn prefix_iter(&self, prefix: UK) -> impl Iterator<Item = (UK, Value)> {
+        self.prefix_get(prefix).into_iter()
+    }
+
     pub fn get(&self, key: UK) -> Result<Option<Value>> {
         let key = LSNKey::new(key, self.lsn);
         match self.table.get_clone(&key) {
@@ -85,7 +147,9 @@ where
     }
 
     pub fn set(&mut self, key: UK, value: Value) -> Result<()> {
+        self.db.inner.check_value_size(&value)?;
         let key = LSNKey::new(key, self.lsn);
+        self.db.inner.check_key_size(key.internal_key())?;
 
         let key_len = key.mem_size() as i64;
         let value_len = value.len() as i64;
@@ -99,6 +163,7 @@ where
 
     pub fn remove(&mut self, key: UK) -> Result<()> {
         let key = LSNKey::new(key, self.lsn);
+        self.db.inner.check_key_size(key.internal_key())?;
 
         let key_mem_size = key.mem_size();
         let mem_add = match self.table.insert(key, Value::default()) {
@@ -113,6 +178,22 @@ where
         std::mem::take(&mut self.table);
         Ok(())
     }
+
+    /// Iterate the pending writes in key order. Removed keys have empty values.
+    pub fn iter(&self) -> impl Iterator<Item = (&UK, &Value)> {
+        self.table.iter().map(|(k, v)| (k.user_key(), v))
+    }
+
+    /// Number of the pending writes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
 }
 
 impl<UK, M, L> Drop for WriteBatch<UK, M, L>
@@ -122,6 +203,11 @@ where
     L: TransactionWAL<LSNKey<UK>, UK> + 'static,
 {
     fn drop(&mut self) {
+        // release the LSN first, so that committing the batch may freeze the memory table
+        self.db
+            .inner
+            .num_lsn_acquired
+            .fetch_sub(1, Ordering::Release);
         if !self.table.is_empty() {
             let table = std::mem::take(&mut self.table);
             let mem_usage = self.mem_usage.load(Ordering::Acquire);
@@ -130,7 +216,37 @@ where
                 .write_batch(&self.write_options, table, mem_usage as u64)
                 .unwrap();
         }
-        self.db.num_lsn_acquired.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// A version of a user key, returned by [WriteCommittedDB::range_get_versions].
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyVersion {
+    /// LSN of the write, or `None` if the version is read from sstables,
+    /// which do not keep LSNs.
+    pub lsn: Option<LSN>,
+    /// The value written, or `None` if the key is removed.
+    pub value: Option<Value>,
+}
+
+impl KeyVersion {
+    fn new(lsn: Option<LSN>, value: Value) -> KeyVersion {
+        KeyVersion {
+            lsn,
+            value: if value.is_empty() { None } else { Some(value) },
+        }
+    }
+}
+
+/// Remove the keys of `kvs` which do not start with `prefix`.
+fn retain_prefix<UK: MemKey>(kvs: &mut SrSwSkipMap<UK, Value>, prefix: &[u8]) {
+    let unmatched: Vec<UK> = kvs
+        .iter()
+        .filter(|(k, _)| !k.internal_key().starts_with(prefix))
+        .map(|(k, _)| k.clone())
+        .collect();
+    for key in unmatched {
+        kvs.remove(key);
     }
 }
 
@@ -148,6 +264,9 @@ where
 /// becomes a major contributor to lower throughput. Moreover this write policy
 /// cannot provide weaker isolation levels, such as READ UNCOMMITTED, that could
 /// potentially provide higher throughput for some applications.
+///
+/// See [crate::db::transaction::write_prepared::WritePreparedDB] for the WritePrepared
+/// write policy.
 pub struct WriteCommittedDB<UK, M, L>
 where
     UK: MemKey + From<LSNKey<UK>> + 'static,
@@ -155,8 +274,24 @@ where
     L: TransactionWAL<LSNKey<UK>, UK> + 'static,
 {
     inner: NoTransactionDB<LSNKey<UK>, UK, M, L>,
-    next_lsn: AtomicU64,
-    num_lsn_acquired: AtomicU64,
+    sequences: SequenceAllocator,
+}
+
+impl<UK, M, L> std::fmt::Debug for WriteCommittedDB<UK, M, L>
+where
+    UK: MemKey + From<LSNKey<UK>> + 'static,
+    M: MemTable<LSNKey<UK>, UK> + 'static,
+    L: TransactionWAL<LSNKey<UK>, UK> + 'static,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteCommittedDB")
+            .field("inner", &self.inner)
+            .field(
+                "num_lsn_acquired",
+                &self.inner.num_lsn_acquired.load(Ordering::Acquire),
+            )
+            .finish()
+    }
 }
 
 impl<UK, M, L> DB<LSNKey<UK>, UK, M> for WriteCommittedDB<UK, M, L>
@@ -165,13 +300,15 @@ where
     M: MemTable<LSNKey<UK>, UK> + 'static,
     L: TransactionWAL<LSNKey<UK>, UK>,
 {
-    fn open(db_path: impl AsRef<Path>) -> Result<Self> {
-        let inner = NoTransactionDB::<LSNKey<UK>, UK, M, L>::open(db_path)?;
-        Ok(WriteCommittedDB {
-            inner,
-            next_lsn: AtomicU64::new(1),
-            num_lsn_acquired: AtomicU64::new(0),
-        })
+    fn open_with_options(db_path: impl AsRef<Path>, options: Options) -> Result<Self> {
+        let in_memory = options.in_memory;
+        let inner = NoTransactionDB::<LSNKey<UK>, UK, M, L>::open_with_options(db_path, options)?;
+        let sequences = if in_memory {
+            SequenceAllocator::in_memory()
+        } else {
+            SequenceAllocator::open(Path::new(inner.db_path()))?
+        };
+        Ok(WriteCommittedDB { inner, sequences })
     }
 
     #[inline]
@@ -184,11 +321,38 @@ where
         self.inner.set(write_options, key, value)
     }
 
+    #[inline]
+    fn try_set(&self, write_options: &WriteOptions, key: LSNKey<UK>, value: Value) -> Result<bool> {
+        self.inner.try_set(write_options, key, value)
+    }
+
     #[inline]
     fn remove(&self, write_options: &WriteOptions, key: LSNKey<UK>) -> Result<()> {
         self.inner.remove(write_options, key)
     }
 
+    #[inline]
+    fn compare_and_swap(
+        &self,
+        write_options: &WriteOptions,
+        key: LSNKey<UK>,
+        expected: Option<&Value>,
+        new: Value,
+    ) -> Result<bool> {
+        self.inner
+            .compare_and_swap(write_options, key, expected, new)
+    }
+
+    #[inline]
+    fn fetch_add(&self, write_options: &WriteOptions, key: LSNKey<UK>, delta: i64) -> Result<i64> {
+        self.inner.fetch_add(write_options, key, delta)
+    }
+
+    #[inline]
+    fn entry<'a>(&'a self, write_options: &'a WriteOptions, key: LSNKey<UK>) -> Entry<'a> {
+        self.inner.entry(write_options, key)
+    }
+
     #[inline]
     fn range_get(
         &self,
@@ -201,6 +365,88 @@ where
     fn db_path(&self) -> &String {
         self.inner.db_path()
     }
+
+    #[inline]
+    fn bulk_ingest(&self, sorted: impl Iterator<Item = (LSNKey<UK>, Value)>) -> Result<()> {
+        self.inner.bulk_ingest(sorted)
+    }
+
+    #[inline]
+    fn subscribe(&self, prefix: &[u8]) -> Receiver<(LSNKey<UK>, Option<Value>)> {
+        self.inner.subscribe(prefix)
+    }
+
+    #[inline]
+    fn plan_compaction(&self) -> CompactionPlan {
+        self.inner.plan_compaction()
+    }
+
+    #[inline]
+    fn memory_usage(&self) -> MemoryUsage {
+        self.inner.memory_usage()
+    }
+
+    #[inline]
+    fn open_table_files(&self) -> usize {
+        self.inner.open_table_files()
+    }
+
+    #[inline]
+    fn levels(&self) -> Vec<LevelInfo> {
+        self.inner.levels()
+    }
+
+    #[inline]
+    fn verify(&self) -> Result<VerifyReport> {
+        self.inner.verify()
+    }
+
+    #[inline]
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    #[inline]
+    fn stats(&self) -> Stats {
+        self.inner.stats()
+    }
+
+    #[inline]
+    fn estimate_count_in_range(&self, key_start: &LSNKey<UK>, key_end: &LSNKey<UK>) -> Result<u64> {
+        self.inner.estimate_count_in_range(key_start, key_end)
+    }
+
+    /// The sequence numbers keep increasing, so that the running transactions never
+    /// reuse one.
+    #[inline]
+    fn clear(&self) -> Result<()> {
+        self.inner.clear()
+    }
+
+    #[inline]
+    fn set_wal_enabled(&self, enabled: bool) {
+        self.inner.set_wal_enabled(enabled)
+    }
+
+    #[inline]
+    fn warm_cache(&self, keys: &[LSNKey<UK>]) -> Result<usize> {
+        self.inner.warm_cache(keys)
+    }
+
+    #[inline]
+    fn pin_range(&self, key_start: &LSNKey<UK>, key_end: &LSNKey<UK>) -> Result<()> {
+        self.inner.pin_range(key_start, key_end)
+    }
+
+    #[inline]
+    fn unpin_range(&self, key_start: &LSNKey<UK>, key_end: &LSNKey<UK>) -> Result<()> {
+        self.inner.unpin_range(key_start, key_end)
+    }
+
+    #[inline]
+    fn latency_stats(&self) -> LatencyStats {
+        self.inner.latency_stats()
+    }
 }
 
 impl<UK, M, L> WriteCommittedDB<UK, M, L>
@@ -210,7 +456,7 @@ where
     L: TransactionWAL<LSNKey<UK>, UK>,
 {
     pub fn get_by_user_key(&self, key: UK) -> Result<Option<Value>> {
-        let lsn_key = LSNKey::new(key, self.next_lsn.fetch_add(1, Ordering::Release));
+        let lsn_key = LSNKey::new(key, self.sequences.allocate().get());
         self.get(&lsn_key)
     }
 
@@ -220,27 +466,80 @@ where
         key: UK,
         value: Value,
     ) -> Result<()> {
-        let lsn_key = LSNKey::new(key, self.next_lsn.fetch_add(1, Ordering::Release));
+        let lsn_key = LSNKey::new(key, self.sequences.allocate().get());
         self.set(write_options, lsn_key, value)
     }
 
+    /// [DB::try_set] with a new version of `key`.
+    pub fn try_set_by_user_key(
+        &self,
+        write_options: &WriteOptions,
+        key: UK,
+        value: Value,
+    ) -> Result<bool> {
+        let lsn_key = LSNKey::new(key, self.sequences.allocate().get());
+        self.try_set(write_options, lsn_key, value)
+    }
+
     pub fn remove_by_user_key(&self, write_options: &WriteOptions, key: UK) -> Result<()> {
-        let lsn_key = LSNKey::new(key, self.next_lsn.fetch_add(1, Ordering::Release));
+        let lsn_key = LSNKey::new(key, self.sequences.allocate().get());
         self.remove(write_options, lsn_key)
     }
 
+    /// [DB::compare_and_swap] with the newest version of `key`. The LSN of the write is
+    /// allocated after the other writers are blocked, so no newer write is missed.
+    pub fn compare_and_swap_by_user_key(
+        &self,
+        write_options: &WriteOptions,
+        key: UK,
+        expected: Option<&Value>,
+        new: Value,
+    ) -> Result<bool> {
+        self.inner.compare_and_swap_with(
+            write_options,
+            || LSNKey::new(key, self.sequences.allocate().get()),
+            expected,
+            new,
+        )
+    }
+
+    /// [DB::fetch_add] with the newest version of `key`, like
+    /// [WriteCommittedDB::compare_and_swap_by_user_key].
+    pub fn fetch_add_by_user_key(
+        &self,
+        write_options: &WriteOptions,
+        key: UK,
+        delta: i64,
+    ) -> Result<i64> {
+        self.inner.fetch_add_with(
+            write_options,
+            || LSNKey::new(key, self.sequences.allocate().get()),
+            delta,
+        )
+    }
+
+    /// [DB::entry] of the newest version of `key`, like
+    /// [WriteCommittedDB::compare_and_swap_by_user_key].
+    pub fn entry_by_user_key<'a>(&'a self, write_options: &'a WriteOptions, key: UK) -> Entry<'a> {
+        self.inner.entry_with(write_options, move || {
+            LSNKey::new(key, self.sequences.allocate().get())
+        })
+    }
+
     pub fn snapshot(db: &Arc<Self>) -> SnapShot<UK, M, L> {
+        db.acquire_lsn();
         SnapShot {
             db: db.clone(),
-            lsn: db.next_lsn.fetch_add(1, Ordering::Release),
+            lsn: db.sequences.allocate().get(),
         }
     }
 
     pub fn start_transaction(db: &Arc<Self>, write_options: WriteOptions) -> WriteBatch<UK, M, L> {
+        db.acquire_lsn();
         WriteBatch {
             db: db.clone(),
             table: SrSwSkipMap::default(),
-            lsn: db.next_lsn.fetch_add(1, Ordering::Release),
+            lsn: db.sequences.allocate().get(),
             mem_usage: AtomicI64::default(),
             write_options,
         }
@@ -252,40 +551,116 @@ where
         batch: SrSwSkipMap<LSNKey<UK>, Value>,
         mem_usage: u64,
     ) -> Result<()> {
-        {
+        self.inner.stall_writes()?;
+        let bytes_written = batch
+            .iter()
+            .map(|(k, v)| k.internal_key().len() + v.len())
+            .sum();
+        // all the keys of a batch have the LSN of its transaction
+        let batch_lsn = batch.iter().next().map(|(key, _)| key.lsn());
+        if let Some(lsn) = batch_lsn {
+            let no_sync = WriteOptions { sync: false };
             let mut wal_guard = self.inner.wal.lock().unwrap();
+            if self.inner.wal_enabled() {
+                wal_guard.start_transaction(lsn)?;
+                for (key, value) in batch.iter() {
+                    let record = if value.is_empty() {
+                        WALRecord::Delete { key }
+                    } else {
+                        WALRecord::Put { key, value }
+                    };
+                    wal_guard.append_record(&no_sync, record)?;
+                }
+                wal_guard.end_transaction(write_options, lsn)?;
+            }
             for (key, value) in batch.iter() {
-                wal_guard.append(write_options, key, Some(value))?;
+                self.inner
+                    .publish(key, if value.is_empty() { None } else { Some(value) });
             }
+            // applied under the WAL lock, so that compare_and_swap sees all or none of it
+            self.inner.get_mut_mem_table().merge(batch, mem_usage);
+            self.inner.advance_sequence();
         }
+        self.inner.add_user_bytes_written(bytes_written);
 
-        let mem_table = self.inner.get_mut_mem_table();
-        mem_table.merge(batch, mem_usage);
-
-        self.may_freeze();
+        self.inner.may_freeze(&self.inner.get_mut_mem_table());
         Ok(())
     }
 
-    fn may_freeze(&self) {
-        let mem_table = self.inner.get_mut_mem_table();
-        if self.num_lsn_acquired.load(Ordering::Acquire) == 0
-            && self
-                .inner
-                .should_freeze(mem_table.approximate_memory_usage())
-        {
-            self.inner.freeze();
+    /// The oldest LSN whose write has not been flushed to sstables. The writes of
+    /// smaller LSNs are durable in sstables, so WAL is no longer needed to recover them.
+    pub fn min_unflushed_lsn(&self) -> LSN {
+        let wal_guard = self.inner.wal.lock().unwrap();
+        wal_guard
+            .min_lsn()
+            .unwrap_or_else(|| self.sequences.peek_next().get())
+    }
+
+    /// Every version of the user keys in [`key_start`, `key_end`] present in the
+    /// database, instead of only the newest visible ones, to inspect the version
+    /// chains. The versions of a key are ordered from the oldest to the newest:
+    /// the ones of the sstables, from the deepest level up, precede the ones of
+    /// the memory tables, which are in ascending order of LSNs.
+    ///
+    /// It is a diagnostic method reading every layer, not meant for the hot path.
+    pub fn range_get_versions(&self, key_start: &UK, key_end: &UK) -> Vec<(UK, Vec<KeyVersion>)> {
+        let (table_versions, mem_versions) = self.inner.versions_in_range(
+            &LSNKey::new(key_start.clone(), LSN::MIN),
+            &LSNKey::new(key_end.clone(), LSN::MAX),
+        );
+        let mut versions: BTreeMap<UK, Vec<KeyVersion>> = BTreeMap::new();
+        for (key, value) in table_versions {
+            versions
+                .entry(UK::from(key))
+                .or_default()
+                .push(KeyVersion::new(None, value));
+        }
+        for (key, value) in mem_versions {
+            let lsn = key.lsn();
+            versions
+                .entry(UK::from(key))
+                .or_default()
+                .push(KeyVersion::new(Some(lsn), value));
+        }
+        versions.into_iter().collect()
+    }
+
+    /// The end of the range query of the keys starting with `prefix`, which may not
+    /// start with `prefix` itself. `pending_max_key` is the max key of the writes not
+    /// committed yet. Return `None` if no key can start with `prefix`.
+    fn prefix_end(
+        &self,
+        prefix: &InternalKey,
+        pending_max_key: Option<&InternalKey>,
+    ) -> Option<InternalKey> {
+        if let Some(key_end) = prefix_successor(prefix) {
+            return Some(key_end);
         }
+        // all the keys not less than `prefix` start with it
+        self.inner
+            .max_key()
+            .max(pending_max_key.cloned())
+            .filter(|max_key| max_key >= prefix)
+    }
+
+    /// Count a new snapshot or transaction. It is counted under the WAL lock, so that
+    /// [NoTransactionDB::may_freeze] never freezes the memory table it reads.
+    fn acquire_lsn(&self) {
+        let _wal_guard = self.inner.wal.lock().unwrap();
+        self.inner.num_lsn_acquired.fetch_add(1, Ordering::Release);
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::collections::skip_list::skipmap::SrSwSkipMap;
     use crate::db::key_types::{I32UserKey, InternalKey, LSNKey, LSN};
     use crate::db::options::WriteOptions;
-    use crate::db::transaction::write_committed::WriteCommittedDB;
+    use crate::db::transaction::write_committed::{KeyVersion, WriteCommittedDB};
     use crate::db::DB;
-    use crate::memory::{MrSwSkipMapMemTable, MutexSkipMapMemTable};
+    use crate::memory::{MemTable, MrSwSkipMapMemTable, MutexSkipMapMemTable};
     use crate::wal::lsn_wal::LSNWriteAheadLog;
+    use crate::wal::{TransactionWAL, WAL};
     use std::sync::Arc;
 
     #[test]
@@ -330,6 +705,91 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_write_batch_iter() {
+        let temp_dir = tempfile::Builder::new().prefix("txn").tempdir().unwrap();
+        let db = Arc::new(
+            WriteCommittedDB::<
+                InternalKey,
+                MutexSkipMapMemTable<LSNKey<InternalKey>>,
+                LSNWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap(),
+        );
+        let mut txn = WriteCommittedDB::start_transaction(&db, WriteOptions { sync: false });
+        assert!(txn.is_empty());
+        for k in ["c", "a", "b"] {
+            txn.set(Vec::from(k), Vec::from(k.repeat(2))).unwrap();
+        }
+        txn.remove(Vec::from("d")).unwrap();
+        txn.set(Vec::from("a"), Vec::from("a")).unwrap();
+
+        assert_eq!(txn.len(), 4);
+        let writes: Vec<(&InternalKey, &Vec<u8>)> = txn.iter().collect();
+        assert_eq!(
+            writes,
+            vec![
+                (&Vec::from("a"), &Vec::from("a")),
+                (&Vec::from("b"), &Vec::from("bb")),
+                (&Vec::from("c"), &Vec::from("cc")),
+                (&Vec::from("d"), &vec![]),
+            ]
+        );
+
+        // iteration does not commit the writes
+        let key = LSNKey::new(Vec::from("b"), LSN::MAX);
+        assert!(db.get(&key).unwrap().is_none());
+        drop(txn);
+        assert_eq!(db.get(&key).unwrap(), Some(Vec::from("bb")));
+    }
+
+    #[test]
+    fn test_range_get_tombstone() {
+        let temp_dir = tempfile::Builder::new().prefix("txn").tempdir().unwrap();
+        let db = Arc::new(
+            WriteCommittedDB::<
+                InternalKey,
+                MutexSkipMapMemTable<LSNKey<InternalKey>>,
+                LSNWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap(),
+        );
+        let keys = |kvs: SrSwSkipMap<InternalKey, Vec<u8>>| -> Vec<InternalKey> {
+            kvs.iter().map(|(k, _v)| k.clone()).collect()
+        };
+
+        {
+            let mut txn = WriteCommittedDB::start_transaction(&db, WriteOptions { sync: false });
+            for k in ["a", "b", "c", "d"] {
+                txn.set(Vec::from(k), Vec::from(k)).unwrap();
+            }
+        }
+        let before_remove = WriteCommittedDB::snapshot(&db);
+        {
+            let mut txn = WriteCommittedDB::start_transaction(&db, WriteOptions { sync: false });
+            txn.remove(Vec::from("b")).unwrap();
+        }
+        let after_remove = WriteCommittedDB::snapshot(&db);
+
+        assert_eq!(
+            keys(before_remove.range_get(Vec::from("a"), Vec::from("c"))),
+            vec![Vec::from("a"), Vec::from("b"), Vec::from("c")]
+        );
+        assert_eq!(
+            keys(after_remove.range_get(Vec::from("a"), Vec::from("c"))),
+            vec![Vec::from("a"), Vec::from("c")]
+        );
+
+        // pending removes of a transaction are applied to its own reads
+        let mut txn = WriteCommittedDB::start_transaction(&db, WriteOptions { sync: false });
+        txn.remove(Vec::from("c")).unwrap();
+        assert_eq!(
+            keys(txn.range_get(Vec::from("a"), Vec::from("d"))),
+            vec![Vec::from("a"), Vec::from("d")]
+        );
+        txn.rollback().unwrap();
+    }
+
     #[test]
     fn test_i32key() {
         let temp_dir = tempfile::Builder::new().prefix("txn").tempdir().unwrap();
@@ -355,4 +815,326 @@ mod tests {
             assert!(db.get_by_user_key(I32UserKey::new(4)).unwrap().is_none());
         }
     }
+
+    #[test]
+    fn test_min_unflushed_lsn() {
+        let temp_dir = tempfile::Builder::new().prefix("txn").tempdir().unwrap();
+        let db = Arc::new(
+            WriteCommittedDB::<
+                InternalKey,
+                MutexSkipMapMemTable<LSNKey<InternalKey>>,
+                LSNWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap(),
+        );
+        let wo = WriteOptions { sync: false };
+        let log_len = |name: &str| {
+            std::fs::metadata(temp_dir.path().join("log").join(name))
+                .unwrap()
+                .len()
+        };
+
+        for i in 0..10 {
+            db.set_by_user_key(&wo, format!("key{}", i).into_bytes(), Vec::from("v"))
+                .unwrap();
+        }
+        assert_eq!(db.min_unflushed_lsn(), 1);
+        db.flush().unwrap();
+        // the log of the flushed writes is deleted
+        assert_eq!(db.min_unflushed_lsn(), 11);
+        assert_eq!(log_len("0.log") + log_len("1.log"), 0);
+
+        for i in 10..15 {
+            db.set_by_user_key(&wo, format!("key{}", i).into_bytes(), Vec::from("v"))
+                .unwrap();
+        }
+        // the freeze swapped the logs, so 0.log is the mutable one now
+        assert_eq!(db.min_unflushed_lsn(), 11);
+        assert_eq!(log_len("1.log"), 0);
+        assert!(log_len("0.log") > 0);
+
+        // trim the immutable log directly
+        let wal_dir = tempfile::Builder::new().prefix("wal").tempdir().unwrap();
+        let mut mem_table = MutexSkipMapMemTable::<LSNKey<InternalKey>>::default();
+        let mut wal =
+            LSNWriteAheadLog::open_and_load_logs(wal_dir.path().to_str().unwrap(), &mut mem_table)
+                .unwrap();
+        let min_lsn = |wal: &LSNWriteAheadLog| {
+            TransactionWAL::<LSNKey<InternalKey>, InternalKey>::min_lsn(wal)
+        };
+        for lsn in 1..=10 {
+            wal.append(
+                &wo,
+                &LSNKey::new(Vec::from("k"), lsn),
+                Some(&Vec::from("v")),
+            )
+            .unwrap();
+        }
+        WAL::<LSNKey<InternalKey>, InternalKey>::freeze_mut_log(&mut wal).unwrap();
+        for lsn in 11..=15 {
+            wal.append(
+                &wo,
+                &LSNKey::new(Vec::from("k"), lsn),
+                Some(&Vec::from("v")),
+            )
+            .unwrap();
+        }
+        let trim_before = |wal: &mut LSNWriteAheadLog, lsn: LSN| {
+            TransactionWAL::<LSNKey<InternalKey>, InternalKey>::trim_before(wal, lsn).unwrap()
+        };
+        assert_eq!(min_lsn(&wal), Some(1));
+        assert!(!trim_before(&mut wal, 10));
+        assert_eq!(min_lsn(&wal), Some(1));
+        assert!(trim_before(&mut wal, 11));
+        assert_eq!(min_lsn(&wal), Some(11));
+        // the mutable log is never trimmed
+        assert!(!trim_before(&mut wal, 100));
+        assert_eq!(min_lsn(&wal), Some(11));
+
+        // neither are the recovered logs
+        drop(wal);
+        let mut mem_table = MutexSkipMapMemTable::<LSNKey<InternalKey>>::default();
+        let mut wal =
+            LSNWriteAheadLog::open_and_load_logs(wal_dir.path().to_str().unwrap(), &mut mem_table)
+                .unwrap();
+        assert!(!trim_before(&mut wal, 100));
+        assert_eq!(min_lsn(&wal), Some(LSN::MIN));
+    }
+
+    #[test]
+    fn test_snapshot_iter() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("snapshot_iter")
+            .tempdir()
+            .unwrap();
+        let db = Arc::new(
+            WriteCommittedDB::<
+                InternalKey,
+                MutexSkipMapMemTable<LSNKey<InternalKey>>,
+                LSNWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap(),
+        );
+        let wo = WriteOptions { sync: false };
+        // half of the keys are in an sstable and the others in the memory table
+        for i in 0..50 {
+            db.set_by_user_key(&wo, format!("key{:02}", i).into_bytes(), b"old".to_vec())
+                .unwrap();
+        }
+        db.flush().unwrap();
+        for i in 50..100 {
+            db.set_by_user_key(&wo, format!("key{:02}", i).into_bytes(), b"old".to_vec())
+                .unwrap();
+        }
+        db.remove_by_user_key(&wo, Vec::from("key99")).unwrap();
+
+        let snapshot = WriteCommittedDB::snapshot(&db);
+        for i in (0..100).step_by(2) {
+            db.set_by_user_key(&wo, format!("key{:02}", i).into_bytes(), b"new".to_vec())
+                .unwrap();
+        }
+        db.remove_by_user_key(&wo, Vec::from("key01")).unwrap();
+        db.set_by_user_key(&wo, Vec::from("key100"), b"new".to_vec())
+            .unwrap();
+
+        let kvs: Vec<(InternalKey, Vec<u8>)> = snapshot.iter().collect();
+        let expected: Vec<(InternalKey, Vec<u8>)> = (0..99)
+            .map(|i| (format!("key{:02}", i).into_bytes(), b"old".to_vec()))
+            .collect();
+        assert_eq!(kvs, expected);
+
+        drop(snapshot);
+        let snapshot = WriteCommittedDB::snapshot(&db);
+        let kvs: Vec<(InternalKey, Vec<u8>)> = snapshot.iter().collect();
+        assert_eq!(kvs.len(), 99);
+        assert!(kvs.iter().all(|(k, _)| k != b"key01" && k != b"key99"));
+        assert!(kvs.contains(&(Vec::from("key100"), b"new".to_vec())));
+        assert_eq!(kvs[0], (Vec::from("key00"), b"new".to_vec()));
+        assert_eq!(kvs.last().unwrap(), &(Vec::from("key98"), b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_stall_with_snapshot() {
+        use crate::db::options::Options;
+        use crate::db::WRITE_BUFFER_SIZE;
+        use crate::memory::InternalKeyValueIterator;
+        use std::time::Duration;
+
+        let temp_dir = tempfile::Builder::new()
+            .prefix("freeze_with_snapshot")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            snapshot_freeze_multiple: 1,
+            ..Options::default()
+        };
+        let db = Arc::new(
+            WriteCommittedDB::<
+                InternalKey,
+                MutexSkipMapMemTable<LSNKey<InternalKey>>,
+                LSNWriteAheadLog,
+            >::open_with_options(temp_dir.path(), options)
+            .unwrap(),
+        );
+        let wo = WriteOptions { sync: false };
+        db.set_by_user_key(&wo, Vec::from("stable"), Vec::from("v1"))
+            .unwrap();
+
+        let snapshot = WriteCommittedDB::snapshot(&db);
+        let value = vec![b'x'; 4096];
+        // twice as much as the memory table may grow to while the snapshot is active
+        let rounds = 2 * WRITE_BUFFER_SIZE as usize / (10 * value.len());
+        let writer = {
+            let db = db.clone();
+            let value = value.clone();
+            std::thread::spawn(move || {
+                for round in 0..rounds {
+                    let mut txn =
+                        WriteCommittedDB::start_transaction(&db, WriteOptions { sync: false });
+                    txn.set(Vec::from("stable"), format!("v{}", round + 2).into_bytes())
+                        .unwrap();
+                    for i in 0..10 {
+                        txn.set(format!("key{:04}_{}", round, i).into_bytes(), value.clone())
+                            .unwrap();
+                    }
+                }
+            })
+        };
+
+        // the writer is stalled instead of freezing the memory table the snapshot reads
+        let mem_usage = || db.inner.get_mut_mem_table().approximate_memory_usage();
+        while mem_usage() < WRITE_BUFFER_SIZE {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let stalled_usage = mem_usage();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(mem_usage(), stalled_usage);
+        assert!(db.inner.get_imm_mem_table().is_empty());
+        assert_eq!(
+            snapshot.get(Vec::from("stable")).unwrap(),
+            Some(Vec::from("v1"))
+        );
+        assert_eq!(snapshot.get(Vec::from("key0000_0")).unwrap(), None);
+
+        // frozen once the snapshot ends
+        drop(snapshot);
+        writer.join().unwrap();
+        assert!(!db.inner.get_imm_mem_table().is_empty());
+        assert_eq!(
+            db.get_by_user_key(Vec::from("stable")).unwrap(),
+            Some(format!("v{}", rounds + 1).into_bytes())
+        );
+        assert_eq!(
+            db.get_by_user_key(format!("key{:04}_9", rounds - 1).into_bytes())
+                .unwrap(),
+            Some(value)
+        );
+    }
+
+    #[test]
+    fn test_prefix_get() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("prefix_get")
+            .tempdir()
+            .unwrap();
+        let db = Arc::new(
+            WriteCommittedDB::<
+                InternalKey,
+                MutexSkipMapMemTable<LSNKey<InternalKey>>,
+                LSNWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap(),
+        );
+        let wo = WriteOptions { sync: false };
+        for key in ["apple", "user:1", "user:2", "user:3", "userx", "zoo"] {
+            db.set_by_user_key(&wo, Vec::from(key), b"old".to_vec())
+                .unwrap();
+        }
+        db.flush().unwrap();
+
+        let snapshot = WriteCommittedDB::snapshot(&db);
+        let mut txn = WriteCommittedDB::start_transaction(&db, WriteOptions { sync: false });
+        txn.set(Vec::from("user:0"), b"new".to_vec()).unwrap();
+        txn.set(Vec::from("user:3"), b"new".to_vec()).unwrap();
+        txn.set(Vec::from("user;"), b"new".to_vec()).unwrap();
+        txn.remove(Vec::from("user:2")).unwrap();
+
+        // the pending writes are visible to the transaction
+        let kvs: Vec<(InternalKey, Vec<u8>)> = txn.prefix_iter(Vec::from("user:")).collect();
+        assert_eq!(
+            kvs,
+            vec![
+                (Vec::from("user:0"), b"new".to_vec()),
+                (Vec::from("user:1"), b"old".to_vec()),
+                (Vec::from("user:3"), b"new".to_vec()),
+            ]
+        );
+        assert_eq!(txn.prefix_get(Vec::from("none")).len(), 0);
+
+        // but not to the snapshot
+        let kvs: Vec<(InternalKey, Vec<u8>)> = snapshot.prefix_iter(Vec::from("user:")).collect();
+        assert_eq!(
+            kvs,
+            vec![
+                (Vec::from("user:1"), b"old".to_vec()),
+                (Vec::from("user:2"), b"old".to_vec()),
+                (Vec::from("user:3"), b"old".to_vec()),
+            ]
+        );
+        assert_eq!(snapshot.prefix_get(Vec::from("user")).len(), 4);
+        assert_eq!(snapshot.prefix_get(Vec::from("")).len(), 6);
+    }
+
+    #[test]
+    fn test_range_get_versions() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("range_get_versions")
+            .tempdir()
+            .unwrap();
+        let db = WriteCommittedDB::<
+            InternalKey,
+            MutexSkipMapMemTable<LSNKey<InternalKey>>,
+            LSNWriteAheadLog,
+        >::open(temp_dir.path())
+        .unwrap();
+        let wo = WriteOptions { sync: false };
+        db.set_by_user_key(&wo, Vec::from("b"), b"b1".to_vec())
+            .unwrap();
+        db.flush().unwrap();
+        db.set_by_user_key(&wo, Vec::from("a"), b"v1".to_vec())
+            .unwrap();
+        db.set_by_user_key(&wo, Vec::from("a"), b"v2".to_vec())
+            .unwrap();
+        db.remove_by_user_key(&wo, Vec::from("a")).unwrap();
+        db.set_by_user_key(&wo, Vec::from("b"), b"b2".to_vec())
+            .unwrap();
+        db.set_by_user_key(&wo, Vec::from("c"), b"c1".to_vec())
+            .unwrap();
+
+        let versions = db.range_get_versions(&Vec::from("a"), &Vec::from("b"));
+        assert_eq!(versions.len(), 2);
+        let (key, a_versions) = &versions[0];
+        assert_eq!(key, &Vec::from("a"));
+        let values: Vec<Option<Vec<u8>>> = a_versions.iter().map(|v| v.value.clone()).collect();
+        assert_eq!(
+            values,
+            vec![Some(b"v1".to_vec()), Some(b"v2".to_vec()), None]
+        );
+        let lsns: Vec<LSN> = a_versions.iter().map(|v| v.lsn.unwrap()).collect();
+        assert!(lsns.windows(2).all(|w| w[0] < w[1]), "{:?}", lsns);
+
+        // the version in sstables has no LSN and precedes the one in memory
+        let (key, b_versions) = &versions[1];
+        assert_eq!(key, &Vec::from("b"));
+        assert_eq!(b_versions.len(), 2);
+        assert_eq!(
+            b_versions[0],
+            KeyVersion {
+                lsn: None,
+                value: Some(b"b1".to_vec())
+            }
+        );
+        assert_eq!(b_versions[1].value, Some(b"b2".to_vec()));
+        assert!(b_versions[1].lsn.is_some());
+    }
 }
@@ -0,0 +1,471 @@
+use crate::collections::skip_list::skipmap::SrSwSkipMap;
+use crate::db::key_types::{LSNKey, MemKey, LSN};
+use crate::db::no_transaction_db::NoTransactionDB;
+use crate::db::options::{Options, WriteOptions};
+use crate::db::sequence::SequenceAllocator;
+use crate::db::stats::Stats;
+use crate::db::{Value, DB};
+use crate::memory::MemTable;
+use crate::wal::{TransactionWAL, WALRecord};
+use crate::Result;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+/// A transaction of [WritePreparedDB]. Its writes are applied to the memory table as
+/// soon as they are made, and become visible to the other readers when it commits.
+/// It is rolled back when dropped, unless it is committed or rolled back before.
+pub struct PreparedTransaction<UK, M, L>
+where
+    UK: MemKey + From<LSNKey<UK>> + 'static,
+    M: MemTable<LSNKey<UK>, UK> + 'static,
+    L: TransactionWAL<LSNKey<UK>, UK> + 'static,
+{
+    db: Arc<WritePreparedDB<UK, M, L>>,
+    lsn: LSN,
+    /// Keys written by the transaction, to publish or roll back its writes. The values
+    /// are only in the memory table.
+    keys: BTreeSet<UK>,
+    write_options: WriteOptions,
+    finished: bool,
+}
+
+impl<UK, M, L> PreparedTransaction<UK, M, L>
+where
+    UK: MemKey + From<LSNKey<UK>> + 'static,
+    M: MemTable<LSNKey<UK>, UK> + 'static,
+    L: TransactionWAL<LSNKey<UK>, UK> + 'static,
+{
+    /// Get the value of `key` written by the transaction, or else the committed one.
+    pub fn get(&self, key: UK) -> Result<Option<Value>> {
+        let lsn = if self.keys.contains(&key) {
+            self.lsn
+        } else {
+            self.db.committed_watermark()
+        };
+        self.db.inner.get(&LSNKey::new(key, lsn))
+    }
+
+    pub fn set(&mut self, key: UK, value: Value) -> Result<()> {
+        self.db.inner.check_value_size(&value)?;
+        self.db.prepare_write(self.lsn, key.clone(), value)?;
+        self.keys.insert(key);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: UK) -> Result<()> {
+        self.db
+            .prepare_write(self.lsn, key.clone(), Value::default())?;
+        self.keys.insert(key);
+        Ok(())
+    }
+
+    /// Make the writes of the transaction visible.
+    pub fn commit(mut self) -> Result<()> {
+        self.finished = true;
+        self.db
+            .commit_prepared(&self.write_options, self.lsn, &self.keys)
+    }
+
+    /// Undo the writes of the transaction by writing back the values before them. They
+    /// are never visible to the other readers.
+    pub fn rollback(mut self) -> Result<()> {
+        self.finished = true;
+        self.rollback_writes()
+    }
+
+    fn rollback_writes(&mut self) -> Result<()> {
+        for key in std::mem::take(&mut self.keys) {
+            let before = self.db.inner.get(&LSNKey::new(key.clone(), self.lsn - 1))?;
+            self.db
+                .prepare_write(self.lsn, key, before.unwrap_or_default())?;
+        }
+        self.db
+            .commit_prepared(&self.write_options, self.lsn, &self.keys)
+    }
+
+    /// Number of the keys written by the transaction.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl<UK, M, L> Drop for PreparedTransaction<UK, M, L>
+where
+    UK: MemKey + From<LSNKey<UK>> + 'static,
+    M: MemTable<LSNKey<UK>, UK> + 'static,
+    L: TransactionWAL<LSNKey<UK>, UK> + 'static,
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.rollback_writes() {
+                warn!("failed to roll back transaction {}: {}", self.lsn, e);
+            }
+        }
+    }
+}
+
+/// Isolation level: Read committed
+///
+/// [See `https://github.com/facebook/rocksdb/wiki/WritePrepared-Transactions`]
+/// With WritePrepared write policy, the writes of a transaction are applied to the
+/// memtable when they are made, tagged with the LSN of the transaction, instead of being
+/// buffered until it commits like in [crate::db::transaction::write_committed::WriteCommittedDB].
+/// The commit only appends a commit marker to the log.
+///
+/// The writes of a transaction are hidden from the readers by the committed watermark,
+/// the LSN below which all the transactions have committed. Reads are made at the
+/// watermark, so the oldest prepared transaction also hides the writes committed after
+/// it began until it commits.
+///
+/// The sstables keep only the newest version of a key without its LSN, so the memory
+/// tables are not frozen while a transaction is prepared. Like the snapshots of
+/// [crate::db::transaction::write_committed::WriteCommittedDB], a prepared transaction
+/// stalls the writes made outside of transactions once the memory table grows past
+/// [Options::snapshot_freeze_multiple], and the writes of the transactions themselves
+/// keep growing it.
+///
+/// Concurrent transactions are not isolated from each other: two of them should not
+/// write the same keys. The log recovers a transaction only if it committed.
+pub struct WritePreparedDB<UK, M, L>
+where
+    UK: MemKey + From<LSNKey<UK>> + 'static,
+    M: MemTable<LSNKey<UK>, UK> + 'static,
+    L: TransactionWAL<LSNKey<UK>, UK> + 'static,
+{
+    inner: NoTransactionDB<LSNKey<UK>, UK, M, L>,
+    sequences: SequenceAllocator,
+    /// LSNs of the transactions which are prepared but not committed yet.
+    prepared: Mutex<BTreeSet<LSN>>,
+}
+
+impl<UK, M, L> std::fmt::Debug for WritePreparedDB<UK, M, L>
+where
+    UK: MemKey + From<LSNKey<UK>> + 'static,
+    M: MemTable<LSNKey<UK>, UK> + 'static,
+    L: TransactionWAL<LSNKey<UK>, UK> + 'static,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WritePreparedDB")
+            .field("inner", &self.inner)
+            .field("prepared", &*self.prepared.lock().unwrap())
+            .finish()
+    }
+}
+
+impl<UK, M, L> WritePreparedDB<UK, M, L>
+where
+    UK: MemKey + From<LSNKey<UK>> + 'static,
+    M: MemTable<LSNKey<UK>, UK> + 'static,
+    L: TransactionWAL<LSNKey<UK>, UK> + 'static,
+{
+    pub fn open(db_path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_options(db_path, Options::default())
+    }
+
+    pub fn open_with_options(db_path: impl AsRef<Path>, options: Options) -> Result<Self> {
+        let in_memory = options.in_memory;
+        let inner = NoTransactionDB::<LSNKey<UK>, UK, M, L>::open_with_options(db_path, options)?;
+        let sequences = if in_memory {
+            SequenceAllocator::in_memory()
+        } else {
+            SequenceAllocator::open(Path::new(inner.db_path()))?
+        };
+        Ok(WritePreparedDB {
+            inner,
+            sequences,
+            prepared: Mutex::default(),
+        })
+    }
+
+    /// Get the committed value of `key`.
+    pub fn get(&self, key: UK) -> Result<Option<Value>> {
+        self.inner
+            .get(&LSNKey::new(key, self.committed_watermark()))
+    }
+
+    /// Get the committed key-values in [`key_start`, `key_end`].
+    pub fn range_get(&self, key_start: UK, key_end: UK) -> Result<SrSwSkipMap<UK, Value>> {
+        let lsn = self.committed_watermark();
+        self.inner
+            .range_get(&LSNKey::new(key_start, lsn), &LSNKey::new(key_end, lsn))
+    }
+
+    /// Write `key` outside of any transaction. It is committed at once, but hidden like
+    /// the writes committed after the oldest prepared transaction began.
+    pub fn set(&self, write_options: &WriteOptions, key: UK, value: Value) -> Result<()> {
+        let lsn_key = LSNKey::new(key, self.sequences.allocate().get());
+        self.inner.set(write_options, lsn_key, value)
+    }
+
+    /// Remove `key` outside of any transaction, like [WritePreparedDB::set].
+    pub fn remove(&self, write_options: &WriteOptions, key: UK) -> Result<()> {
+        let lsn_key = LSNKey::new(key, self.sequences.allocate().get());
+        self.inner.remove(write_options, lsn_key)
+    }
+
+    /// Flush the memory tables to sstables, unless a transaction is prepared, in which
+    /// case the mutable memory table is kept.
+    pub fn flush(&self) -> Result<()> {
+        loop {
+            self.inner.wait_for_level0_writer()?;
+            let mut prepared = false;
+            let frozen = self.inner.freeze_unless(|| {
+                prepared = self.has_prepared();
+                prepared
+            });
+            if frozen || prepared {
+                break;
+            }
+        }
+        self.inner.wait_for_level0_writer()
+    }
+
+    /// Whether a transaction is prepared, which keeps the memory tables from being
+    /// frozen. Called under the WAL lock.
+    fn has_prepared(&self) -> bool {
+        self.inner.num_lsn_acquired.load(Ordering::Acquire) != 0
+    }
+
+    #[inline]
+    pub fn stats(&self) -> Stats {
+        self.inner.stats()
+    }
+
+    /// Begin a transaction, committed with `write_options`.
+    pub fn start_transaction(
+        db: &Arc<Self>,
+        write_options: WriteOptions,
+    ) -> Result<PreparedTransaction<UK, M, L>> {
+        let lsn = {
+            // allocated under the lock, so that no reader takes a watermark above it
+            let mut prepared = db.prepared.lock().unwrap();
+            let lsn = db.sequences.allocate().get();
+            prepared.insert(lsn);
+            lsn
+        };
+        {
+            let mut wal_guard = db.inner.wal.lock().unwrap();
+            // the markers are logged even if the WAL is disabled, so that the records
+            // logged after it is enabled are never recovered without the commit
+            if let Err(e) = wal_guard.start_transaction(lsn) {
+                db.prepared.lock().unwrap().remove(&lsn);
+                return Err(e);
+            }
+            db.inner.num_lsn_acquired.fetch_add(1, Ordering::Release);
+        }
+        Ok(PreparedTransaction {
+            db: db.clone(),
+            lsn,
+            keys: BTreeSet::new(),
+            write_options,
+            finished: false,
+        })
+    }
+
+    /// The LSN below which all the transactions have committed. The readers read at it,
+    /// so that no write of a prepared transaction is visible.
+    pub fn committed_watermark(&self) -> LSN {
+        let prepared = self.prepared.lock().unwrap();
+        match prepared.iter().next() {
+            Some(oldest) => *oldest - 1,
+            // the transactions allocate under the lock, so none is above it
+            None => self.sequences.peek_next().get() - 1,
+        }
+    }
+
+    /// Log and apply the write of `key` by the prepared transaction `lsn`. An empty
+    /// `value` is a tombstone.
+    fn prepare_write(&self, lsn: LSN, key: UK, value: Value) -> Result<()> {
+        let key = LSNKey::new(key, lsn);
+        self.inner.check_key_size(key.internal_key())?;
+        self.inner.stall_writes()?;
+        let bytes_written = key.internal_key().len() + value.len();
+        let mut_mem_table = {
+            let mut wal_guard = self.inner.wal.lock().unwrap();
+            if self.inner.wal_enabled() {
+                let no_sync = WriteOptions { sync: false };
+                let record = if value.is_empty() {
+                    WALRecord::Delete { key: &key }
+                } else {
+                    WALRecord::Put {
+                        key: &key,
+                        value: &value,
+                    }
+                };
+                wal_guard.append_record(&no_sync, record)?;
+            }
+            let mut_mem_table = self.inner.get_mut_mem_table();
+            if value.is_empty() {
+                mut_mem_table.remove(key)?;
+            } else {
+                mut_mem_table.set(key, value)?;
+            }
+            mut_mem_table
+        };
+        self.inner.add_user_bytes_written(bytes_written);
+        // frozen only once no transaction is prepared, which never stalls this one
+        if self.inner.should_freeze(&mut_mem_table) {
+            self.inner.freeze_unless(|| self.has_prepared());
+        }
+        Ok(())
+    }
+
+    /// Log the commit of the transaction `lsn` and advance the watermark past it, then
+    /// publish the values of `keys` written by it.
+    fn commit_prepared(
+        &self,
+        write_options: &WriteOptions,
+        lsn: LSN,
+        keys: &BTreeSet<UK>,
+    ) -> Result<()> {
+        {
+            let mut wal_guard = self.inner.wal.lock().unwrap();
+            wal_guard.end_transaction(write_options, lsn)?;
+            self.prepared.lock().unwrap().remove(&lsn);
+            self.inner.num_lsn_acquired.fetch_sub(1, Ordering::Release);
+            if !keys.is_empty() {
+                self.inner.advance_sequence();
+            }
+        }
+        for key in keys {
+            let key = LSNKey::new(key.clone(), lsn);
+            let value = self.inner.get(&key)?;
+            self.inner.publish(&key, value.as_ref());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::key_types::{InternalKey, LSNKey};
+    use crate::db::options::WriteOptions;
+    use crate::db::transaction::write_prepared::WritePreparedDB;
+    use crate::memory::MutexSkipMapMemTable;
+    use crate::wal::lsn_wal::LSNWriteAheadLog;
+    use std::sync::Arc;
+
+    type TestDB =
+        WritePreparedDB<InternalKey, MutexSkipMapMemTable<LSNKey<InternalKey>>, LSNWriteAheadLog>;
+
+    #[test]
+    fn test_prepare_and_commit() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("write_prepared")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+        let value = vec![b'v'; 1024];
+        let count = 2000;
+
+        {
+            let db = Arc::new(TestDB::open(temp_dir.path()).unwrap());
+            db.set(&wo, key(0), Vec::from("old")).unwrap();
+
+            // the memory table is kept until the commit
+            let mut txn = TestDB::start_transaction(&db, WriteOptions { sync: true }).unwrap();
+            for i in 0..count {
+                txn.set(key(i), value.clone()).unwrap();
+                if i == count / 2 {
+                    db.flush().unwrap();
+                }
+            }
+            assert_eq!(db.stats().sstable_bytes_written, 0);
+            assert_eq!(txn.len(), count);
+            assert_eq!(txn.get(key(1)).unwrap(), Some(value.clone()));
+
+            // invisible until the commit
+            assert_eq!(db.get(key(0)).unwrap(), Some(Vec::from("old")));
+            assert_eq!(db.get(key(1)).unwrap(), None);
+            assert_eq!(db.range_get(key(0), key(count)).unwrap().len(), 1);
+
+            txn.commit().unwrap();
+            assert_eq!(db.get(key(0)).unwrap(), Some(value.clone()));
+            assert_eq!(db.get(key(count - 1)).unwrap(), Some(value.clone()));
+            assert_eq!(db.range_get(key(0), key(count)).unwrap().len(), count);
+
+            // a rolled back transaction is never visible
+            let mut txn = TestDB::start_transaction(&db, WriteOptions { sync: true }).unwrap();
+            txn.set(key(0), Vec::from("rolled back")).unwrap();
+            txn.remove(key(1)).unwrap();
+            txn.set(key(count), Vec::from("rolled back")).unwrap();
+            txn.rollback().unwrap();
+            assert_eq!(db.get(key(0)).unwrap(), Some(value.clone()));
+            assert_eq!(db.get(key(1)).unwrap(), Some(value.clone()));
+            assert_eq!(db.get(key(count)).unwrap(), None);
+
+            // the database crashes before the commit
+            let mut txn = TestDB::start_transaction(&db, WriteOptions { sync: true }).unwrap();
+            txn.set(key(count + 1), Vec::from("prepared")).unwrap();
+            txn.finished = true;
+        }
+
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get(key(0)).unwrap(), Some(value.clone()));
+        assert_eq!(db.get(key(count - 1)).unwrap(), Some(value));
+        assert_eq!(db.get(key(count)).unwrap(), None);
+        assert_eq!(db.get(key(count + 1)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_flush_while_prepared() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("write_prepared_flush")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+
+        {
+            let db = Arc::new(TestDB::open(temp_dir.path()).unwrap());
+            db.set(&wo, key(0), Vec::from("old")).unwrap();
+            db.flush().unwrap();
+            let flushed = db.stats().sstable_bytes_written;
+            assert!(flushed > 0);
+
+            let mut txn = TestDB::start_transaction(&db, WriteOptions { sync: false }).unwrap();
+            txn.set(key(0), Vec::from("new")).unwrap();
+            db.flush().unwrap();
+            txn.set(key(1), Vec::from("new")).unwrap();
+            db.set(&wo, key(2), Vec::from("outside")).unwrap();
+            db.flush().unwrap();
+
+            // nothing is flushed while the transaction is prepared
+            assert_eq!(db.stats().sstable_bytes_written, flushed);
+            assert_eq!(db.get(key(0)).unwrap(), Some(Vec::from("old")));
+            assert_eq!(db.get(key(1)).unwrap(), None);
+            assert_eq!(db.get(key(2)).unwrap(), None);
+
+            txn.commit().unwrap();
+            db.flush().unwrap();
+            assert!(db.stats().sstable_bytes_written > flushed);
+            assert_eq!(db.get(key(0)).unwrap(), Some(Vec::from("new")));
+            assert_eq!(db.get(key(1)).unwrap(), Some(Vec::from("new")));
+            assert_eq!(db.get(key(2)).unwrap(), Some(Vec::from("outside")));
+
+            // a dropped transaction is rolled back
+            let mut txn = TestDB::start_transaction(&db, WriteOptions { sync: false }).unwrap();
+            txn.set(key(0), Vec::from("dropped")).unwrap();
+            txn.set(key(3), Vec::from("dropped")).unwrap();
+            drop(txn);
+            assert_eq!(db.get(key(0)).unwrap(), Some(Vec::from("new")));
+            assert_eq!(db.get(key(3)).unwrap(), None);
+            db.flush().unwrap();
+        }
+
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        assert_eq!(db.get(key(0)).unwrap(), Some(Vec::from("new")));
+        assert_eq!(db.get(key(1)).unwrap(), Some(Vec::from("new")));
+        assert_eq!(db.get(key(2)).unwrap(), Some(Vec::from("outside")));
+        assert_eq!(db.get(key(3)).unwrap(), None);
+    }
+}
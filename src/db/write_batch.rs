@@ -0,0 +1,50 @@
+use crate::collections::skip_list::skipmap::SrSwSkipMap;
+use crate::db::key_types::MemKey;
+use crate::db::Value;
+
+/// Writes collected by [crate::db::no_transaction_db::NoTransactionDB::batch] and applied
+/// atomically by [crate::db::no_transaction_db::NoTransactionDB::write].
+///
+/// Unlike the write batch of transactions, it has no LSN and no isolation. A later write
+/// of the same key in the batch replaces the earlier one.
+#[derive(Default)]
+pub struct WriteBatch<SK: MemKey> {
+    table: SrSwSkipMap<SK, Value>,
+    mem_usage: u64,
+}
+
+impl<SK: MemKey> WriteBatch<SK> {
+    pub fn put(&mut self, key: SK, value: Value) {
+        let key_mem_size = key.mem_size() as u64;
+        let value_len = value.len() as u64;
+        match self.table.insert(key, value) {
+            Some(v) => self.mem_usage = self.mem_usage + value_len - v.len() as u64,
+            None => self.mem_usage += key_mem_size + value_len,
+        }
+    }
+
+    /// Remove `key`. Like [crate::db::DB::remove], a tombstone is written whether `key`
+    /// exists or not.
+    pub fn delete(&mut self, key: SK) {
+        self.put(key, Value::default());
+    }
+
+    /// Number of the keys in the batch.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&SK, &Value)> {
+        self.table.iter()
+    }
+
+    pub(crate) fn into_inner(self) -> (SrSwSkipMap<SK, Value>, u64) {
+        (self.table, self.mem_usage)
+    }
+}
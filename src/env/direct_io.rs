@@ -0,0 +1,174 @@
+//! Writing files with `O_DIRECT`, which bypasses the page cache.
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Alignment of the buffer, the length and the file offset of each direct write.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Number of aligned blocks buffered before they are written.
+const BUFFER_BLOCKS: usize = 64;
+
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct AlignedBlock([u8; DIRECT_IO_ALIGNMENT]);
+
+/// A new file written with `O_DIRECT`. Bytes are buffered until whole aligned blocks
+/// can be written, and the unaligned tail is written by [DirectFile::finish].
+///
+/// Only sequential writes are supported. Seeking anywhere other than the current
+/// position fails.
+pub struct DirectFile {
+    file: File,
+    buffer: Vec<AlignedBlock>,
+    /// Count of the bytes in `buffer`.
+    len: usize,
+    /// Count of the bytes written to `file`.
+    written: u64,
+}
+
+impl DirectFile {
+    /// Create a new file at `path` for direct writes. Fail if the platform or the
+    /// filesystem does not support direct I/O.
+    pub fn create_new(path: &Path) -> std::io::Result<DirectFile> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::fs::OpenOptions;
+            use std::os::unix::fs::OpenOptionsExt;
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(path)?;
+            Ok(DirectFile {
+                file,
+                buffer: vec![AlignedBlock([0; DIRECT_IO_ALIGNMENT]); BUFFER_BLOCKS],
+                len: 0,
+                written: 0,
+            })
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = path;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "direct I/O is only supported on Linux",
+            ))
+        }
+    }
+
+    /// Write the remaining bytes padded to [DIRECT_IO_ALIGNMENT], cut the padding off,
+    /// and sync the file.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.write_aligned()?;
+        if self.len > 0 {
+            let padded_len =
+                (self.len + DIRECT_IO_ALIGNMENT - 1) / DIRECT_IO_ALIGNMENT * DIRECT_IO_ALIGNMENT;
+            let bytes = as_bytes_mut(&mut self.buffer);
+            bytes[self.len..padded_len].fill(0);
+            self.file.write_all(&bytes[..padded_len])?;
+            self.written += self.len as u64;
+            self.len = 0;
+            self.file.set_len(self.written)?;
+        }
+        self.file.sync_data()
+    }
+
+    #[inline]
+    fn position(&self) -> u64 {
+        self.written + self.len as u64
+    }
+
+    /// Write the whole aligned blocks in the buffer, and move the rest to its front.
+    fn write_aligned(&mut self) -> std::io::Result<()> {
+        let aligned_len = self.len / DIRECT_IO_ALIGNMENT * DIRECT_IO_ALIGNMENT;
+        if aligned_len == 0 {
+            return Ok(());
+        }
+        let bytes = as_bytes_mut(&mut self.buffer);
+        self.file.write_all(&bytes[..aligned_len])?;
+        bytes.copy_within(aligned_len..self.len, 0);
+        self.written += aligned_len as u64;
+        self.len -= aligned_len;
+        Ok(())
+    }
+}
+
+fn as_bytes_mut(blocks: &mut [AlignedBlock]) -> &mut [u8] {
+    // SAFETY: `AlignedBlock` is a plain byte array without padding.
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            blocks.as_mut_ptr() as *mut u8,
+            blocks.len() * DIRECT_IO_ALIGNMENT,
+        )
+    }
+}
+
+impl Write for DirectFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let capacity = self.buffer.len() * DIRECT_IO_ALIGNMENT;
+        if self.len == capacity {
+            self.write_aligned()?;
+        }
+        let n = buf.len().min(capacity - self.len);
+        as_bytes_mut(&mut self.buffer)[self.len..self.len + n].copy_from_slice(&buf[..n]);
+        self.len += n;
+        Ok(n)
+    }
+
+    /// Write the whole aligned blocks only, the tail is left to [DirectFile::finish].
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_aligned()
+    }
+}
+
+impl Seek for DirectFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = self.position();
+        match pos {
+            SeekFrom::Current(0) | SeekFrom::End(0) => Ok(position),
+            SeekFrom::Start(p) if p == position => Ok(position),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "direct file only supports sequential writes",
+            )),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::env::direct_io::DirectFile;
+    use std::io::Write;
+
+    #[test]
+    fn test_write_unaligned() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("direct_io")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path().join("file");
+        let mut file = match DirectFile::create_new(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                // e.g. tmpfs does not support O_DIRECT
+                warn!("skip test_write_unaligned: {}", e);
+                return;
+            }
+        };
+
+        let mut expected = vec![];
+        for i in 0..500 {
+            let chunk = vec![i as u8; i * 7 % 1000 + 1];
+            file.write_all(&chunk).unwrap();
+            expected.extend(chunk);
+            if i % 100 == 0 {
+                file.flush().unwrap();
+            }
+        }
+        file.finish().unwrap();
+        drop(file);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::Result;
-use std::io::{Read, Seek};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
+use std::sync::Arc;
 
 pub struct FileSystem {}
 
@@ -14,16 +16,120 @@ impl FileSystem {
         #[cfg(not(feature = "mmap"))]
         {
             use crate::ioutils::BufReaderWithPos;
-            use std::fs::File;
             BufReaderWithPos::new(File::open(path)?)
         }
     }
+
+    /// Like [FileSystem::create_seq_readable_file], but read an opened `file` which may
+    /// be shared by many readers, e.g. from the table file cache.
+    pub fn open_seq_readable_file(file: Arc<File>) -> Result<impl SequentialReadableFile> {
+        #[cfg(feature = "mmap")]
+        {
+            use crate::env::file_system::mmap::MmapFile;
+            MmapFile::from_file(file)
+        }
+        #[cfg(not(feature = "mmap"))]
+        {
+            use crate::ioutils::BufReaderWithPos;
+            BufReaderWithPos::new(SharedFile::new(file)?)
+        }
+    }
 }
 
 pub trait SequentialReadableFile: Read + Seek {
     fn position(&self) -> usize;
 }
 
+/// Reader of the bytes of a file kept in memory, which may be shared by many readers.
+pub struct MemFile {
+    data: Arc<Vec<u8>>,
+    pos: usize,
+}
+
+impl MemFile {
+    pub fn new(data: Arc<Vec<u8>>) -> MemFile {
+        MemFile { data, pos: 0 }
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = self.pos.min(self.data.len());
+        let end = (start + buf.len()).min(self.data.len());
+        buf[..end - start].copy_from_slice(&self.data[start..end]);
+        self.pos = end;
+        Ok(end - start)
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+            SeekFrom::End(p) => self.data.len() as i64 + p,
+        };
+        if new_pos < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        }
+        self.pos = new_pos as usize;
+        Ok(self.pos as u64)
+    }
+}
+
+impl SequentialReadableFile for MemFile {
+    fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+/// Reader of a file which may be shared by many readers. Each reader keeps its own
+/// position and reads at it without moving the cursor of the file.
+pub struct SharedFile {
+    file: Arc<File>,
+    pos: u64,
+    len: u64,
+}
+
+impl SharedFile {
+    pub fn new(file: Arc<File>) -> Result<SharedFile> {
+        let len = file.metadata()?.len();
+        Ok(SharedFile { file, pos: 0, len })
+    }
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        let len = std::os::unix::fs::FileExt::read_at(&*self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let len = std::os::windows::fs::FileExt::seek_read(&*self.file, buf, self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+            SeekFrom::End(p) => self.len as i64 + p,
+        };
+        if new_pos < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
 #[cfg(feature = "mmap")]
 pub mod mmap {
     use crate::env::file_system::SequentialReadableFile;
@@ -32,17 +138,21 @@ pub mod mmap {
     use std::fs::File;
     use std::io::{Read, Seek, SeekFrom};
     use std::path::Path;
+    use std::sync::Arc;
 
     pub struct MmapFile {
-        file: File,
+        file: Arc<File>,
         mmap: Mmap,
         pos: usize,
     }
 
     impl MmapFile {
         pub fn open(path: &Path) -> Result<MmapFile> {
-            let file = File::open(path)?;
-            let mmap = unsafe { MmapOptions::new().map(&file).unwrap() };
+            Self::from_file(Arc::new(File::open(path)?))
+        }
+
+        pub fn from_file(file: Arc<File>) -> Result<MmapFile> {
+            let mmap = unsafe { MmapOptions::new().map(&*file).unwrap() };
             Ok(MmapFile { file, mmap, pos: 0 })
         }
     }
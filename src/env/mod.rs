@@ -1 +1,2 @@
+pub mod direct_io;
 pub mod file_system;
@@ -1,5 +1,6 @@
 use rayon::ThreadPoolBuildError;
 use std::io;
+use std::path::PathBuf;
 
 #[derive(thiserror::Error, Debug)]
 pub enum KVLiteError {
@@ -15,6 +16,33 @@ pub enum KVLiteError {
     #[error("invalid command")]
     InvalidCommand,
 
+    #[error("background task failed")]
+    BackgroundFailure,
+
+    #[error("value of {size} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge { size: usize, limit: usize },
+
+    #[error("key of {size} bytes exceeds the limit of {limit} bytes")]
+    KeyTooLarge { size: usize, limit: usize },
+
+    #[error("corruption: {0}")]
+    Corruption(String),
+
+    #[error("recovery mismatch: {0}")]
+    RecoveryMismatch(String),
+
+    #[error("pinned blocks exceed the room of the block cache")]
+    CacheBudgetExceeded,
+
+    #[error("database path {0:?} does not exist")]
+    DbPathNotFound(PathBuf),
+
+    #[error("database path {0:?} is not a directory")]
+    DbPathNotDirectory(PathBuf),
+
+    #[error("database at {0:?} is opened by another instance")]
+    DbLocked(PathBuf),
+
     #[error("{0}")]
     Custom(String),
 }
@@ -22,10 +50,30 @@ pub enum KVLiteError {
 impl PartialEq for KVLiteError {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Self::IOError(_), Self::IOError(_)) | (Self::InvalidCommand, Self::InvalidCommand) => {
-                true
-            }
-            (Self::Custom(s1), Self::Custom(s2)) => s1.eq(s2),
+            (Self::IOError(_), Self::IOError(_))
+            | (Self::InvalidCommand, Self::InvalidCommand)
+            | (Self::BackgroundFailure, Self::BackgroundFailure)
+            | (Self::CacheBudgetExceeded, Self::CacheBudgetExceeded) => true,
+            (
+                Self::ValueTooLarge { size, limit },
+                Self::ValueTooLarge {
+                    size: size2,
+                    limit: limit2,
+                },
+            )
+            | (
+                Self::KeyTooLarge { size, limit },
+                Self::KeyTooLarge {
+                    size: size2,
+                    limit: limit2,
+                },
+            ) => size == size2 && limit == limit2,
+            (Self::DbPathNotFound(p1), Self::DbPathNotFound(p2))
+            | (Self::DbPathNotDirectory(p1), Self::DbPathNotDirectory(p2))
+            | (Self::DbLocked(p1), Self::DbLocked(p2)) => p1.eq(p2),
+            (Self::Custom(s1), Self::Custom(s2))
+            | (Self::Corruption(s1), Self::Corruption(s2))
+            | (Self::RecoveryMismatch(s1), Self::RecoveryMismatch(s2)) => s1.eq(s2),
             _ => false,
         }
     }
@@ -1,8 +1,47 @@
 use crate::env::file_system::SequentialReadableFile;
+use crate::error::KVLiteError;
 use crate::Result;
-use std::fs::File;
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Sync the directory `dir`, so that the entries created or renamed in it are durable.
+pub(crate) fn sync_dir(dir: impl AsRef<Path>) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Replace the file `file_name` in `dir` with `contents` atomically, by writing a
+/// temporary file and renaming it. The new contents are durable when it returns.
+pub(crate) fn replace_file(dir: &Path, file_name: &str, contents: &[u8]) -> Result<()> {
+    let temp_path = dir.join(format!("{}_write", file_name));
+    std::fs::write(&temp_path, contents)?;
+    File::open(&temp_path)?.sync_all()?;
+    std::fs::rename(&temp_path, dir.join(file_name))?;
+    sync_dir(dir)
+}
+
+/// Name of the file locked by an opened database in its directory.
+pub const LOCK_FILE: &str = "LOCK";
+
+/// Take an exclusive advisory lock on the [LOCK_FILE] in `dir`.
+/// The lock is held until the returned file is dropped, and is released
+/// by the OS if the process exits.
+pub(crate) fn lock_dir(dir: impl AsRef<Path>) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(dir.as_ref().join(LOCK_FILE))?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+            Err(KVLiteError::DbLocked(dir.as_ref().to_path_buf()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
 
 pub struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
@@ -78,6 +117,18 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
             pos,
         })
     }
+
+    #[inline]
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+
+    /// Writing to the returned writer directly bypasses the buffer,
+    /// so it should be flushed first.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut W {
+        self.writer.get_mut()
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
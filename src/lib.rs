@@ -2,6 +2,7 @@
 #![feature(map_first_last)]
 #![feature(core_intrinsics)]
 #![feature(const_generics)]
+#![feature(allocator_api)]
 
 #[macro_use]
 extern crate log;
@@ -12,7 +13,7 @@ mod bloom;
 pub mod byteutils;
 pub mod cache;
 pub mod collections;
-mod compaction;
+pub mod compaction;
 pub mod db;
 mod env;
 pub mod error;
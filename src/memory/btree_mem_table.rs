@@ -1,7 +1,9 @@
 use crate::collections::skip_list::skipmap::SrSwSkipMap;
 use crate::db::key_types::{InternalKey, MemKey};
 use crate::db::{DBCommand, Value};
-use crate::memory::{InternalKeyValueIterator, MemTable};
+use crate::memory::{
+    count_tombstones, tombstone_delta, GuardedIter, InternalKeyValueIterator, MemTable,
+};
 use crate::Result;
 use std::cell::UnsafeCell;
 use std::collections::BTreeMap;
@@ -13,6 +15,8 @@ pub struct BTreeMemTable<SK: MemKey> {
     rw_lock: RwLock<()>,
     inner: UnsafeCell<BTreeMap<SK, Value>>,
     mem_usage: AtomicI64,
+    /// Approximate count of the tombstones.
+    tombstones: AtomicI64,
 }
 
 unsafe impl<SK: MemKey> Sync for BTreeMemTable<SK> {}
@@ -27,7 +31,6 @@ impl DBCommand<InternalKey, InternalKey> for BTreeMemTable<InternalKey> {
         let _guard = self.rw_lock.read().unwrap();
         let inner_ptr = self.inner.get();
         unsafe {
-            (*inner_ptr).get_key_value(key_end);
             for (k, v) in (*inner_ptr).range::<InternalKey, _>(key_start..=key_end) {
                 kvs.insert(k.clone(), v.clone());
             }
@@ -40,40 +43,45 @@ impl DBCommand<InternalKey, InternalKey> for BTreeMemTable<InternalKey> {
     }
 
     fn set(&self, key: InternalKey, value: Value) -> Result<()> {
-        let _lock = self.rw_lock.write().unwrap();
-        let key_length = key.len();
-        let value_length = value.len();
-        let option = unsafe { (*self.inner.get()).insert(key, value) };
-        let mem_add = match option {
-            Some(v) => (value_length as i64 - v.len() as i64) * std::mem::size_of::<u8>() as i64,
-            None => ((key_length + value_length) * std::mem::size_of::<u8>()) as i64,
-        };
-        self.mem_usage.fetch_add(mem_add, Ordering::Release);
+        self.upsert(key, value);
         Ok(())
     }
 
     fn remove(&self, key: InternalKey) -> Result<()> {
-        let _lock = self.rw_lock.write().unwrap();
-        unsafe {
-            let key_len = key.len();
-            let option = (*self.inner.get()).insert(key, InternalKey::default());
-            let mem_add = match option {
-                Some(v) => -(v.len() as i64),
-                None => key_len as i64 * std::mem::size_of::<u8>() as i64,
-            };
-
-            self.mem_usage.fetch_add(mem_add, Ordering::Release);
-        }
+        self.upsert(key, Value::default());
         Ok(())
     }
 }
 
+impl<SK: MemKey> BTreeMemTable<SK> {
+    /// Insert `value` of `key` or replace the old one under the write lock, and account
+    /// for it like the skip map memory tables: a new key adds the sizes of the key and
+    /// the value, a replaced value adds the difference of the value sizes. An empty
+    /// value is a tombstone.
+    fn upsert(&self, key: SK, value: Value) {
+        let _lock = self.rw_lock.write().unwrap();
+        let key_mem_size = key.mem_size();
+        let value_len = value.len();
+        let old_value = unsafe { (*self.inner.get()).insert(key, value) };
+        self.tombstones.fetch_add(
+            tombstone_delta(old_value.as_ref(), value_len == 0),
+            Ordering::Release,
+        );
+        let mem_add = match old_value {
+            Some(v) => value_len as i64 - v.len() as i64,
+            None => (key_mem_size + value_len) as i64,
+        };
+        self.mem_usage.fetch_add(mem_add, Ordering::Release);
+    }
+}
+
 impl<K: MemKey> Default for BTreeMemTable<K> {
     fn default() -> Self {
         BTreeMemTable {
             rw_lock: RwLock::default(),
             inner: UnsafeCell::new(BTreeMap::default()),
             mem_usage: AtomicI64::default(),
+            tombstones: AtomicI64::default(),
         }
     }
 }
@@ -85,14 +93,28 @@ impl InternalKeyValueIterator for BTreeMemTable<InternalKey> {
     }
 
     fn kv_iter(&self) -> Box<dyn Iterator<Item = (&InternalKey, &Value)> + '_> {
-        let _lock = self.rw_lock.read().unwrap();
-        Box::new(unsafe { (*self.inner.get()).iter() })
+        Box::new(GuardedIter {
+            _guard: self.rw_lock.read().unwrap(),
+            iter: unsafe { (*self.inner.get()).iter() },
+        })
+    }
+
+    fn kv_iter_from(
+        &self,
+        start: &InternalKey,
+    ) -> Box<dyn Iterator<Item = (&InternalKey, &Value)> + '_> {
+        Box::new(GuardedIter {
+            _guard: self.rw_lock.read().unwrap(),
+            iter: unsafe { (*self.inner.get()).range(start.clone()..) },
+        })
     }
 }
 
 impl MemTable<InternalKey, InternalKey> for BTreeMemTable<InternalKey> {
     fn merge(&self, kvs: SrSwSkipMap<InternalKey, Value>, memory_size: u64) {
         let mut _lock_guard = self.rw_lock.write().unwrap();
+        self.tombstones
+            .fetch_add(count_tombstones(&kvs), Ordering::Release);
         unsafe {
             (*self.inner.get()).extend(kvs.into_iter());
         }
@@ -105,13 +127,19 @@ impl MemTable<InternalKey, InternalKey> for BTreeMemTable<InternalKey> {
         debug_assert!(mem_size >= 0);
         mem_size as u64
     }
+
+    fn tombstone_count(&self) -> u64 {
+        self.tombstones.load(Ordering::Acquire).max(0) as u64
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::db::DBCommand;
-    use crate::memory::{BTreeMemTable, InternalKeyValueIterator};
+    use crate::db::key_types::InternalKey;
+    use crate::db::{DBCommand, Value};
+    use crate::memory::{BTreeMemTable, InternalKeyValueIterator, MemTable};
     use crate::Result;
+    use std::sync::Arc;
 
     #[test]
     fn test_iter() -> Result<()> {
@@ -125,4 +153,68 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_concurrent_set() {
+        const THREADS: usize = 8;
+        const KEYS: usize = 200;
+        let mem_table = Arc::new(BTreeMemTable::<InternalKey>::default());
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let mem_table = mem_table.clone();
+                std::thread::spawn(move || {
+                    for i in 0..KEYS {
+                        // keys shared by all the threads, and keys of this thread only
+                        let value = format!("value{}-{}", t, i).into_bytes();
+                        mem_table
+                            .set(format!("shared{:03}", i).into_bytes(), value.clone())
+                            .unwrap();
+                        mem_table
+                            .set(format!("own{}-{:03}", t, i).into_bytes(), value)
+                            .unwrap();
+                        if i % 2 == 0 {
+                            mem_table
+                                .remove(format!("own{}-{:03}", t, i).into_bytes())
+                                .unwrap();
+                        }
+                        // readers iterate while the others write
+                        let mut prev: Option<&InternalKey> = None;
+                        for (key, _) in mem_table.kv_iter() {
+                            assert!(prev < Some(key));
+                            prev = Some(key);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(mem_table.len(), KEYS + THREADS * KEYS);
+        for i in 0..KEYS {
+            let value = mem_table
+                .get(&format!("shared{:03}", i).into_bytes())
+                .unwrap()
+                .unwrap();
+            let value = String::from_utf8(value).unwrap();
+            assert!(value.starts_with("value") && value.ends_with(&format!("-{}", i)));
+            for t in 0..THREADS {
+                let expected = if i % 2 == 0 {
+                    Value::default()
+                } else {
+                    format!("value{}-{}", t, i).into_bytes()
+                };
+                assert_eq!(
+                    mem_table
+                        .get(&format!("own{}-{:03}", t, i).into_bytes())
+                        .unwrap(),
+                    Some(expected)
+                );
+            }
+        }
+        let mem_size: usize = mem_table.kv_iter().map(|(k, v)| k.len() + v.len()).sum();
+        assert_eq!(mem_table.approximate_memory_usage(), mem_size as u64);
+        assert_eq!(mem_table.tombstone_count(), (THREADS * KEYS / 2) as u64);
+    }
 }
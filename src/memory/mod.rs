@@ -22,12 +22,41 @@ pub trait MemTable<SK: MemKey, UK: MemKey>:
 {
     fn merge(&self, kvs: SrSwSkipMap<SK, Value>, memory_size: u64);
     fn approximate_memory_usage(&self) -> u64;
+    /// Approximate count of the tombstones. Tombstones merged over existing keys
+    /// are counted without checking the replaced values.
+    fn tombstone_count(&self) -> u64;
+    /// Every version kept of the keys in [`key_start`, `key_end`], in ascending order.
+    /// The memory tables keeping a single version of each key yield the newest one.
+    fn versions_in_range(&self, key_start: &SK, key_end: &SK) -> Vec<(SK, Value)> {
+        let key_end = key_end.internal_key();
+        self.kv_iter_from(key_start.internal_key())
+            .take_while(|(k, _)| *k <= key_end)
+            .map(|(k, v)| (SK::from(k.clone()), v.clone()))
+            .collect()
+    }
+}
+
+/// Change of the tombstone count after a value is replaced by a tombstone or not.
+#[inline]
+pub(super) fn tombstone_delta(old_value: Option<&Value>, new_is_tombstone: bool) -> i64 {
+    let old_is_tombstone = matches!(old_value, Some(v) if v.is_empty());
+    new_is_tombstone as i64 - old_is_tombstone as i64
+}
+
+pub(super) fn count_tombstones<K: Ord + Default>(kvs: &SrSwSkipMap<K, Value>) -> i64 {
+    kvs.iter().filter(|(_, v)| v.is_empty()).count() as i64
 }
 
 pub trait SkipMapMemTable<SK: MemKey, UK: MemKey, const RW_MODE: ReadWriteMode>:
     MemTable<SK, UK> + 'static
 {
     fn get_inner(&self) -> &SkipMap<SK, Value, RW_MODE>;
+
+    /// Like [DBCommand::get], but call `f` on the stored value instead of returning
+    /// a clone of it, which saves copying large values that are only inspected.
+    fn get_with<R>(&self, key: &SK, f: impl FnOnce(&Value) -> R) -> crate::Result<Option<R>> {
+        Ok(self.get_inner().get(key).map(|entry| f(&entry.value)))
+    }
 }
 
 /// Used for iterate all the key-value pairs in database.
@@ -92,6 +121,34 @@ pub trait InternalKeyValueIterator {
 
     /// # Note: InternalKey should not be duplicated.
     fn kv_iter(&self) -> Box<dyn Iterator<Item = (&InternalKey, &Value)> + '_>;
+
+    /// Like [InternalKeyValueIterator::kv_iter], but start from the first key greater
+    /// or equal than `start`, and be empty if `start` is greater than all the keys.
+    /// A paginated scan passes the last key of a page as `start` of the next page,
+    /// and skips that key.
+    fn kv_iter_from(
+        &self,
+        start: &InternalKey,
+    ) -> Box<dyn Iterator<Item = (&InternalKey, &Value)> + '_> {
+        let start = start.clone();
+        Box::new(self.kv_iter().skip_while(move |(k, _)| **k < start))
+    }
+}
+
+/// Iterator holding the lock guard of the memory table it iterates, so that no
+/// writer changes the table meanwhile.
+pub(crate) struct GuardedIter<G, I> {
+    pub(crate) _guard: G,
+    pub(crate) iter: I,
+}
+
+impl<G, I: Iterator> Iterator for GuardedIter<G, I> {
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
 }
 
 impl InternalKeyValueIterator for SrSwSkipMap<InternalKey, Value> {
@@ -105,4 +162,90 @@ impl InternalKeyValueIterator for SrSwSkipMap<InternalKey, Value> {
                 .map(|node| unsafe { (&(*node).entry.key, &(*node).entry.value) }),
         )
     }
+
+    fn kv_iter_from(
+        &self,
+        start: &InternalKey,
+    ) -> Box<dyn Iterator<Item = (&InternalKey, &Value)>> {
+        Box::new(
+            self.iter_ptr_from(start)
+                .map(|node| unsafe { (&(*node).entry.key, &(*node).entry.value) }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::key_types::InternalKey;
+    use crate::db::Value;
+    use crate::memory::{
+        BTreeMemTable, MemTable, MrMwSkipMapMemTable, MrSwSkipMapMemTable, MutexSkipMapMemTable,
+    };
+
+    fn remove_keys<M: MemTable<InternalKey, InternalKey>>() -> Vec<(InternalKey, Value)> {
+        let table = M::default();
+        let present = Vec::from("present");
+        let absent = Vec::from("absent");
+
+        table.set(present.clone(), Vec::from("value")).unwrap();
+        assert!(table.remove(present.clone()).is_ok());
+        assert!(table.remove(absent.clone()).is_ok());
+
+        // both of them are tombstones now
+        assert_eq!(table.get(&present).unwrap(), Some(Value::default()));
+        assert_eq!(table.get(&absent).unwrap(), Some(Value::default()));
+        assert_eq!(table.len(), 2);
+
+        table
+            .kv_iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_remove_consistency() {
+        let expected = remove_keys::<BTreeMemTable<InternalKey>>();
+        assert_eq!(expected, remove_keys::<MutexSkipMapMemTable<InternalKey>>());
+        assert_eq!(expected, remove_keys::<MrSwSkipMapMemTable<InternalKey>>());
+        assert_eq!(expected, remove_keys::<MrMwSkipMapMemTable<InternalKey>>());
+    }
+
+    fn paginate<M: MemTable<InternalKey, InternalKey>>() {
+        let table = M::default();
+        for i in 0..100 {
+            let key = format!("key{:03}", i * 2).into_bytes();
+            table.set(key, Vec::from("value")).unwrap();
+        }
+        let all: Vec<InternalKey> = table.kv_iter().map(|(k, _)| k.clone()).collect();
+
+        let page1: Vec<InternalKey> = table.kv_iter().take(40).map(|(k, _)| k.clone()).collect();
+        let last = page1.last().unwrap().clone();
+        let page2: Vec<InternalKey> = table
+            .kv_iter_from(&last)
+            .skip_while(|(k, _)| **k == last)
+            .map(|(k, _)| k.clone())
+            .collect();
+        assert_eq!(page1.len() + page2.len(), all.len());
+        assert_eq!([page1, page2].concat(), all);
+
+        // `start` between two keys, before the first key and past the last key
+        let from = |start: &str| -> Vec<InternalKey> {
+            table
+                .kv_iter_from(&Vec::from(start))
+                .map(|(k, _)| k.clone())
+                .collect()
+        };
+        assert_eq!(from("key051"), all[26..].to_vec());
+        assert_eq!(from("a"), all);
+        assert!(from("key199").is_empty());
+        assert!(from("z").is_empty());
+    }
+
+    #[test]
+    fn test_kv_iter_from() {
+        paginate::<BTreeMemTable<InternalKey>>();
+        paginate::<MutexSkipMapMemTable<InternalKey>>();
+        paginate::<MrSwSkipMapMemTable<InternalKey>>();
+        paginate::<MrMwSkipMapMemTable<InternalKey>>();
+    }
 }
@@ -3,7 +3,9 @@ use crate::collections::skip_list::skipmap::{MrMwSkipMap, SkipMap, SrSwSkipMap};
 use crate::db::key_types::{InternalKey, LSNKey, MemKey};
 use crate::db::{DBCommand, Value};
 use crate::memory::skip_map_mem_table::{get_by_lsn_key, range_get_by_lsn_key};
-use crate::memory::{InternalKeyValueIterator, MemTable, SkipMapMemTable};
+use crate::memory::{
+    count_tombstones, tombstone_delta, InternalKeyValueIterator, MemTable, SkipMapMemTable,
+};
 use crate::Result;
 use std::sync::atomic::{AtomicI64, Ordering};
 
@@ -11,6 +13,8 @@ use std::sync::atomic::{AtomicI64, Ordering};
 pub struct MrMwSkipMapMemTable<SK: MemKey> {
     inner: MrMwSkipMap<SK, Value>,
     mem_usage: AtomicI64,
+    /// Approximate count of the tombstones.
+    tombstones: AtomicI64,
 }
 
 impl DBCommand<InternalKey, InternalKey> for MrMwSkipMapMemTable<InternalKey> {
@@ -33,7 +37,12 @@ impl DBCommand<InternalKey, InternalKey> for MrMwSkipMapMemTable<InternalKey> {
     fn set(&self, key: InternalKey, value: Value) -> crate::Result<()> {
         let key_mem_size = key.mem_size();
         let value_len = value.len();
-        let mem_add = match self.inner.insert(key, value) {
+        let old_value = self.inner.insert(key, value);
+        self.tombstones.fetch_add(
+            tombstone_delta(old_value.as_ref(), value_len == 0),
+            Ordering::Release,
+        );
+        let mem_add = match old_value {
             Some(v) => (value_len as i64 - v.len() as i64),
             None => (key_mem_size + value_len) as i64,
         } * std::mem::size_of::<u8>() as i64;
@@ -43,7 +52,10 @@ impl DBCommand<InternalKey, InternalKey> for MrMwSkipMapMemTable<InternalKey> {
 
     fn remove(&self, key: InternalKey) -> crate::Result<()> {
         let key_mem_size = key.mem_size();
-        let mem_add = match self.inner.insert(key, Value::default()) {
+        let old_value = self.inner.insert(key, Value::default());
+        self.tombstones
+            .fetch_add(tombstone_delta(old_value.as_ref(), true), Ordering::Release);
+        let mem_add = match old_value {
             Some(v) => -((v.len() * std::mem::size_of::<u8>()) as i64),
             None => (key_mem_size * std::mem::size_of::<u8>()) as i64,
         };
@@ -64,10 +76,23 @@ impl InternalKeyValueIterator for MrMwSkipMapMemTable<InternalKey> {
                 .map(|n| unsafe { (&(*n).entry.key, &(*n).entry.value) }),
         )
     }
+
+    fn kv_iter_from(
+        &self,
+        start: &InternalKey,
+    ) -> Box<dyn Iterator<Item = (&InternalKey, &Value)> + '_> {
+        Box::new(
+            self.inner
+                .iter_ptr_from(start)
+                .map(|n| unsafe { (&(*n).entry.key, &(*n).entry.value) }),
+        )
+    }
 }
 
 impl MemTable<InternalKey, InternalKey> for MrMwSkipMapMemTable<InternalKey> {
     fn merge(&self, kvs: SrSwSkipMap<InternalKey, Value>, mem_usage: u64) {
+        self.tombstones
+            .fetch_add(count_tombstones(&kvs), Ordering::Release);
         self.mem_usage
             .fetch_add(mem_usage as i64, Ordering::Release);
         self.inner.merge(kvs);
@@ -78,6 +103,10 @@ impl MemTable<InternalKey, InternalKey> for MrMwSkipMapMemTable<InternalKey> {
         debug_assert!(mem_usage >= 0);
         mem_usage as u64
     }
+
+    fn tombstone_count(&self) -> u64 {
+        self.tombstones.load(Ordering::Acquire).max(0) as u64
+    }
 }
 
 impl SkipMapMemTable<InternalKey, InternalKey, { MrMw }> for MrMwSkipMapMemTable<InternalKey> {
@@ -106,7 +135,12 @@ impl<UK: MemKey> DBCommand<LSNKey<UK>, UK> for MrMwSkipMapMemTable<LSNKey<UK>> {
     fn set(&self, key: LSNKey<UK>, value: Value) -> Result<()> {
         let key_mem_size = key.mem_size() as i64;
         let value_len = value.len() as i64;
-        let mem_add = match self.inner.insert(key, value) {
+        let old_value = self.inner.insert(key, value);
+        self.tombstones.fetch_add(
+            tombstone_delta(old_value.as_ref(), value_len == 0),
+            Ordering::Release,
+        );
+        let mem_add = match old_value {
             Some(v) => (value_len as i64 - v.len() as i64),
             None => (key_mem_size + value_len),
         } * std::mem::size_of::<u8>() as i64;
@@ -117,7 +151,10 @@ impl<UK: MemKey> DBCommand<LSNKey<UK>, UK> for MrMwSkipMapMemTable<LSNKey<UK>> {
     fn remove(&self, key: LSNKey<UK>) -> Result<()> {
         let key_mem_size = key.mem_size();
 
-        let mem_add = match self.inner.insert(key, Value::default()) {
+        let old_value = self.inner.insert(key, Value::default());
+        self.tombstones
+            .fetch_add(tombstone_delta(old_value.as_ref(), true), Ordering::Release);
+        let mem_add = match old_value {
             Some(v) => -((v.len() * std::mem::size_of::<u8>()) as i64),
             None => (key_mem_size * std::mem::size_of::<u8>()) as i64,
         };
@@ -1,17 +1,26 @@
-use crate::collections::skip_list::skipmap::{MrSwSkipMap, SrSwSkipMap};
+use crate::collections::skip_list::skipmap::ReadWriteMode::MrSw;
+use crate::collections::skip_list::skipmap::{MrSwSkipMap, SkipMap, SrSwSkipMap};
 use crate::db::key_types::{InternalKey, LSNKey, MemKey};
 use crate::db::{DBCommand, Value};
 use crate::memory::skip_map_mem_table::{get_by_lsn_key, range_get_by_lsn_key};
-use crate::memory::{InternalKeyValueIterator, MemTable};
+use crate::memory::{
+    count_tombstones, tombstone_delta, GuardedIter, InternalKeyValueIterator, MemTable,
+    SkipMapMemTable,
+};
 use crate::Result;
 use std::sync::atomic::{AtomicI64, Ordering};
-use std::sync::Mutex;
+use std::sync::RwLock;
 
 #[derive(Default)]
 pub struct MrSwSkipMapMemTable<SK: MemKey> {
-    lock: Mutex<()>,
+    /// Written by the writers and read by the readers. The skip map lets readers walk
+    /// it while a node is being linked, but a writer replaces the value of an existing
+    /// key in place, which a reader must not be cloning at the same time.
+    lock: RwLock<()>,
     inner: MrSwSkipMap<SK, Value>,
     mem_usage: AtomicI64,
+    /// Approximate count of the tombstones.
+    tombstones: AtomicI64,
 }
 
 unsafe impl<SK: MemKey> Sync for MrSwSkipMapMemTable<SK> {}
@@ -23,18 +32,25 @@ impl DBCommand<InternalKey, InternalKey> for MrSwSkipMapMemTable<InternalKey> {
         key_end: &InternalKey,
         kvs: &mut SrSwSkipMap<InternalKey, Value>,
     ) {
+        let _guard = self.lock.read().unwrap();
         self.inner.range_get(key_start, key_end, kvs)
     }
 
     fn get(&self, key: &InternalKey) -> Result<Option<Value>> {
+        let _guard = self.lock.read().unwrap();
         Ok(self.inner.get_clone(key))
     }
 
     fn set(&self, key: InternalKey, value: Value) -> Result<()> {
-        let _guard = self.lock.lock().unwrap();
+        let _guard = self.lock.write().unwrap();
         let key_mem_size = key.mem_size();
         let value_len = value.len();
-        let mem_add = match self.inner.insert(key, value) {
+        let old_value = self.inner.insert(key, value);
+        self.tombstones.fetch_add(
+            tombstone_delta(old_value.as_ref(), value_len == 0),
+            Ordering::Release,
+        );
+        let mem_add = match old_value {
             Some(v) => (value_len as i64 - v.len() as i64),
             None => (key_mem_size + value_len) as i64,
         } * std::mem::size_of::<u8>() as i64;
@@ -43,9 +59,12 @@ impl DBCommand<InternalKey, InternalKey> for MrSwSkipMapMemTable<InternalKey> {
     }
 
     fn remove(&self, key: InternalKey) -> Result<()> {
-        let _guard = self.lock.lock().unwrap();
+        let _guard = self.lock.write().unwrap();
         let key_mem_size = key.mem_size();
-        let mem_add = match self.inner.insert(key, Value::default()) {
+        let old_value = self.inner.insert(key, Value::default());
+        self.tombstones
+            .fetch_add(tombstone_delta(old_value.as_ref(), true), Ordering::Release);
+        let mem_add = match old_value {
             Some(v) => -((v.len() * std::mem::size_of::<u8>()) as i64),
             None => (key_mem_size * std::mem::size_of::<u8>()) as i64,
         };
@@ -59,18 +78,37 @@ impl InternalKeyValueIterator for MrSwSkipMapMemTable<InternalKey> {
         self.inner.len()
     }
 
-    fn kv_iter(&self) -> Box<dyn Iterator<Item = (&InternalKey, &Value)>> {
-        Box::new(
-            self.inner
+    /// The values are replaced in place by the writer, so the read lock is held until
+    /// the iterator is dropped.
+    fn kv_iter(&self) -> Box<dyn Iterator<Item = (&InternalKey, &Value)> + '_> {
+        Box::new(GuardedIter {
+            _guard: self.lock.read().unwrap(),
+            iter: self
+                .inner
                 .iter_ptr()
                 .map(|n| unsafe { (&(*n).entry.key, &(*n).entry.value) }),
-        )
+        })
+    }
+
+    fn kv_iter_from(
+        &self,
+        start: &InternalKey,
+    ) -> Box<dyn Iterator<Item = (&InternalKey, &Value)> + '_> {
+        Box::new(GuardedIter {
+            _guard: self.lock.read().unwrap(),
+            iter: self
+                .inner
+                .iter_ptr_from(start)
+                .map(|n| unsafe { (&(*n).entry.key, &(*n).entry.value) }),
+        })
     }
 }
 
 impl MemTable<InternalKey, InternalKey> for MrSwSkipMapMemTable<InternalKey> {
     fn merge(&self, kvs: SrSwSkipMap<InternalKey, Value>, mem_usage: u64) {
-        let _guard = self.lock.lock().unwrap();
+        self.tombstones
+            .fetch_add(count_tombstones(&kvs), Ordering::Release);
+        let _guard = self.lock.write().unwrap();
         self.mem_usage
             .fetch_add(mem_usage as i64, Ordering::Release);
         self.inner.merge(kvs);
@@ -81,6 +119,21 @@ impl MemTable<InternalKey, InternalKey> for MrSwSkipMapMemTable<InternalKey> {
         debug_assert!(mem_usage >= 0);
         mem_usage as u64
     }
+
+    fn tombstone_count(&self) -> u64 {
+        self.tombstones.load(Ordering::Acquire).max(0) as u64
+    }
+}
+
+impl SkipMapMemTable<InternalKey, InternalKey, { MrSw }> for MrSwSkipMapMemTable<InternalKey> {
+    fn get_inner(&self) -> &SkipMap<InternalKey, Value, { MrSw }> {
+        &self.inner
+    }
+
+    fn get_with<R>(&self, key: &InternalKey, f: impl FnOnce(&Value) -> R) -> Result<Option<R>> {
+        let _guard = self.lock.read().unwrap();
+        Ok(self.inner.get(key).map(|entry| f(&entry.value)))
+    }
 }
 
 impl<UK: MemKey> DBCommand<LSNKey<UK>, UK> for MrSwSkipMapMemTable<LSNKey<UK>> {
@@ -93,10 +146,12 @@ impl<UK: MemKey> DBCommand<LSNKey<UK>, UK> for MrSwSkipMapMemTable<LSNKey<UK>> {
         debug_assert!(key_start.le(key_end));
         debug_assert_eq!(key_start.lsn(), key_end.lsn());
 
+        let _guard = self.lock.read().unwrap();
         range_get_by_lsn_key(&self.inner, key_start, key_end, kvs)
     }
 
     fn get(&self, key: &LSNKey<UK>) -> Result<Option<Value>> {
+        let _guard = self.lock.read().unwrap();
         get_by_lsn_key(&self.inner, key)
     }
 
@@ -104,8 +159,13 @@ impl<UK: MemKey> DBCommand<LSNKey<UK>, UK> for MrSwSkipMapMemTable<LSNKey<UK>> {
         let key_mem_size = key.mem_size() as i64;
         let value_len = value.len() as i64;
 
-        let _guard = self.lock.lock().unwrap();
-        let mem_add = match self.inner.insert(key, value) {
+        let _guard = self.lock.write().unwrap();
+        let old_value = self.inner.insert(key, value);
+        self.tombstones.fetch_add(
+            tombstone_delta(old_value.as_ref(), value_len == 0),
+            Ordering::Release,
+        );
+        let mem_add = match old_value {
             Some(v) => (value_len as i64 - v.len() as i64),
             None => (key_mem_size + value_len),
         } * std::mem::size_of::<u8>() as i64;
@@ -115,8 +175,11 @@ impl<UK: MemKey> DBCommand<LSNKey<UK>, UK> for MrSwSkipMapMemTable<LSNKey<UK>> {
 
     fn remove(&self, key: LSNKey<UK>) -> Result<()> {
         let key_mem_size = key.mem_size();
-        let _guard = self.lock.lock().unwrap();
-        let mem_add = match self.inner.insert(key, Value::default()) {
+        let _guard = self.lock.write().unwrap();
+        let old_value = self.inner.insert(key, Value::default());
+        self.tombstones
+            .fetch_add(tombstone_delta(old_value.as_ref(), true), Ordering::Release);
+        let mem_add = match old_value {
             Some(v) => -((v.len() * std::mem::size_of::<u8>()) as i64),
             None => (key_mem_size * std::mem::size_of::<u8>()) as i64,
         };
@@ -124,3 +187,56 @@ impl<UK: MemKey> DBCommand<LSNKey<UK>, UK> for MrSwSkipMapMemTable<LSNKey<UK>> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::db::key_types::InternalKey;
+    use crate::db::DBCommand;
+    use crate::memory::MrSwSkipMapMemTable;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Readers get the keys while a writer keeps replacing their values with values of
+    /// other lengths. Run it under a sanitizer, e.g. `cargo +nightly miri test` or
+    /// `RUSTFLAGS=-Zsanitizer=thread`, to check that no read races the replacement.
+    #[test]
+    fn test_concurrent_get_and_replace() {
+        const KEYS: usize = 16;
+        let key = |i: usize| format!("key{:02}", i).into_bytes();
+        // every value is a run of one byte, whose length depends on the round
+        let value = |round: usize, i: usize| vec![(round % 251) as u8; (round + i) % 64 + 1];
+        let table = Arc::new(MrSwSkipMapMemTable::<InternalKey>::default());
+        for i in 0..KEYS {
+            table.set(key(i), value(0, i)).unwrap();
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let table = table.clone();
+                let stop = stop.clone();
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::Acquire) {
+                        for i in 0..KEYS {
+                            let v = table.get(&key(i)).unwrap().unwrap();
+                            assert!(!v.is_empty() && v.len() <= 64);
+                            assert!(v.iter().all(|b| *b == v[0]));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for round in 1..2000 {
+            for i in 0..KEYS {
+                table.set(key(i), value(round, i)).unwrap();
+            }
+        }
+        stop.store(true, Ordering::Release);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        for i in 0..KEYS {
+            assert_eq!(table.get(&key(i)).unwrap(), Some(value(1999, i)));
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use crate::collections::skip_list::skipmap::{ReadWriteMode, SkipMap, SrSwSkipMap};
-use crate::db::key_types::{InternalKey, LSNKey, MemKey};
+use crate::db::key_types::{InternalKey, LSNKey, MemKey, LSN};
 use crate::db::{DBCommand, Value};
-use crate::memory::{InternalKeyValueIterator, MemTable};
+use crate::memory::{
+    count_tombstones, tombstone_delta, GuardedIter, InternalKeyValueIterator, MemTable,
+};
 use crate::Result;
 use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Mutex;
@@ -11,6 +13,8 @@ pub struct MutexSkipMapMemTable<SK: MemKey> {
     lock: Mutex<()>,
     inner_guarded: SrSwSkipMap<SK, Value>,
     mem_usage: AtomicI64,
+    /// Approximate count of the tombstones.
+    tombstones: AtomicI64,
 }
 
 impl DBCommand<InternalKey, InternalKey> for MutexSkipMapMemTable<InternalKey> {
@@ -33,8 +37,13 @@ impl DBCommand<InternalKey, InternalKey> for MutexSkipMapMemTable<InternalKey> {
         let _guard = self.lock.lock().unwrap();
         let key_len = key.len();
         let value_len = value.len();
-        let mem_add = match self.inner_guarded.insert(key, value) {
-            Some(v) => ((key_len + value_len - v.len()) * std::mem::size_of::<u8>()) as i64,
+        let old_value = self.inner_guarded.insert(key, value);
+        self.tombstones.fetch_add(
+            tombstone_delta(old_value.as_ref(), value_len == 0),
+            Ordering::Release,
+        );
+        let mem_add = match old_value {
+            Some(v) => value_len as i64 - v.len() as i64,
             None => ((key_len + value_len) * std::mem::size_of::<u8>()) as i64,
         };
         self.mem_usage.fetch_add(mem_add, Ordering::Release);
@@ -45,7 +54,10 @@ impl DBCommand<InternalKey, InternalKey> for MutexSkipMapMemTable<InternalKey> {
         let _guard = self.lock.lock().unwrap();
 
         let key_len = key.len();
-        let mem_add = match self.inner_guarded.insert(key, Value::default()) {
+        let old_value = self.inner_guarded.insert(key, Value::default());
+        self.tombstones
+            .fetch_add(tombstone_delta(old_value.as_ref(), true), Ordering::Release);
+        let mem_add = match old_value {
             Some(v) => -((v.len() * std::mem::size_of::<u8>()) as i64),
             None => (key_len * std::mem::size_of::<u8>()) as i64,
         };
@@ -54,22 +66,49 @@ impl DBCommand<InternalKey, InternalKey> for MutexSkipMapMemTable<InternalKey> {
     }
 }
 
+impl MutexSkipMapMemTable<InternalKey> {
+    /// Like [DBCommand::get], but call `f` on the stored value under the lock instead
+    /// of returning a clone of it, which saves copying large values that are only inspected.
+    pub fn get_with<R>(&self, key: &InternalKey, f: impl FnOnce(&Value) -> R) -> Result<Option<R>> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(self.inner_guarded.get(key).map(|entry| f(&entry.value)))
+    }
+}
+
 impl InternalKeyValueIterator for MutexSkipMapMemTable<InternalKey> {
     fn len(&self) -> usize {
         self.inner_guarded.len()
     }
 
+    /// The lock is held until the iterator is dropped.
     fn kv_iter(&self) -> Box<dyn Iterator<Item = (&InternalKey, &Value)> + '_> {
-        Box::new(
-            self.inner_guarded
+        Box::new(GuardedIter {
+            _guard: self.lock.lock().unwrap(),
+            iter: self
+                .inner_guarded
                 .iter_ptr()
                 .map(|n| unsafe { (&(*n).entry.key, &(*n).entry.value) }),
-        )
+        })
+    }
+
+    fn kv_iter_from(
+        &self,
+        start: &InternalKey,
+    ) -> Box<dyn Iterator<Item = (&InternalKey, &Value)> + '_> {
+        Box::new(GuardedIter {
+            _guard: self.lock.lock().unwrap(),
+            iter: self
+                .inner_guarded
+                .iter_ptr_from(start)
+                .map(|n| unsafe { (&(*n).entry.key, &(*n).entry.value) }),
+        })
     }
 }
 
 impl MemTable<InternalKey, InternalKey> for MutexSkipMapMemTable<InternalKey> {
     fn merge(&self, kvs: SrSwSkipMap<InternalKey, Value>, mem_size: u64) {
+        self.tombstones
+            .fetch_add(count_tombstones(&kvs), Ordering::Release);
         let _guard = self.lock.lock().unwrap();
         self.mem_usage.fetch_add(mem_size as i64, Ordering::Release);
         self.inner_guarded.merge(kvs);
@@ -77,9 +116,13 @@ impl MemTable<InternalKey, InternalKey> for MutexSkipMapMemTable<InternalKey> {
 
     fn approximate_memory_usage(&self) -> u64 {
         let mem_usage = self.mem_usage.load(Ordering::Acquire);
-        debug_assert!(mem_usage > 0);
+        debug_assert!(mem_usage >= 0);
         mem_usage as u64
     }
+
+    fn tombstone_count(&self) -> u64 {
+        self.tombstones.load(Ordering::Acquire).max(0) as u64
+    }
 }
 
 pub(super) fn range_get_by_lsn_key<UK: MemKey, const RW_MODE: ReadWriteMode>(
@@ -88,32 +131,27 @@ pub(super) fn range_get_by_lsn_key<UK: MemKey, const RW_MODE: ReadWriteMode>(
     key_end: &LSNKey<UK>,
     kvs: &mut SrSwSkipMap<UK, Value>,
 ) {
-    let mut node = skip_map.find_last_le(key_start);
-    if node.is_null() {
-        return;
-    }
-    unsafe {
-        let user_key = (*node).entry.key.user_key();
-        if user_key.eq(key_start.user_key()) {
-            kvs.insert(user_key.clone(), (*node).entry.value.clone());
-        }
-    }
-
-    loop {
-        let lsn_max = unsafe { LSNKey::upper_bound(&(*node).entry.key) };
+    // the oldest version of the first user key in range
+    let mut node =
+        skip_map.find_first_ge(&LSNKey::new(key_start.user_key().clone(), LSN::MIN), None);
+    while !node.is_null() {
         unsafe {
-            // get next user key
-            node = SkipMap::find_first_ge_from_node(node, &lsn_max);
-            if node.is_null() || (*node).entry.key.user_key().gt(key_end.user_key()) {
+            let user_key = (*node).entry.key.user_key();
+            if user_key.gt(key_end.user_key()) {
                 return;
             }
-
-            let lsn_key = LSNKey::new((*node).entry.key.user_key().clone(), key_end.lsn());
-            node = SkipMap::find_last_le_from_node(node, &lsn_key);
-            debug_assert!(!node.is_null());
-            if (*node).entry.key.user_key().eq(lsn_key.user_key()) {
-                kvs.insert(lsn_key.user_key().clone(), (*node).entry.value.clone());
+            let lsn_max = LSNKey::upper_bound(&(*node).entry.key);
+            let next_user_key =
+                SkipMap::<LSNKey<UK>, Value, RW_MODE>::find_first_ge_from_node(node, &lsn_max);
+
+            // the newest version visible at the LSN, if any
+            let lsn_key = LSNKey::new(user_key.clone(), key_end.lsn());
+            if (*node).entry.key.le(&lsn_key) {
+                let visible =
+                    SkipMap::<LSNKey<UK>, Value, RW_MODE>::find_last_le_from_node(node, &lsn_key);
+                kvs.insert(user_key.clone(), (*visible).entry.value.clone());
             }
+            node = next_user_key;
         }
     }
 }
@@ -159,7 +197,12 @@ impl<UK: MemKey> DBCommand<LSNKey<UK>, UK> for MutexSkipMapMemTable<LSNKey<UK>>
 
         let key_mem_size = key.mem_size() as i64;
         let value_len = value.len() as i64;
-        let mem_add = match self.inner_guarded.insert(key, value) {
+        let old_value = self.inner_guarded.insert(key, value);
+        self.tombstones.fetch_add(
+            tombstone_delta(old_value.as_ref(), value_len == 0),
+            Ordering::Release,
+        );
+        let mem_add = match old_value {
             Some(v) => (value_len as i64 - v.len() as i64),
             None => (key_mem_size + value_len),
         } * std::mem::size_of::<u8>() as i64;
@@ -171,7 +214,10 @@ impl<UK: MemKey> DBCommand<LSNKey<UK>, UK> for MutexSkipMapMemTable<LSNKey<UK>>
         let _guard = self.lock.lock().unwrap();
         let key_mem_size = key.mem_size();
 
-        let mem_add = match self.inner_guarded.insert(key, Value::default()) {
+        let old_value = self.inner_guarded.insert(key, Value::default());
+        self.tombstones
+            .fetch_add(tombstone_delta(old_value.as_ref(), true), Ordering::Release);
+        let mem_add = match old_value {
             Some(v) => -((v.len() * std::mem::size_of::<u8>()) as i64),
             None => (key_mem_size * std::mem::size_of::<u8>()) as i64,
         };
@@ -181,12 +227,21 @@ impl<UK: MemKey> DBCommand<LSNKey<UK>, UK> for MutexSkipMapMemTable<LSNKey<UK>>
 }
 
 impl<K: MemKey + 'static> InternalKeyValueIterator for MutexSkipMapMemTable<LSNKey<K>> {
+    /// Number of the user keys yielded by [InternalKeyValueIterator::kv_iter], which
+    /// keeps only the newest of the versions, so they are counted one by one.
     fn len(&self) -> usize {
-        self.inner_guarded.len()
+        self.kv_iter().count()
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.inner_guarded.is_empty()
     }
 
-    fn kv_iter(&self) -> Box<dyn Iterator<Item = (&InternalKey, &Value)>> {
-        Box::new(self.inner_guarded.iter_ptr().filter_map(|n| {
+    /// The lock is held until the iterator is dropped.
+    fn kv_iter(&self) -> Box<dyn Iterator<Item = (&InternalKey, &Value)> + '_> {
+        let guard = self.lock.lock().unwrap();
+        let iter = self.inner_guarded.iter_ptr().filter_map(|n| {
             debug_assert!(!n.is_null());
             unsafe {
                 let next = (*n).get_next(0);
@@ -200,12 +255,18 @@ impl<K: MemKey + 'static> InternalKeyValueIterator for MutexSkipMapMemTable<LSNK
                     }
                 }
             }
-        }))
+        });
+        Box::new(GuardedIter {
+            _guard: guard,
+            iter,
+        })
     }
 }
 
 impl<UK: 'static + MemKey> MemTable<LSNKey<UK>, UK> for MutexSkipMapMemTable<LSNKey<UK>> {
     fn merge(&self, kvs: SrSwSkipMap<LSNKey<UK>, Value>, mem_size: u64) {
+        self.tombstones
+            .fetch_add(count_tombstones(&kvs), Ordering::Release);
         let _guard = self.lock.lock().unwrap();
         self.mem_usage.fetch_add(mem_size as i64, Ordering::Release);
         self.inner_guarded.merge(kvs);
@@ -216,12 +277,32 @@ impl<UK: 'static + MemKey> MemTable<LSNKey<UK>, UK> for MutexSkipMapMemTable<LSN
         debug_assert!(mem_usage >= 0, "mem_usage: {}", mem_usage);
         mem_usage as u64
     }
+
+    fn tombstone_count(&self) -> u64 {
+        self.tombstones.load(Ordering::Acquire).max(0) as u64
+    }
+
+    fn versions_in_range(
+        &self,
+        key_start: &LSNKey<UK>,
+        key_end: &LSNKey<UK>,
+    ) -> Vec<(LSNKey<UK>, Value)> {
+        let _guard = self.lock.lock().unwrap();
+        let first = LSNKey::new(key_start.user_key().clone(), LSN::MIN);
+        self.inner_guarded
+            .iter_ptr_from(&first)
+            .map(|node| unsafe { &(*node).entry })
+            .take_while(|entry| entry.key.user_key() <= key_end.user_key())
+            .map(|entry| (entry.key.clone(), entry.value.clone()))
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod internal_key_tests {
     use crate::db::DBCommand;
-    use crate::memory::MutexSkipMapMemTable;
+    use crate::hash::murmur_hash;
+    use crate::memory::{MrMwSkipMapMemTable, MutexSkipMapMemTable, SkipMapMemTable};
 
     #[test]
     fn test_insert() {
@@ -239,6 +320,34 @@ mod internal_key_tests {
         table.remove(one.clone()).unwrap();
         assert_eq!(table.get(&one).unwrap().unwrap(), vec![]);
     }
+
+    #[test]
+    fn test_get_with() {
+        let large_value: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = murmur_hash(&large_value, 0x87654321);
+
+        let table = MutexSkipMapMemTable::default();
+        table.set(Vec::from("large"), large_value.clone()).unwrap();
+        assert_eq!(
+            table
+                .get_with(&Vec::from("large"), |v| murmur_hash(v, 0x87654321))
+                .unwrap(),
+            Some(expected)
+        );
+        assert_eq!(
+            table.get_with(&Vec::from("absent"), |v| v.len()).unwrap(),
+            None
+        );
+
+        let table = MrMwSkipMapMemTable::default();
+        table.set(Vec::from("large"), large_value).unwrap();
+        assert_eq!(
+            table
+                .get_with(&Vec::from("large"), |v| murmur_hash(v, 0x87654321))
+                .unwrap(),
+            Some(expected)
+        );
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,170 @@
+//! Cache of the uncompressed data blocks of sstables, which may be shared by several
+//! databases. See [crate::db::options::Options::block_cache].
+
+use crate::cache::ShardLRUCache;
+use crate::hash::murmur_hash;
+use crate::sstable::TableID;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Identifies a data block in a [BlockCache].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BlockKey {
+    /// Assigned to each database opened with the cache, so that the blocks of different
+    /// databases sharing the cache never collide.
+    pub cache_id: u64,
+    pub table_id: TableID,
+    /// Offset of the data block in the sstable.
+    pub offset: u32,
+}
+
+impl BlockKey {
+    /// Hash of the key, which is also the shard of [LRUBlockCache] it belongs to.
+    pub fn cache_hash(&self) -> u32 {
+        const SEED: u32 = 0x5b1c_4e3d;
+        let mut bytes = [0u8; 20];
+        bytes[..8].copy_from_slice(&self.cache_id.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.table_id.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.offset.to_le_bytes());
+        murmur_hash(&bytes, SEED)
+    }
+}
+
+/// Cache of data blocks. Point lookups consult it before reading a data block from an
+/// sstable, and insert the blocks they read. Blocks are immutable, so an implementation
+/// is free to evict any of them at any time.
+pub trait BlockCache: Send + Sync + fmt::Debug {
+    fn get(&self, key: &BlockKey) -> Option<Arc<Vec<u8>>>;
+    fn insert(&self, key: BlockKey, block: Arc<Vec<u8>>);
+
+    /// Remove a block of an sstable which is deleted, e.g. by compaction. Does nothing
+    /// by default, leaving the block to be evicted.
+    fn erase(&self, _key: &BlockKey) {}
+
+    /// Insert `block` if it is not cached, and keep it cached until it is unpinned as
+    /// many times as it is pinned, see [crate::db::DB::pin_range]. Return false without
+    /// pinning if the cache has no room for more pinned blocks. Pinning is not supported
+    /// by default, so it always returns false.
+    fn pin(&self, _key: BlockKey, _block: Arc<Vec<u8>>) -> bool {
+        false
+    }
+
+    /// Undo a [BlockCache::pin] of `key`.
+    fn unpin(&self, _key: &BlockKey) {}
+
+    /// Whether a block can be inserted without evicting other blocks.
+    /// [crate::db::DB::warm_cache] stops once it is false. Always true by default.
+    fn has_room(&self) -> bool {
+        true
+    }
+}
+
+/// The built-in [BlockCache], backed by a [ShardLRUCache].
+#[derive(Debug, Default)]
+pub struct LRUBlockCache {
+    cache: ShardLRUCache<BlockKey, Arc<Vec<u8>>>,
+}
+
+impl BlockCache for LRUBlockCache {
+    fn get(&self, key: &BlockKey) -> Option<Arc<Vec<u8>>> {
+        self.cache
+            .get(key, key.cache_hash())
+            .map(|block| Arc::clone(&block))
+    }
+
+    fn insert(&self, key: BlockKey, block: Arc<Vec<u8>>) {
+        let hash = key.cache_hash();
+        self.cache.upsert(key, block, hash);
+    }
+
+    fn erase(&self, key: &BlockKey) {
+        self.cache.erase(key, key.cache_hash());
+    }
+
+    fn pin(&self, key: BlockKey, block: Arc<Vec<u8>>) -> bool {
+        let hash = key.cache_hash();
+        self.cache.pin(key, block, hash)
+    }
+
+    fn unpin(&self, key: &BlockKey) {
+        self.cache.unpin(key, key.cache_hash());
+    }
+
+    fn has_room(&self) -> bool {
+        self.cache.has_room_in_all_shards()
+    }
+}
+
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A [BlockCache] together with the [BlockKey::cache_id] of one database.
+#[derive(Clone, Debug)]
+pub(crate) struct SharedBlockCache {
+    cache: Arc<dyn BlockCache>,
+    cache_id: u64,
+    /// Offsets of the blocks inserted of each sstable, to erase them when the sstable
+    /// is deleted. Some of them may be evicted already.
+    inserted: Arc<Mutex<HashMap<TableID, HashSet<u32>>>>,
+}
+
+impl SharedBlockCache {
+    pub(crate) fn new(cache: Arc<dyn BlockCache>) -> SharedBlockCache {
+        SharedBlockCache {
+            cache,
+            cache_id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            inserted: Arc::default(),
+        }
+    }
+
+    fn key(&self, table_id: TableID, offset: u32) -> BlockKey {
+        BlockKey {
+            cache_id: self.cache_id,
+            table_id,
+            offset,
+        }
+    }
+
+    pub(crate) fn get(&self, table_id: TableID, offset: u32) -> Option<Arc<Vec<u8>>> {
+        self.cache.get(&self.key(table_id, offset))
+    }
+
+    pub(crate) fn insert(&self, table_id: TableID, offset: u32, block: Arc<Vec<u8>>) {
+        self.inserted
+            .lock()
+            .unwrap()
+            .entry(table_id)
+            .or_default()
+            .insert(offset);
+        self.cache.insert(self.key(table_id, offset), block)
+    }
+
+    /// Pin the block at `offset` of `table_id`, see [BlockCache::pin].
+    pub(crate) fn pin(&self, table_id: TableID, offset: u32, block: Arc<Vec<u8>>) -> bool {
+        self.inserted
+            .lock()
+            .unwrap()
+            .entry(table_id)
+            .or_default()
+            .insert(offset);
+        self.cache.pin(self.key(table_id, offset), block)
+    }
+
+    pub(crate) fn unpin(&self, table_id: TableID, offset: u32) {
+        self.cache.unpin(&self.key(table_id, offset))
+    }
+
+    /// Erase the blocks of the deleted sstable `table_id`.
+    pub(crate) fn erase_table(&self, table_id: TableID) {
+        let offsets = self.inserted.lock().unwrap().remove(&table_id);
+        for offset in offsets.into_iter().flatten() {
+            self.cache.erase(&self.key(table_id, offset));
+        }
+    }
+
+    #[inline]
+    pub(crate) fn has_room(&self) -> bool {
+        self.cache.has_room()
+    }
+}
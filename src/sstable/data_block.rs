@@ -2,146 +2,254 @@ use crate::byteutils::u32_from_le_bytes;
 use crate::collections::skip_list::skipmap::SrSwSkipMap;
 use crate::db::key_types::{InternalKey, MemKey};
 use crate::db::Value;
+use crate::error::KVLiteError;
+use crate::sstable::CompressionType;
+use crate::Result;
 use std::cmp::Ordering;
 use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
 
 pub struct DataBlock {
-    data: Vec<u8>,
-    num_records: i64,
-    data_idx_offset: usize,
+    /// Uncompressed bytes of the block, shared with the block cache if there is one.
+    data: Arc<Vec<u8>>,
+    num_restarts: usize,
+    /// Offset of the restart points, which is also the end of the key-value entries.
+    restarts_offset: usize,
 }
 
 impl DataBlock {
+    /// Bytes of the uncompressed block held in memory.
+    #[inline]
+    pub(super) fn memory_size(&self) -> usize {
+        self.data.len()
+    }
+
     pub(super) fn from_reader(
         reader: &mut (impl Read + Seek),
         start: u32,
         length: u32,
         index_offset_uncompressed: u32,
+        compression: CompressionType,
     ) -> DataBlock {
         debug_assert!(start < index_offset_uncompressed);
-        reader.seek(SeekFrom::Start(start as u64)).unwrap();
+        Self::try_from_reader(
+            reader,
+            start,
+            length,
+            index_offset_uncompressed,
+            compression,
+        )
+        .unwrap()
+    }
+
+    /// Block whose uncompressed bytes, already checked when they were read, are taken
+    /// from a [crate::sstable::block_cache::BlockCache].
+    pub(super) fn from_cached(
+        data: Arc<Vec<u8>>,
+        start: u32,
+        index_offset_uncompressed: u32,
+    ) -> DataBlock {
+        let restarts_offset = (index_offset_uncompressed - start) as usize;
+        DataBlock {
+            num_restarts: (data.len() - restarts_offset) / std::mem::size_of::<u32>(),
+            data,
+            restarts_offset,
+        }
+    }
+
+    /// Uncompressed bytes of the block, to be inserted into a block cache.
+    #[inline]
+    pub(super) fn data(&self) -> &Arc<Vec<u8>> {
+        &self.data
+    }
+
+    /// Like [DataBlock::from_reader], but return an error instead of panicking
+    /// if the block cannot be read or decompressed, or its record offsets are malformed.
+    /// A key or value length running past the end of the entries is reported as
+    /// [KVLiteError::Corruption].
+    pub(super) fn try_from_reader(
+        reader: &mut (impl Read + Seek),
+        start: u32,
+        length: u32,
+        index_offset_uncompressed: u32,
+        compression: CompressionType,
+    ) -> Result<DataBlock> {
+        reader.seek(SeekFrom::Start(start as u64))?;
         let mut data_block = vec![0u8; length as usize];
-        reader.read_exact(data_block.as_mut_slice()).unwrap();
-        #[cfg(feature = "snappy_compression")]
-        {
+        reader.read_exact(data_block.as_mut_slice())?;
+        if compression == CompressionType::Snappy {
             let mut decoder = snap::raw::Decoder::new();
-            data_block = decoder.decompress_vec(&data_block).unwrap();
+            data_block = decoder
+                .decompress_vec(&data_block)
+                .map_err(|e| KVLiteError::Corruption(format!("data block: {}", e)))?;
         }
 
-        debug_assert_eq!(
-            (start as usize + data_block.len() - index_offset_uncompressed as usize)
-                % std::mem::size_of::<u32>(),
-            0
-        );
-        let data_block_length = data_block.len() as u32;
-        DataBlock {
-            data: data_block,
-            num_records: (start + data_block_length - index_offset_uncompressed) as i64
-                / std::mem::size_of::<u32>() as i64,
-            data_idx_offset: (index_offset_uncompressed - start) as usize,
+        let data_end = start as usize + data_block.len();
+        if (index_offset_uncompressed as usize) <= (start as usize)
+            || (index_offset_uncompressed as usize) >= data_end
+            || (data_end - index_offset_uncompressed as usize) % std::mem::size_of::<u32>() != 0
+        {
+            return Err(KVLiteError::Corruption(
+                "invalid restart points of data block".into(),
+            ));
+        }
+        let restarts_offset = (index_offset_uncompressed - start) as usize;
+        let data_block = DataBlock {
+            num_restarts: (data_block.len() - restarts_offset) / std::mem::size_of::<u32>(),
+            data: Arc::new(data_block),
+            restarts_offset,
+        };
+        // restart points are strictly increasing, and the first one is the first entry
+        let mut prev = None;
+        for idx in 0..data_block.num_restarts {
+            let restart = data_block.restart_point(idx);
+            if restart >= restarts_offset || prev.map_or(restart != 0, |p| restart <= p) {
+                return Err(KVLiteError::Corruption(
+                    "invalid restart points of data block".into(),
+                ));
+            }
+            prev = Some(restart);
+        }
+        data_block.check_entries()?;
+        Ok(data_block)
+    }
+
+    /// Check that the key and value lengths of every entry stay inside the entries,
+    /// and every restart point is at the start of an entry.
+    fn check_entries(&self) -> Result<()> {
+        let mut offset = 0;
+        let mut restart_idx = 0;
+        while offset < self.restarts_offset {
+            if restart_idx < self.num_restarts && self.restart_point(restart_idx) == offset {
+                restart_idx += 1;
+            }
+            let remain = self.restarts_offset - offset;
+            if remain < 8 {
+                return Err(KVLiteError::Corruption("data block is truncated".into()));
+            }
+            let key_length = u32_from_le_bytes(&self.data[offset..offset + 4]) as u64;
+            let value_length = u32_from_le_bytes(&self.data[offset + 4..offset + 8]) as u64;
+            if key_length + value_length > (remain - 8) as u64 {
+                return Err(KVLiteError::Corruption(format!(
+                    "key length {} and value length {} in data block are implausible",
+                    key_length, value_length
+                )));
+            }
+            offset += 8 + (key_length + value_length) as usize;
+        }
+        if restart_idx != self.num_restarts {
+            return Err(KVLiteError::Corruption(
+                "restart point of data block is not at an entry".into(),
+            ));
         }
+        Ok(())
     }
 
     #[allow(clippy::ptr_arg)]
     pub(super) fn get_value(&self, key: &InternalKey) -> Option<Value> {
+        self.search(key).0
+    }
+
+    /// Return the value of `key`, and the count of the entries read after the restart
+    /// point chosen by binary search, which is at most the restart interval.
+    fn search(&self, key: &[u8]) -> (Option<Value>, usize) {
+        let restart_idx = self.seek_restart(key);
+        let mut offset = self.restart_point(restart_idx);
+        let end = if restart_idx + 1 < self.num_restarts {
+            self.restart_point(restart_idx + 1)
+        } else {
+            self.restarts_offset
+        };
+        let mut entries_read = 0;
+        while offset < end {
+            let (key_read, value_read, next) = self.entry_at(offset);
+            entries_read += 1;
+            match key_read.cmp(key) {
+                Ordering::Less => offset = next,
+                Ordering::Equal => return (Some(Value::from(value_read)), entries_read),
+                Ordering::Greater => break,
+            }
+        }
+        (None, entries_read)
+    }
+
+    /// Return the index of the last restart point whose key is not greater than `key`,
+    /// or 0 if `key` is less than all the keys.
+    fn seek_restart(&self, key: &[u8]) -> usize {
+        // the key at `left` is not greater than `key` unless `left` is 0,
+        // and the keys at `right` and after are greater than `key`.
         let mut left = 0;
-        let mut right = self.num_records;
-        while left <= right {
+        let mut right = self.num_restarts;
+        while right - left > 1 {
             let mid = (left + right) / 2;
-            let record_start_offset = self.data_idx_offset + mid as usize * 4;
-
-            debug_assert!(
-                record_start_offset < self.data.len(),
-                "{}, {}",
-                record_start_offset,
-                self.data.len()
-            );
-            let record_start =
-                u32_from_le_bytes(&self.data[record_start_offset..record_start_offset + 4])
-                    as usize;
-            let key_length = u32_from_le_bytes(&self.data[record_start..record_start + 4]) as usize;
-            let key_start = record_start + 8;
-            let value_length = u32_from_le_bytes(&self.data[record_start + 4..key_start]) as usize;
-            let value_start = key_start + key_length;
-            let key_read = &self.data[key_start..value_start];
-            match key_read.cmp(key) {
-                Ordering::Less => left = mid + 1,
-                Ordering::Equal => {
-                    return Some(Value::from(
-                        &self.data[value_start..value_start + value_length],
-                    ))
-                }
-                Ordering::Greater => right = mid - 1,
+            let (key_read, _, _) = self.entry_at(self.restart_point(mid));
+            if key_read <= key {
+                left = mid;
+            } else {
+                right = mid;
             }
         }
-        None
+        left
     }
 
-    /// Return whether the data block remains keys.
-    pub(super) fn get_all_record_le<UK: MemKey>(
+    /// Insert all the key-values in [`key_start`, `key_end`] into `kvs`.
+    pub(super) fn get_all_record_in<UK: MemKey>(
         &self,
-        key: &InternalKey,
+        key_start: &InternalKey,
+        key_end: &InternalKey,
         kvs: &mut SrSwSkipMap<UK, Value>,
-    ) -> bool {
-        let mut left = 0;
-        let mut right = self.num_records;
-        while left <= right {
-            let mid = (left + right + 1) / 2;
-            let record_start_offset = self.data_idx_offset + mid as usize * 4;
-
-            debug_assert!(
-                record_start_offset < self.data.len(),
-                "{}, {}",
-                record_start_offset,
-                self.data.len()
-            );
-            let record_start =
-                u32_from_le_bytes(&self.data[record_start_offset..record_start_offset + 4])
-                    as usize;
-            let key_length = u32_from_le_bytes(&self.data[record_start..record_start + 4]) as usize;
-            let key_start = record_start + 8;
-            let value_start = key_start + key_length;
-            let key_read = &self.data[key_start..value_start];
-
-            match key_read.cmp(key) {
-                Ordering::Less => left = mid,
-                Ordering::Equal => {
-                    left = mid;
-                    break;
-                }
-                Ordering::Greater => right = mid - 1,
+    ) {
+        let mut offset = self.restart_point(self.seek_restart(key_start));
+        while offset < self.restarts_offset {
+            let (key_read, value_read, next) = self.entry_at(offset);
+            if key_read > key_end.as_slice() {
+                return;
             }
+            if key_read >= key_start.as_slice() {
+                let key_read = InternalKey::from(key_read);
+                kvs.insert(key_read.into(), Value::from(value_read));
+            }
+            offset = next;
         }
-        for i in 0..=(left as usize) {
-            let (key_read, value_read) = self.key_value_at(i);
-            kvs.insert(key_read.into(), value_read);
-        }
-        right < self.num_records
     }
 
-    fn key_value_at(&self, idx: usize) -> (InternalKey, Value) {
-        let record_start_offset = self.data_idx_offset + idx as usize * 4;
+    #[inline]
+    fn restart_point(&self, idx: usize) -> usize {
+        debug_assert!(idx < self.num_restarts);
+        let offset = self.restarts_offset + idx * 4;
+        u32_from_le_bytes(&self.data[offset..offset + 4]) as usize
+    }
 
+    /// Return the key, the value and the offset of the next entry of the entry at `offset`.
+    fn entry_at(&self, offset: usize) -> (&[u8], &[u8], usize) {
         debug_assert!(
-            record_start_offset < self.data.len(),
+            offset < self.restarts_offset,
             "{}, {}",
-            record_start_offset,
-            self.data.len()
+            offset,
+            self.restarts_offset
         );
-        let record_start =
-            u32_from_le_bytes(&self.data[record_start_offset..record_start_offset + 4]) as usize;
-        let key_length = u32_from_le_bytes(&self.data[record_start..record_start + 4]) as usize;
-        let key_start = record_start + 8;
-        let value_length = u32_from_le_bytes(&self.data[record_start + 4..key_start]) as usize;
+        let key_length = u32_from_le_bytes(&self.data[offset..offset + 4]) as usize;
+        let key_start = offset + 8;
+        let value_length = u32_from_le_bytes(&self.data[offset + 4..key_start]) as usize;
         let value_start = key_start + key_length;
-        let key_read = InternalKey::from(&self.data[key_start..value_start]);
-        let value_read = Value::from(&self.data[value_start..value_start + value_length]);
-        (key_read, value_read)
+        let next = value_start + value_length;
+        (
+            &self.data[key_start..value_start],
+            &self.data[value_start..next],
+            next,
+        )
     }
+}
 
-    #[inline]
-    pub fn len(&self) -> usize {
-        self.num_records as usize
+impl DataBlock {
+    /// Iterate the key-values from the last one to the first one. The entries between
+    /// two restart points are decoded forward, then yielded in reverse.
+    pub(super) fn into_rev_iter(self) -> DataBlockRevIter {
+        DataBlockRevIter {
+            next_restart_idx: self.num_restarts,
+            data_block: self,
+            buffered: Vec::new(),
+        }
     }
 }
 
@@ -152,26 +260,138 @@ impl IntoIterator for DataBlock {
     fn into_iter(self) -> Self::IntoIter {
         DataBlockIter {
             data_block: self,
-            idx: 0,
+            offset: 0,
         }
     }
 }
 
 pub struct DataBlockIter {
     data_block: DataBlock,
-    idx: usize,
+    /// Offset of the next entry.
+    offset: usize,
 }
 
 impl Iterator for DataBlockIter {
     type Item = (InternalKey, Value);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.idx < self.data_block.len() {
-            let record = self.data_block.key_value_at(self.idx);
-            self.idx += 1;
-            Some(record)
+        if self.offset < self.data_block.restarts_offset {
+            let (key, value, next) = self.data_block.entry_at(self.offset);
+            self.offset = next;
+            Some((InternalKey::from(key), Value::from(value)))
         } else {
             None
         }
     }
 }
+
+pub struct DataBlockRevIter {
+    data_block: DataBlock,
+    /// The restart interval before this one is decoded next.
+    next_restart_idx: usize,
+    /// Key-values of the current restart interval which are not yielded yet.
+    buffered: Vec<(InternalKey, Value)>,
+}
+
+impl Iterator for DataBlockRevIter {
+    type Item = (InternalKey, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.buffered.pop() {
+            return Some(item);
+        }
+        if self.next_restart_idx == 0 {
+            return None;
+        }
+        self.next_restart_idx -= 1;
+        let data_block = &self.data_block;
+        let mut offset = data_block.restart_point(self.next_restart_idx);
+        let end = if self.next_restart_idx + 1 < data_block.num_restarts {
+            data_block.restart_point(self.next_restart_idx + 1)
+        } else {
+            data_block.restarts_offset
+        };
+        while offset < end {
+            let (key, value, next) = data_block.entry_at(offset);
+            self.buffered
+                .push((InternalKey::from(key), Value::from(value)));
+            offset = next;
+        }
+        self.buffered.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::options::Options;
+    use crate::sstable::data_block::DataBlock;
+    use crate::sstable::footer::Footer;
+    use crate::sstable::index_block::IndexBlock;
+    use crate::sstable::table_handle::{TableReadHandle, TableWriteHandle};
+    use crate::sstable::CompressionType;
+
+    #[test]
+    fn test_restart_interval() {
+        const INTERVAL: usize = 5;
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("1")).unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+
+        let mut write_handle = TableWriteHandle::new(
+            &path,
+            1,
+            1,
+            100,
+            &Options {
+                compression: CompressionType::NoCompression,
+                block_restart_interval: INTERVAL,
+                ..Options::default()
+            },
+        );
+        let kvs = (0..100)
+            .map(|i| {
+                (
+                    format!("key{:03}", i * 2).into_bytes(),
+                    format!("value{}", i * 2).into_bytes(),
+                )
+            })
+            .collect();
+        write_handle.write_sstable_from_vec(kvs).unwrap();
+        write_handle.rename();
+        let read_handle = TableReadHandle::open(&path, 1, 1, &Options::default());
+
+        let mut reader = read_handle.create_buf_reader_with_pos();
+        let footer = Footer::load_footer(&mut reader).unwrap();
+        let index_block = IndexBlock::load_index(&mut reader, &footer);
+        assert_eq!(index_block.indexes.len(), 1);
+        let index = &index_block.indexes[0];
+        let data_block = DataBlock::from_reader(
+            &mut reader,
+            index.0,
+            index.1,
+            index.2,
+            footer.compression_type,
+        );
+        assert_eq!(data_block.num_restarts, 100 / INTERVAL);
+
+        for i in 0..200 {
+            let (value, entries_read) = data_block.search(format!("key{:03}", i).as_bytes());
+            if i % 2 == 0 {
+                assert_eq!(value, Some(format!("value{}", i).into_bytes()));
+            } else {
+                assert!(value.is_none());
+            }
+            assert!((1..=INTERVAL).contains(&entries_read), "{}", entries_read);
+        }
+        // keys out of the range of the block
+        for key in ["a", "key", "key999"] {
+            let (value, entries_read) = data_block.search(key.as_bytes());
+            assert!(value.is_none());
+            assert!((1..=INTERVAL).contains(&entries_read), "{}", entries_read);
+        }
+
+        let kvs: Vec<_> = data_block.into_iter().collect();
+        assert_eq!(kvs.len(), 100);
+        assert!(kvs.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+}
@@ -0,0 +1,129 @@
+//! Dictionary compression of values.
+//!
+//! If [Options::dictionary_compression] is true, a zstd dictionary is trained on the
+//! values of the first flush and saved to [DICTIONARY_FILE] in the database directory.
+//! From then on, flush, bulk ingestion and compaction compress each value of the
+//! sstables they write against it, which suits many similar small values that
+//! compress poorly one block at a time. The footer of such an sstable records that its
+//! values are compressed, and each of its values is stored as:
+//!
+//! ```text
+//! +---------------------+------------+
+//! | uncompressed length | zstd frame |
+//! +---------------------+------------+
+//!           u32
+//! ```
+//!
+//! Tombstones are still empty. The dictionary is never replaced, so every sstable
+//! compressed with it stays readable, even if the option is turned off later.
+//! Values are not compressed with the dictionary if they are separated, see
+//! [crate::sstable::value_log].
+//!
+//! [Options::dictionary_compression]: crate::db::options::Options::dictionary_compression
+
+use crate::db::Value;
+use crate::error::KVLiteError;
+use crate::ioutils::replace_file;
+use crate::Result;
+use std::convert::TryInto;
+use std::fmt;
+use std::path::Path;
+use zstd::bulk::{Compressor, Decompressor};
+use zstd::dict::DecoderDictionary;
+
+/// Name of the file in the database directory which holds the dictionary.
+pub const DICTIONARY_FILE: &str = "DICTIONARY";
+
+/// Max size of a trained dictionary.
+const DICTIONARY_MAX_BYTES: usize = 16 * 1024;
+/// Max count of the values the dictionary is trained on.
+const MAX_SAMPLES: usize = 10000;
+/// The dictionary is not trained on fewer values than this.
+const MIN_SAMPLES: usize = 16;
+const COMPRESSION_LEVEL: i32 = 3;
+const LENGTH_BYTES: usize = 4;
+
+/// A zstd dictionary shared by all the sstables of a database.
+pub struct ValueDictionary {
+    raw: Vec<u8>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl fmt::Debug for ValueDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueDictionary")
+            .field("len", &self.raw.len())
+            .finish()
+    }
+}
+
+impl ValueDictionary {
+    fn new(raw: Vec<u8>) -> ValueDictionary {
+        let decoder = DecoderDictionary::copy(&raw);
+        ValueDictionary { raw, decoder }
+    }
+
+    /// Train a dictionary on the non-empty `values`. Return `None` if there are too
+    /// few of them or the training fails, e.g. because they have nothing in common.
+    pub(crate) fn train<'a>(values: impl Iterator<Item = &'a Value>) -> Option<ValueDictionary> {
+        let samples: Vec<&Value> = values
+            .filter(|value| !value.is_empty())
+            .take(MAX_SAMPLES)
+            .collect();
+        if samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        match zstd::dict::from_samples(&samples, DICTIONARY_MAX_BYTES) {
+            Ok(raw) => Some(ValueDictionary::new(raw)),
+            Err(e) => {
+                warn!("failed to train the value dictionary: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Load the dictionary saved in `db_path`, if there is one.
+    pub(crate) fn load(db_path: &str) -> Result<Option<ValueDictionary>> {
+        match std::fs::read(Path::new(db_path).join(DICTIONARY_FILE)) {
+            Ok(raw) => Ok(Some(ValueDictionary::new(raw))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the dictionary to `db_path`. It is durable when it returns.
+    pub(crate) fn save(&self, db_path: &str) -> Result<()> {
+        replace_file(Path::new(db_path), DICTIONARY_FILE, &self.raw)
+    }
+
+    /// Compressor of the values of one sstable.
+    pub(crate) fn compressor(&self) -> Result<Compressor<'static>> {
+        Ok(Compressor::with_dictionary(COMPRESSION_LEVEL, &self.raw)?)
+    }
+
+    /// Decompress a value written by [compress_value].
+    pub(crate) fn decompress(&self, stored: Value) -> Result<Value> {
+        if stored.is_empty() {
+            return Ok(stored);
+        }
+        if stored.len() < LENGTH_BYTES {
+            return Err(KVLiteError::Corruption("truncated compressed value".into()));
+        }
+        let length = u32::from_le_bytes(stored[..LENGTH_BYTES].try_into().unwrap()) as usize;
+        let mut decompressor = Decompressor::with_prepared_dictionary(&self.decoder)?;
+        Ok(decompressor.decompress(&stored[LENGTH_BYTES..], length)?)
+    }
+}
+
+/// Compress `value` with a compressor made by [ValueDictionary::compressor], keeping
+/// tombstones empty.
+pub(crate) fn compress_value(compressor: &mut Compressor, value: Value) -> Result<Value> {
+    if value.is_empty() {
+        return Ok(value);
+    }
+    let frame = compressor.compress(&value)?;
+    let mut stored = Vec::with_capacity(LENGTH_BYTES + frame.len());
+    stored.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    stored.extend_from_slice(&frame);
+    Ok(stored)
+}
@@ -18,6 +18,14 @@ pub(super) fn load_filter_block(
     BloomFilter(arr)
 }
 
+/// Bytes of `key` added to and probed in a filter block of the key prefixes of
+/// `prefix_length` bytes, see [crate::db::options::BloomMode::Prefix]. Keys shorter
+/// than the prefixes are added whole, so that point lookups of them still work.
+#[inline]
+pub(super) fn bloom_prefix(key: &[u8], prefix_length: u16) -> &[u8] {
+    key.get(..prefix_length as usize).unwrap_or(key)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::bloom::BloomFilter;
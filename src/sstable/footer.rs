@@ -1,75 +1,172 @@
 use crate::error::KVLiteError;
-use crate::ioutils::BufWriterWithPos;
+use crate::sstable::{ChecksumType, CompressionType};
 use crate::Result;
-use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
 
-pub const FOOTER_MAGIC_NUMBER: u32 = 0xdb991122;
-pub const FOOTER_BYTE_SIZE: i64 = 20;
+/// Magic number of the footers recording their format versions, see [FORMAT_VERSION].
+pub const FOOTER_MAGIC_NUMBER: u32 = 0xdb991123;
+/// Byte size of the footer of [FORMAT_VERSION].
+pub const FOOTER_BYTE_SIZE: i64 = 28;
+/// Format version of the sstables written. The readers dispatch on the version of
+/// each table, so the tables of the older versions remain readable.
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Magic number of the version 1 footers, which have no format version field.
+const FOOTER_V1_MAGIC_NUMBER: u32 = 0xdb991122;
+/// Byte size of the version 1 footers, the smallest of all the versions. They hold
+/// only the block positions and `kv_total`: the tables have neither checksums nor
+/// prefix filters, and are compressed only if feature `snappy_compression` is enabled.
+pub const FOOTER_V1_BYTE_SIZE: i64 = 20;
+
+/// Set in the flags byte if the values of the table are separated, see
+/// [crate::sstable::value_log].
+const SEPARATED_VALUES_FLAG: u8 = 0x80;
+/// Set in the flags byte if the filter block holds key prefixes instead of whole keys,
+/// see [crate::db::options::BloomMode::Prefix].
+const PREFIX_BLOOM_FLAG: u8 = 0x40;
+/// Set in the flags byte if the values of the table are compressed with the dictionary
+/// of the database, see [crate::sstable::dictionary].
+const DICTIONARY_FLAG: u8 = 0x20;
 
 pub(crate) struct Footer {
     pub(crate) index_block_offset: u32,
     pub(crate) index_block_length: u32,
     pub(crate) filter_length: u32,
     pub(crate) kv_total: u32,
+    pub(crate) compression_type: CompressionType,
+    pub(crate) checksum_type: ChecksumType,
+    /// Length of the key prefixes added to the prefix filter block, 0 if there is no such block.
+    /// If `prefix_bloom` is set, it is the length of the prefixes in the filter block instead.
+    pub(crate) prefix_length: u16,
+    /// Whether the filter block holds the key prefixes of `prefix_length` bytes instead of
+    /// the whole keys, in which case there is no prefix filter block.
+    pub(crate) prefix_bloom: bool,
+    /// Whether the values are stored as described in [crate::sstable::value_log].
+    pub(crate) separated_values: bool,
+    /// Whether the values are stored as described in [crate::sstable::dictionary].
+    pub(crate) dictionary_values: bool,
+    /// Format version of the table, [FORMAT_VERSION] for the tables written.
+    pub(crate) format_version: u16,
 }
 
 impl Footer {
+    /// Write the footer in the layout of [FORMAT_VERSION].
     pub(crate) fn write_to_file(&self, writer: &mut (impl Write + Seek)) -> Result<()> {
+        debug_assert_eq!(self.format_version, FORMAT_VERSION);
         writer.write_all(&self.index_block_offset.to_le_bytes())?;
         writer.write_all(&self.index_block_length.to_le_bytes())?;
         writer.write_all(&self.filter_length.to_le_bytes())?;
         writer.write_all(&self.kv_total.to_le_bytes())?;
+        writer.write_all(&[self.compression_type as u8, self.checksum_type as u8])?;
+        writer.write_all(&self.prefix_length.to_le_bytes())?;
+        let mut flags = 0;
+        if self.separated_values {
+            flags |= SEPARATED_VALUES_FLAG;
+        }
+        if self.prefix_bloom {
+            flags |= PREFIX_BLOOM_FLAG;
+        }
+        if self.dictionary_values {
+            flags |= DICTIONARY_FLAG;
+        }
+        writer.write_all(&[flags, 0])?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
         writer.write_all(&FOOTER_MAGIC_NUMBER.to_le_bytes())?;
         Ok(())
     }
 
+    /// Load the footer of any format version, told by the magic number.
     pub(crate) fn load_footer(reader: &mut (impl Read + Seek)) -> Result<Footer> {
-        reader.seek(SeekFrom::End(-FOOTER_BYTE_SIZE))?;
-
-        let mut buffer = [0u8; 20];
-        reader.read_exact(&mut buffer).unwrap();
-
-        let mut index_block_offset = [0u8; 4];
-        index_block_offset.clone_from_slice(&buffer[0..4]);
-
-        let mut index_block_length = [0u8; 4];
-        index_block_length.clone_from_slice(&buffer[4..8]);
+        reader.seek(SeekFrom::End(-4))?;
+        let mut magic_number = [0u8; 4];
+        reader.read_exact(&mut magic_number)?;
+        match u32::from_le_bytes(magic_number) {
+            FOOTER_MAGIC_NUMBER => {
+                let mut buffer = [0u8; FOOTER_BYTE_SIZE as usize];
+                reader.seek(SeekFrom::End(-FOOTER_BYTE_SIZE))?;
+                reader.read_exact(&mut buffer)?;
+                let format_version = u16::from_le_bytes([buffer[22], buffer[23]]);
+                match format_version {
+                    FORMAT_VERSION => Self::decode(&buffer, format_version),
+                    _ => Err(KVLiteError::Custom(format!(
+                        "unsupported sstable format version {}",
+                        format_version
+                    ))),
+                }
+            }
+            FOOTER_V1_MAGIC_NUMBER => {
+                let mut buffer = [0u8; FOOTER_V1_BYTE_SIZE as usize];
+                let footer_offset = reader.seek(SeekFrom::End(-FOOTER_V1_BYTE_SIZE))?;
+                reader.read_exact(&mut buffer)?;
+                let footer = Self::decode_v1(&buffer);
+                // The footer is right after the filter block in version 1. The 24-byte
+                // footers of the unreleased layouts share the magic number, and their
+                // fields read as version 1 do not end there.
+                let filter_end = footer.index_block_offset as u64
+                    + footer.index_block_length as u64
+                    + footer.filter_length as u64;
+                if filter_end != footer_offset {
+                    return Err(KVLiteError::Custom(
+                        "unsupported sstable layout of footer magic number 0xdb991122".into(),
+                    ));
+                }
+                Ok(footer)
+            }
+            _ => Err(KVLiteError::Custom("invalid footer magic number".into())),
+        }
+    }
 
-        let mut filter_length = [0u8; 4];
-        filter_length.clone_from_slice(&buffer[8..12]);
+    /// Decode the footer of `format_version` from `buffer`.
+    fn decode(buffer: &[u8], format_version: u16) -> Result<Footer> {
+        let compression_type = CompressionType::from_u8(buffer[16])
+            .ok_or_else(|| KVLiteError::Custom("unknown compression type".into()))?;
+        let checksum_type = ChecksumType::from_u8(buffer[17])
+            .ok_or_else(|| KVLiteError::Custom("unknown checksum type".into()))?;
+        let flags = buffer[20];
 
-        let mut kv_total = [0u8; 4];
-        kv_total.clone_from_slice(&buffer[12..16]);
+        Ok(Footer {
+            compression_type,
+            checksum_type,
+            prefix_length: u16::from_le_bytes([buffer[18], buffer[19]]),
+            prefix_bloom: flags & PREFIX_BLOOM_FLAG != 0,
+            separated_values: flags & SEPARATED_VALUES_FLAG != 0,
+            dictionary_values: flags & DICTIONARY_FLAG != 0,
+            format_version,
+            ..Self::decode_v1(buffer)
+        })
+    }
 
-        let footer = Footer {
-            index_block_offset: u32::from_le_bytes(index_block_offset),
-            index_block_length: u32::from_le_bytes(index_block_length),
-            filter_length: u32::from_le_bytes(filter_length),
-            kv_total: u32::from_le_bytes(kv_total),
+    /// Decode the footer of version 1 from `buffer`. The fields it has are kept at the
+    /// same place by all the later versions.
+    fn decode_v1(buffer: &[u8]) -> Footer {
+        let u32_at = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.clone_from_slice(&buffer[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
         };
-
-        // validate magic number
-        if buffer[16..20] != FOOTER_MAGIC_NUMBER.to_le_bytes() {
-            return Err(KVLiteError::Custom("invalid footer magic number".into()));
+        Footer {
+            index_block_offset: u32_at(0),
+            index_block_length: u32_at(4),
+            filter_length: u32_at(8),
+            kv_total: u32_at(12),
+            // version 1 compressed the data blocks by the feature of the build
+            compression_type: CompressionType::default(),
+            checksum_type: ChecksumType::NoChecksum,
+            prefix_length: 0,
+            prefix_bloom: false,
+            separated_values: false,
+            dictionary_values: false,
+            format_version: 1,
         }
-
-        Ok(footer)
     }
-}
 
-pub(super) fn write_footer(
-    index_block_offset: u32,
-    index_block_length: u32,
-    writer: &mut BufWriterWithPos<File>,
-    filter_length: u32,
-    kv_total: u32,
-) {
-    let footer = Footer {
-        index_block_offset,
-        index_block_length,
-        filter_length,
-        kv_total,
-    };
-    footer.write_to_file(writer).unwrap();
+    /// Byte size of the footer in the layout of its format version.
+    #[inline]
+    pub(crate) fn byte_size(&self) -> u64 {
+        if self.format_version == 1 {
+            FOOTER_V1_BYTE_SIZE as u64
+        } else {
+            FOOTER_BYTE_SIZE as u64
+        }
+    }
 }
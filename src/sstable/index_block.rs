@@ -1,8 +1,18 @@
 use crate::db::key_types::InternalKey;
+use crate::error::KVLiteError;
 use crate::ioutils::{read_bytes_exact, read_u32};
 use crate::sstable::footer::Footer;
 use crate::Result;
+use std::collections::VecDeque;
+use std::convert::TryInto;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+/// Approximate bytes of the index entries in each partition of a [PartitionedIndex].
+pub(crate) const INDEX_PARTITION_SIZE: usize = 4096;
+
+/// Max count of the partitions a [PartitionedIndex] keeps in memory without a block cache.
+pub(crate) const MAX_LOADED_PARTITIONS: usize = 8;
 
 #[derive(Default)]
 pub struct IndexBlock {
@@ -12,6 +22,16 @@ pub struct IndexBlock {
 }
 
 impl IndexBlock {
+    /// Bytes of the keys and offsets held in memory.
+    pub(crate) fn memory_size(&self) -> usize {
+        self.min_key.len()
+            + self
+                .indexes
+                .iter()
+                .map(|index| std::mem::size_of::<(u32, u32, u32, u32)>() + index.4.len())
+                .sum::<usize>()
+    }
+
     pub(crate) fn add_index(
         &mut self,
         offset: u32,
@@ -31,7 +51,6 @@ impl IndexBlock {
 
     pub(crate) fn write_to_file(&mut self, writer: &mut (impl Write + Seek)) -> Result<()> {
         let min_key_len = self.min_key.len() as u32;
-        debug_assert_ne!(min_key_len, 0);
         writer.write_all(&min_key_len.to_le_bytes()).unwrap();
         writer.write_all(&self.min_key).unwrap();
         for index in &self.indexes {
@@ -44,26 +63,61 @@ impl IndexBlock {
         Ok(())
     }
 
+    /// Load the index block of a table which has been opened successfully before.
     pub(crate) fn load_index<R: Read + Seek>(reader: &mut R, footer: &Footer) -> IndexBlock {
-        reader
-            .seek(SeekFrom::Start(footer.index_block_offset as u64))
-            .unwrap();
+        Self::try_load_index(reader, footer, 0).unwrap()
+    }
 
+    /// Like [IndexBlock::load_index], but return [KVLiteError::Corruption] instead of
+    /// panicking if the index block is malformed. A key length larger than
+    /// `max_key_length` or than the rest of the index block is rejected before the key
+    /// is allocated. 0 means no limit other than the index block.
+    pub(crate) fn try_load_index<R: Read + Seek>(
+        reader: &mut R,
+        footer: &Footer,
+        max_key_length: usize,
+    ) -> Result<IndexBlock> {
+        reader.seek(SeekFrom::Start(footer.index_block_offset as u64))?;
+
+        let index_block_length = footer.index_block_length as u64;
+        let check_key_length = |key_length: u32, remain: u64| {
+            if (max_key_length != 0 && key_length as usize > max_key_length)
+                || key_length as u64 > remain
+            {
+                return Err(KVLiteError::Corruption(format!(
+                    "key length {} in index block is implausible",
+                    key_length
+                )));
+            }
+            Ok(())
+        };
         let mut index_block = IndexBlock::default();
 
-        let min_key_length = read_u32(reader).unwrap();
-        let min_key = read_bytes_exact(reader, min_key_length as u64).unwrap();
-        let mut offset: u32 = (std::mem::size_of::<u32>() + min_key.len()) as u32;
+        if index_block_length < 4 {
+            return Err(KVLiteError::Corruption("index block is too short".into()));
+        }
+        let min_key_length = read_u32(reader)?;
+        check_key_length(min_key_length, index_block_length - 4)?;
+        let min_key = read_bytes_exact(reader, min_key_length as u64)?;
+        let mut offset = 4 + min_key_length as u64;
         index_block.min_key = min_key;
-        debug_assert!(offset < footer.index_block_length);
-        while offset < footer.index_block_length {
-            let block_offset = read_u32(reader).unwrap();
-            let block_length = read_u32(reader).unwrap();
-            let index_offset_uncompressed = read_u32(reader).unwrap();
-            debug_assert!(block_offset < index_offset_uncompressed);
-            let max_key_length = read_u32(reader).unwrap();
-
-            let max_key = read_bytes_exact(reader, max_key_length as u64).unwrap();
+        while offset < index_block_length {
+            if index_block_length - offset < 16 {
+                return Err(KVLiteError::Corruption("index block is truncated".into()));
+            }
+            let block_offset = read_u32(reader)?;
+            let block_length = read_u32(reader)?;
+            let index_offset_uncompressed = read_u32(reader)?;
+            if block_offset >= index_offset_uncompressed
+                || block_offset as u64 + block_length as u64 > footer.index_block_offset as u64
+            {
+                return Err(KVLiteError::Corruption("data block is out of range".into()));
+            }
+            let max_key_length = read_u32(reader)?;
+            offset += 16;
+            check_key_length(max_key_length, index_block_length - offset)?;
+
+            let max_key = read_bytes_exact(reader, max_key_length as u64)?;
             index_block.indexes.push((
                 block_offset,
                 block_length,
@@ -72,7 +126,30 @@ impl IndexBlock {
                 max_key,
             ));
 
-            offset += 16 + max_key_length;
+            offset += max_key_length as u64;
+        }
+        if index_block.indexes.is_empty() {
+            return Err(KVLiteError::Corruption("index block is empty".into()));
+        }
+        Ok(index_block)
+    }
+
+    /// Parse the index entries in `bytes` which have been validated before, without min key.
+    pub(crate) fn parse_indexes(bytes: &[u8]) -> IndexBlock {
+        let u32_at = |pos: usize| u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        let mut index_block = IndexBlock::default();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let max_key_length = u32_at(pos + 12);
+            let max_key = bytes[pos + 16..pos + 16 + max_key_length as usize].to_vec();
+            index_block.indexes.push((
+                u32_at(pos),
+                u32_at(pos + 4),
+                u32_at(pos + 8),
+                max_key_length,
+                max_key,
+            ));
+            pos += 16 + max_key_length as usize;
         }
         index_block
     }
@@ -99,13 +176,194 @@ impl IndexBlock {
     }
 
     /// Find all the first data block whose max key is greater or equal to `key`
-    pub(crate) fn find_all_ge(&self, key: &InternalKey) -> &[(u32, u32, u32, u32, InternalKey)] {
-        match self.indexes.binary_search_by(|probe| probe.4.cmp(key)) {
+    pub(crate) fn find_all_ge(&self, key: &[u8]) -> &[(u32, u32, u32, u32, InternalKey)] {
+        match self
+            .indexes
+            .binary_search_by(|probe| probe.4.as_slice().cmp(key))
+        {
             Ok(i) | Err(i) => &self.indexes[i..],
         }
     }
 }
 
+/// Index of a table held by [crate::sstable::table_cache::TableCache], either the whole
+/// index block or a two-level [PartitionedIndex] if the index block is large.
+pub enum TableIndex {
+    Full(IndexBlock),
+    Partitioned(PartitionedIndex),
+}
+
+impl TableIndex {
+    /// Load the index of a table which has been opened successfully before. The index
+    /// block is partitioned if it is longer than `partition_threshold` bytes,
+    /// 0 means it is never partitioned.
+    pub(crate) fn load<R: Read + Seek>(
+        reader: &mut R,
+        footer: &Footer,
+        partition_threshold: u32,
+    ) -> TableIndex {
+        if partition_threshold != 0 && footer.index_block_length > partition_threshold {
+            TableIndex::Partitioned(PartitionedIndex::load(reader, footer))
+        } else {
+            TableIndex::Full(IndexBlock::load_index(reader, footer))
+        }
+    }
+
+    /// Like [IndexBlock::may_contain_key]. `reader` is called if a partition of the
+    /// index has to be read from the table.
+    pub(crate) fn may_contain_key<R: Read + Seek>(
+        &self,
+        key: &InternalKey,
+        reader: impl FnOnce() -> R,
+    ) -> Option<(u32, u32, u32)> {
+        match self {
+            TableIndex::Full(index_block) => index_block.may_contain_key(key),
+            TableIndex::Partitioned(index) => index.may_contain_key(key, reader),
+        }
+    }
+
+    /// Bytes of the keys and offsets held in memory.
+    pub(crate) fn memory_size(&self) -> usize {
+        match self {
+            TableIndex::Full(index_block) => index_block.memory_size(),
+            TableIndex::Partitioned(index) => index.memory_size(),
+        }
+    }
+}
+
+/// Two-level index of a table. The entries of the index block are split into partitions
+/// of about [INDEX_PARTITION_SIZE] bytes, and only the max key and the position of each
+/// partition stay in memory. A partition is read from the table by the lookups falling
+/// in it. It is kept in the block cache if there is one, otherwise the index keeps the
+/// [MAX_LOADED_PARTITIONS] partitions used most recently.
+///
+/// The index block itself is not changed, so any table can be loaded either way.
+pub struct PartitionedIndex {
+    /// (max key, offset in the table, length) of each partition
+    partitions: Vec<(InternalKey, u64, u32)>,
+    /// Partitions read by lookups and their positions in `partitions`, from the least
+    /// recently used.
+    loaded: Mutex<VecDeque<(usize, Arc<IndexBlock>)>>,
+}
+
+impl PartitionedIndex {
+    fn load<R: Read + Seek>(reader: &mut R, footer: &Footer) -> PartitionedIndex {
+        reader
+            .seek(SeekFrom::Start(footer.index_block_offset as u64))
+            .unwrap();
+        let bytes = read_bytes_exact(reader, footer.index_block_length as u64).unwrap();
+        let min_key_length = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+
+        let mut partitions = vec![];
+        let mut start = 4 + min_key_length;
+        let mut pos = start;
+        while pos < bytes.len() {
+            let max_key_length =
+                u32::from_le_bytes(bytes[pos + 12..pos + 16].try_into().unwrap()) as usize;
+            let max_key = &bytes[pos + 16..pos + 16 + max_key_length];
+            pos += 16 + max_key_length;
+            if pos - start >= INDEX_PARTITION_SIZE || pos == bytes.len() {
+                partitions.push((
+                    max_key.to_vec(),
+                    footer.index_block_offset as u64 + start as u64,
+                    (pos - start) as u32,
+                ));
+                start = pos;
+            }
+        }
+        PartitionedIndex {
+            partitions,
+            loaded: Mutex::default(),
+        }
+    }
+
+    /// Position in `partitions` of the partition which may contain `key`.
+    fn find_partition(&self, key: &InternalKey) -> Option<usize> {
+        let i = match self.partitions.binary_search_by(|probe| probe.0.cmp(key)) {
+            Ok(i) | Err(i) => i,
+        };
+        if i < self.partitions.len() {
+            Some(i)
+        } else {
+            None
+        }
+    }
+
+    /// (offset in the table, length) of the partition which may contain `key`.
+    pub(crate) fn partition_of(&self, key: &InternalKey) -> Option<(u64, u32)> {
+        self.find_partition(key)
+            .map(|i| (self.partitions[i].1, self.partitions[i].2))
+    }
+
+    /// Read the partition at `offset` of `length` bytes.
+    pub(crate) fn read_partition<R: Read + Seek>(
+        reader: &mut R,
+        offset: u64,
+        length: u32,
+    ) -> Vec<u8> {
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        read_bytes_exact(reader, length as u64).unwrap()
+    }
+
+    fn may_contain_key<R: Read + Seek>(
+        &self,
+        key: &InternalKey,
+        reader: impl FnOnce() -> R,
+    ) -> Option<(u32, u32, u32)> {
+        let i = self.find_partition(key)?;
+        let cached = {
+            let mut loaded = self.loaded.lock().unwrap();
+            let pos = loaded.iter().position(|(j, _)| *j == i);
+            pos.map(|pos| {
+                let entry = loaded.remove(pos).unwrap();
+                let partition = entry.1.clone();
+                loaded.push_back(entry);
+                partition
+            })
+        };
+        let partition = match cached {
+            Some(partition) => partition,
+            None => {
+                // read the partition without holding the lock
+                let (_, offset, length) = self.partitions[i];
+                let bytes = Self::read_partition(&mut reader(), offset, length);
+                let partition = Arc::new(IndexBlock::parse_indexes(&bytes));
+                let mut loaded = self.loaded.lock().unwrap();
+                if !loaded.iter().any(|(j, _)| *j == i) {
+                    if loaded.len() == MAX_LOADED_PARTITIONS {
+                        loaded.pop_front();
+                    }
+                    loaded.push_back((i, partition.clone()));
+                }
+                partition
+            }
+        };
+        partition.may_contain_key(key)
+    }
+
+    fn memory_size(&self) -> usize {
+        let partitions_size: usize = self
+            .partitions
+            .iter()
+            .map(|partition| std::mem::size_of::<(u64, u32)>() + partition.0.len())
+            .sum();
+        let guard = self.loaded.lock().unwrap();
+        partitions_size + guard.iter().map(|(_, p)| p.memory_size()).sum::<usize>()
+    }
+
+    /// Count of the partitions.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// Count of the partitions kept in memory.
+    #[cfg(test)]
+    pub(crate) fn loaded_len(&self) -> usize {
+        self.loaded.lock().unwrap().len()
+    }
+}
+
 #[test]
 fn test_may_contain_key() {
     let mut index = IndexBlock::default();
@@ -1,25 +1,29 @@
-use crate::cache::{LRUEntry, ShardLRUCache};
+use crate::cache::ShardLRUCache;
 use crate::collections::skip_list::skipmap::SrSwSkipMap;
-use crate::compaction::level_0::{compact_and_insert, LEVEL0_FILES_THRESHOLD};
+use crate::compaction::level_0::{compact_and_insert, compact_intra_level0};
+use crate::db::db_iter::IterErrors;
 use crate::db::key_types::{InternalKey, MemKey};
-use crate::db::Value;
+use crate::db::latency::LatencyTimer;
+use crate::db::stats::StatsCounters;
+use crate::db::{PanicGuard, Value};
 use crate::memory::MemTable;
 use crate::sstable::manager::level_iter::Level0Iterator;
 use crate::sstable::manager::level_n::LevelNManager;
 use crate::sstable::table_cache::TableCache;
-use crate::sstable::table_handle::{TableReadHandle, TableWriteHandle};
-use crate::sstable::{TableID, NUM_LEVEL0_TABLE_TO_COMPACT};
+use crate::sstable::table_handle::{TableReadHandle, TableStatus, TableWriteHandle};
+use crate::sstable::{level_dir, TableID, NUM_LEVEL0_TABLE_TO_COMPACT};
 use crate::wal::WAL;
 use crate::Result;
 use arc_swap::ArcSwap;
 use crossbeam_channel::Receiver;
 use rand::Rng;
 use std::collections::BTreeMap;
+use std::fs::DirEntry;
 use std::marker::PhantomData;
 use std::num::NonZeroUsize;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
 use std::thread::JoinHandle;
 
@@ -29,6 +33,11 @@ pub struct Level0Manager<SK: MemKey, UK: MemKey, M: MemTable<SK, UK>, L: WAL<SK,
 
     level0_tables: std::sync::RwLock<BTreeMap<TableID, Arc<TableReadHandle>>>,
     file_size: AtomicU64,
+    /// Held while an sstable is written to level 0, from allocating its table id to
+    /// inserting it, so that [Self::assign_intra_level0_tables] sees every table id
+    /// allocated to level 0. The tables are also claimed under it by
+    /// [Self::claim_idle_tables].
+    table_writing_lock: Mutex<()>,
 
     table_manager: std::sync::Arc<LevelNManager>,
     sender: crossbeam_channel::Sender<bool>,
@@ -57,8 +66,13 @@ where
         index_cache: Arc<ShardLRUCache<TableID, TableCache>>,
         background_task_write_to_level0_is_running: Arc<AtomicBool>,
     ) -> Result<Arc<Level0Manager<SK, UK, M, L>>> {
-        std::fs::create_dir_all(format!("{}/0", db_path)).unwrap();
-        let dir = std::fs::read_dir(format!("{}/0", db_path))?;
+        // an in-memory database starts empty
+        let dir: Vec<std::io::Result<DirEntry>> = if table_manager.options().in_memory {
+            Vec::new()
+        } else {
+            std::fs::create_dir_all(level_dir(&db_path, 0)).unwrap();
+            std::fs::read_dir(level_dir(&db_path, 0))?.collect()
+        };
 
         let mut file_size = 0;
         let mut level0_tables = BTreeMap::new();
@@ -73,8 +87,16 @@ where
                 .parse::<TableID>();
             if let Ok(table_id) = table_id {
                 file_size += d.metadata().unwrap().len();
-
-                let handle = TableReadHandle::open(&db_path, 0, table_id);
+                table_manager.table_ids().observe(table_id);
+
+                let handle = TableReadHandle::open_with_dictionary(
+                    &db_path,
+                    0,
+                    table_id,
+                    table_manager.options(),
+                    table_manager.value_dictionary().as_ref(),
+                )
+                .with_file_cache(table_manager.file_cache().cloned());
                 level0_tables.insert(handle.table_id(), Arc::new(handle));
             } else {
                 // remove temporary file.
@@ -87,6 +109,7 @@ where
             db_path,
             level0_tables: std::sync::RwLock::new(level0_tables),
             file_size: AtomicU64::new(file_size),
+            table_writing_lock: Mutex::new(()),
             table_manager,
             sender,
             wal,
@@ -102,6 +125,8 @@ where
             let mut guard = level0_manager.handle.lock().unwrap();
             *guard = Some(handle);
         }
+        // the threshold may be reduced since the tables were written
+        level0_manager.may_compact();
 
         Ok(level0_manager)
     }
@@ -129,6 +154,7 @@ where
         let handle = thread::Builder::new()
             .name("level0 writer".to_owned())
             .spawn(move || {
+                let _panic_guard = PanicGuard(manager2.table_manager.failure_flag().clone());
                 info!("thread `{}` start!", thread::current().name().unwrap());
                 while let Ok(()) = recv.recv() {
                     debug_assert!(manager2
@@ -159,14 +185,52 @@ where
 
     /// Persistently write the `table` to disk.
     fn write_to_table(&self, table: Arc<M>) -> Result<()> {
-        let mut handle = self.create_table_write_handle(table.len() as u32);
-        handle.write_sstable(table.deref())?;
-        self.insert_table_handle(handle);
-        self.delete_imm_table_log()?;
-        self.may_compact();
+        let _writing_guard = self.table_writing_lock.lock().unwrap();
+        if self.table_manager.options().dictionary_compression {
+            self.table_manager
+                .train_value_dictionary(table.kv_iter().map(|(_, v)| v))?;
+        }
+        if self.can_append_to_level1(table.deref()) {
+            let level1 = unsafe { NonZeroUsize::new_unchecked(1) };
+            let mut handle = self
+                .table_manager
+                .create_table_write_handle(level1, table.len() as u32);
+            handle.write_sstable(table.deref())?;
+            self.table_manager.upsert_table_handle(handle);
+            self.delete_imm_table_log()?;
+            self.table_manager.may_compact(level1);
+        } else {
+            let mut handle = self.create_table_write_handle(table.len() as u32);
+            handle.write_sstable(table.deref())?;
+            self.insert_table_handle(handle);
+            self.delete_imm_table_log()?;
+            self.may_compact();
+        }
         Ok(())
     }
 
+    /// Whether all the keys in `table` are greater than the keys in level0 and level1,
+    /// so that `table` can be written to level1 directly without compaction.
+    fn can_append_to_level1(&self, table: &M) -> bool {
+        let min_key = match table.kv_iter().next() {
+            Some((k, _)) => k.clone(),
+            None => return false,
+        };
+        {
+            let guard = self.level0_tables.read().unwrap();
+            if guard.values().any(|t| t.max_key() >= &min_key) {
+                return false;
+            }
+        }
+        match self
+            .table_manager
+            .max_key(unsafe { NonZeroUsize::new_unchecked(1) })
+        {
+            Some(max_key) => max_key < min_key,
+            None => true,
+        }
+    }
+
     // delete immutable log after writing to level0 sstable
     fn delete_imm_table_log(&self) -> Result<()> {
         let mut wal_guard = self.wal.lock().unwrap();
@@ -174,9 +238,28 @@ where
         Ok(())
     }
 
+    /// See [crate::db::options::Options::level0_files_threshold].
+    #[inline]
+    pub(crate) fn files_threshold(&self) -> usize {
+        self.table_manager.options().level0_files_threshold
+    }
+
+    /// See [crate::db::options::Options::level0_file_size_threshold].
+    #[inline]
+    pub(crate) fn file_size_threshold(&self) -> u64 {
+        self.table_manager.options().level0_file_size_threshold
+    }
+
+    /// Whether level0 has more sstables than [Self::files_threshold], or takes more
+    /// bytes than a non-zero [Self::file_size_threshold].
+    pub(crate) fn needs_compaction(&self) -> bool {
+        let size_threshold = self.file_size_threshold();
+        self.file_count() > self.files_threshold()
+            || (size_threshold > 0 && self.file_size() > size_threshold)
+    }
+
     pub fn may_compact(&self) {
-        let table_count = self.file_count();
-        if table_count > LEVEL0_FILES_THRESHOLD {
+        if self.needs_compaction() {
             if let Err(e) = self.sender.send(true) {
                 warn!("{:#?}", e);
             }
@@ -191,23 +274,44 @@ where
         std::thread::spawn(move || {
             let table_manager = table_manager;
             let level0_manager = level0_manager;
+            let _panic_guard = PanicGuard(table_manager.failure_flag().clone());
             info!("compaction 0 task start");
             while let Ok(true) = receiver.recv() {
-                let table_count = level0_manager.file_count();
-                if table_count > LEVEL0_FILES_THRESHOLD {
+                // at most NUM_LEVEL0_TABLE_TO_COMPACT tables are compacted each time,
+                // so repeat until the backlog is under the thresholds
+                while level0_manager.needs_compaction() {
+                    if let Some((level0_tables, table_id)) =
+                        level0_manager.assign_intra_level0_tables()
+                    {
+                        let timer = LatencyTimer::start();
+                        compact_intra_level0(
+                            &level0_manager,
+                            &table_manager,
+                            level0_tables,
+                            table_id,
+                        );
+                        timer.record(&table_manager.stats().latency.compaction);
+                        continue;
+                    }
+                    // all the tables may be claimed by DB::clear
                     let (level0_tables, min_key, max_key) =
-                        level0_manager.assign_level0_tables_to_compact();
+                        match level0_manager.assign_level0_tables_to_compact() {
+                            Some(assigned) => assigned,
+                            None => break,
+                        };
                     let level1_tables = table_manager.get_overlap_tables(
                         unsafe { NonZeroUsize::new_unchecked(1) },
                         &min_key,
                         &max_key,
                     );
+                    let timer = LatencyTimer::start();
                     compact_and_insert(
                         &level0_manager,
                         &table_manager,
                         level0_tables,
                         level1_tables,
                     );
+                    timer.record(&table_manager.stats().latency.compaction);
                 }
             }
             info!("compaction 0 task exit!");
@@ -221,24 +325,41 @@ where
         &self.level0_tables
     }
 
-    /// Iterate all the key-value pairs in level0
-    pub fn get_level0_iterator(&self) -> Level0Iterator {
+    /// Bytes of the prefix filters of the sstables in level0.
+    pub(crate) fn prefix_filters_size(&self) -> usize {
+        let guard = self.level0_tables.read().unwrap();
+        guard.values().map(|table| table.prefix_filter_size()).sum()
+    }
+
+    /// Iterate all the key-value pairs in level0, recording the errors of reading the
+    /// sstables in `errors`.
+    pub(crate) fn get_level0_iterator(&self, errors: &IterErrors) -> Level0Iterator {
         let guard = self.level0_tables.read().unwrap();
         let tables = &*guard;
-        Level0Iterator::new(tables)
+        Level0Iterator::new(tables, errors)
     }
 
+    /// Query the key-values in [`key_start`, `key_end`]. The sstables whose prefix
+    /// filters show that they have no key starting with `prefix` are skipped.
     pub fn range_query(
         &self,
         key_start: &InternalKey,
         key_end: &InternalKey,
+        prefix: &[u8],
         kvs: &mut SrSwSkipMap<UK, Value>,
     ) {
         let tables_guard = self.level0_tables.read().unwrap();
 
         // query the latest table first
         for table in tables_guard.values().rev() {
-            table.range_query(key_start, key_end, kvs);
+            if table.may_contain_prefix(prefix) {
+                table.range_query(
+                    key_start,
+                    key_end,
+                    kvs,
+                    &self.table_manager.stats().disk_bytes_read,
+                );
+            }
         }
     }
 
@@ -247,14 +368,23 @@ where
 
         // query the latest table first
         for table in tables_guard.values().rev() {
+            if !table.may_contain(key) {
+                continue;
+            }
             // get cache
-            let entry_tracker = self.table_cache.look_up(&table.table_key(), table.hash());
-            let option = if !entry_tracker.0.is_null() {
-                let mut table_cache =
-                    unsafe { (*(entry_tracker.0 as *mut LRUEntry<u64, TableCache>)).value_mut() };
-                table.query_sstable_with_cache(key, &mut table_cache)
-            } else {
-                table.query_sstable(key, &self.table_cache)
+            let option = match self.table_cache.get(&table.table_key(), table.hash()) {
+                Some(table_cache) => table.query_sstable_with_cache(
+                    key,
+                    &table_cache,
+                    self.table_manager.block_cache(),
+                    self.table_manager.stats(),
+                )?,
+                None => table.query_sstable(
+                    key,
+                    &self.table_cache,
+                    self.table_manager.block_cache(),
+                    self.table_manager.stats(),
+                )?,
             };
 
             if option.is_some() {
@@ -264,47 +394,123 @@ where
         Ok(None)
     }
 
-    fn get_next_table_id(&self) -> TableID {
-        let table_guard = self.level0_tables.read().unwrap();
-        match table_guard.last_key_value() {
-            Some((k, _v)) => k + 1,
-            None => 1,
-        }
+    /// The sstables of level 0 which a query of `key` may read, the latest first.
+    pub(crate) fn tables_may_contain(&self, key: &InternalKey) -> Vec<Arc<TableReadHandle>> {
+        let tables_guard = self.level0_tables.read().unwrap();
+        tables_guard
+            .values()
+            .rev()
+            .filter(|table| table.may_contain(key))
+            .cloned()
+            .collect()
     }
 
     fn insert_table_handle(&self, handle: TableWriteHandle) {
         let file_size = handle.writer.writer.pos;
         debug_assert!(file_size > 0);
         debug_assert_eq!(handle.level(), 0);
+        let value_log_bytes = handle.value_log_bytes_written();
 
-        let handle = Arc::new(TableReadHandle::from_table_write_handle(handle));
+        let _value_log_guard = self.table_manager.value_log_lock().lock().unwrap();
+        let handle = Arc::new(
+            TableReadHandle::from_table_write_handle(handle)
+                .with_file_cache(self.table_manager.file_cache().cloned()),
+        );
         let mut table_guard = self.level0_tables.write().unwrap();
 
         table_guard.insert(handle.table_id(), handle);
         self.file_size.fetch_add(file_size, Ordering::Release);
+        StatsCounters::add(
+            &self.table_manager.stats().sstable_bytes_written,
+            file_size as usize,
+        );
+        StatsCounters::add(
+            &self.table_manager.stats().value_log_bytes_written,
+            value_log_bytes as usize,
+        );
+    }
+
+    /// Move `handles`, ingested tables written for another level, to level 0 and insert
+    /// them. Nothing is inserted if any of them fails to move.
+    pub(crate) fn ingest_table_handles(&self, mut handles: Vec<TableWriteHandle>) -> Result<()> {
+        let moved = handles
+            .iter_mut()
+            .try_for_each(|handle| handle.move_to_level(&self.db_path, 0));
+        if let Err(e) = moved {
+            handles.into_iter().for_each(TableWriteHandle::discard);
+            return Err(e);
+        }
+        for handle in handles {
+            self.insert_table_handle(handle);
+        }
+        self.may_compact();
+        Ok(())
+    }
+
+    /// Write the key-values merged by an intra level 0 compaction to the level 0 sstable
+    /// `table_id`, and insert it. The values are written as they are stored, see
+    /// [LevelNManager::compaction_iter].
+    pub(crate) fn write_compacted_table(
+        &self,
+        table_id: TableID,
+        kvs: Vec<(InternalKey, Value)>,
+    ) -> Result<()> {
+        let options = self.table_manager.options();
+        let mut handle = TableWriteHandle::new_with_direct_io(
+            &self.db_path,
+            0,
+            table_id,
+            kvs.len() as u32,
+            options,
+            options.use_direct_io_for_compaction,
+        )?
+        .with_value_dictionary(self.table_manager.compression_dictionary());
+        handle.write_sstable_from_stored_vec(kvs)?;
+        self.insert_table_handle(handle);
+        Ok(())
     }
 
     pub fn create_table_write_handle(&self, kv_total: u32) -> TableWriteHandle {
-        let next_table_id = self.get_next_table_id();
-        TableWriteHandle::new(&self.db_path, 0, next_table_id, kv_total)
+        let next_table_id = self.table_manager.table_ids().allocate();
+        TableWriteHandle::new(
+            &self.db_path,
+            0,
+            next_table_id,
+            kv_total,
+            self.table_manager.options(),
+        )
+        .with_value_dictionary(self.table_manager.compression_dictionary())
+    }
+
+    /// Get the max key of all the level0 sstables.
+    pub(crate) fn max_key(&self) -> Option<InternalKey> {
+        let guard = self.level0_tables.read().unwrap();
+        guard.values().map(|table| table.max_key()).max().cloned()
     }
 
     /// Get sstable file count of level 0, used for judging whether need compacting.
-    fn file_count(&self) -> usize {
+    pub(crate) fn file_count(&self) -> usize {
         let guard = self.level0_tables.read().unwrap();
         guard.len()
     }
 
+    /// Total bytes of the sstables in level 0.
+    #[inline]
+    pub(crate) fn file_size(&self) -> u64 {
+        self.file_size.load(Ordering::Acquire)
+    }
+
     pub fn ready_to_delete(&self, table_id: u64) {
         let mut guard = self.level0_tables.write().unwrap();
+        self.table_manager
+            .add_deleted_table(guard.get(&table_id).unwrap());
         let table_handle = guard.remove(&table_id).unwrap();
 
         self.file_size
             .fetch_sub(table_handle.file_size(), Ordering::Release);
 
         table_handle.ready_to_delete();
-        self.table_cache
-            .erase(&table_handle.table_key(), table_handle.hash());
+        self.table_manager.evict_table_caches(&table_handle);
     }
 
     pub fn random_handle(&self) -> Arc<TableReadHandle> {
@@ -315,10 +521,110 @@ where
         v.clone()
     }
 
-    /// Return level0 tables to compaction
+    /// Return level0 tables to compaction, or `None` if all of them are compacting.
     pub fn assign_level0_tables_to_compact(
         &self,
-    ) -> (Vec<Arc<TableReadHandle>>, InternalKey, InternalKey) {
+    ) -> Option<(Vec<Arc<TableReadHandle>>, InternalKey, InternalKey)> {
+        let (tables, min_key, max_key) = self.pick_level0_tables(true);
+        min_key.map(|min_key| (tables, min_key, max_key))
+    }
+
+    /// Mark all the level 0 tables which are not compacting as compacting, and return
+    /// them. See [crate::db::DB::clear].
+    pub(crate) fn claim_idle_tables(&self) -> Vec<Arc<TableReadHandle>> {
+        let _writing_guard = self.table_writing_lock.lock().unwrap();
+        let guard = self.level0_tables.read().unwrap();
+        guard
+            .values()
+            .filter(|table| table.test_and_set_compacting())
+            .cloned()
+            .collect()
+    }
+
+    /// Return the tables [Self::assign_level0_tables_to_compact] would return,
+    /// without marking them as compacting.
+    pub(crate) fn peek_level0_tables_to_compact(
+        &self,
+    ) -> Option<(Vec<Arc<TableReadHandle>>, InternalKey, InternalKey)> {
+        if !self.needs_compaction() {
+            return None;
+        }
+        let (tables, min_key, max_key) = self.pick_level0_tables(false);
+        min_key.map(|min_key| (tables, min_key, max_key))
+    }
+
+    /// Claim all the level 0 tables for an intra level 0 compaction if
+    /// [Options::level0_intra_compaction_bytes] allows it, and allocate the table id of
+    /// the merged table.
+    ///
+    /// Level 0 tables are ordered by table id, so the merged table must be newer than
+    /// all the tables it replaces and older than every table written afterwards. No
+    /// table is being written while the id is allocated, and every other table is an input.
+    ///
+    /// [Options::level0_intra_compaction_bytes]: crate::db::options::Options::level0_intra_compaction_bytes
+    pub(crate) fn assign_intra_level0_tables(
+        &self,
+    ) -> Option<(Vec<Arc<TableReadHandle>>, TableID)> {
+        let _writing_guard = self.table_writing_lock.lock().unwrap();
+        let tables = self.pick_intra_level0_tables(true)?;
+        Some((tables, self.table_manager.table_ids().allocate()))
+    }
+
+    /// Return the tables [Self::assign_intra_level0_tables] would return, without
+    /// marking them as compacting.
+    pub(crate) fn peek_intra_level0_tables(&self) -> Option<Vec<Arc<TableReadHandle>>> {
+        if !self.needs_compaction() {
+            return None;
+        }
+        self.pick_intra_level0_tables(false)
+    }
+
+    /// Pick all the level 0 tables, oldest first, if there are at least two of them, none
+    /// is compacting, and they take at most [Options::level0_intra_compaction_bytes]
+    /// bytes, fewer than the level 1 tables they overlap.
+    /// If `claim` is true, the picked tables are marked as compacting.
+    ///
+    /// [Options::level0_intra_compaction_bytes]: crate::db::options::Options::level0_intra_compaction_bytes
+    fn pick_intra_level0_tables(&self, claim: bool) -> Option<Vec<Arc<TableReadHandle>>> {
+        let max_bytes = self.table_manager.options().level0_intra_compaction_bytes;
+        if max_bytes == 0 {
+            return None;
+        }
+        let guard = self.level0_tables.read().unwrap();
+        if guard.len() < 2 || guard.values().any(|t| t.status() != TableStatus::Store) {
+            return None;
+        }
+        let bytes: u64 = guard.values().map(|t| t.file_size()).sum();
+        if bytes > max_bytes {
+            return None;
+        }
+        let min_key = guard.values().map(|t| t.min_key()).min().unwrap();
+        let max_key = guard.values().map(|t| t.max_key()).max().unwrap();
+        let level1_bytes: u64 = self
+            .table_manager
+            .peek_overlap_tables(unsafe { NonZeroUsize::new_unchecked(1) }, min_key, max_key)
+            .iter()
+            .map(|t| t.file_size())
+            .sum();
+        if level1_bytes <= bytes {
+            return None;
+        }
+        if claim {
+            for table in guard.values() {
+                // the other claims are made by this thread or under `table_writing_lock`
+                let claimed = table.test_and_set_compacting();
+                debug_assert!(claimed);
+            }
+        }
+        Some(guard.values().cloned().collect())
+    }
+
+    /// Pick at most [NUM_LEVEL0_TABLE_TO_COMPACT] oldest tables which are not compacting.
+    /// If `claim` is true, the picked tables are marked as compacting.
+    fn pick_level0_tables(
+        &self,
+        claim: bool,
+    ) -> (Vec<Arc<TableReadHandle>>, Option<InternalKey>, InternalKey) {
         let guard = self.level0_tables.read().unwrap();
 
         let mut tables = Vec::new();
@@ -329,7 +635,12 @@ where
         let max = InternalKey::default();
         let mut max_key: &InternalKey = &max;
         for (_id, table) in guard.iter() {
-            if table.test_and_set_compacting() {
+            let picked = if claim {
+                table.test_and_set_compacting()
+            } else {
+                table.status() == TableStatus::Store
+            };
+            if picked {
                 tables.push(table.clone());
                 count += 1;
                 let keys = table.min_max_key();
@@ -343,28 +654,36 @@ where
                 }
             }
         }
-        (tables, min_key.unwrap().clone(), max_key.clone())
+        (tables, min_key.cloned(), max_key.clone())
     }
 
     pub(crate) fn close(&self) {
-        self.sender.send(false).unwrap();
-        let mut guard = self.handle.lock().unwrap();
-        let handle = guard.take().unwrap();
-        handle.join().unwrap();
+        // the receiver is dropped if the compacting task panicked.
+        let _ = self.sender.send(false);
+        let mut guard = self.handle.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(handle) = guard.take() {
+            if handle.join().is_err() {
+                error!("compaction 0 task panicked");
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::compaction::level_0::LEVEL0_FILES_THRESHOLD;
+    use crate::compaction::plan::plan_compaction;
     use crate::db::key_types::InternalKey;
     use crate::db::DBCommand;
     use crate::memory::{InternalKeyValueIterator, MutexSkipMapMemTable};
     use crate::sstable::manager::level_0::Level0Manager;
     use crate::sstable::manager::level_n::tests::create_manager;
+    use crate::sstable::NUM_LEVEL0_TABLE_TO_COMPACT;
     use crate::wal::simple_wal::SimpleWriteAheadLog;
     use crate::wal::WAL;
     use arc_swap::ArcSwap;
-    use std::sync::atomic::AtomicBool;
+    use std::num::NonZeroUsize;
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
     use tempfile::TempDir;
@@ -418,7 +737,7 @@ mod tests {
             }
             manager
                 .background_task_write_to_level0_is_running
-                .store(true, std::sync::atomic::Ordering::Release);
+                .store(true, Ordering::Release);
             sender.send(()).unwrap();
         }
 
@@ -437,4 +756,147 @@ mod tests {
         drop(sender);
         handle.join().unwrap();
     }
+
+    #[test]
+    fn test_append_to_level1() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().to_str().unwrap().to_string();
+        let leveln_manager = create_manager(&path);
+        let level1 = NonZeroUsize::new(1).unwrap();
+
+        let mut mut_mem = MutexSkipMapMemTable::<InternalKey>::default();
+        let wal = SimpleWriteAheadLog::open_and_load_logs(&path, &mut mut_mem).unwrap();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let imm_mem = Arc::new(ArcSwap::new(Arc::new(MutexSkipMapMemTable::default())));
+        let background = Arc::new(AtomicBool::default());
+        let (manager, handle) = Level0Manager::start_task_write_level0(
+            path,
+            leveln_manager.clone(),
+            Arc::new(Mutex::new(wal)),
+            imm_mem.clone(),
+            leveln_manager.index_cache.clone(),
+            receiver,
+            background.clone(),
+        );
+
+        let flush = |keys: std::ops::Range<u64>| {
+            let table = MutexSkipMapMemTable::<InternalKey>::default();
+            for i in keys {
+                table
+                    .set(
+                        format!("key{:06}", i).into_bytes(),
+                        format!("value{}", i).into_bytes(),
+                    )
+                    .unwrap();
+            }
+            imm_mem.store(Arc::new(table));
+            background.store(true, Ordering::Release);
+            sender.send(()).unwrap();
+            while background.load(Ordering::Acquire) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        // monotonically increasing keys are appended to level1
+        let mut level1_size = 0;
+        for i in 0..8 {
+            flush(i * 1000..(i + 1) * 1000);
+            assert_eq!(manager.file_count(), 0);
+            assert_eq!(leveln_manager.file_count(1), i as usize + 1);
+            // existing level1 tables are never rewritten
+            assert!(leveln_manager.level_size(1) > level1_size);
+            level1_size = leveln_manager.level_size(1);
+        }
+        assert_eq!(leveln_manager.max_key(level1), Some(Vec::from("key007999")));
+
+        // overlapping keys are written to level0
+        flush(500..600);
+        assert_eq!(manager.file_count(), 1);
+        assert_eq!(leveln_manager.file_count(1), 8);
+
+        for i in 0..8000 {
+            let key = format!("key{:06}", i).into_bytes();
+            let v = manager
+                .query(&key)
+                .unwrap()
+                .unwrap_or_else(|| leveln_manager.query(&key).unwrap().unwrap());
+            assert_eq!(format!("value{}", i).into_bytes(), v);
+        }
+
+        drop(sender);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_plan_compaction() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().to_str().unwrap().to_string();
+        let leveln_manager = create_manager(&path);
+
+        let mut mut_mem = MutexSkipMapMemTable::<InternalKey>::default();
+        let wal = SimpleWriteAheadLog::open_and_load_logs(&path, &mut mut_mem).unwrap();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let imm_mem = Arc::new(ArcSwap::new(Arc::new(MutexSkipMapMemTable::default())));
+        let (manager, handle) = Level0Manager::start_task_write_level0(
+            path,
+            leveln_manager.clone(),
+            Arc::new(Mutex::new(wal)),
+            imm_mem,
+            leveln_manager.index_cache.clone(),
+            receiver,
+            Arc::new(AtomicBool::default()),
+        );
+
+        let kvs = |keys: std::ops::Range<u64>| {
+            keys.map(|i| {
+                (
+                    format!("key{:06}", i).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                )
+            })
+            .collect::<Vec<_>>()
+        };
+
+        let mut level1_table =
+            leveln_manager.create_table_write_handle(NonZeroUsize::new(1).unwrap(), 100);
+        level1_table.write_sstable_from_vec(kvs(0..100)).unwrap();
+        let level1_id = level1_table.table_id();
+        leveln_manager.upsert_table_handle(level1_table);
+
+        // tables are inserted without notifying the compacting task
+        let mut input_tables = vec![];
+        for i in 0..LEVEL0_FILES_THRESHOLD as u64 {
+            let mut table = manager.create_table_write_handle(100);
+            table
+                .write_sstable_from_vec(kvs(i * 50..i * 50 + 100))
+                .unwrap();
+            input_tables.push((0, table.table_id()));
+            manager.insert_table_handle(table);
+        }
+        input_tables.truncate(NUM_LEVEL0_TABLE_TO_COMPACT);
+        input_tables.push((1, level1_id));
+        let plan = plan_compaction(&manager, &leveln_manager);
+        assert!(plan.tasks.is_empty());
+        assert_eq!(plan.level_file_counts, vec![4, 1, 0, 0, 0, 0, 0, 0]);
+
+        let mut table = manager.create_table_write_handle(100);
+        table.write_sstable_from_vec(kvs(1000..1100)).unwrap();
+        manager.insert_table_handle(table);
+
+        let plan = plan_compaction(&manager, &leveln_manager);
+        assert_eq!(plan.tasks.len(), 1);
+        let task = &plan.tasks[0];
+        assert_eq!(task.level, 0);
+        assert_eq!(task.input_tables, input_tables);
+        assert!(task.estimated_output_bytes > 0);
+        assert_eq!(task.estimated_output_files, 1);
+        assert_eq!(plan.level_file_counts, vec![1, 1, 0, 0, 0, 0, 0, 0]);
+
+        // planning does not claim any table
+        let (tables, _, _) = manager.assign_level0_tables_to_compact().unwrap();
+        assert_eq!(tables.len(), NUM_LEVEL0_TABLE_TO_COMPACT);
+
+        drop(sender);
+        handle.join().unwrap();
+    }
 }
@@ -1,49 +1,61 @@
-use crate::db::db_iter::{InternalKeyValue, KeyValueIterItem};
+use crate::collections::MergeIterator;
+use crate::db::db_iter::{InternalKeyValue, IterErrors};
 use crate::db::key_types::InternalKey;
+use crate::db::Value;
 use crate::sstable::table_handle::{TableIterator, TableReadHandle};
 use crate::sstable::TableID;
-use std::collections::{BTreeMap, BinaryHeap};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 pub type Level0Iterator = MergingIterator<TableIterator>;
 
 impl Level0Iterator {
-    pub(super) fn new(tables: &BTreeMap<TableID, Arc<TableReadHandle>>) -> Level0Iterator {
+    /// The errors of reading the tables are recorded in `errors`.
+    pub(super) fn new(
+        tables: &BTreeMap<TableID, Arc<TableReadHandle>>,
+        errors: &IterErrors,
+    ) -> Level0Iterator {
         let iterators: Vec<_> = tables
             .values()
-            .map(|handle| TableIterator::new(handle.clone()))
+            .map(|handle| TableIterator::with_errors(handle.clone(), Some(errors.clone())))
             .collect();
         Self::from_iterators(iterators)
     }
 }
 
+/// Key-values of a source of [MergingIterator], tagged with the index of the source.
+struct IndexedSource<It: Iterator<Item = InternalKeyValue>> {
+    iter: It,
+    iter_id: usize,
+}
+
+impl<It: Iterator<Item = InternalKeyValue>> Iterator for IndexedSource<It> {
+    type Item = (InternalKey, Value, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(k, v)| (k, v, self.iter_id))
+    }
+}
+
+/// Merge iterators without duplicate keys. If several of them yield the same key, the
+/// value of the last one is kept, so the iterators are ordered from the oldest to the newest.
 pub struct MergingIterator<It: Iterator<Item = InternalKeyValue>> {
-    pub(crate) iterators: Vec<It>,
-    priority_queue: BinaryHeap<KeyValueIterItem>,
+    inner: MergeIterator<InternalKey, Value, usize, IndexedSource<It>>,
     #[cfg(debug_assertions)]
-    prev_key: InternalKey,
+    prev_key: Option<InternalKey>,
 }
 
 impl<It: Iterator<Item = InternalKeyValue>> MergingIterator<It> {
-    pub(crate) fn from_iterators(mut iterators: Vec<It>) -> MergingIterator<It> {
-        let mut priority_queue = BinaryHeap::with_capacity(iterators.len());
-        for (iter_id, iter) in iterators.iter_mut().enumerate() {
-            if let Some((k, v)) = iter.next() {
-                priority_queue.push(KeyValueIterItem::new(k, v, iter_id));
-            }
-        }
+    pub(crate) fn from_iterators(iterators: Vec<It>) -> MergingIterator<It> {
+        let sources = iterators
+            .into_iter()
+            .enumerate()
+            .map(|(iter_id, iter)| IndexedSource { iter, iter_id })
+            .collect();
         MergingIterator {
-            iterators,
-            priority_queue,
+            inner: MergeIterator::new(sources),
             #[cfg(debug_assertions)]
-            prev_key: InternalKey::default(),
-        }
-    }
-
-    fn try_pop_ith_elem_to_queue(&mut self, iter_id: usize) {
-        if let Some((k, v)) = self.iterators[iter_id].next() {
-            self.priority_queue
-                .push(KeyValueIterItem::new(k, v, iter_id));
+            prev_key: None,
         }
     }
 }
@@ -52,25 +64,13 @@ impl<It: Iterator<Item = InternalKeyValue>> Iterator for MergingIterator<It> {
     type Item = InternalKeyValue;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.priority_queue.pop().map(|item| {
-            self.try_pop_ith_elem_to_queue(item.iter_id);
-
-            while let Some(next_item) = self.priority_queue.peek() {
-                if next_item.key == item.key {
-                    let next_item_iter_id = next_item.iter_id;
-                    debug_assert!(item.iter_id > next_item_iter_id);
-                    self.priority_queue.pop();
-                    self.try_pop_ith_elem_to_queue(next_item_iter_id);
-                } else {
-                    break;
-                }
-            }
+        self.inner.next().map(|(key, value, _)| {
             #[cfg(debug_assertions)]
             {
-                assert!(self.prev_key < item.key);
-                self.prev_key = item.key.clone();
+                assert!(self.prev_key.as_ref() < Some(&key));
+                self.prev_key = Some(key.clone());
             }
-            (item.key, item.value)
+            (key, value)
         })
     }
 }
@@ -79,33 +79,35 @@ pub struct LevelNIterator {
     iterators: Vec<TableIterator>,
     idx: usize,
     #[cfg(debug_assertions)]
-    prev_key: InternalKey,
+    prev_key: Option<InternalKey>,
     #[cfg(debug_assertions)]
     prev_idx: usize,
 }
 
 impl LevelNIterator {
+    /// The errors of reading the tables are recorded in `errors`.
     pub(super) fn new(
         table_handles: &BTreeMap<(InternalKey, TableID), Arc<TableReadHandle>>,
+        errors: &IterErrors,
     ) -> LevelNIterator {
         #[cfg(debug_assertions)]
         {
-            let mut last_max_key = InternalKey::default();
+            let mut last_max_key: Option<&InternalKey> = None;
             for table in table_handles.values() {
-                assert!(last_max_key.lt(table.min_key()));
-                last_max_key = table.max_key().clone();
+                assert!(last_max_key < Some(table.min_key()));
+                last_max_key = Some(table.max_key());
             }
         }
 
         let iterators: Vec<_> = table_handles
             .values()
-            .map(|handle| TableIterator::new(handle.clone()))
+            .map(|handle| TableIterator::with_errors(handle.clone(), Some(errors.clone())))
             .collect();
         LevelNIterator {
             iterators,
             idx: 0,
             #[cfg(debug_assertions)]
-            prev_key: InternalKey::default(),
+            prev_key: None,
             #[cfg(debug_assertions)]
             prev_idx: 0,
         }
@@ -135,7 +137,7 @@ impl Iterator for LevelNIterator {
         if !reenter {
             if let Some((k, _v)) = &item {
                 assert!(
-                    self.prev_key.lt(k),
+                    self.prev_key.as_ref() < Some(k),
                     r#"prev_key: {:?} key: {:?}
                        prev_idx: {} idx: {}
                     "#,
@@ -144,7 +146,7 @@ impl Iterator for LevelNIterator {
                     self.prev_idx,
                     self.idx
                 );
-                self.prev_key = k.clone();
+                self.prev_key = Some(k.clone());
             }
         }
         item
@@ -1,51 +1,130 @@
-use crate::cache::{LRUEntry, ShardLRUCache};
+use crate::cache::ShardLRUCache;
 use crate::collections::skip_list::skipmap::SrSwSkipMap;
-use crate::compaction::level_n::start_compact;
-use crate::db::db_iter::InternalKeyValue;
+use crate::compaction::level_n::{compact, rewrite_bottom_table};
+use crate::db::db_iter::{InternalKeyValue, IterErrors};
 use crate::db::key_types::{InternalKey, MemKey};
-use crate::db::{Value, MAX_LEVEL};
+use crate::db::latency::LatencyTimer;
+use crate::db::options::Options;
+use crate::db::stats::{MemoryUsage, StatsCounters};
+use crate::db::{FailureFlag, PanicGuard, Value, MAX_LEVEL};
+use crate::sstable::block_cache::SharedBlockCache;
+use crate::sstable::dictionary::ValueDictionary;
 use crate::sstable::manager::level_iter::LevelNIterator;
+use crate::sstable::manager::table_id::TableIdAllocator;
 use crate::sstable::table_cache::TableCache;
-use crate::sstable::table_handle::{TableReadHandle, TableWriteHandle};
-use crate::sstable::TableID;
+use crate::sstable::table_handle::{
+    TableFileCache, TableIterator, TableReadHandle, TableStatus, TableWriteHandle,
+};
+use crate::sstable::value_log::value_log_dir;
+use crate::sstable::{level_dir, TableID, TEMP_FILE_SUFFIX};
 use crate::Result;
-use crossbeam_channel::{Receiver, Sender};
+use arc_swap::ArcSwapOption;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use std::collections::{BTreeMap, VecDeque};
+use std::fs::DirEntry;
 use std::num::NonZeroUsize;
+use std::path::Path;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
 use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// The longest the compacting tasks wait between two looks for the sstables to compact
+/// by [Options::periodic_compaction_seconds].
+const PERIODIC_COMPACTION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A table to compact and its overlapping tables in the next level.
+type CompactionInput = (Arc<TableReadHandle>, VecDeque<Arc<TableReadHandle>>);
 
 /// Struct for adding and removing sstable files.
 pub struct LevelNManager {
     db_path: String,
+    options: Options,
     /// map: <max key, tableID>
     level_tables:
         [std::sync::RwLock<BTreeMap<(InternalKey, TableID), Arc<TableReadHandle>>>; MAX_LEVEL],
     level_sizes: [AtomicU64; MAX_LEVEL],
-    next_table_id: [AtomicU64; MAX_LEVEL],
+    table_ids: TableIdAllocator,
 
     pub(crate) index_cache: Arc<ShardLRUCache<TableID, TableCache>>,
+    /// See [Options::block_cache], shared with level 0.
+    block_cache: Option<SharedBlockCache>,
+    /// See [Options::max_open_files], shared with level 0.
+    file_cache: Option<Arc<TableFileCache>>,
     senders: Vec<Sender<bool>>,
     handles: RwLock<Vec<JoinHandle<()>>>,
     next_to_compact: AtomicUsize,
+    /// Held while claiming the tables of a compaction, so that two compactions never
+    /// claim overlapping key ranges of the same level.
+    compaction_lock: Mutex<()>,
+    /// Held from renaming the value log of a new sstable until the sstable is installed,
+    /// and while the garbage collection of the value log lists the files and the tables,
+    /// so that it never sees a value log file without the table pointing to it.
+    value_log_lock: Mutex<()>,
+    /// The tables with separated values removed from all the levels, level 0 included,
+    /// whose handles may still be read, e.g. by iterators. See
+    /// [LevelNManager::deleted_tables].
+    deleted_tables: Mutex<Vec<Weak<TableReadHandle>>>,
+    /// See [crate::sstable::dictionary], shared with level 0.
+    value_dictionary: ArcSwapOption<ValueDictionary>,
+    failure_flag: Arc<FailureFlag>,
+    stats: StatsCounters,
 }
 
 unsafe impl Sync for LevelNManager {}
 unsafe impl Send for LevelNManager {}
 
+/// Remove the value log files of the sstables which were being written when the database
+/// was closed. Like their sstables, they are never pointed to.
+fn clear_value_log_temp_files(db_path: &str) {
+    let dir = value_log_dir(db_path);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.to_str().unwrap().ends_with(TEMP_FILE_SUFFIX) {
+            info!("clear temporary file {:?}", path);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
+
 impl LevelNManager {
     /// Open all the sstables at `db_path` when initializing DB.
     pub fn open_tables(
         db_path: String,
+        options: Options,
         index_cache: Arc<ShardLRUCache<u64, TableCache>>,
     ) -> Arc<LevelNManager> {
-        for i in 1..=MAX_LEVEL {
-            std::fs::create_dir_all(format!("{}/{}", db_path, i)).unwrap();
-        }
+        let table_ids = if options.in_memory {
+            TableIdAllocator::in_memory()
+        } else {
+            for i in 1..=MAX_LEVEL {
+                std::fs::create_dir_all(level_dir(&db_path, i)).unwrap();
+            }
+            clear_value_log_temp_files(&db_path);
+            TableIdAllocator::open(Path::new(&db_path)).unwrap()
+        };
+        let value_dictionary = if options.in_memory {
+            None
+        } else {
+            ValueDictionary::load(&db_path).unwrap().map(Arc::new)
+        };
 
+        let compaction_threads = options.compaction_threads_per_level.max(1);
+        let block_cache = options.block_cache.clone().map(SharedBlockCache::new);
+        let file_cache = if options.max_open_files > 0 && !options.in_memory {
+            Some(Arc::new(TableFileCache::with_capacity(
+                options.max_open_files,
+            )))
+        } else {
+            None
+        };
         let mut manager = LevelNManager {
             db_path,
+            options,
             level_tables: [
                 std::sync::RwLock::default(),
                 std::sync::RwLock::default(),
@@ -64,34 +143,47 @@ impl LevelNManager {
                 AtomicU64::default(),
                 AtomicU64::default(),
             ],
-            next_table_id: [
-                AtomicU64::default(),
-                AtomicU64::default(),
-                AtomicU64::default(),
-                AtomicU64::default(),
-                AtomicU64::default(),
-                AtomicU64::default(),
-                AtomicU64::default(),
-            ],
+            table_ids,
             senders: Vec::with_capacity(MAX_LEVEL - 1),
-            handles: RwLock::new(Vec::with_capacity(MAX_LEVEL - 1)),
+            handles: RwLock::new(Vec::with_capacity((MAX_LEVEL - 1) * compaction_threads)),
             next_to_compact: AtomicUsize::default(),
+            compaction_lock: Mutex::default(),
+            value_log_lock: Mutex::default(),
+            deleted_tables: Mutex::default(),
+            value_dictionary: ArcSwapOption::new(value_dictionary),
+            failure_flag: Arc::default(),
+            stats: StatsCounters::default(),
             index_cache,
+            block_cache,
+            file_cache,
         };
 
         let mut receivers = VecDeque::with_capacity(MAX_LEVEL - 1);
 
         for i in 1..=MAX_LEVEL {
-            let dir = std::fs::read_dir(format!("{}/{}", &manager.db_path, i)).unwrap();
+            // an in-memory database starts empty
+            let dir: Vec<std::io::Result<DirEntry>> = if manager.options.in_memory {
+                Vec::new()
+            } else {
+                std::fs::read_dir(level_dir(&manager.db_path, i))
+                    .unwrap()
+                    .collect()
+            };
             let mut file_size = 0;
-            let mut next_table_id = 0;
             for d in dir {
                 let d = d.unwrap();
                 let path = d.path();
                 // The file whose file_name is a number is considered as sstable.
                 if let Ok(table_id) = path.file_name().unwrap().to_str().unwrap().parse::<u64>() {
-                    next_table_id = next_table_id.max(table_id);
-                    let handle = TableReadHandle::open(&manager.db_path, i as _, table_id);
+                    manager.table_ids.observe(table_id);
+                    let handle = TableReadHandle::open_with_dictionary(
+                        &manager.db_path,
+                        i as _,
+                        table_id,
+                        &manager.options,
+                        manager.value_dictionary().as_ref(),
+                    )
+                    .with_file_cache(manager.file_cache.clone());
 
                     // Safety: i is in range [1, MAX_LEVEL]
                     unsafe {
@@ -118,10 +210,6 @@ impl LevelNManager {
                     .level_sizes
                     .get_unchecked(i - 1)
                     .store(file_size, Ordering::Release);
-                manager
-                    .next_table_id
-                    .get_unchecked(i - 1)
-                    .store(next_table_id as u64 + 1, Ordering::Release);
             }
 
             if i < MAX_LEVEL {
@@ -133,31 +221,71 @@ impl LevelNManager {
 
         let manager = Arc::new(manager);
         for i in 1..=MAX_LEVEL - 1 {
+            let receiver = receivers.pop_front().unwrap();
             let mut guard = manager.handles.write().unwrap();
-            guard.push(Self::start_compacting_task(
-                manager.clone(),
-                unsafe { NonZeroUsize::new_unchecked(i) },
-                receivers.pop_front().unwrap(),
-            ));
+            for _ in 0..compaction_threads {
+                guard.push(Self::start_compacting_task(
+                    manager.clone(),
+                    unsafe { NonZeroUsize::new_unchecked(i) },
+                    receiver.clone(),
+                ));
+            }
         }
         manager
     }
 
+    /// Wait for a signal of [LevelNManager::may_compact], and return whether the compacting
+    /// task should go on. With [Options::periodic_compaction_seconds], it also returns true
+    /// after waiting for [PERIODIC_COMPACTION_CHECK_INTERVAL], to look for old sstables.
+    fn recv_compaction_signal(&self, receiver: &Receiver<bool>) -> bool {
+        if self.options.periodic_compaction_seconds == 0 {
+            return receiver.recv().unwrap_or(false);
+        }
+        match receiver.recv_timeout(PERIODIC_COMPACTION_CHECK_INTERVAL) {
+            Ok(signal) => signal,
+            Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => false,
+        }
+    }
+
     fn start_compacting_task(
         leveln_manager: Arc<LevelNManager>,
         compact_level: NonZeroUsize,
         receiver: Receiver<bool>,
     ) -> JoinHandle<()> {
         std::thread::spawn(move || {
+            let _panic_guard = PanicGuard(leveln_manager.failure_flag.clone());
             info!("start compacting task for level {}.", compact_level);
-            while let Ok(true) = receiver.recv() {
-                let leveln_manager2 = leveln_manager.clone();
+            let run = |(handle_to_compact, next_level_tables): CompactionInput| {
+                debug!("compaction level: {}", compact_level);
+                let timer = LatencyTimer::start();
+                compact(
+                    compact_level,
+                    handle_to_compact,
+                    next_level_tables,
+                    leveln_manager.clone(),
+                );
+                timer.record(&leveln_manager.stats().latency.compaction);
+            };
+            while leveln_manager.recv_compaction_signal(&receiver) {
                 if leveln_manager.size_over(compact_level) {
-                    if let Some(handle_to_compact) =
-                        leveln_manager.get_handle_to_compact(compact_level)
+                    if let Some(picked) = leveln_manager.pick_compaction(compact_level) {
+                        run(picked);
+                    }
+                } else {
+                    // the old tables do not wait for the next signal one by one
+                    while let Some(picked) = leveln_manager.pick_periodic_compaction(compact_level)
                     {
-                        debug!("compaction level: {}", compact_level);
-                        start_compact(compact_level, handle_to_compact, leveln_manager2);
+                        run(picked);
+                    }
+                    // the task of the level above the last one rewrites its old tables
+                    if compact_level.get() == MAX_LEVEL - 1 {
+                        while let Some(handle) = leveln_manager.pick_periodic_rewrite() {
+                            debug!("rewrite table {} of level {}", handle.table_id(), MAX_LEVEL);
+                            let timer = LatencyTimer::start();
+                            rewrite_bottom_table(handle, leveln_manager.clone());
+                            timer.record(&leveln_manager.stats().latency.compaction);
+                        }
                     }
                 }
             }
@@ -173,22 +301,30 @@ impl LevelNManager {
         lock
     }
 
-    pub fn get_iterators(&self) -> Vec<Box<dyn Iterator<Item = InternalKeyValue>>> {
+    /// Iterate the key-value pairs of each level, recording the errors of reading the
+    /// sstables in `errors`.
+    pub(crate) fn get_iterators(
+        &self,
+        errors: &IterErrors,
+    ) -> Vec<Box<dyn Iterator<Item = InternalKeyValue>>> {
         self.level_tables
             .iter()
             .map(|tables| {
                 let guard = tables.read().unwrap();
                 let elem: Box<dyn Iterator<Item = InternalKeyValue>> =
-                    Box::new(LevelNIterator::new(&*guard));
+                    Box::new(LevelNIterator::new(&*guard, errors));
                 elem
             })
             .collect()
     }
 
+    /// Query the key-values in [`key_start`, `key_end`]. The sstables whose prefix
+    /// filters show that they have no key starting with `prefix` are skipped.
     pub fn range_query<UK: MemKey>(
         &self,
         key_start: &InternalKey,
         key_end: &InternalKey,
+        prefix: &[u8],
         kvs: &mut SrSwSkipMap<UK, Value>,
     ) {
         for level in (1..=MAX_LEVEL).rev() {
@@ -196,7 +332,18 @@ impl LevelNManager {
                 self.get_level_tables_lock(unsafe { NonZeroUsize::new_unchecked(level) });
             let tables_guard = tables_lock.read().unwrap();
             for (_k, table_read_handle) in tables_guard.range((key_start.clone(), 0)..) {
-                if !table_read_handle.range_query(key_start, key_end, kvs) {
+                if !table_read_handle.may_contain_prefix(prefix) {
+                    if table_read_handle.min_key() > key_end {
+                        break;
+                    }
+                    continue;
+                }
+                if !table_read_handle.range_query(
+                    key_start,
+                    key_end,
+                    kvs,
+                    &self.stats.disk_bytes_read,
+                ) {
                     break;
                 }
             }
@@ -212,17 +359,23 @@ impl LevelNManager {
             if let Some((k, table_read_handle)) = tables_guard.range((key.clone(), 0)..).next() {
                 debug_assert!(key.le(&k.0));
                 debug_assert!(table_read_handle.readable());
-                let entry_tracker = self
+                let entry = self
                     .index_cache
-                    .look_up(&table_read_handle.table_key(), table_read_handle.hash());
+                    .get(&table_read_handle.table_key(), table_read_handle.hash());
 
-                let option = if entry_tracker.0.is_null() {
-                    table_read_handle.query_sstable(key, &self.index_cache)
-                } else {
-                    let mut table_cache = unsafe {
-                        (*(entry_tracker.0 as *mut LRUEntry<u64, TableCache>)).value_mut()
-                    };
-                    table_read_handle.query_sstable_with_cache(key, &mut table_cache)
+                let option = match entry {
+                    Some(table_cache) => table_read_handle.query_sstable_with_cache(
+                        key,
+                        &table_cache,
+                        self.block_cache.as_ref(),
+                        &self.stats,
+                    )?,
+                    None => table_read_handle.query_sstable(
+                        key,
+                        &self.index_cache,
+                        self.block_cache.as_ref(),
+                        &self.stats,
+                    )?,
                 };
                 if option.is_some() {
                     return Ok(option);
@@ -232,12 +385,10 @@ impl LevelNManager {
         Ok(None)
     }
 
-    fn get_next_table_id(&self, level: NonZeroUsize) -> u64 {
-        unsafe {
-            self.next_table_id
-                .get_unchecked(level.get() - 1)
-                .fetch_add(1, Ordering::SeqCst)
-        }
+    /// Allocator of the table ids of all the levels, level 0 included.
+    #[inline]
+    pub(crate) fn table_ids(&self) -> &TableIdAllocator {
+        &self.table_ids
     }
 
     pub fn upsert_table_handle(&self, handle: TableWriteHandle) {
@@ -245,8 +396,11 @@ impl LevelNManager {
         debug_assert!(file_size > 0);
 
         let level = NonZeroUsize::new(handle.level()).unwrap();
+        let value_log_bytes = handle.value_log_bytes_written();
 
-        let handle = TableReadHandle::from_table_write_handle(handle);
+        let _value_log_guard = self.value_log_lock.lock().unwrap();
+        let handle = TableReadHandle::from_table_write_handle(handle)
+            .with_file_cache(self.file_cache.clone());
 
         let lock = self.get_level_tables_lock(level);
         let mut table_guard = lock.write().unwrap();
@@ -263,6 +417,100 @@ impl LevelNManager {
                 .get_unchecked(level.get() - 1)
                 .fetch_add(file_size, Ordering::SeqCst);
         }
+        StatsCounters::add(&self.stats.sstable_bytes_written, file_size as usize);
+        StatsCounters::add(
+            &self.stats.value_log_bytes_written,
+            value_log_bytes as usize,
+        );
+    }
+
+    /// Insert all the tables of the same level under one lock,
+    /// so that readers see either none or all of them.
+    pub(crate) fn install_table_handles(&self, handles: Vec<TableWriteHandle>) {
+        let level = match handles.first() {
+            Some(handle) => NonZeroUsize::new(handle.level()).unwrap(),
+            None => return,
+        };
+        let _value_log_guard = self.value_log_lock.lock().unwrap();
+        let value_log_bytes = Self::value_log_bytes_written(&handles);
+        let handles = self.to_read_handles(handles);
+        let mut table_guard = self.get_level_tables_lock(level).write().unwrap();
+        self.insert_read_handles(&mut table_guard, level, handles, value_log_bytes);
+    }
+
+    /// Like [LevelNManager::install_table_handles], but install none of `handles` and
+    /// give them back if any of them overlaps with a table of their level, since the
+    /// tables of a level must not overlap each other.
+    pub(crate) fn install_disjoint_table_handles(
+        &self,
+        handles: Vec<TableWriteHandle>,
+    ) -> std::result::Result<(), Vec<TableWriteHandle>> {
+        let level = match handles.first() {
+            Some(handle) => NonZeroUsize::new(handle.level()).unwrap(),
+            None => return Ok(()),
+        };
+        let _value_log_guard = self.value_log_lock.lock().unwrap();
+        let mut table_guard = self.get_level_tables_lock(level).write().unwrap();
+        let overlapping = handles.iter().any(|handle| {
+            table_guard
+                .values()
+                .any(|table| table.is_overlapping(handle.min_key(), handle.max_key()))
+        });
+        if overlapping {
+            return Err(handles);
+        }
+        let value_log_bytes = Self::value_log_bytes_written(&handles);
+        let handles = self.to_read_handles(handles);
+        self.insert_read_handles(&mut table_guard, level, handles, value_log_bytes);
+        Ok(())
+    }
+
+    fn value_log_bytes_written(handles: &[TableWriteHandle]) -> u64 {
+        handles
+            .iter()
+            .map(TableWriteHandle::value_log_bytes_written)
+            .sum()
+    }
+
+    /// Rename the files of `handles` and open them for reading.
+    fn to_read_handles(&self, handles: Vec<TableWriteHandle>) -> Vec<TableReadHandle> {
+        handles
+            .into_iter()
+            .map(|handle| {
+                TableReadHandle::from_table_write_handle(handle)
+                    .with_file_cache(self.file_cache.clone())
+            })
+            .collect()
+    }
+
+    fn insert_read_handles(
+        &self,
+        table_guard: &mut BTreeMap<(InternalKey, u64), Arc<TableReadHandle>>,
+        level: NonZeroUsize,
+        handles: Vec<TableReadHandle>,
+        value_log_bytes: u64,
+    ) {
+        let mut file_size = 0;
+        for handle in handles {
+            debug_assert_eq!(handle.level(), level.get());
+            file_size += handle.file_size();
+            let option = table_guard.insert(
+                (handle.max_key().clone(), handle.table_id()),
+                Arc::new(handle),
+            );
+            debug_assert!(option.is_none());
+        }
+
+        unsafe {
+            self.level_sizes
+                .get_unchecked(level.get() - 1)
+                .fetch_add(file_size, Ordering::SeqCst);
+        }
+        StatsCounters::add(&self.stats.sstable_bytes_written, file_size as usize);
+        StatsCounters::add(
+            &self.stats.value_log_bytes_written,
+            value_log_bytes as usize,
+        );
     }
 
     pub fn ready_to_delete(&self, table_handle: Arc<TableReadHandle>) {
@@ -278,13 +526,26 @@ impl LevelNManager {
             .get_level_tables_lock(unsafe { NonZeroUsize::new_unchecked(level) })
             .write()
             .unwrap();
+        self.add_deleted_table(&table_handle);
         let t = guard
             .remove(&(table_handle.max_key().clone(), table_handle.table_id()))
             .unwrap();
 
         t.ready_to_delete();
-        self.index_cache
-            .erase(&table_handle.table_key(), table_handle.hash());
+        self.evict_table_caches(&table_handle);
+    }
+
+    /// Erase the cache entry of the deleted `table` and its cached data blocks, which
+    /// would otherwise take the room of live data until they are evicted, and close its
+    /// file if it is kept open.
+    pub(crate) fn evict_table_caches(&self, table: &TableReadHandle) {
+        self.index_cache.erase(&table.table_key(), table.hash());
+        if let Some(file_cache) = &self.file_cache {
+            file_cache.erase(&table.table_key(), table.hash());
+        }
+        if let Some(block_cache) = &self.block_cache {
+            block_cache.erase_table(table.table_id());
+        }
     }
 
     /// Create a new sstable without `min_key` or `max_key`
@@ -293,14 +554,255 @@ impl LevelNManager {
         level: NonZeroUsize,
         kv_total: u32,
     ) -> TableWriteHandle {
-        let next_table_id = self.get_next_table_id(level);
-        TableWriteHandle::new(&self.db_path, level.get(), next_table_id, kv_total)
+        let next_table_id = self.table_ids.allocate();
+        TableWriteHandle::new(
+            &self.db_path,
+            level.get(),
+            next_table_id,
+            kv_total,
+            &self.options,
+        )
+        .with_value_dictionary(self.compression_dictionary())
+    }
+
+    /// Like [LevelNManager::create_table_write_handle], but the table is written with
+    /// direct I/O if [Options::use_direct_io_for_compaction] is true.
+    pub(crate) fn create_compaction_table_write_handle(
+        &self,
+        level: NonZeroUsize,
+        kv_total: u32,
+    ) -> Result<TableWriteHandle> {
+        let next_table_id = self.table_ids.allocate();
+        self.create_compaction_table_write_handle_with_id(level, next_table_id, kv_total)
+    }
+
+    /// Like [LevelNManager::create_compaction_table_write_handle], but the table id is
+    /// already allocated.
+    pub(crate) fn create_compaction_table_write_handle_with_id(
+        &self,
+        level: NonZeroUsize,
+        table_id: TableID,
+        kv_total: u32,
+    ) -> Result<TableWriteHandle> {
+        Ok(TableWriteHandle::new_with_direct_io(
+            &self.db_path,
+            level.get(),
+            table_id,
+            kv_total,
+            &self.options,
+            self.options.use_direct_io_for_compaction,
+        )?
+        .with_value_dictionary(self.compression_dictionary()))
+    }
+
+    /// The dictionary the values of the database are compressed with, if it has been
+    /// trained, see [crate::sstable::dictionary].
+    #[inline]
+    pub(crate) fn value_dictionary(&self) -> Option<Arc<ValueDictionary>> {
+        self.value_dictionary.load_full()
+    }
+
+    /// The dictionary to compress the values of a new sstable with, if
+    /// [Options::dictionary_compression] is true.
+    pub(crate) fn compression_dictionary(&self) -> Option<Arc<ValueDictionary>> {
+        if self.options.dictionary_compression {
+            self.value_dictionary()
+        } else {
+            None
+        }
+    }
+
+    /// Train the dictionary of the database on `values` and save it, unless it has been
+    /// trained already or the values are separated. Only the flush of level 0 calls it.
+    pub(crate) fn train_value_dictionary<'a>(
+        &self,
+        values: impl Iterator<Item = &'a Value>,
+    ) -> Result<()> {
+        if self.value_dictionary.load().is_some() || self.separates_values() {
+            return Ok(());
+        }
+        if let Some(dictionary) = ValueDictionary::train(values) {
+            if !self.options.in_memory {
+                dictionary.save(&self.db_path)?;
+            }
+            self.value_dictionary.store(Some(Arc::new(dictionary)));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Flag shared by all the background tasks of the database.
+    #[inline]
+    pub(crate) fn failure_flag(&self) -> &Arc<FailureFlag> {
+        &self.failure_flag
+    }
+
+    /// Whether the sstables written by compaction separate values,
+    /// see [crate::sstable::value_log].
+    #[inline]
+    pub(crate) fn separates_values(&self) -> bool {
+        self.options.value_log_threshold != 0 && !self.options.in_memory
+    }
+
+    /// Iterator over a table being compacted, yielding the values in the form written
+    /// by [TableWriteHandle::write_sstable_from_stored_vec].
+    pub(crate) fn compaction_iter(&self, handle: Arc<TableReadHandle>) -> TableIterator {
+        if self.separates_values() {
+            TableReadHandle::stored_iter(handle)
+        } else {
+            TableReadHandle::iter(handle)
+        }
+    }
+
+    /// Directory of the value log if compaction separates values, for [filter_kvs].
+    ///
+    /// [filter_kvs]: crate::compaction::filter::filter_kvs
+    pub(crate) fn compaction_value_log_dir(&self) -> Option<String> {
+        if self.separates_values() {
+            Some(value_log_dir(&self.db_path))
+        } else {
+            None
+        }
+    }
+
+    /// Remember `table`, which is being removed from its level with the level locked, so
+    /// that the value log files it points to outlive its handle.
+    pub(crate) fn add_deleted_table(&self, table: &Arc<TableReadHandle>) {
+        if table.separated_values() {
+            let mut guard = self.deleted_tables.lock().unwrap();
+            guard.retain(|table| table.strong_count() > 0);
+            guard.push(Arc::downgrade(table));
+        }
+    }
+
+    /// The tables with separated values which are removed from the levels but still
+    /// alive. The garbage collection of the value log lists them after the levels, so
+    /// that a table removed meanwhile is seen in either.
+    pub(crate) fn deleted_tables(&self) -> Vec<Arc<TableReadHandle>> {
+        let mut guard = self.deleted_tables.lock().unwrap();
+        guard.retain(|table| table.strong_count() > 0);
+        guard.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /// See the field `value_log_lock`.
+    #[inline]
+    pub(crate) fn value_log_lock(&self) -> &Mutex<()> {
+        &self.value_log_lock
+    }
+
+    /// Counters of bytes read and written, shared by all the levels.
+    pub(crate) fn stats(&self) -> &StatsCounters {
+        &self.stats
+    }
+
+    #[inline]
+    pub(crate) fn block_cache(&self) -> Option<&SharedBlockCache> {
+        self.block_cache.as_ref()
+    }
+
+    #[inline]
+    pub(crate) fn file_cache(&self) -> Option<&Arc<TableFileCache>> {
+        self.file_cache.as_ref()
+    }
+
+    /// Number of the sstable files kept open by [Options::max_open_files], not counting
+    /// the evicted files which are still being read.
+    pub(crate) fn open_table_files(&self) -> usize {
+        self.file_cache
+            .as_ref()
+            .map_or(0, |file_cache| file_cache.live_entries())
+    }
+
+    /// Whether a query of `table` can load its caches without evicting other entries.
+    pub(crate) fn cache_has_room(&self, table: &TableReadHandle) -> bool {
+        (self.index_cache.has_room(table.hash())
+            || self
+                .index_cache
+                .get(&table.table_key(), table.hash())
+                .is_some())
+            && self
+                .block_cache
+                .as_ref()
+                .map_or(true, SharedBlockCache::has_room)
+    }
+
+    /// Load the filter and the index of `table` into the table cache if they are not
+    /// cached and the cache has room for them.
+    pub(crate) fn warm_table_cache(&self, table: &TableReadHandle) {
+        if self.index_cache.has_room(table.hash())
+            && self
+                .index_cache
+                .get(&table.table_key(), table.hash())
+                .is_none()
+        {
+            let cache = table.load_table_cache();
+            table.insert_if_readable(|| {
+                self.index_cache
+                    .insert_no_exists(table.table_key(), cache, table.hash())
+            });
+        }
+    }
+
+    /// The sstables of levels 1 to [MAX_LEVEL] which a query of `key` may read.
+    pub(crate) fn tables_may_contain(&self, key: &InternalKey) -> Vec<Arc<TableReadHandle>> {
+        let mut tables = vec![];
+        for level in 1..=MAX_LEVEL {
+            let tables_lock =
+                self.get_level_tables_lock(unsafe { NonZeroUsize::new_unchecked(level) });
+            let tables_guard = tables_lock.read().unwrap();
+            if let Some((_, table)) = tables_guard.range((key.clone(), 0)..).next() {
+                if table.may_contain(key) {
+                    tables.push(table.clone());
+                }
+            }
+        }
+        tables
+    }
+
+    /// Bytes held by the table cache, which is shared with level 0, and by the prefix
+    /// filters of the sstables in levels other than level 0.
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        let prefix_filters_size: usize = self
+            .level_tables
+            .iter()
+            .map(|tables| {
+                let guard = tables.read().unwrap();
+                guard
+                    .values()
+                    .map(|table| table.prefix_filter_size())
+                    .sum::<usize>()
+            })
+            .sum();
+        MemoryUsage {
+            block_cache: self
+                .index_cache
+                .total_charge(|_, cache| cache.data_blocks_size()) as u64,
+            filters: (self
+                .index_cache
+                .total_charge(|_, cache| cache.filter.len() as usize)
+                + prefix_filters_size) as u64,
+            index_blocks: self
+                .index_cache
+                .total_charge(|_, cache| cache.index.memory_size())
+                as u64,
+            ..MemoryUsage::default()
+        }
+    }
+
+    /// Get the max key of all the sstables in `level`.
+    pub(crate) fn max_key(&self, level: NonZeroUsize) -> Option<InternalKey> {
+        let guard = self.get_level_tables_lock(level).read().unwrap();
+        guard.keys().next_back().map(|(max_key, _)| max_key.clone())
     }
 
     /// Get sstable file count of `level`, used for judging whether need compacting.
     pub fn file_count(&self, level: usize) -> usize {
         debug_assert!((1..=MAX_LEVEL).contains(&level));
-        let tables = self.level_tables.get(level).unwrap();
+        let tables = self.level_tables.get(level - 1).unwrap();
         let guard = tables.read().unwrap();
         guard.len()
     }
@@ -312,6 +814,32 @@ impl LevelNManager {
         min_key: &InternalKey,
         max_key: &InternalKey,
     ) -> VecDeque<Arc<TableReadHandle>> {
+        self.overlap_tables(level, min_key, max_key, true)
+    }
+
+    /// Return the tables [Self::get_overlap_tables] would return,
+    /// without marking them as compacting.
+    pub(crate) fn peek_overlap_tables(
+        &self,
+        level: NonZeroUsize,
+        min_key: &InternalKey,
+        max_key: &InternalKey,
+    ) -> VecDeque<Arc<TableReadHandle>> {
+        self.overlap_tables(level, min_key, max_key, false)
+    }
+
+    fn overlap_tables(
+        &self,
+        level: NonZeroUsize,
+        min_key: &InternalKey,
+        max_key: &InternalKey,
+        claim: bool,
+    ) -> VecDeque<Arc<TableReadHandle>> {
+        let _claim_guard = if claim {
+            Some(self.compaction_lock.lock().unwrap())
+        } else {
+            None
+        };
         let tables_lock = self.get_level_tables_lock(level);
         let tables_guard = tables_lock.read().unwrap();
 
@@ -322,7 +850,12 @@ impl LevelNManager {
         // max_key:  "1", "3", "5", "7" ...
         for (_key, handle) in tables_guard.range((min_key.clone(), 0)..) {
             if handle.is_overlapping(min_key, max_key) {
-                if handle.test_and_set_compacting() {
+                let picked = if claim {
+                    handle.test_and_set_compacting()
+                } else {
+                    handle.status() == TableStatus::Store
+                };
+                if picked {
                     let handle = handle.clone();
                     tables.push_back(handle);
                 }
@@ -349,10 +882,12 @@ impl LevelNManager {
         size > 10u64.pow(level.get() as u32) * 1024 * 1024
     }
 
+    #[cfg(test)]
     pub(crate) fn get_handle_to_compact(
         &self,
         level: NonZeroUsize,
     ) -> Option<Arc<TableReadHandle>> {
+        let _claim_guard = self.compaction_lock.lock().unwrap();
         let lock = self.get_level_tables_lock(level);
         let guard = lock.read().unwrap();
 
@@ -369,9 +904,181 @@ impl LevelNManager {
         None
     }
 
+    /// Claim a table of `level` and its overlapping tables in the next level for a
+    /// compaction. The tables whose overlapping tables in the next level are being
+    /// compacted are skipped, so the compactions running at the same time never write
+    /// overlapping key ranges to the next level.
+    pub(crate) fn pick_compaction(&self, level: NonZeroUsize) -> Option<CompactionInput> {
+        debug_assert!(level.get() < MAX_LEVEL);
+        let _claim_guard = self.compaction_lock.lock().unwrap();
+
+        let (start, candidates): (usize, Vec<Arc<TableReadHandle>>) = {
+            let guard = self.get_level_tables_lock(level).read().unwrap();
+            if guard.is_empty() {
+                return None;
+            }
+            let start = self.next_to_compact.load(Ordering::Acquire) % guard.len();
+            let candidates = guard
+                .values()
+                .skip(start)
+                .chain(guard.values().take(start))
+                .cloned()
+                .collect();
+            (start, candidates)
+        };
+
+        let (i, picked) = self.claim_first_compaction(level, &candidates)?;
+        self.next_to_compact
+            .store((start + i + 1) % candidates.len(), Ordering::SeqCst);
+        Some(picked)
+    }
+
+    /// Like [LevelNManager::pick_compaction], but only claim a table older than
+    /// [Options::periodic_compaction_seconds], the oldest first.
+    pub(crate) fn pick_periodic_compaction(&self, level: NonZeroUsize) -> Option<CompactionInput> {
+        debug_assert!(level.get() < MAX_LEVEL);
+        let _claim_guard = self.compaction_lock.lock().unwrap();
+
+        let mut candidates: Vec<Arc<TableReadHandle>> = {
+            let guard = self.get_level_tables_lock(level).read().unwrap();
+            guard
+                .values()
+                .filter(|table| self.is_expired(table))
+                .cloned()
+                .collect()
+        };
+        candidates.sort_by_key(|table| table.created_at());
+        self.claim_first_compaction(level, &candidates)
+            .map(|(_, picked)| picked)
+    }
+
+    /// Claim the oldest table of [MAX_LEVEL] older than
+    /// [Options::periodic_compaction_seconds], to be rewritten in place as there is no
+    /// next level to compact it into.
+    pub(crate) fn pick_periodic_rewrite(&self) -> Option<Arc<TableReadHandle>> {
+        let _claim_guard = self.compaction_lock.lock().unwrap();
+        let guard = self
+            .get_level_tables_lock(NonZeroUsize::new(MAX_LEVEL).unwrap())
+            .read()
+            .unwrap();
+        let mut candidates: Vec<&Arc<TableReadHandle>> = guard
+            .values()
+            .filter(|table| self.is_expired(table))
+            .collect();
+        candidates.sort_by_key(|table| table.created_at());
+        candidates
+            .into_iter()
+            .find(|table| table.test_and_set_compacting())
+            .cloned()
+    }
+
+    /// Claim the first of `candidates` of `level` whose overlapping tables in the next
+    /// level are not compacting, together with those tables, and return its index.
+    /// Called with `compaction_lock` held.
+    fn claim_first_compaction(
+        &self,
+        level: NonZeroUsize,
+        candidates: &[Arc<TableReadHandle>],
+    ) -> Option<(usize, CompactionInput)> {
+        let next_level = unsafe { NonZeroUsize::new_unchecked(level.get() + 1) };
+        let next_level_guard = self.get_level_tables_lock(next_level).read().unwrap();
+        for (i, handle) in candidates.iter().enumerate() {
+            if handle.status() != TableStatus::Store {
+                continue;
+            }
+            let next_level_tables: VecDeque<Arc<TableReadHandle>> = next_level_guard
+                .range((handle.min_key().clone(), 0)..)
+                .map(|(_, table)| table)
+                .take_while(|table| table.is_overlapping(handle.min_key(), handle.max_key()))
+                .cloned()
+                .collect();
+            if next_level_tables
+                .iter()
+                .any(|table| table.status() != TableStatus::Store)
+            {
+                continue;
+            }
+
+            // all the claims are made under `compaction_lock`, so none of them fails
+            let claimed = handle.test_and_set_compacting();
+            debug_assert!(claimed);
+            for table in next_level_tables.iter() {
+                let claimed = table.test_and_set_compacting();
+                debug_assert!(claimed);
+            }
+            return Some((i, (handle.clone(), next_level_tables)));
+        }
+        None
+    }
+
+    /// Whether `table` was created more than [Options::periodic_compaction_seconds] ago.
+    fn is_expired(&self, table: &TableReadHandle) -> bool {
+        let period = self.options.periodic_compaction_seconds;
+        period != 0 && table.created_at().saturating_add(period) < self.options.clock.now_secs()
+    }
+
+    /// Whether `level` has a table for [LevelNManager::pick_periodic_compaction].
+    fn has_expired_table(&self, level: NonZeroUsize) -> bool {
+        self.options.periodic_compaction_seconds != 0
+            && self
+                .get_level_tables_lock(level)
+                .read()
+                .unwrap()
+                .values()
+                .any(|table| table.status() == TableStatus::Store && self.is_expired(table))
+    }
+
+    /// Mark all the tables of levels 1 to [MAX_LEVEL] which are not compacting as
+    /// compacting, and return them. See [crate::db::DB::clear].
+    pub(crate) fn claim_idle_tables(&self) -> Vec<Arc<TableReadHandle>> {
+        let _claim_guard = self.compaction_lock.lock().unwrap();
+        let mut tables = vec![];
+        for level in 1..=MAX_LEVEL {
+            let guard = self
+                .get_level_tables_lock(unsafe { NonZeroUsize::new_unchecked(level) })
+                .read()
+                .unwrap();
+            tables.extend(
+                guard
+                    .values()
+                    .filter(|table| table.test_and_set_compacting())
+                    .cloned(),
+            );
+        }
+        tables
+    }
+
+    /// Return the handle [Self::pick_compaction] would likely claim if `level` should be
+    /// compacted, without marking it as compacting.
+    pub(crate) fn peek_handle_to_compact(
+        &self,
+        level: NonZeroUsize,
+    ) -> Option<Arc<TableReadHandle>> {
+        if level.get() >= MAX_LEVEL || !self.size_over(level) {
+            return None;
+        }
+        let lock = self.get_level_tables_lock(level);
+        let guard = lock.read().unwrap();
+        if guard.is_empty() {
+            return None;
+        }
+
+        let start = self.next_to_compact.load(Ordering::Acquire);
+        (0..10)
+            .map(|i| guard.values().nth((start + i) % guard.len()).unwrap())
+            .find(|v| v.status() == TableStatus::Store)
+            .cloned()
+    }
+
     /// May compaction `level`th sstables.
     pub fn may_compact(&self, level: NonZeroUsize) {
-        if level.get() < MAX_LEVEL && self.size_over(level) {
+        // the task of level MAX_LEVEL - 1 also rewrites the old tables of MAX_LEVEL
+        if level.get() < MAX_LEVEL
+            && (self.size_over(level)
+                || self.has_expired_table(level)
+                || (level.get() == MAX_LEVEL - 1
+                    && self.has_expired_table(NonZeroUsize::new(MAX_LEVEL).unwrap())))
+        {
             if let Err(e) = self.senders.get(level.get() - 1).unwrap().send(true) {
                 warn!("{:#?}", e);
             }
@@ -379,13 +1086,19 @@ impl LevelNManager {
     }
 
     pub(crate) fn close(&self) {
+        let compaction_threads = self.options.compaction_threads_per_level.max(1);
         for sender in self.senders.iter() {
-            sender.send(false).unwrap();
+            // every compacting task of the level exits after receiving one `false`,
+            // and the receiver is dropped if all of them panicked.
+            for _ in 0..compaction_threads {
+                let _ = sender.send(false);
+            }
         }
-        let mut guard = self.handles.write().unwrap();
-        while !guard.is_empty() {
-            let handle = guard.pop().unwrap();
-            handle.join().unwrap();
+        let mut guard = self.handles.write().unwrap_or_else(PoisonError::into_inner);
+        while let Some(handle) = guard.pop() {
+            if handle.join().is_err() {
+                error!("compacting task panicked");
+            }
         }
     }
 }
@@ -395,13 +1108,23 @@ pub(crate) mod tests {
     use std::sync::Arc;
 
     use crate::cache::ShardLRUCache;
+    use crate::compaction::filter::{CompactionFilter, Decision};
+    use crate::db::clock::Clock;
+    use crate::db::options::Options;
     use crate::db::MAX_LEVEL;
     use crate::sstable::manager::level_n::LevelNManager;
     use crate::sstable::table_handle::tests::create_read_handle;
+    use crate::sstable::table_handle::TableReadHandle;
+    use crate::sstable::{sstable_file, CompressionType};
+    use std::num::NonZeroUsize;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::time::{Duration, Instant};
 
     pub(crate) fn create_manager(db_path: &str) -> Arc<LevelNManager> {
         let index_cache = Arc::new(ShardLRUCache::default());
-        LevelNManager::open_tables(db_path.to_string(), index_cache)
+        LevelNManager::open_tables(db_path.to_string(), Options::default(), index_cache)
     }
 
     #[test]
@@ -424,4 +1147,340 @@ pub(crate) mod tests {
         );
         manager.close();
     }
+
+    #[test]
+    fn test_create_tables_concurrently() {
+        let path = tempfile::TempDir::new().unwrap();
+        let db_path = path.path().to_str().unwrap();
+        let manager = create_manager(db_path);
+
+        let handles: Vec<_> = (1..=MAX_LEVEL)
+            .map(|level| {
+                let manager = manager.clone();
+                std::thread::spawn(move || {
+                    let level = NonZeroUsize::new(level).unwrap();
+                    let mut table_ids = vec![];
+                    for i in 0..20 {
+                        // the temporary file is created exclusively, so a collision panics
+                        let mut handle = manager.create_table_write_handle(level, 1);
+                        handle
+                            .write_sstable_from_vec(vec![(
+                                format!("key{}", i).into_bytes(),
+                                Vec::from("value"),
+                            )])
+                            .unwrap();
+                        table_ids.push(handle.table_id());
+                        handle.discard();
+                    }
+                    table_ids
+                })
+            })
+            .collect();
+        let mut all_ids = vec![];
+        for handle in handles {
+            let table_ids = handle.join().unwrap();
+            assert!(table_ids.windows(2).all(|w| w[0] < w[1]));
+            all_ids.extend(table_ids);
+        }
+        all_ids.sort_unstable();
+        all_ids.dedup();
+        assert_eq!(all_ids.len(), 20 * MAX_LEVEL);
+        manager.close();
+
+        // ids are not reused after reopening
+        let manager = create_manager(db_path);
+        let handle = manager.create_table_write_handle(NonZeroUsize::new(1).unwrap(), 1);
+        assert!(handle.table_id() > *all_ids.last().unwrap());
+        handle.discard();
+        manager.close();
+    }
+
+    /// Write the keys `key{i}` of `range` to a new sstable in `level`.
+    fn add_table(
+        manager: &LevelNManager,
+        level: usize,
+        range: std::ops::Range<usize>,
+        value: &[u8],
+    ) {
+        let mut handle = manager
+            .create_table_write_handle(NonZeroUsize::new(level).unwrap(), range.len() as u32);
+        let kvs = range
+            .map(|i| (format!("key{:05}", i).into_bytes(), value.to_vec()))
+            .collect();
+        handle.write_sstable_from_vec(kvs).unwrap();
+        manager.upsert_table_handle(handle);
+    }
+
+    #[test]
+    fn test_pick_compaction() {
+        let path = tempfile::TempDir::new().unwrap();
+        let db_path = path.path().to_str().unwrap();
+        let manager = create_manager(db_path);
+
+        add_table(&manager, 1, 100..110, b"v1");
+        add_table(&manager, 1, 110..120, b"v1");
+        add_table(&manager, 1, 130..140, b"v1");
+        // overlaps with both the first and the second table of level 1
+        add_table(&manager, 2, 105..115, b"v2");
+
+        let one = NonZeroUsize::new(1).unwrap();
+        let (handle, next_level_tables) = manager.pick_compaction(one).unwrap();
+        assert_eq!(handle.min_key(), b"key00100");
+        assert_eq!(next_level_tables.len(), 1);
+        assert_eq!(next_level_tables[0].min_key(), b"key00105");
+
+        // the second table waits for the table of level 2 claimed by the first one
+        let (handle, next_level_tables) = manager.pick_compaction(one).unwrap();
+        assert_eq!(handle.min_key(), b"key00130");
+        assert!(next_level_tables.is_empty());
+        assert!(manager.pick_compaction(one).is_none());
+        manager.close();
+    }
+
+    #[derive(Debug, Default)]
+    struct SlowFilter {
+        running: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl CompactionFilter for SlowFilter {
+        fn filter(&self, key: &[u8], _value: &[u8]) -> Decision {
+            // the first key of each sstable
+            if key.ends_with(b"000") {
+                let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+                self.peak.fetch_max(running, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(200));
+                self.running.fetch_sub(1, Ordering::SeqCst);
+            }
+            Decision::Keep
+        }
+    }
+
+    #[test]
+    fn test_concurrent_compaction() {
+        let path = tempfile::TempDir::new().unwrap();
+        let db_path = path.path().to_str().unwrap();
+        let filter = Arc::new(SlowFilter::default());
+        let options = Options {
+            compression: CompressionType::NoCompression,
+            compaction_filter: Some(filter.clone()),
+            compaction_threads_per_level: 4,
+            ..Options::default()
+        };
+        let manager = LevelNManager::open_tables(
+            db_path.to_string(),
+            options,
+            Arc::new(ShardLRUCache::default()),
+        );
+
+        // 16 sstables of about 1MB, so that level 1 is still over 10MB after 4 compactions
+        let value = vec![b'v'; 1000];
+        for i in 0..16 {
+            add_table(&manager, 1, i * 1000..(i + 1) * 1000, &value);
+        }
+        let one = NonZeroUsize::new(1).unwrap();
+        assert!(manager.size_over(one));
+
+        for _ in 0..4 {
+            manager.may_compact(one);
+        }
+        let start = Instant::now();
+        while manager.file_count(2) < 4 {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "compaction stalls"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(filter.peak.load(Ordering::SeqCst) >= 2);
+        assert_eq!(manager.file_count(1), 12);
+        assert_eq!(manager.file_count(2), 4);
+
+        for i in (0..16000).step_by(97) {
+            assert_eq!(
+                manager.query(&format!("key{:05}", i).into_bytes()).unwrap(),
+                Some(value.clone())
+            );
+        }
+        manager.close();
+    }
+
+    #[derive(Debug)]
+    struct ManualClock(AtomicU64);
+
+    impl Clock for ManualClock {
+        fn now_secs(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn test_periodic_compaction() {
+        let path = tempfile::TempDir::new().unwrap();
+        let db_path = path.path().to_str().unwrap();
+        let clock = Arc::new(ManualClock(AtomicU64::new(1000)));
+        let options = Options {
+            periodic_compaction_seconds: 3600,
+            clock: clock.clone(),
+            ..Options::default()
+        };
+        let manager = LevelNManager::open_tables(
+            db_path.to_string(),
+            options,
+            Arc::new(ShardLRUCache::default()),
+        );
+        let one = NonZeroUsize::new(1).unwrap();
+
+        add_table(&manager, 1, 0..100, b"v");
+        clock.0.fetch_add(1000, Ordering::SeqCst);
+        add_table(&manager, 1, 100..200, b"v");
+        assert!(!manager.size_over(one));
+        manager.may_compact(one);
+        assert!(manager.pick_periodic_compaction(one).is_none());
+
+        // only the older table is past the threshold
+        clock.0.fetch_add(3000, Ordering::SeqCst);
+        manager.may_compact(one);
+        let start = Instant::now();
+        while manager.file_count(2) < 1 || manager.file_count(1) > 1 {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "compaction stalls"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(manager.file_count(1), 1);
+        let guard = manager.get_level_tables_lock(one).read().unwrap();
+        assert_eq!(guard.values().next().unwrap().min_key(), b"key00100");
+        drop(guard);
+        // the rewritten table is new
+        let two = NonZeroUsize::new(2).unwrap();
+        assert!(manager.pick_periodic_compaction(two).is_none());
+        assert_eq!(
+            manager.query(&b"key00042".to_vec()).unwrap(),
+            Some(b"v".to_vec())
+        );
+        manager.close();
+    }
+
+    #[test]
+    fn test_periodic_rewrite_bottom_level() {
+        let path = tempfile::TempDir::new().unwrap();
+        let db_path = path.path().to_str().unwrap();
+        let clock = Arc::new(ManualClock(AtomicU64::new(1000)));
+        let options = Options {
+            periodic_compaction_seconds: 3600,
+            clock: clock.clone(),
+            ..Options::default()
+        };
+        let manager = LevelNManager::open_tables(
+            db_path.to_string(),
+            options,
+            Arc::new(ShardLRUCache::default()),
+        );
+        let max_level = NonZeroUsize::new(MAX_LEVEL).unwrap();
+        let above = NonZeroUsize::new(MAX_LEVEL - 1).unwrap();
+
+        // the odd keys are deleted
+        let mut handle = manager.create_table_write_handle(max_level, 100);
+        let kvs = (0..100)
+            .map(|i| {
+                let value = if i % 2 == 0 { b"v".to_vec() } else { vec![] };
+                (format!("key{:05}", i).into_bytes(), value)
+            })
+            .collect();
+        handle.write_sstable_from_vec(kvs).unwrap();
+        manager.upsert_table_handle(handle);
+        manager.may_compact(above);
+        assert!(manager.pick_periodic_rewrite().is_none());
+        assert_eq!(manager.query(&b"key00043".to_vec()).unwrap(), Some(vec![]));
+
+        clock.0.fetch_add(3601, Ordering::SeqCst);
+        manager.may_compact(above);
+        let start = Instant::now();
+        loop {
+            let kv_total: u32 = {
+                let guard = manager.get_level_tables_lock(max_level).read().unwrap();
+                guard.values().map(|table| table.kv_total()).sum()
+            };
+            if manager.file_count(MAX_LEVEL) == 1 && kv_total == 50 {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(30), "rewrite stalls");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            manager.query(&b"key00042".to_vec()).unwrap(),
+            Some(b"v".to_vec())
+        );
+        assert_eq!(manager.query(&b"key00043".to_vec()).unwrap(), None);
+        // the rewritten table is new
+        assert!(manager.pick_periodic_rewrite().is_none());
+        manager.close();
+    }
+
+    #[test]
+    fn test_read_table_being_deleted() {
+        let path = tempfile::TempDir::new().unwrap();
+        let db_path = path.path().to_str().unwrap();
+        let options = Options {
+            max_open_files: 16,
+            ..Options::default()
+        };
+        let manager = LevelNManager::open_tables(db_path.to_string(), options, Arc::default());
+        add_table(&manager, 1, 0..1000, b"v");
+        let one = NonZeroUsize::new(1).unwrap();
+        let handle = manager
+            .get_level_tables_lock(one)
+            .read()
+            .unwrap()
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+        let file = sstable_file(db_path, 1, handle.table_id());
+
+        let started = Arc::new(Barrier::new(2));
+        let deleted = Arc::new(Barrier::new(2));
+        let reader = {
+            let handle = handle.clone();
+            let manager = manager.clone();
+            let started = started.clone();
+            let deleted = deleted.clone();
+            std::thread::spawn(move || {
+                let mut iter = TableReadHandle::iter(handle.clone());
+                let first = iter.next().unwrap();
+                started.wait();
+                deleted.wait();
+                // the file is still readable, both by the open reader and by new ones
+                let count = 1 + iter.filter(|(_, value)| value == b"v").count();
+                let value = handle
+                    .query_sstable(
+                        &b"key00500".to_vec(),
+                        &manager.index_cache,
+                        manager.block_cache(),
+                        manager.stats(),
+                    )
+                    .unwrap();
+                (first, count, value)
+            })
+        };
+
+        started.wait();
+        assert!(handle.test_and_set_compacting());
+        manager.ready_to_delete(handle);
+        assert_eq!(manager.file_count(1), 0);
+        deleted.wait();
+        assert!(Path::new(&file).exists());
+
+        let (first, count, value) = reader.join().unwrap();
+        assert_eq!(first, (b"key00000".to_vec(), b"v".to_vec()));
+        assert_eq!(count, 1000);
+        assert_eq!(value, Some(b"v".to_vec()));
+        // unlinked once the last reader drops the handle, without being cached again
+        assert!(!Path::new(&file).exists());
+        assert_eq!(manager.open_table_files(), 0);
+        assert_eq!(manager.index_cache.live_entries(), 0);
+        manager.close();
+    }
 }
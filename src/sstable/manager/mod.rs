@@ -1,3 +1,4 @@
 pub mod level_0;
 pub(crate) mod level_iter;
 pub mod level_n;
+pub mod table_id;
@@ -0,0 +1,136 @@
+use crate::ioutils::replace_file;
+use crate::sstable::TableID;
+use crate::Result;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Name of the file in the database directory which records the upper bound of
+/// the allocated table ids.
+pub const TABLE_ID_FILE: &str = "TABLE_ID";
+
+/// Count of table ids reserved each time [TABLE_ID_FILE] is rewritten.
+const RESERVED_IDS: u64 = 1024;
+
+/// Allocator of the table ids of all the levels.
+///
+/// Ids are strictly increasing, even across reopening, because the upper bound of
+/// the allocated ids is written to [TABLE_ID_FILE] before any id below it is used.
+/// An id is never reused, so a new table never collides with a deleted table whose
+/// file is still being removed.
+pub(crate) struct TableIdAllocator {
+    next: AtomicU64,
+    /// Ids less than it can be allocated without rewriting [TABLE_ID_FILE].
+    reserved: Mutex<u64>,
+    /// `None` if the database is in memory.
+    db_path: Option<PathBuf>,
+}
+
+impl TableIdAllocator {
+    /// Load the upper bound persisted in `db_path`, or start from 1 if there is none.
+    pub(crate) fn open(db_path: &Path) -> Result<TableIdAllocator> {
+        let next = match std::fs::read(db_path.join(TABLE_ID_FILE)) {
+            Ok(bytes) if bytes.len() == 8 => {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes);
+                u64::from_le_bytes(buf)
+            }
+            Ok(_) => {
+                warn!("ignore invalid {}", TABLE_ID_FILE);
+                1
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 1,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(TableIdAllocator {
+            next: AtomicU64::new(next),
+            reserved: Mutex::new(next),
+            db_path: Some(db_path.to_path_buf()),
+        })
+    }
+
+    /// Allocator of a database which is never persisted.
+    pub(crate) fn in_memory() -> TableIdAllocator {
+        TableIdAllocator {
+            next: AtomicU64::new(1),
+            reserved: Mutex::new(u64::MAX),
+            db_path: None,
+        }
+    }
+
+    /// Make the ids not greater than `table_id` never allocated, which is called
+    /// with the ids of the existing sstables when the database is opened.
+    pub(crate) fn observe(&self, table_id: TableID) {
+        self.next.fetch_max(table_id + 1, Ordering::AcqRel);
+    }
+
+    pub(crate) fn allocate(&self) -> TableID {
+        self.allocate_many(1).start
+    }
+
+    /// Allocate `count` consecutive ids at once.
+    pub(crate) fn allocate_many(&self, count: u64) -> Range<TableID> {
+        let start = self.next.fetch_add(count, Ordering::AcqRel);
+        let end = start + count;
+        let mut reserved = self.reserved.lock().unwrap();
+        if end > *reserved {
+            let new_reserved = end - 1 + RESERVED_IDS;
+            if let Some(db_path) = &self.db_path {
+                replace_file(db_path, TABLE_ID_FILE, &new_reserved.to_le_bytes()).unwrap();
+            }
+            *reserved = new_reserved;
+        }
+        start..end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sstable::manager::table_id::{TableIdAllocator, RESERVED_IDS};
+    use std::sync::{Arc, Barrier};
+
+    #[test]
+    fn test_allocate_concurrently() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("table_id")
+            .tempdir()
+            .unwrap();
+        const THREADS: usize = 8;
+        const IDS_PER_THREAD: usize = 1000;
+
+        let allocator = Arc::new(TableIdAllocator::open(temp_dir.path()).unwrap());
+        allocator.observe(10);
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let allocator = allocator.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    (0..IDS_PER_THREAD)
+                        .map(|_| allocator.allocate())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut all_ids = vec![];
+        for handle in handles {
+            let ids = handle.join().unwrap();
+            // increasing in each thread
+            assert!(ids.windows(2).all(|w| w[0] < w[1]));
+            all_ids.extend(ids);
+        }
+        all_ids.sort_unstable();
+        let expected: Vec<u64> = (11..11 + (THREADS * IDS_PER_THREAD) as u64).collect();
+        assert_eq!(all_ids, expected);
+
+        // ids allocated before reopening are never allocated again
+        drop(allocator);
+        let allocator = TableIdAllocator::open(temp_dir.path()).unwrap();
+        let table_id = allocator.allocate();
+        assert!(table_id > *expected.last().unwrap());
+        assert!(table_id <= *expected.last().unwrap() + RESERVED_IDS);
+    }
+}
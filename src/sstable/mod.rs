@@ -17,6 +17,8 @@
 //! +-------------------------+       |
 //! | Filter Block            |<-+    |
 //! +-------------------------+  |    |
+//! | Prefix Filter Block     |  |    |
+//! +-------------------------+  |    |
 //! | Footer                  |--+----+
 //! +-------------------------+
 //! ```
@@ -27,8 +29,15 @@
 //! +-----------------------------------------------------------------+
 //! | Key/Value Entry 1 | Key/Value Entry 2 | ... | Key/Value Entry n |
 //! +-----------------------------------------------------------------+
+//! | Restart Point 1 | Restart Point 2 | ... | Restart Point m |
+//! +---------------------------------------------------------+
 //! ```
 //!
+//! Restart points are the offsets (u32) of every [Options::block_restart_interval]-th
+//! entry in the block, the first of which is 0. `index_offset` of the Index Block points
+//! to the first restart point. Readers do not need the interval: a point lookup binary
+//! searches the keys at the restart points, then scans the entries until the next restart point.
+//!
 //! ### Key/Value Entry
 //!
 //! ```text
@@ -63,33 +72,136 @@
 //! +---------------------------------+
 //! ```
 //!
+//! ## Prefix Filter Block
+//!
+//! Bloom filter of the first `prefix length` bytes of the keys, written only if
+//! [Options::prefix_bloom_length] is not 0. Keys shorter than `prefix length` are not added.
+//! The block fills the space between the Filter Block and the Footer.
+//!
 //! ## Footer
 //!
-//! Length of Footer is fixed (64bit).
+//! Length of Footer is fixed (28 bytes) in the current format version, see
+//! [footer::FORMAT_VERSION].
 //!
 //! ```text
-//! +--------------------------------------------------------------------------------------------+
-//! | IndexBlock offset | IndexBlock length | filter length | kv_total | Magic Number 0xdb991122 |
-//! +--------------------------------------------------------------------------------------------+
-//! \------------------/\-------------------/\-------------/\----------/\------------------------/
-//!         u32                  u32             u32            u32               u32
+//! +---------------------------------------------------------------------------------------+
+//! | IndexBlock offset | IndexBlock length | filter length | kv_total | compression type |
+//! +---------------------------------------------------------------------------------------+
+//! \------------------/\-------------------/\-------------/\----------/\-----------------/
+//!         u32                  u32             u32            u32            u8
+//!
+//! +-----------------------------------------------------------------------------------------+
+//! | checksum type | prefix length | flags | reserved | format version | Magic Number 0xdb991123 |
+//! +-----------------------------------------------------------------------------------------+
+//! \--------------/\--------------/\------/\---------/\---------------/\------------------------/
+//!        u8             u16          u8       u8           u16                 u32
 //! ```
 //!
+//! The highest bit of the flags byte is set if the values of the table are separated,
+//! see [value_log]. The next bit is set if the Filter Block holds the key prefixes of
+//! `prefix length` bytes instead of the whole keys, see [BloomMode::Prefix], in which
+//! case there is no Prefix Filter Block. The third bit is set if the values are
+//! compressed with the dictionary of the database, see [dictionary].
+//!
+//! Readers dispatch on the format version, and keep reading the tables of the older
+//! versions. The footer of version 1 is 20 bytes long and has no format version: it ends
+//! with the Magic Number 0xdb991122 right after `kv_total`. Tables of version 1 have
+//! neither checksums nor a Prefix Filter Block, and are compressed with snappy only if
+//! feature `snappy_compression` is enabled.
+//!
+//! Compression type and checksum type are the settings used when the table was written.
+//! Readers always follow the settings recorded in the footer, so changing [Options] does not
+//! require rewriting existing tables.
+//!
+//! [Options]: crate::db::options::Options
+//! [Options::prefix_bloom_length]: crate::db::options::Options::prefix_bloom_length
+//! [BloomMode::Prefix]: crate::db::options::BloomMode::Prefix
+//! [Options::block_restart_interval]: crate::db::options::Options::block_restart_interval
+//!
 //! NOTE: All fixed-length integer are little-endian.
 
+pub mod block_cache;
 pub(super) mod data_block;
+pub mod dictionary;
 pub(super) mod filter_block;
 pub(crate) mod footer;
 pub(crate) mod index_block;
 pub mod manager;
+pub mod repair;
 mod table_cache;
 pub mod table_handle;
+pub mod table_reader;
+pub mod value_log;
+pub mod verify;
+
+pub use table_reader::read_table;
 
 pub type TableID = u64;
 
+/// Compression algorithm of data blocks.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CompressionType {
+    NoCompression = 0,
+    Snappy = 1,
+}
+
+impl Default for CompressionType {
+    /// Use snappy if feature `snappy_compression` is enabled.
+    fn default() -> Self {
+        if cfg!(feature = "snappy_compression") {
+            CompressionType::Snappy
+        } else {
+            CompressionType::NoCompression
+        }
+    }
+}
+
+impl CompressionType {
+    pub(crate) fn from_u8(n: u8) -> Option<CompressionType> {
+        match n {
+            0 => Some(CompressionType::NoCompression),
+            1 => Some(CompressionType::Snappy),
+            _ => None,
+        }
+    }
+}
+
+/// Checksum algorithm of data blocks.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChecksumType {
+    NoChecksum = 0,
+}
+
+impl Default for ChecksumType {
+    fn default() -> Self {
+        ChecksumType::NoChecksum
+    }
+}
+
+impl ChecksumType {
+    pub(crate) fn from_u8(n: u8) -> Option<ChecksumType> {
+        match n {
+            0 => Some(ChecksumType::NoChecksum),
+            _ => None,
+        }
+    }
+}
+
 pub const DATA_BLOCK_SIZE: usize = 4096;
 pub const NUM_LEVEL0_TABLE_TO_COMPACT: usize = 4;
 
-pub fn sstable_file(db_path: &str, level: u32, table_id: u128) -> String {
+/// Directory of the sstables of `level`, see [crate::db] for the layout.
+pub fn level_dir(db_path: &str, level: usize) -> String {
+    format!("{}/{}", db_path, level)
+}
+
+/// Path of an sstable, which is named by its table id in the directory of its level.
+pub fn sstable_file(db_path: &str, level: usize, table_id: TableID) -> String {
     format!("{}/{}/{}", db_path, level, table_id)
 }
+
+/// Suffix of the file an sstable is written to before it is renamed to
+/// [sstable_file]. Such files are removed when the database is opened.
+pub const TEMP_FILE_SUFFIX: &str = "_write";
@@ -0,0 +1,184 @@
+use crate::db::key_types::InternalKey;
+use crate::db::MAX_LEVEL;
+use crate::ioutils::{lock_dir, replace_file, sync_dir};
+use crate::sstable::footer::Footer;
+use crate::sstable::index_block::IndexBlock;
+use crate::sstable::manager::table_id::TABLE_ID_FILE;
+use crate::sstable::{level_dir, sstable_file, TableID};
+use crate::Result;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Directory under the database path which the unreadable sstables are moved to by
+/// [repair], named `<level>_<table id>`.
+pub const LOST_DIR: &str = "lost";
+
+/// A readable sstable found by [repair].
+struct RecoveredTable {
+    table_id: TableID,
+    min_key: InternalKey,
+    max_key: InternalKey,
+}
+
+/// Rebuild the metadata of the database at `db_path` from the sstables which survive.
+/// See [crate::db::DB::repair].
+pub(crate) fn repair(db_path: &Path) -> Result<()> {
+    let _lock_file = lock_dir(db_path)?;
+    let path = db_path.to_str().unwrap();
+    let mut max_table_id = 0;
+    let mut levels = Vec::with_capacity(MAX_LEVEL + 1);
+    for level in 0..=MAX_LEVEL {
+        std::fs::create_dir_all(level_dir(path, level))?;
+        let mut tables = vec![];
+        for entry in std::fs::read_dir(level_dir(path, level))? {
+            let file_name = entry?.file_name();
+            // temporary files are removed when the database is opened
+            let table_id = match file_name.to_str().and_then(|name| name.parse().ok()) {
+                Some(table_id) => table_id,
+                None => continue,
+            };
+            max_table_id = max_table_id.max(table_id);
+            match recover_table(path, level, table_id) {
+                Ok(table) => tables.push(table),
+                Err(e) => {
+                    warn!(
+                        "move unreadable table {} of level {}: {}",
+                        table_id, level, e
+                    );
+                    std::fs::create_dir_all(db_path.join(LOST_DIR))?;
+                    std::fs::rename(
+                        sstable_file(path, level, table_id),
+                        db_path
+                            .join(LOST_DIR)
+                            .join(format!("{}_{}", level, table_id)),
+                    )?;
+                }
+            }
+        }
+        sync_dir(level_dir(path, level))?;
+        levels.push(tables);
+    }
+
+    // Level 0 is searched before the other levels, so a table moved there from level N
+    // would hide the newer values in the tables of levels 1 to N - 1 overlapping with
+    // it. Those tables are moved as well, from the deepest level, and the tables in
+    // level 0 are renumbered after the moved ones so that the table of a shallower
+    // level keeps winning.
+    let mut moved: Vec<(usize, TableID)> = vec![];
+    let mut moved_ranges: Vec<(InternalKey, InternalKey)> = vec![];
+    for level in (1..=MAX_LEVEL).rev() {
+        let tables = std::mem::take(&mut levels[level]);
+        let overlapping = overlapping_tables(&tables);
+        let mut level_moved = vec![];
+        for table in tables {
+            if overlapping.contains(&table.table_id)
+                || moved_ranges.iter().any(|(min_key, max_key)| {
+                    table.min_key <= *max_key && *min_key <= table.max_key
+                })
+            {
+                level_moved.push(table);
+            }
+        }
+        level_moved.sort_by_key(|table| table.table_id);
+        for table in level_moved {
+            moved.push((level, table.table_id));
+            moved_ranges.push((table.min_key, table.max_key));
+        }
+    }
+    let mut next_table_id = max_table_id + 1;
+    if !moved.is_empty() {
+        let mut level0_tables: Vec<TableID> =
+            levels[0].iter().map(|table| table.table_id).collect();
+        level0_tables.sort_unstable();
+        let renamed: Vec<(usize, TableID)> = moved
+            .into_iter()
+            .chain(level0_tables.into_iter().map(|table_id| (0, table_id)))
+            .collect();
+        // rename from the newest table, so that an interrupted repair leaves the tables
+        // in an order which is still correct and can be repaired again
+        for (i, (level, table_id)) in renamed.iter().enumerate().rev() {
+            let new_table_id = next_table_id + i as TableID;
+            info!(
+                "move table {} of level {} to table {} of level 0",
+                table_id, level, new_table_id
+            );
+            std::fs::rename(
+                sstable_file(path, *level, *table_id),
+                sstable_file(path, 0, new_table_id),
+            )?;
+        }
+        next_table_id += renamed.len() as TableID;
+        for level in 0..=MAX_LEVEL {
+            sync_dir(level_dir(path, level))?;
+        }
+    }
+
+    // the ids of the existing tables are also observed when the database is opened,
+    // but the ids of the tables moved away must not be reused either
+    replace_file(
+        db_path,
+        TABLE_ID_FILE,
+        &next_table_id
+            .max(read_table_id_bound(db_path))
+            .to_le_bytes(),
+    )
+}
+
+/// Read the footer and the index block of a table.
+fn recover_table(db_path: &str, level: usize, table_id: TableID) -> Result<RecoveredTable> {
+    let mut reader = BufReader::new(File::open(sstable_file(db_path, level, table_id))?);
+    let footer = Footer::load_footer(&mut reader)?;
+    let index_block = IndexBlock::try_load_index(&mut reader, &footer, 0)?;
+    Ok(RecoveredTable {
+        table_id,
+        max_key: index_block.max_key().clone(),
+        min_key: index_block.min_key,
+    })
+}
+
+/// Ids of the tables whose key ranges overlap with another table of the same level.
+/// Their order in the level is ambiguous, so they are moved to level 0, where the
+/// table of the greater id wins.
+fn overlapping_tables(tables: &[RecoveredTable]) -> Vec<TableID> {
+    let mut tables: Vec<&RecoveredTable> = tables.iter().collect();
+    tables.sort_by(|a, b| a.min_key.cmp(&b.min_key));
+    let mut overlapping = vec![];
+    let mut group: Vec<TableID> = vec![];
+    let mut group_max_key: Option<InternalKey> = None;
+    for table in tables {
+        match &group_max_key {
+            Some(max_key) if table.min_key <= *max_key => {}
+            _ => {
+                if group.len() > 1 {
+                    overlapping.append(&mut group);
+                }
+                group.clear();
+                group_max_key = None;
+            }
+        }
+        group.push(table.table_id);
+        if group_max_key
+            .as_ref()
+            .map_or(true, |max_key| table.max_key > *max_key)
+        {
+            group_max_key = Some(table.max_key.clone());
+        }
+    }
+    if group.len() > 1 {
+        overlapping.append(&mut group);
+    }
+    overlapping
+}
+
+/// The upper bound in [TABLE_ID_FILE], or 0 if it is missing or invalid.
+fn read_table_id_bound(db_path: &Path) -> u64 {
+    match std::fs::read(db_path.join(TABLE_ID_FILE)) {
+        Ok(bytes) if bytes.len() == 8 => {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes);
+            u64::from_le_bytes(buf)
+        }
+        _ => 0,
+    }
+}
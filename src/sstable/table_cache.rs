@@ -1,20 +1,28 @@
 use crate::bloom::BloomFilter;
 use crate::sstable::data_block::DataBlock;
-use crate::sstable::index_block::IndexBlock;
+use crate::sstable::index_block::TableIndex;
 use std::collections::HashMap;
+use std::sync::Mutex;
 
 pub struct TableCache {
     pub filter: BloomFilter,
-    pub index: IndexBlock,
-    pub start_data_block_map: HashMap<u32, DataBlock>,
+    pub index: TableIndex,
+    /// Data blocks read by queries, shared by the readers holding the cache entry.
+    pub start_data_block_map: Mutex<HashMap<u32, DataBlock>>,
 }
 
 impl TableCache {
-    pub fn new(filter: BloomFilter, index: IndexBlock) -> TableCache {
+    pub fn new(filter: BloomFilter, index: TableIndex) -> TableCache {
         TableCache {
             filter,
             index,
-            start_data_block_map: HashMap::with_capacity(10),
+            start_data_block_map: Mutex::new(HashMap::with_capacity(10)),
         }
     }
+
+    /// Bytes of the data blocks read by queries.
+    pub fn data_blocks_size(&self) -> usize {
+        let guard = self.start_data_block_map.lock().unwrap();
+        guard.values().map(DataBlock::memory_size).sum()
+    }
 }
@@ -1,23 +1,36 @@
 use crate::bloom::BloomFilter;
 use crate::cache::ShardLRUCache;
 use crate::collections::skip_list::skipmap::SrSwSkipMap;
-use crate::db::db_iter::InternalKeyValue;
+use crate::db::clock::unix_secs;
+use crate::db::db_iter::{InternalKeyValue, IterErrors};
 use crate::db::key_types::{InternalKey, MemKey};
+use crate::db::options::{BloomMode, Options};
+use crate::db::stats::StatsCounters;
 use crate::db::{max_level_shift, Value, WRITE_BUFFER_SIZE};
-use crate::env::file_system::{FileSystem, SequentialReadableFile};
+use crate::env::direct_io::DirectFile;
+use crate::env::file_system::{FileSystem, MemFile, SequentialReadableFile};
+use crate::error::KVLiteError;
 use crate::hash::murmur_hash;
-use crate::ioutils::{BufReaderWithPos, BufWriterWithPos};
+use crate::ioutils::{sync_dir, BufReaderWithPos, BufWriterWithPos};
 use crate::memory::InternalKeyValueIterator;
-use crate::sstable::data_block::{DataBlock, DataBlockIter};
-use crate::sstable::filter_block::{load_filter_block, write_filter_block};
-use crate::sstable::footer::{write_footer, Footer};
-use crate::sstable::index_block::IndexBlock;
+use crate::sstable::block_cache::SharedBlockCache;
+use crate::sstable::data_block::{DataBlock, DataBlockIter, DataBlockRevIter};
+use crate::sstable::dictionary::{compress_value, ValueDictionary};
+use crate::sstable::filter_block::{bloom_prefix, load_filter_block, write_filter_block};
+use crate::sstable::footer::{Footer, FORMAT_VERSION};
+use crate::sstable::index_block::{IndexBlock, PartitionedIndex, TableIndex};
 use crate::sstable::table_cache::TableCache;
-use crate::sstable::{TableID, DATA_BLOCK_SIZE};
+use crate::sstable::value_log::{encode_inline, load_value, value_log_dir, ValueLogWriter};
+use crate::sstable::{
+    sstable_file, ChecksumType, CompressionType, TableID, DATA_BLOCK_SIZE, TEMP_FILE_SUFFIX,
+};
+use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::ops::Deref;
+use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, RwLock};
+use zstd::bulk::Compressor;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TableStatus {
@@ -29,64 +42,253 @@ pub enum TableStatus {
     ToDelete,
 }
 
+/// Where the bytes of a new sstable are written to.
+pub(crate) enum TableFile {
+    /// A temporary file renamed to the sstable file after it is written.
+    Disk(File),
+    /// Like [TableFile::Disk], but written with direct I/O, see
+    /// [Options::use_direct_io_for_compaction].
+    Direct(DirectFile),
+    /// Bytes kept in memory, see [Options::in_memory].
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Write for TableFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            TableFile::Disk(file) => file.write(buf),
+            TableFile::Direct(file) => file.write(buf),
+            TableFile::Memory(cursor) => cursor.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            TableFile::Disk(file) => file.flush(),
+            TableFile::Direct(file) => file.flush(),
+            TableFile::Memory(cursor) => cursor.flush(),
+        }
+    }
+}
+
+impl Seek for TableFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            TableFile::Disk(file) => file.seek(pos),
+            TableFile::Direct(file) => file.seek(pos),
+            TableFile::Memory(cursor) => cursor.seek(pos),
+        }
+    }
+}
+
 /// Handle of new sstable for single-thread writing.
 pub struct TableWriteHandle {
     pub(crate) file_path: String,
     level: usize,
     table_id: u64,
     pub(crate) writer: TableWriter,
+    /// Passed on to the [TableReadHandle] of the table.
+    partitioned_index_threshold: u32,
+    /// Writer of the value log of the table, if its values are separated.
+    value_log: Option<ValueLogWriter>,
+    /// Compressor of the values, if they are compressed with a dictionary.
+    compressor: Option<Compressor<'static>>,
+    /// Passed on to the [TableReadHandle] of the table.
+    dictionary: Option<Arc<ValueDictionary>>,
+    /// Creation time of the table in seconds since the UNIX epoch, see [Options::clock].
+    created_at: u64,
 }
 
 impl TableWriteHandle {
-    pub fn new(db_path: &str, level: usize, table_id: u64, kv_total: u32) -> TableWriteHandle {
-        let file_path = format!("{}/{}/{}", db_path, level, table_id);
+    pub fn new(
+        db_path: &str,
+        level: usize,
+        table_id: u64,
+        kv_total: u32,
+        options: &Options,
+    ) -> TableWriteHandle {
+        Self::new_with_direct_io(db_path, level, table_id, kv_total, options, false).unwrap()
+    }
+
+    /// Like [TableWriteHandle::new], but write the file with direct I/O if `direct_io`
+    /// is true. Buffered I/O is used instead if direct I/O is not supported.
+    pub(crate) fn new_with_direct_io(
+        db_path: &str,
+        level: usize,
+        table_id: u64,
+        kv_total: u32,
+        options: &Options,
+        direct_io: bool,
+    ) -> crate::Result<TableWriteHandle> {
+        let file_path = sstable_file(db_path, level, table_id);
+        let direct_file = if direct_io && !options.in_memory {
+            let temp_file = temp_file_name(&file_path);
+            match DirectFile::create_new(std::path::Path::new(&temp_file)) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    warn!("direct I/O is not available, use buffered I/O: {}", e);
+                    // the file may be created before O_DIRECT is rejected
+                    match std::fs::remove_file(&temp_file) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => None,
+                    }
+                }
+            }
+        } else {
+            None
+        };
         let writer = {
-            let mut file = OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .append(true)
-                .open(temp_file_name(&file_path))
-                .unwrap();
-            debug_assert!(std::path::Path::new(&temp_file_name(&file_path)).exists());
-            file.seek(SeekFrom::Start(0)).unwrap();
-            let buf_writer = BufWriterWithPos::new(file).unwrap();
-            TableWriter::new(buf_writer, kv_total)
+            let file = if options.in_memory {
+                TableFile::Memory(Cursor::new(Vec::new()))
+            } else if let Some(file) = direct_file {
+                TableFile::Direct(file)
+            } else {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .append(true)
+                    .open(temp_file_name(&file_path))?;
+                debug_assert!(std::path::Path::new(&temp_file_name(&file_path)).exists());
+                file.seek(SeekFrom::Start(0))?;
+                TableFile::Disk(file)
+            };
+            let buf_writer = BufWriterWithPos::new(file)?;
+            TableWriter::new(
+                buf_writer,
+                kv_total,
+                options.compression,
+                options.bloom_mode,
+                options.prefix_bloom_length,
+                options.block_restart_interval,
+            )
+        };
+        let value_log = if options.value_log_threshold != 0 && !options.in_memory {
+            Some(ValueLogWriter::new(
+                value_log_dir(db_path),
+                table_id,
+                options.value_log_threshold,
+            ))
+        } else {
+            None
         };
 
-        TableWriteHandle {
+        let mut handle = TableWriteHandle {
             file_path,
             level,
             table_id,
             writer,
+            partitioned_index_threshold: options.partitioned_index_threshold,
+            value_log,
+            compressor: None,
+            dictionary: None,
+            created_at: options.clock.now_secs(),
+        };
+        handle.writer.separated_values = handle.value_log.is_some();
+        Ok(handle)
+    }
+
+    /// Compress the values of the table with `dictionary`, unless they are separated.
+    /// See [crate::sstable::dictionary].
+    pub(crate) fn with_value_dictionary(
+        mut self,
+        dictionary: Option<Arc<ValueDictionary>>,
+    ) -> TableWriteHandle {
+        if let (Some(dictionary), None) = (dictionary, &self.value_log) {
+            self.compressor = Some(dictionary.compressor().unwrap());
+            self.writer.dictionary_values = true;
+            self.dictionary = Some(dictionary);
         }
+        self
     }
 
     pub fn write_sstable(&mut self, table: &impl InternalKeyValueIterator) -> crate::Result<()> {
         // write Data Blocks
-        for (i, (k, v)) in table.kv_iter().enumerate() {
-            self.writer.add_key_value(k.clone(), v.clone());
-            if self.writer.data.len() >= DATA_BLOCK_SIZE || i == table.len() - 1 {
+        let mut kvs = table.kv_iter().peekable();
+        while let Some((k, v)) = kvs.next() {
+            let v = self.store_value(v.clone())?;
+            self.writer.add_key_value(k.clone(), v);
+            if self.writer.data.len() >= DATA_BLOCK_SIZE || kvs.peek().is_none() {
                 self.writer.flush_data(k.clone());
             }
         }
-        self.writer.write_index_filter_footer();
-        Ok(())
+        self.finish()
     }
 
     pub fn write_sstable_from_vec(&mut self, kvs: Vec<(InternalKey, Value)>) -> crate::Result<()> {
         // write Data Blocks
         let length = kvs.len();
         for (i, (k, v)) in kvs.into_iter().enumerate() {
+            let v = self.store_value(v)?;
             self.writer.add_key_value(k.clone(), v);
             if self.writer.data.len() >= DATA_BLOCK_SIZE || i == length - 1 {
                 self.writer.flush_data(k);
             }
         }
+        self.finish()
+    }
+
+    /// Like [TableWriteHandle::write_sstable_from_vec], but if this table separates
+    /// values, they are read from [TableReadHandle::stored_iter] and written as they are,
+    /// so that compaction copies the pointers to the value log instead of the values.
+    pub(crate) fn write_sstable_from_stored_vec(
+        &mut self,
+        kvs: Vec<(InternalKey, Value)>,
+    ) -> crate::Result<()> {
+        let length = kvs.len();
+        for (i, (k, v)) in kvs.into_iter().enumerate() {
+            let v = match &mut self.compressor {
+                Some(compressor) => compress_value(compressor, v)?,
+                None => v,
+            };
+            self.writer.add_key_value(k.clone(), v);
+            if self.writer.data.len() >= DATA_BLOCK_SIZE || i == length - 1 {
+                self.writer.flush_data(k);
+            }
+        }
+        self.finish()
+    }
+
+    /// Encode `value` written by users to be stored in the table.
+    #[inline]
+    fn store_value(&mut self, value: Value) -> crate::Result<Value> {
+        match (&mut self.value_log, &mut self.compressor) {
+            (Some(value_log), _) => value_log.store(value),
+            (None, Some(compressor)) => compress_value(compressor, value),
+            (None, None) => Ok(value),
+        }
+    }
+
+    fn finish(&mut self) -> crate::Result<()> {
+        // the values must be durable before the pointers to them
+        if let Some(value_log) = &mut self.value_log {
+            value_log.finish()?;
+        }
         self.writer.write_index_filter_footer();
         Ok(())
     }
 
+    /// Bytes appended to the value log of the table.
+    #[inline]
+    pub(crate) fn value_log_bytes_written(&self) -> u64 {
+        self.value_log
+            .as_ref()
+            .map_or(0, ValueLogWriter::bytes_written)
+    }
+
+    /// Whether the table is kept in memory instead of a file.
+    #[inline]
+    pub(crate) fn is_in_memory(&self) -> bool {
+        matches!(self.writer.writer.get_ref(), TableFile::Memory(_))
+    }
+
     pub(crate) fn rename(&self) {
+        if self.is_in_memory() {
+            return;
+        }
+        // the value log is renamed first, so that a table never points to a missing file
+        if let Some(value_log) = &self.value_log {
+            value_log.rename().unwrap();
+        }
         debug_assert!(
             !std::path::Path::new(&self.file_path).exists(),
             "{}",
@@ -99,6 +301,24 @@ impl TableWriteHandle {
         );
         std::fs::rename(temp_file_name(&self.file_path), &self.file_path)
             .unwrap_or_else(|e| panic!("{:#?}, file_path: {}", e, &self.file_path));
+        // the content is synced in `write_index_filter_footer`, sync the renamed entry too.
+        let dir = std::path::Path::new(&self.file_path).parent().unwrap();
+        sync_dir(dir).unwrap_or_else(|e| panic!("{:#?}, dir: {:?}", e, dir));
+    }
+
+    /// Remove the temporary file of a table that will not be used.
+    pub(crate) fn discard(mut self) {
+        if self.is_in_memory() {
+            return;
+        }
+        if let Some(value_log) = self.value_log.take() {
+            value_log.discard();
+        }
+        let temp_file = temp_file_name(&self.file_path);
+        drop(self);
+        if let Err(e) = std::fs::remove_file(&temp_file) {
+            warn!("failed to remove {}: {:?}", temp_file, e);
+        }
     }
 
     #[inline]
@@ -111,9 +331,24 @@ impl TableWriteHandle {
         self.table_id
     }
 
+    /// Move the table to `level` before it is renamed to its final path.
+    pub(crate) fn move_to_level(&mut self, db_path: &str, level: usize) -> crate::Result<()> {
+        let file_path = sstable_file(db_path, level, self.table_id);
+        if !self.is_in_memory() {
+            std::fs::rename(temp_file_name(&self.file_path), temp_file_name(&file_path))?;
+        }
+        self.file_path = file_path;
+        self.level = level;
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn min_key(&self) -> &InternalKey {
+        &self.writer.index_block.min_key
+    }
+
     #[inline]
     pub fn take_min_key(&mut self) -> InternalKey {
-        debug_assert_ne!(self.writer.index_block.min_key.len(), 0);
         std::mem::take(&mut self.writer.index_block.min_key)
     }
 
@@ -129,15 +364,44 @@ pub(crate) struct TableWriter {
     kv_count: u32,
     data: Vec<u8>,
     pub(crate) index_block: IndexBlock,
-    pub(crate) writer: BufWriterWithPos<File>,
-    record_offsets: Vec<u8>,
+    pub(crate) writer: BufWriterWithPos<TableFile>,
+    /// Offsets of the restart points of the current data block.
+    restart_points: Vec<u8>,
+    /// A restart point is added every `restart_interval` key-values of a data block.
+    restart_interval: usize,
+    /// Count of the key-values added to the current data block.
+    block_kv_count: usize,
     filter: BloomFilter,
-    #[cfg(feature = "snappy_compression")]
+    /// Filter of the key prefixes of `prefix_length` bytes, see [crate::sstable].
+    prefix_filter: Option<BloomFilter>,
+    prefix_length: u16,
+    /// Whether `filter` holds the key prefixes of `prefix_length` bytes instead of the
+    /// whole keys, see [BloomMode::Prefix].
+    prefix_bloom: bool,
+    /// Prefix added to `prefix_filter` last time. Keys are sorted, so the same
+    /// prefixes are added only once.
+    last_prefix: Option<InternalKey>,
+    compression: CompressionType,
     snappy_encoder: snap::raw::Encoder,
+    /// Recorded in the footer, see [crate::sstable::value_log].
+    separated_values: bool,
+    /// Recorded in the footer, see [crate::sstable::dictionary].
+    dictionary_values: bool,
 }
 
 impl TableWriter {
-    fn new(writer: BufWriterWithPos<File>, kv_total: u32) -> TableWriter {
+    fn new(
+        writer: BufWriterWithPos<TableFile>,
+        kv_total: u32,
+        compression: CompressionType,
+        bloom_mode: BloomMode,
+        prefix_bloom_length: u16,
+        restart_interval: usize,
+    ) -> TableWriter {
+        let (prefix_bloom, prefix_length) = match bloom_mode {
+            BloomMode::WholeKey => (false, prefix_bloom_length),
+            BloomMode::Prefix(length) => (true, length),
+        };
         TableWriter {
             kv_total,
             #[cfg(debug_assertions)]
@@ -145,27 +409,58 @@ impl TableWriter {
             data: Vec::with_capacity(WRITE_BUFFER_SIZE as usize + 500),
             index_block: IndexBlock::default(),
             writer,
-            record_offsets: Vec::with_capacity(kv_total as usize),
+            restart_points: Vec::with_capacity(kv_total as usize),
+            restart_interval: restart_interval.max(1),
+            block_kv_count: 0,
             filter: BloomFilter::create_filter(kv_total as usize),
-            #[cfg(feature = "snappy_compression")]
+            prefix_filter: if prefix_bloom || prefix_length == 0 {
+                None
+            } else {
+                Some(BloomFilter::create_filter(kv_total as usize))
+            },
+            prefix_length,
+            prefix_bloom,
+            last_prefix: None,
+            compression,
             snappy_encoder: snap::raw::Encoder::new(),
+            separated_values: false,
+            dictionary_values: false,
         }
     }
 
     fn add_key_value(&mut self, mut k: InternalKey, mut v: Value) {
-        debug_assert!(!k.is_empty(), "attempt to write empty key");
-        self.filter.add(&k);
-        debug_assert!(self.filter.may_contain(&k));
+        if self.prefix_bloom {
+            let prefix = bloom_prefix(&k, self.prefix_length);
+            if self.last_prefix.as_deref() != Some(prefix) {
+                self.filter.add(prefix);
+                self.last_prefix = Some(prefix.to_vec());
+            }
+        } else {
+            self.filter.add(&k);
+            debug_assert!(self.filter.may_contain(&k));
+        }
+        if let Some(prefix_filter) = &mut self.prefix_filter {
+            if let Some(prefix) = k.get(..self.prefix_length as usize) {
+                if self.last_prefix.as_deref() != Some(prefix) {
+                    prefix_filter.add(prefix);
+                    self.last_prefix = Some(prefix.to_vec());
+                }
+            }
+        }
 
         #[cfg(debug_assertions)]
         let excepted_data_len = self.data.len() + 8 + k.len() + v.len();
 
-        if unsafe { std::intrinsics::unlikely(self.index_block.min_key.is_empty()) } {
+        // the empty key is a valid key, so the first key is detected by position.
+        if self.is_first_key() {
             self.index_block.min_key = k.clone();
         }
 
-        let record_offset = (self.data.len() as u32).to_le_bytes();
-        self.record_offsets.append(&mut Vec::from(record_offset));
+        if self.block_kv_count % self.restart_interval == 0 {
+            let restart_point = (self.data.len() as u32).to_le_bytes();
+            self.restart_points.extend_from_slice(&restart_point);
+        }
+        self.block_kv_count += 1;
 
         self.data
             .append(&mut Vec::from((k.len() as u32).to_le_bytes()));
@@ -180,12 +475,17 @@ impl TableWriter {
         }
     }
 
+    #[inline]
+    fn is_first_key(&self) -> bool {
+        self.index_block.indexes.is_empty() && self.data.is_empty()
+    }
+
     fn flush_data(&mut self, max_key: InternalKey) {
         let index_offset_uncompressed = self.writer.pos as u32 + self.data.len() as u32;
-        self.data.append(&mut self.record_offsets);
+        self.data.append(&mut self.restart_points);
+        self.block_kv_count = 0;
 
-        #[cfg(feature = "snappy_compression")]
-        {
+        if self.compression == CompressionType::Snappy {
             #[cfg(debug_assertions)]
             let before_length = self.data.len();
             self.data = self.snappy_encoder.compress_vec(&self.data).unwrap();
@@ -211,18 +511,32 @@ impl TableWriter {
         self.index_block.write_to_file(&mut self.writer).unwrap();
         let index_block_length = self.writer.pos as u32 - index_block_offset;
         write_filter_block(&mut self.filter, &mut self.writer);
-        write_footer(
+        if let Some(prefix_filter) = &mut self.prefix_filter {
+            write_filter_block(prefix_filter, &mut self.writer);
+        }
+        let footer = Footer {
             index_block_offset,
             index_block_length,
-            &mut self.writer,
-            self.filter.len(),
-            self.kv_total,
-        );
+            filter_length: self.filter.len(),
+            kv_total: self.kv_total,
+            compression_type: self.compression,
+            checksum_type: ChecksumType::NoChecksum,
+            prefix_length: self.prefix_length,
+            prefix_bloom: self.prefix_bloom,
+            separated_values: self.separated_values,
+            dictionary_values: self.dictionary_values,
+            format_version: FORMAT_VERSION,
+        };
+        footer.write_to_file(&mut self.writer).unwrap();
         #[cfg(debug_assertions)]
         debug_assert_eq!(self.kv_count, self.kv_total);
 
         self.writer.flush().unwrap();
-        self.writer.sync_data().unwrap();
+        match self.writer.get_mut() {
+            TableFile::Disk(file) => file.sync_data().unwrap(),
+            TableFile::Direct(file) => file.finish().unwrap(),
+            TableFile::Memory(_) => {}
+        }
     }
 
     #[inline]
@@ -231,6 +545,10 @@ impl TableWriter {
     }
 }
 
+/// Files of the sstables kept open for reading, keyed by [TableReadHandle::table_key].
+/// See [Options::max_open_files].
+pub(crate) type TableFileCache = ShardLRUCache<u64, Arc<File>>;
+
 pub struct TableReadHandle {
     file_path: String,
     level: usize,
@@ -242,27 +560,107 @@ pub struct TableReadHandle {
     max_key: InternalKey,
     kv_total: u32,
     file_size: u64,
+    compression: CompressionType,
+    /// Length of the prefixes and the prefix filter, if the table has one. If the filter
+    /// block holds the prefixes, it is a copy of the filter block.
+    prefix_filter: Option<(u16, BloomFilter)>,
+    /// Length of the prefixes in the filter block, if it holds prefixes instead of whole
+    /// keys, see [BloomMode::Prefix].
+    bloom_prefix_length: Option<u16>,
+    /// Bytes of the table if it is kept in memory instead of a file.
+    memory: Option<Arc<Vec<u8>>>,
+    /// See [Options::partitioned_index_threshold].
+    partitioned_index_threshold: u32,
+    /// Directory of the value logs, if the values of the table are separated.
+    value_log_dir: Option<String>,
+    /// Dictionary the values are compressed with, see [crate::sstable::dictionary].
+    dictionary: Option<Arc<ValueDictionary>>,
+    /// Creation time of the table in seconds since the UNIX epoch, see
+    /// [Options::periodic_compaction_seconds].
+    created_at: u64,
+    /// Where the file of the table is kept open, if the number of open files is capped.
+    file_cache: Option<Arc<TableFileCache>>,
 }
 
 unsafe impl Send for TableReadHandle {}
 unsafe impl Sync for TableReadHandle {}
 
+/// Shows the metadata of the table, not its key-values.
+impl fmt::Debug for TableReadHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TableReadHandle")
+            .field("table_id", &self.table_id)
+            .field("level", &self.level)
+            .field("min_key", &String::from_utf8_lossy(&self.min_key))
+            .field("max_key", &String::from_utf8_lossy(&self.max_key))
+            .field("kv_total", &self.kv_total)
+            .field("file_size", &self.file_size)
+            .field("status", &*self.status.read().unwrap())
+            .finish()
+    }
+}
+
+impl fmt::Display for TableReadHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "table {} of level {}", self.table_id, self.level)
+    }
+}
+
 impl TableReadHandle {
     /// Create a table handle for existing sstable.
-    pub fn open(db_path: &str, level: usize, table_id: u64) -> TableReadHandle {
-        let file_path = format!("{}/{}/{}", db_path, level, table_id);
+    /// Keys longer than [Options::max_key_bytes] in its index are considered corrupted.
+    pub fn open(db_path: &str, level: usize, table_id: u64, options: &Options) -> TableReadHandle {
+        Self::open_with_dictionary(db_path, level, table_id, options, None)
+    }
+
+    /// Like [TableReadHandle::open], but decompress the values with `dictionary` if they
+    /// are compressed with a dictionary. It is loaded from `db_path` if it is `None`.
+    pub(crate) fn open_with_dictionary(
+        db_path: &str,
+        level: usize,
+        table_id: u64,
+        options: &Options,
+        dictionary: Option<&Arc<ValueDictionary>>,
+    ) -> TableReadHandle {
+        let file_path = sstable_file(db_path, level, table_id);
 
         let file = File::open(&file_path).unwrap();
-        let file_size = file.metadata().unwrap().len();
+        let metadata = file.metadata().unwrap();
+        let file_size = metadata.len();
+        // sstables are never modified once written
+        let created_at = metadata
+            .modified()
+            .map(unix_secs)
+            .unwrap_or_else(|_| options.clock.now_secs());
 
         let mut buf_reader = BufReaderWithPos::new(file).unwrap();
 
         let footer = Footer::load_footer(&mut buf_reader).unwrap();
-        let mut index_block = IndexBlock::load_index(&mut buf_reader, &footer);
+        let mut index_block =
+            IndexBlock::try_load_index(&mut buf_reader, &footer, options.max_key_bytes).unwrap();
 
         let min_key = std::mem::take(&mut index_block.min_key);
         let max_key = index_block.max_key().clone();
 
+        let prefix_filter = if footer.prefix_bloom {
+            let offset = footer.index_block_offset as u64 + footer.index_block_length as u64;
+            Some((
+                footer.prefix_length,
+                load_filter_block(offset, footer.filter_length as usize, &mut buf_reader),
+            ))
+        } else if footer.prefix_length == 0 {
+            None
+        } else {
+            let offset = footer.index_block_offset as u64
+                + footer.index_block_length as u64
+                + footer.filter_length as u64;
+            let length = file_size - offset - footer.byte_size();
+            Some((
+                footer.prefix_length,
+                load_filter_block(offset, length as usize, &mut buf_reader),
+            ))
+        };
+
         let table_key = Self::calc_table_key(table_id, level);
         TableReadHandle {
             file_path,
@@ -275,6 +673,33 @@ impl TableReadHandle {
             max_key,
             kv_total: footer.kv_total,
             file_size,
+            compression: footer.compression_type,
+            prefix_filter,
+            bloom_prefix_length: if footer.prefix_bloom {
+                Some(footer.prefix_length)
+            } else {
+                None
+            },
+            memory: None,
+            partitioned_index_threshold: options.partitioned_index_threshold,
+            value_log_dir: if footer.separated_values {
+                Some(value_log_dir(db_path))
+            } else {
+                None
+            },
+            dictionary: if footer.dictionary_values {
+                Some(dictionary.cloned().unwrap_or_else(|| {
+                    Arc::new(
+                        ValueDictionary::load(db_path)
+                            .unwrap()
+                            .expect("the value dictionary is missing"),
+                    )
+                }))
+            } else {
+                None
+            },
+            created_at,
+            file_cache: None,
         }
     }
 
@@ -307,6 +732,24 @@ impl TableReadHandle {
         let min_key = table_write_handle.take_min_key();
         let max_key: InternalKey = table_write_handle.max_key().clone();
 
+        let writer = &mut table_write_handle.writer;
+        let prefix_length = writer.prefix_length;
+        let bloom_prefix_length = if writer.prefix_bloom {
+            Some(prefix_length)
+        } else {
+            None
+        };
+        let prefix_filter = match bloom_prefix_length {
+            Some(_) => Some(BloomFilter(writer.filter.0.clone())),
+            None => writer.prefix_filter.take(),
+        }
+        .map(|filter| (prefix_length, filter));
+
+        let memory = match table_write_handle.writer.writer.get_mut() {
+            TableFile::Memory(cursor) => Some(Arc::new(std::mem::take(cursor.get_mut()))),
+            TableFile::Disk(_) | TableFile::Direct(_) => None,
+        };
+
         let table_id = table_write_handle.table_id;
         let level = table_write_handle.level;
         let table_key = Self::calc_table_key(table_id, level);
@@ -321,12 +764,68 @@ impl TableReadHandle {
             max_key,
             kv_total: table_write_handle.writer.kv_total,
             file_size,
+            compression: table_write_handle.writer.compression,
+            prefix_filter,
+            bloom_prefix_length,
+            memory,
+            partitioned_index_threshold: table_write_handle.partitioned_index_threshold,
+            value_log_dir: table_write_handle
+                .value_log
+                .as_ref()
+                .map(|value_log| value_log.dir().to_string()),
+            dictionary: table_write_handle.dictionary,
+            created_at: table_write_handle.created_at,
+            file_cache: None,
         }
     }
 
+    /// Read the file of the table through `file_cache` instead of opening it per read.
+    pub(crate) fn with_file_cache(
+        mut self,
+        file_cache: Option<Arc<TableFileCache>>,
+    ) -> TableReadHandle {
+        if self.memory.is_none() {
+            self.file_cache = file_cache;
+        }
+        self
+    }
+
     /// Used for read sstable
-    pub fn create_buf_reader_with_pos(&self) -> impl SequentialReadableFile {
-        FileSystem::create_seq_readable_file((&self.file_path).as_ref()).unwrap()
+    pub fn create_buf_reader_with_pos(&self) -> Box<dyn SequentialReadableFile> {
+        match (&self.memory, &self.file_cache) {
+            (Some(data), _) => Box::new(MemFile::new(data.clone())),
+            (None, Some(file_cache)) => {
+                Box::new(FileSystem::open_seq_readable_file(self.cached_file(file_cache)).unwrap())
+            }
+            (None, None) => {
+                Box::new(FileSystem::create_seq_readable_file((&self.file_path).as_ref()).unwrap())
+            }
+        }
+    }
+
+    /// The file of the table in `file_cache`, which is opened and inserted on a miss.
+    fn cached_file(&self, file_cache: &TableFileCache) -> Arc<File> {
+        if let Some(file) = file_cache.get(&self.table_key, self.hash) {
+            return Arc::clone(&file);
+        }
+        let file = Arc::new(File::open(&self.file_path).unwrap());
+        self.insert_if_readable(|| {
+            file_cache.insert_no_exists(self.table_key, file.clone(), self.hash)
+        });
+        file
+    }
+
+    /// Run `insert`, which inserts an entry of the table into a cache, unless the table
+    /// is deleted. A reader holding the handle of a deleted table can still read it, but
+    /// must not put it back into the caches after they are evicted by
+    /// [crate::sstable::manager::level_n::LevelNManager::evict_table_caches]. The status
+    /// is locked during `insert`, so every insertion either precedes the deletion, and is
+    /// evicted with it, or sees the table deleted.
+    pub(crate) fn insert_if_readable(&self, insert: impl FnOnce()) {
+        let guard = self.status.read().unwrap();
+        if *guard != TableStatus::ToDelete {
+            insert();
+        }
     }
 
     #[inline]
@@ -344,11 +843,50 @@ impl TableReadHandle {
         self.file_size
     }
 
+    /// Creation time of the table in seconds since the UNIX epoch.
+    #[inline]
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
     #[inline]
     pub fn kv_total(&self) -> u32 {
         self.kv_total
     }
 
+    /// Compression type recorded in the footer when the sstable was written.
+    #[inline]
+    pub fn compression(&self) -> CompressionType {
+        self.compression
+    }
+
+    /// Whether the table may contain keys starting with `prefix`. Always true if
+    /// the table has no prefix filter or `prefix` is shorter than its prefixes.
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        match &self.prefix_filter {
+            Some((prefix_length, filter)) => match prefix.get(..*prefix_length as usize) {
+                Some(prefix) => filter.may_contain(prefix),
+                None => true,
+            },
+            None => true,
+        }
+    }
+
+    /// Bytes of the prefix filter, which is kept in memory as long as the handle.
+    pub fn prefix_filter_size(&self) -> usize {
+        self.prefix_filter
+            .as_ref()
+            .map_or(0, |(_, filter)| filter.len() as usize)
+    }
+
+    /// Whether the table may contain `key`, judged by its key range and its prefix
+    /// filter without reading the file. The whole-key filter is only loaded into
+    /// [TableCache] by the queries.
+    #[allow(clippy::ptr_arg)]
+    pub fn may_contain(&self, key: &InternalKey) -> bool {
+        self.min_key.le(key) && key.le(&self.max_key) && self.may_contain_prefix(key)
+    }
+
     #[inline]
     pub fn table_key(&self) -> u64 {
         self.table_key
@@ -364,36 +902,157 @@ impl TableReadHandle {
         *guard.deref()
     }
 
-    /// Query value by `key` with `cache`
-    pub fn query_sstable_with_cache(
+    /// Probe the filter block of the table, which holds either whole keys or prefixes.
+    #[inline]
+    fn filter_may_contain(&self, filter: &BloomFilter, key: &[u8]) -> bool {
+        match self.bloom_prefix_length {
+            Some(prefix_length) => filter.may_contain(bloom_prefix(key, prefix_length)),
+            None => filter.may_contain(key),
+        }
+    }
+
+    /// Load the filter block and the index of the table, which are cached by the
+    /// queries.
+    pub(crate) fn load_table_cache(&self) -> TableCache {
+        let mut buf_reader = self.create_buf_reader_with_pos();
+        let footer = Footer::load_footer(&mut buf_reader).unwrap();
+        let bloom_filter = load_filter_block(
+            footer.index_block_offset as u64 + footer.index_block_length as u64,
+            footer.filter_length as usize,
+            &mut buf_reader,
+        );
+        let index = TableIndex::load(&mut buf_reader, &footer, self.partitioned_index_threshold);
+        TableCache::new(bloom_filter, index)
+    }
+
+    /// Get the data block at `offset` from `block_cache`, or read it with the reader
+    /// returned by `reader` and insert it into `block_cache`.
+    fn load_data_block<R: Read + Seek>(
+        &self,
+        block_cache: &SharedBlockCache,
+        (offset, length, index_offset): (u32, u32, u32),
+        reader: impl FnOnce() -> R,
+        stats: &StatsCounters,
+    ) -> DataBlock {
+        if let Some(data) = block_cache.get(self.table_id, offset) {
+            stats.add_block_cache_hit();
+            return DataBlock::from_cached(data, offset, index_offset);
+        }
+        stats.add_block_cache_miss(length as usize);
+        let data_block = DataBlock::from_reader(
+            &mut reader(),
+            offset,
+            length,
+            index_offset,
+            self.compression,
+        );
+        self.insert_if_readable(|| {
+            block_cache.insert(self.table_id, offset, data_block.data().clone())
+        });
+        data_block
+    }
+
+    /// Like [TableIndex::may_contain_key]. The partitions of a partitioned index are
+    /// read through `block_cache` if it is given, so that they are evicted with the
+    /// data blocks.
+    fn index_may_contain_key<R: Read + Seek>(
+        &self,
+        index: &TableIndex,
+        key: &InternalKey,
+        reader: impl FnOnce() -> R,
+        block_cache: Option<&SharedBlockCache>,
+    ) -> Option<(u32, u32, u32)> {
+        let (index, block_cache) = match (index, block_cache) {
+            (TableIndex::Partitioned(index), Some(block_cache)) => (index, block_cache),
+            _ => return index.may_contain_key(key, reader),
+        };
+        let (offset, length) = index.partition_of(key)?;
+        // the index block follows the data blocks, so the offsets never collide
+        let offset = offset as u32;
+        let bytes = match block_cache.get(self.table_id, offset) {
+            Some(bytes) => bytes,
+            None => {
+                let bytes = Arc::new(PartitionedIndex::read_partition(
+                    &mut reader(),
+                    offset as u64,
+                    length,
+                ));
+                self.insert_if_readable(|| {
+                    block_cache.insert(self.table_id, offset, bytes.clone())
+                });
+                bytes
+            }
+        };
+        IndexBlock::parse_indexes(&bytes).may_contain_key(key)
+    }
+
+    /// Query value by `key` with `cache`. Data blocks are read through `block_cache` if
+    /// it is given, otherwise they are kept in `cache`.
+    pub(crate) fn query_sstable_with_cache(
         &self,
         #[allow(clippy::ptr_arg)] key: &InternalKey,
-        cache: &mut TableCache,
-    ) -> Option<Value> {
-        if cache.filter.may_contain(key) {
-            if let Some((offset, length, index_offset)) = cache.index.may_contain_key(key) {
-                return match cache.start_data_block_map.get(&offset) {
-                    Some(data_block) => data_block.get_value(key),
-                    None => {
-                        let mut buf_reader = self.create_buf_reader_with_pos();
-                        let data_block =
-                            DataBlock::from_reader(&mut buf_reader, offset, length, index_offset);
-                        let option = data_block.get_value(key);
-                        cache.start_data_block_map.insert(offset, data_block);
-                        option
-                    }
-                };
+        cache: &TableCache,
+        block_cache: Option<&SharedBlockCache>,
+        stats: &StatsCounters,
+    ) -> crate::Result<Option<Value>> {
+        if self.filter_may_contain(&cache.filter, key) {
+            if let Some((offset, length, index_offset)) = self.index_may_contain_key(
+                &cache.index,
+                key,
+                || self.create_buf_reader_with_pos(),
+                block_cache,
+            ) {
+                if let Some(block_cache) = block_cache {
+                    let data_block = self.load_data_block(
+                        block_cache,
+                        (offset, length, index_offset),
+                        || self.create_buf_reader_with_pos(),
+                        stats,
+                    );
+                    return data_block
+                        .get_value(key)
+                        .map(|v| self.load_value(v))
+                        .transpose();
+                }
+                if let Some(data_block) = cache.start_data_block_map.lock().unwrap().get(&offset) {
+                    stats.add_block_cache_hit();
+                    return data_block
+                        .get_value(key)
+                        .map(|v| self.load_value(v))
+                        .transpose();
+                }
+                // read the data block without holding the lock
+                stats.add_block_cache_miss(length as usize);
+                let mut buf_reader = self.create_buf_reader_with_pos();
+                let data_block = DataBlock::from_reader(
+                    &mut buf_reader,
+                    offset,
+                    length,
+                    index_offset,
+                    self.compression,
+                );
+                let option = data_block.get_value(key);
+                cache
+                    .start_data_block_map
+                    .lock()
+                    .unwrap()
+                    .entry(offset)
+                    .or_insert(data_block);
+                return option.map(|v| self.load_value(v)).transpose();
             }
         }
-        None
+        Ok(None)
     }
 
-    /// Query value by `key` and insert cache into `lru_cache`.
-    pub fn query_sstable(
+    /// Query value by `key` and insert cache into `lru_cache`. The data block is read
+    /// through `block_cache` if it is given, otherwise it is kept in the cache.
+    pub(crate) fn query_sstable(
         &self,
         #[allow(clippy::ptr_arg)] key: &InternalKey,
         lru_cache: &Arc<ShardLRUCache<u64, TableCache>>,
-    ) -> Option<Value> {
+        block_cache: Option<&SharedBlockCache>,
+        stats: &StatsCounters,
+    ) -> crate::Result<Option<Value>> {
         let mut buf_reader = self.create_buf_reader_with_pos();
         let footer = Footer::load_footer(&mut buf_reader).unwrap();
         let bloom_filter = load_filter_block(
@@ -402,52 +1061,190 @@ impl TableReadHandle {
             &mut buf_reader,
         );
 
-        if bloom_filter.may_contain(key) {
-            let index_block = IndexBlock::load_index(&mut buf_reader, &footer);
-            let may_contain_key = index_block.may_contain_key(key);
-            let mut cache = TableCache::new(bloom_filter, index_block);
-
-            let option = if let Some((offset, length, index_offset)) = may_contain_key {
-                let data_block =
-                    DataBlock::from_reader(&mut buf_reader, offset, length, index_offset);
-                let option = data_block.get_value(key);
-                cache.start_data_block_map.insert(offset, data_block);
-                option
-            } else {
-                None
+        if self.filter_may_contain(&bloom_filter, key) {
+            let index =
+                TableIndex::load(&mut buf_reader, &footer, self.partitioned_index_threshold);
+            let may_contain_key =
+                self.index_may_contain_key(&index, key, || &mut buf_reader, block_cache);
+            let mut cache = TableCache::new(bloom_filter, index);
+
+            let option = match (may_contain_key, block_cache) {
+                (Some(block), Some(block_cache)) => self
+                    .load_data_block(block_cache, block, || &mut buf_reader, stats)
+                    .get_value(key),
+                (Some((offset, length, index_offset)), None) => {
+                    stats.add_block_cache_miss(length as usize);
+                    let data_block = DataBlock::from_reader(
+                        &mut buf_reader,
+                        offset,
+                        length,
+                        index_offset,
+                        footer.compression_type,
+                    );
+                    let option = data_block.get_value(key);
+                    cache
+                        .start_data_block_map
+                        .get_mut()
+                        .unwrap()
+                        .insert(offset, data_block);
+                    option
+                }
+                (None, _) => None,
             };
-            lru_cache.insert_no_exists(self.table_key, cache, self.hash);
-            option
+            self.insert_if_readable(|| {
+                lru_cache.insert_no_exists(self.table_key, cache, self.hash)
+            });
+            option.map(|v| self.load_value(v)).transpose()
         } else {
-            None
+            Ok(None)
         }
     }
 
-    /// Query all the key-value pairs in [`key_start`, `key_end`] and insert them into `kvs`
-    /// Return whether table_read_handle is overlapping with [`key_start`, `key_end`]
+    /// Query all the key-value pairs in [`key_start`, `key_end`] and insert them into `kvs`.
+    /// Return whether the tables after this one in its level may hold keys in the range,
+    /// i.e. whether its max key is less than `key_end`.
     pub fn range_query<UK: MemKey>(
         &self,
         #[allow(clippy::ptr_arg)] key_start: &InternalKey,
         #[allow(clippy::ptr_arg)] key_end: &InternalKey,
         kvs: &mut SrSwSkipMap<UK, Value>,
+        bytes_read: &AtomicU64,
     ) -> bool {
         if self.is_overlapping(key_start, key_end) {
             let mut buf_reader = self.create_buf_reader_with_pos();
             let footer = Footer::load_footer(&mut buf_reader).unwrap();
             let index_block = IndexBlock::load_index(&mut buf_reader, &footer);
             let data_blocks = index_block.find_all_ge(key_start);
-            let mut remain = false;
             for (offset, length, index_offset, _key_length, max_key) in data_blocks {
-                if max_key > key_end {
+                StatsCounters::add(bytes_read, *length as usize);
+                let data_block = DataBlock::from_reader(
+                    &mut buf_reader,
+                    *offset,
+                    *length,
+                    *index_offset,
+                    footer.compression_type,
+                );
+                if self.encodes_values() {
+                    let mut stored = SrSwSkipMap::new();
+                    data_block.get_all_record_in(key_start, key_end, &mut stored);
+                    for (k, v) in stored.into_iter() {
+                        kvs.insert(k, self.load_value(v).unwrap());
+                    }
+                } else {
+                    data_block.get_all_record_in(key_start, key_end, kvs);
+                }
+                // the blocks after the one holding `key_end` are out of the range
+                if max_key >= key_end {
+                    break;
+                }
+            }
+        }
+        self.max_key < *key_end
+    }
+
+    /// Data blocks of the table which may hold keys in [`key_start`, `key_end`], as
+    /// `(offset, block)`. The index is taken from `index_cache` like
+    /// [TableReadHandle::estimate_count_in_range], and the blocks found in
+    /// `block_cache` are not read again.
+    pub(crate) fn data_blocks_in_range(
+        &self,
+        key_start: &[u8],
+        key_end: &[u8],
+        index_cache: &ShardLRUCache<TableID, TableCache>,
+        block_cache: &SharedBlockCache,
+    ) -> crate::Result<Vec<(u32, Arc<Vec<u8>>)>> {
+        if key_start > key_end
+            || key_start > self.max_key.as_slice()
+            || key_end < self.min_key.as_slice()
+        {
+            return Ok(vec![]);
+        }
+        let indexes = self.with_index_block(index_cache, |index_block| {
+            let mut indexes = vec![];
+            for (offset, length, index_offset, _key_length, max_key) in
+                index_block.find_all_ge(key_start)
+            {
+                indexes.push((*offset, *length, *index_offset));
+                // the keys of the next block are greater than `max_key`
+                if max_key.as_slice() >= key_end {
                     break;
                 }
-                let data_block =
-                    DataBlock::from_reader(&mut buf_reader, *offset, *length, *index_offset);
-                remain |= data_block.get_all_record_le(key_end, kvs);
             }
-            return remain;
+            indexes
+        })?;
+        let mut buf_reader = None;
+        let mut blocks = Vec::with_capacity(indexes.len());
+        for (offset, length, index_offset) in indexes {
+            let block = match block_cache.get(self.table_id, offset) {
+                Some(block) => block,
+                None => DataBlock::try_from_reader(
+                    buf_reader.get_or_insert_with(|| self.create_buf_reader_with_pos()),
+                    offset,
+                    length,
+                    index_offset,
+                    self.compression,
+                )?
+                .data()
+                .clone(),
+            };
+            blocks.push((offset, block));
+        }
+        Ok(blocks)
+    }
+
+    /// Call `f` with the whole index block of the table, which is taken from
+    /// `index_cache` if it is cached there unpartitioned, or read from the table.
+    fn with_index_block<T>(
+        &self,
+        index_cache: &ShardLRUCache<TableID, TableCache>,
+        f: impl FnOnce(&IndexBlock) -> T,
+    ) -> crate::Result<T> {
+        if let Some(cache) = index_cache.get(&self.table_key, self.hash) {
+            if let TableIndex::Full(index_block) = &cache.index {
+                return Ok(f(index_block));
+            }
         }
-        false
+        let mut buf_reader = self.create_buf_reader_with_pos();
+        let footer = Footer::load_footer(&mut buf_reader)?;
+        let index_block = IndexBlock::try_load_index(&mut buf_reader, &footer, 0)?;
+        Ok(f(&index_block))
+    }
+
+    /// Approximate count of the key-values in [`key_start`, `key_end`), tombstones
+    /// included, read from the index block without reading any data block. The data
+    /// blocks are assumed to hold the same count of key-values each, and a block which
+    /// is partially in the range counts as half of it.
+    pub fn estimate_count_in_range(
+        &self,
+        key_start: &[u8],
+        key_end: &[u8],
+        index_cache: &ShardLRUCache<TableID, TableCache>,
+    ) -> crate::Result<u64> {
+        if key_start >= key_end
+            || key_start > self.max_key.as_slice()
+            || key_end < self.min_key.as_slice()
+        {
+            return Ok(0);
+        }
+        let (half_blocks, block_count) = self.with_index_block(index_cache, |index_block| {
+            // the keys of a block are not less than the max key of the previous block
+            let mut lower = index_block.min_key.as_slice();
+            let mut half_blocks = 0u64;
+            for index in &index_block.indexes {
+                let upper = index.4.as_slice();
+                if lower < key_end && upper >= key_start {
+                    half_blocks += if lower >= key_start && upper < key_end {
+                        2
+                    } else {
+                        1
+                    };
+                }
+                lower = upper;
+            }
+            (half_blocks, index_block.indexes.len())
+        })?;
+        let kvs_per_block = self.kv_total() as f64 / block_count as f64;
+        Ok((half_blocks as f64 * kvs_per_block / 2.0).round() as u64)
     }
 
     /// Check whether status of sstable is `Store`.
@@ -499,89 +1296,228 @@ impl TableReadHandle {
             || min_key.le(&self.min_key) && self.max_key.le(max_key)
     }
 
+    /// Iterate the key-values of the table in key order. Keys and values are arbitrary
+    /// bytes, yielded exactly as they were written.
     pub fn iter(handle: Arc<Self>) -> TableIterator {
         TableIterator::new(handle)
     }
+
+    /// Iterate the key-values of the table from the greatest key to the smallest one,
+    /// reading the data blocks back to front.
+    pub fn iter_rev(handle: Arc<Self>) -> TableRevIterator {
+        TableRevIterator::new(handle)
+    }
+
+    /// Like [TableReadHandle::iter], but yield the values as stored in a table whose
+    /// values are separated, see [crate::sstable::value_log]. The values of the other
+    /// tables are encoded the same way, and the value log is never read.
+    pub(crate) fn stored_iter(handle: Arc<Self>) -> TableIterator {
+        let mut iter = TableIterator::new(handle);
+        iter.stored = true;
+        iter
+    }
+
+    /// Whether the values of the table are separated, see [crate::sstable::value_log].
+    #[inline]
+    pub fn separated_values(&self) -> bool {
+        self.value_log_dir.is_some()
+    }
+
+    /// Whether the values read from the table must go through
+    /// [TableReadHandle::load_value].
+    #[inline]
+    fn encodes_values(&self) -> bool {
+        self.value_log_dir.is_some() || self.dictionary.is_some()
+    }
+
+    /// Get the value which `stored` read from the table stands for.
+    fn load_value(&self, stored: Value) -> crate::Result<Value> {
+        match (&self.value_log_dir, &self.dictionary) {
+            (Some(dir), _) => load_value(dir, stored),
+            (None, Some(dictionary)) => dictionary.decompress(stored),
+            (None, None) => Ok(stored),
+        }
+    }
 }
 
 impl Drop for TableReadHandle {
     fn drop(&mut self) {
         if let TableStatus::ToDelete = self.status() {
-            std::fs::remove_file(&self.file_path).unwrap();
+            if self.memory.is_none() {
+                std::fs::remove_file(&self.file_path).unwrap();
+            }
         }
     }
 }
 
 pub(crate) fn temp_file_name(file_name: &str) -> String {
-    format!("{}_write", file_name)
+    format!("{}{}", file_name, TEMP_FILE_SUFFIX)
 }
 
 pub struct TableIterator {
     reader: Box<dyn SequentialReadableFile>,
     handle: Arc<TableReadHandle>,
     index_block: IndexBlock,
-    data_block: DataBlockIter,
+    /// Iterator of the data block `cur_data_block_idx`, `None` until the block is read.
+    data_block: Option<DataBlockIter>,
     cur_data_block_idx: usize,
+    /// See [TableReadHandle::stored_iter].
+    stored: bool,
+    /// Where an error of reading the table is recorded, see [TableIterator::with_errors].
+    errors: Option<IterErrors>,
     #[cfg(debug_assertions)]
-    prev_key: InternalKey,
+    prev_key: Option<InternalKey>,
 }
 
 impl TableIterator {
     pub(super) fn new(handle: Arc<TableReadHandle>) -> TableIterator {
-        let mut reader = Box::new(handle.create_buf_reader_with_pos());
-        let footer = Footer::load_footer(&mut reader).unwrap();
-        let index_block = IndexBlock::load_index(&mut reader, &footer);
-
-        let index = &index_block.indexes[0];
-        let data_block = DataBlock::from_reader(&mut reader, index.0, index.1, index.2);
+        Self::with_errors(handle, None)
+    }
 
-        TableIterator {
+    /// Like [TableIterator::new], but if `errors` is given, an error of reading the table
+    /// is recorded in it and ends the iteration, instead of panicking.
+    pub(crate) fn with_errors(
+        handle: Arc<TableReadHandle>,
+        errors: Option<IterErrors>,
+    ) -> TableIterator {
+        let reader = handle.create_buf_reader_with_pos();
+        let mut iter = TableIterator {
             reader,
             handle,
-            index_block,
-            data_block: data_block.into_iter(),
+            index_block: IndexBlock::default(),
+            data_block: None,
             cur_data_block_idx: 0,
+            stored: false,
+            errors,
             #[cfg(debug_assertions)]
-            prev_key: InternalKey::default(),
+            prev_key: None,
+        };
+        let index_block = Footer::load_footer(&mut iter.reader)
+            .and_then(|footer| IndexBlock::try_load_index(&mut iter.reader, &footer, 0));
+        match index_block {
+            Ok(index_block) => iter.index_block = index_block,
+            Err(e) => iter.fail(e),
         }
+        iter
     }
 
     #[inline]
     pub fn end(&self) -> bool {
         self.cur_data_block_idx == self.index_block.indexes.len()
     }
-}
 
-impl Iterator for TableIterator {
-    type Item = InternalKeyValue;
+    /// Record `e` and end the iteration, or panic if there is nowhere to record it.
+    fn fail(&mut self, e: KVLiteError) {
+        match &self.errors {
+            Some(errors) => errors.record(e),
+            None => panic!("failed to read {}: {}", self.handle, e),
+        }
+        self.cur_data_block_idx = self.index_block.indexes.len();
+        self.data_block = None;
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.end() {
-            None
-        } else {
-            match self.data_block.next() {
-                Some(item) => {
+    /// Next key-value as written in the table.
+    fn next_raw(&mut self) -> Option<InternalKeyValue> {
+        while !self.end() {
+            match self.data_block.as_mut().map(Iterator::next) {
+                Some(Some(item)) => {
                     #[cfg(debug_assertions)]
                     {
-                        assert!(self.prev_key < item.0);
-                        self.prev_key = item.0.clone();
+                        assert!(self.prev_key.as_ref() < Some(&item.0));
+                        self.prev_key = Some(item.0.clone());
                     }
-                    Some(item)
+                    return Some(item);
                 }
-                None => {
+                Some(None) => {
                     self.cur_data_block_idx += 1;
-                    if self.end() {
-                        None
-                    } else {
-                        let index = &self.index_block.indexes[self.cur_data_block_idx];
-                        let data_block =
-                            DataBlock::from_reader(&mut self.reader, index.0, index.1, index.2);
-                        self.data_block = data_block.into_iter();
-                        self.next()
+                    self.data_block = None;
+                }
+                None => {
+                    let index = &self.index_block.indexes[self.cur_data_block_idx];
+                    match DataBlock::try_from_reader(
+                        &mut self.reader,
+                        index.0,
+                        index.1,
+                        index.2,
+                        self.handle.compression(),
+                    ) {
+                        Ok(data_block) => self.data_block = Some(data_block.into_iter()),
+                        Err(e) => self.fail(e),
                     }
                 }
             }
         }
+        None
+    }
+}
+
+impl Iterator for TableIterator {
+    type Item = InternalKeyValue;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.next_raw()?;
+        let value = match (self.stored, self.handle.separated_values()) {
+            (true, true) => Ok(value),
+            (true, false) => self.handle.load_value(value).map(encode_inline),
+            (false, _) => self.handle.load_value(value),
+        };
+        match value {
+            Ok(value) => Some((key, value)),
+            Err(e) => {
+                self.fail(e);
+                None
+            }
+        }
+    }
+}
+
+/// See [TableReadHandle::iter_rev].
+pub struct TableRevIterator {
+    reader: Box<dyn SequentialReadableFile>,
+    handle: Arc<TableReadHandle>,
+    index_block: IndexBlock,
+    data_block: Option<DataBlockRevIter>,
+    /// Number of the data blocks before the current one, which are not read yet.
+    remaining_blocks: usize,
+}
+
+impl TableRevIterator {
+    fn new(handle: Arc<TableReadHandle>) -> TableRevIterator {
+        let mut reader = handle.create_buf_reader_with_pos();
+        let footer = Footer::load_footer(&mut reader).unwrap();
+        let index_block = IndexBlock::load_index(&mut reader, &footer);
+        TableRevIterator {
+            reader,
+            handle,
+            remaining_blocks: index_block.indexes.len(),
+            index_block,
+            data_block: None,
+        }
+    }
+}
+
+impl Iterator for TableRevIterator {
+    type Item = InternalKeyValue;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, value)) = self.data_block.as_mut().and_then(Iterator::next) {
+                return Some((key, self.handle.load_value(value).unwrap()));
+            }
+            if self.remaining_blocks == 0 {
+                return None;
+            }
+            self.remaining_blocks -= 1;
+            let index = &self.index_block.indexes[self.remaining_blocks];
+            let data_block = DataBlock::from_reader(
+                &mut self.reader,
+                index.0,
+                index.1,
+                index.2,
+                self.handle.compression(),
+            );
+            self.data_block = Some(data_block.into_rev_iter());
+        }
     }
 }
 
@@ -589,10 +1525,17 @@ impl Iterator for TableIterator {
 pub(crate) mod tests {
     use std::ops::Range;
 
+    use crate::cache::ShardLRUCache;
+    use crate::db::key_types::InternalKey;
+    use crate::db::options::Options;
+    use crate::db::stats::StatsCounters;
+    use crate::db::Value;
+    use crate::sstable::block_cache::{LRUBlockCache, SharedBlockCache};
     use crate::sstable::data_block::DataBlock;
     use crate::sstable::footer::Footer;
-    use crate::sstable::index_block::IndexBlock;
+    use crate::sstable::index_block::{IndexBlock, TableIndex, MAX_LOADED_PARTITIONS};
     use crate::sstable::table_handle::{TableReadHandle, TableWriteHandle};
+    use crate::sstable::CompressionType;
     use std::sync::Arc;
 
     pub(crate) fn create_write_handle(
@@ -602,7 +1545,8 @@ pub(crate) mod tests {
         range: Range<i32>,
     ) -> TableWriteHandle {
         let kv_total: u32 = (range.end - range.start) as u32;
-        let mut write_handle = TableWriteHandle::new(db_path, level, table_id, kv_total);
+        let mut write_handle =
+            TableWriteHandle::new(db_path, level, table_id, kv_total, &Options::default());
 
         let mut kvs = vec![];
         for i in range {
@@ -623,7 +1567,7 @@ pub(crate) mod tests {
     ) -> TableReadHandle {
         let write_handle = create_write_handle(db_path, level, table_id, range);
         write_handle.rename();
-        TableReadHandle::open(db_path, level, table_id)
+        TableReadHandle::open(db_path, level, table_id, &Options::default())
     }
 
     #[test]
@@ -655,7 +1599,13 @@ pub(crate) mod tests {
         let index_block = IndexBlock::load_index(&mut reader, &footer);
         assert_eq!(index_block.indexes.len(), 1);
         for index in index_block.indexes {
-            let data_block = DataBlock::from_reader(&mut reader, index.0, index.1, index.2);
+            let data_block = DataBlock::from_reader(
+                &mut reader,
+                index.0,
+                index.1,
+                index.2,
+                footer.compression_type,
+            );
             for i in 0..100 {
                 let res = data_block.get_value(&Vec::from(format!("key{:02}", i)));
                 assert_eq!(
@@ -676,4 +1626,290 @@ pub(crate) mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_compression_per_table() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("1")).unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+
+        let mut read_handles = vec![];
+        for (table_id, compression) in [CompressionType::NoCompression, CompressionType::Snappy]
+            .iter()
+            .enumerate()
+        {
+            let mut write_handle = TableWriteHandle::new(
+                &path,
+                1,
+                table_id as u64,
+                1000,
+                &Options {
+                    compression: *compression,
+                    ..Options::default()
+                },
+            );
+            let kvs = (0..1000)
+                .map(|i| {
+                    (
+                        format!("key{:04}", i).into_bytes(),
+                        Vec::from("value".repeat(20)),
+                    )
+                })
+                .collect();
+            write_handle.write_sstable_from_vec(kvs).unwrap();
+            write_handle.rename();
+
+            // reopen the table and read it with the compression type recorded in footer
+            let read_handle = TableReadHandle::open(&path, 1, table_id as u64, &Options::default());
+            assert_eq!(read_handle.compression(), *compression);
+
+            let read_handle = Arc::new(read_handle);
+            let mut count = 0;
+            for (i, (k, v)) in TableReadHandle::iter(read_handle.clone()).enumerate() {
+                assert_eq!(format!("key{:04}", i).into_bytes(), k);
+                assert_eq!(Vec::from("value".repeat(20)), v);
+                count += 1;
+            }
+            assert_eq!(count, 1000);
+            read_handles.push(read_handle);
+        }
+        assert!(read_handles[1].file_size() < read_handles[0].file_size());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_direct_io() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("1")).unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+
+        // large enough for several aligned writes and an unaligned tail
+        let kvs: Vec<(Vec<u8>, Vec<u8>)> = (0..5000)
+            .map(|i| {
+                (
+                    format!("key{:04}", i).into_bytes(),
+                    format!("value{}", i).repeat(10).into_bytes(),
+                )
+            })
+            .collect();
+        let mut write_handle =
+            TableWriteHandle::new_with_direct_io(&path, 1, 1, 5000, &Options::default(), true)
+                .unwrap();
+        write_handle.write_sstable_from_vec(kvs.clone()).unwrap();
+        let file_size = write_handle.writer.writer.pos;
+        write_handle.rename();
+
+        // falls back to buffered I/O on filesystems without O_DIRECT, e.g. tmpfs
+        let read_handle = TableReadHandle::open(&path, 1, 1, &Options::default());
+        assert_eq!(read_handle.file_size(), file_size);
+        let read_handle = Arc::new(read_handle);
+        let read: Vec<(Vec<u8>, Vec<u8>)> = TableReadHandle::iter(read_handle).collect();
+        assert_eq!(read, kvs);
+    }
+
+    #[test]
+    fn test_overlap_and_contain() {
+        let options = Options {
+            in_memory: true,
+            prefix_bloom_length: 5,
+            ..Options::default()
+        };
+        let mut write_handle = TableWriteHandle::new("", 1, 1, 40, &options);
+        let kvs = (20..60)
+            .map(|i| (format!("key{}", i).into_bytes(), Vec::from("value")))
+            .collect();
+        write_handle.write_sstable_from_vec(kvs).unwrap();
+        let handle = TableReadHandle::from_table_write_handle(write_handle);
+        assert_eq!(
+            handle.min_max_key(),
+            (&Vec::from("key20"), &Vec::from("key59"))
+        );
+
+        for (start, end, overlapping) in [
+            ("key00", "key19", false),
+            ("key00", "key20", true),
+            ("key30", "key40", true),
+            ("key00", "key99", true),
+            ("key59", "key99", true),
+            ("key60", "key99", false),
+        ] {
+            assert_eq!(
+                handle.is_overlapping(&Vec::from(start), &Vec::from(end)),
+                overlapping,
+                "[{}, {}]",
+                start,
+                end
+            );
+        }
+
+        for i in 20..60 {
+            assert!(handle.may_contain(&format!("key{}", i).into_bytes()));
+        }
+        assert!(!handle.may_contain(&Vec::from("key19")));
+        assert!(!handle.may_contain(&Vec::from("key60")));
+        // in the key range, but not in the prefix filter
+        assert!(!(b'a'..=b'z')
+            .map(|c| vec![b'k', b'e', b'y', b'2', c])
+            .all(|key| handle.may_contain(&key)));
+    }
+
+    #[test]
+    fn test_partitioned_index() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("1")).unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+        let options = Options {
+            partitioned_index_threshold: 1,
+            ..Options::default()
+        };
+        let key = |i: usize| format!("key{:06}", i).into_bytes();
+        let value = |i: usize| format!("value{:06}", i).repeat(10).into_bytes();
+
+        // about 1500 data blocks, whose index entries take several partitions
+        let mut write_handle = TableWriteHandle::new(&path, 1, 1, 50000, &options);
+        write_handle
+            .write_sstable_from_vec((0..50000).map(|i| (key(i), value(i))).collect())
+            .unwrap();
+        write_handle.rename();
+        let read_handle = TableReadHandle::open(&path, 1, 1, &options);
+
+        let lru_cache = Arc::new(ShardLRUCache::default());
+        let stats = StatsCounters::default();
+        assert_eq!(
+            read_handle
+                .query_sstable(&key(25000), &lru_cache, None, &stats)
+                .unwrap(),
+            Some(value(25000))
+        );
+        let cache = lru_cache
+            .get(&read_handle.table_key(), read_handle.hash())
+            .unwrap();
+        let index = match &cache.index {
+            TableIndex::Partitioned(index) => index,
+            TableIndex::Full(_) => panic!("the index should be partitioned"),
+        };
+        assert!(index.len() > 4);
+        assert_eq!(index.loaded_len(), 1);
+
+        for i in (0..50000).step_by(7).chain(std::iter::once(49999)) {
+            assert_eq!(
+                read_handle
+                    .query_sstable_with_cache(&key(i), &cache, None, &stats)
+                    .unwrap(),
+                Some(value(i)),
+                "key{:06}",
+                i
+            );
+        }
+        assert_eq!(index.loaded_len(), index.len().min(MAX_LOADED_PARTITIONS));
+        assert_eq!(
+            read_handle
+                .query_sstable_with_cache(&key(50000), &cache, None, &stats)
+                .unwrap(),
+            None
+        );
+
+        // the partitions are kept in the block cache instead of the index if given
+        let lru_cache = Arc::new(ShardLRUCache::default());
+        let block_cache = SharedBlockCache::new(Arc::new(LRUBlockCache::default()));
+        assert_eq!(
+            read_handle
+                .query_sstable(&key(25000), &lru_cache, Some(&block_cache), &stats)
+                .unwrap(),
+            Some(value(25000))
+        );
+        let cache = lru_cache
+            .get(&read_handle.table_key(), read_handle.hash())
+            .unwrap();
+        for i in (0..50000).step_by(7) {
+            assert_eq!(
+                read_handle
+                    .query_sstable_with_cache(&key(i), &cache, Some(&block_cache), &stats)
+                    .unwrap(),
+                Some(value(i))
+            );
+        }
+        match &cache.index {
+            TableIndex::Partitioned(index) => assert_eq!(index.loaded_len(), 0),
+            TableIndex::Full(_) => panic!("the index should be partitioned"),
+        }
+    }
+
+    #[test]
+    fn test_debug() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("2")).unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+        let read_handle = create_read_handle(&path, 2, 7, 0..50);
+
+        let debug = format!("{:?}", read_handle);
+        for field in &[
+            "table_id: 7",
+            "level: 2",
+            "min_key: \"key00\"",
+            "max_key: \"key49\"",
+            "kv_total: 50",
+        ] {
+            assert!(debug.contains(field), "{} not in {}", field, debug);
+        }
+        assert!(!debug.contains("value"));
+        assert_eq!(read_handle.to_string(), "table 7 of level 2");
+    }
+
+    #[test]
+    fn test_iter_rev() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("1")).unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+        let options = Options {
+            block_restart_interval: 5,
+            ..Options::default()
+        };
+        let mut write_handle = TableWriteHandle::new(&path, 1, 1, 3000, &options);
+        write_handle
+            .write_sstable_from_vec(
+                (0..3000)
+                    .map(|i| {
+                        (
+                            format!("key{:05}", i).into_bytes(),
+                            format!("value{}", i).repeat(i % 7 + 1).into_bytes(),
+                        )
+                    })
+                    .collect(),
+            )
+            .unwrap();
+        let read_handle = Arc::new(TableReadHandle::from_table_write_handle(write_handle));
+
+        let mut reader = read_handle.create_buf_reader_with_pos();
+        let footer = Footer::load_footer(&mut reader).unwrap();
+        assert!(IndexBlock::load_index(&mut reader, &footer).indexes.len() > 10);
+
+        let mut kvs: Vec<_> = TableReadHandle::iter(read_handle.clone()).collect();
+        assert_eq!(kvs.len(), 3000);
+        kvs.reverse();
+        let kvs_rev: Vec<_> = TableReadHandle::iter_rev(read_handle).collect();
+        assert_eq!(kvs_rev, kvs);
+    }
+
+    #[test]
+    fn test_iter_binary() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("1")).unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+        // not valid UTF-8, with zero bytes and every byte value
+        let kvs: Vec<(InternalKey, Value)> = (0..=255u8)
+            .map(|i| {
+                let key = vec![i, 0xff, 0, 0xc3, 0x28];
+                let value = (0..=i).rev().chain([0, 0xfe]).collect();
+                (key, value)
+            })
+            .collect();
+        let mut write_handle =
+            TableWriteHandle::new(&path, 1, 1, kvs.len() as u32, &Options::default());
+        write_handle.write_sstable_from_vec(kvs.clone()).unwrap();
+        let read_handle = Arc::new(TableReadHandle::from_table_write_handle(write_handle));
+
+        let read: Vec<(InternalKey, Value)> = TableReadHandle::iter(read_handle).collect();
+        assert_eq!(read, kvs);
+    }
 }
@@ -0,0 +1,431 @@
+//! Read a single sstable file without opening the database, e.g. for dumping or
+//! verifying the output of compaction.
+
+use crate::db::db_iter::InternalKeyValue;
+use crate::db::key_types::InternalKey;
+use crate::db::options::DEFAULT_MAX_KEY_BYTES;
+use crate::env::file_system::SequentialReadableFile;
+use crate::error::KVLiteError;
+use crate::ioutils::BufReaderWithPos;
+use crate::sstable::data_block::{DataBlock, DataBlockIter};
+use crate::sstable::footer::{Footer, FOOTER_V1_BYTE_SIZE};
+use crate::sstable::index_block::IndexBlock;
+use crate::sstable::{ChecksumType, CompressionType};
+use crate::Result;
+use std::fs::File;
+use std::path::Path;
+
+/// Open the sstable at `path` and iterate all of its key-value pairs in order,
+/// tombstones included. The values of a table whose values are separated or compressed
+/// with a dictionary are yielded as stored, see [crate::sstable::value_log] and
+/// [crate::sstable::dictionary].
+///
+/// The footer and the position of every block are validated first, and keys longer
+/// than [DEFAULT_MAX_KEY_BYTES] are rejected. An error met while iterating is yielded
+/// once and ends the iteration.
+pub fn read_table(path: impl AsRef<Path>) -> Result<TableReader> {
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    TableReader::new(
+        Box::new(BufReaderWithPos::new(file)?),
+        file_size,
+        DEFAULT_MAX_KEY_BYTES,
+    )
+}
+
+/// Iterator returned by [read_table].
+pub struct TableReader {
+    reader: Box<dyn SequentialReadableFile>,
+    compression: CompressionType,
+    kv_total: u32,
+    kv_count: u32,
+    indexes: std::vec::IntoIter<(u32, u32, u32, u32, InternalKey)>,
+    /// Offset of the data block being iterated.
+    block_offset: Option<u32>,
+    data_block: Option<DataBlockIter>,
+    prev_key: Option<InternalKey>,
+    failed: bool,
+}
+
+impl TableReader {
+    /// Like [read_table], but read the sstable of `file_size` bytes from `reader`,
+    /// rejecting keys longer than `max_key_length`.
+    pub(crate) fn new(
+        mut reader: Box<dyn SequentialReadableFile>,
+        file_size: u64,
+        max_key_length: usize,
+    ) -> Result<TableReader> {
+        if file_size < FOOTER_V1_BYTE_SIZE as u64 {
+            return Err(KVLiteError::Custom("sstable is shorter than footer".into()));
+        }
+
+        let footer = Footer::load_footer(&mut reader)?;
+        // verify the blocks here once a checksum type other than `NoChecksum` is added
+        match footer.checksum_type {
+            ChecksumType::NoChecksum => {}
+        }
+        let filter_end = footer.index_block_offset as u64
+            + footer.index_block_length as u64
+            + footer.filter_length as u64;
+        if filter_end + footer.byte_size() > file_size {
+            return Err(KVLiteError::Custom(
+                "index block or filter block is out of range".into(),
+            ));
+        }
+
+        let index_block = IndexBlock::try_load_index(&mut reader, &footer, max_key_length)?;
+
+        Ok(TableReader {
+            reader,
+            compression: footer.compression_type,
+            kv_total: footer.kv_total,
+            kv_count: 0,
+            indexes: index_block.indexes.into_iter(),
+            block_offset: None,
+            data_block: None,
+            prev_key: None,
+            failed: false,
+        })
+    }
+
+    /// Number of key-value pairs recorded in the footer.
+    #[inline]
+    pub fn kv_total(&self) -> u32 {
+        self.kv_total
+    }
+
+    /// Offset of the data block which the last key-value or error comes from.
+    #[inline]
+    pub fn block_offset(&self) -> Option<u32> {
+        self.block_offset
+    }
+
+    fn next_kv(&mut self) -> Result<Option<InternalKeyValue>> {
+        loop {
+            if let Some(kv) = self.data_block.as_mut().and_then(|block| block.next()) {
+                if self.prev_key.as_ref() >= Some(&kv.0) {
+                    return Err(KVLiteError::Custom("keys are not sorted".into()));
+                }
+                self.prev_key = Some(kv.0.clone());
+                self.kv_count += 1;
+                return Ok(Some(kv));
+            }
+
+            match self.indexes.next() {
+                Some((offset, length, index_offset, ..)) => {
+                    self.block_offset = Some(offset);
+                    let data_block = DataBlock::try_from_reader(
+                        &mut self.reader,
+                        offset,
+                        length,
+                        index_offset,
+                        self.compression,
+                    )?;
+                    self.data_block = Some(data_block.into_iter());
+                }
+                None if self.kv_count != self.kv_total => {
+                    return Err(KVLiteError::Custom(format!(
+                        "{} key-values are read, but footer records {}",
+                        self.kv_count, self.kv_total
+                    )));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Iterator for TableReader {
+    type Item = Result<InternalKeyValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.next_kv() {
+            Ok(kv) => kv.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bloom::BloomFilter;
+    use crate::db::key_types::InternalKey;
+    use crate::db::no_transaction_db::NoTransactionDB;
+    use crate::db::options::{Options, WriteOptions, DEFAULT_MAX_KEY_BYTES};
+    use crate::db::stats::StatsCounters;
+    use crate::db::{Value, DB, MAX_LEVEL};
+    use crate::error::KVLiteError;
+    use crate::memory::MutexSkipMapMemTable;
+    use crate::sstable::footer::{Footer, FORMAT_VERSION};
+    use crate::sstable::table_handle::TableReadHandle;
+    use crate::sstable::{read_table, sstable_file};
+    use crate::sstable::{ChecksumType, CompressionType};
+    use crate::wal::simple_wal::SimpleWriteAheadLog;
+    use std::collections::BTreeMap;
+    use std::io::{Cursor, Seek, SeekFrom};
+    use std::path::Path;
+
+    #[test]
+    fn test_read_table() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("read_table")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: false };
+        let mut expected = BTreeMap::new();
+        {
+            let db = NoTransactionDB::<
+                InternalKey,
+                InternalKey,
+                MutexSkipMapMemTable<InternalKey>,
+                SimpleWriteAheadLog,
+            >::open(temp_dir.path())
+            .unwrap();
+            for i in 0..1000 {
+                let key = format!("key{:04}", i).into_bytes();
+                let value = format!("value{}", i).into_bytes();
+                db.set(&wo, key.clone(), value.clone()).unwrap();
+                expected.insert(key, value);
+            }
+            db.remove(&wo, Vec::from("key0500")).unwrap();
+            expected.insert(Vec::from("key0500"), Value::default());
+            db.flush().unwrap();
+        }
+
+        let mut tables = vec![];
+        for level in 0..=MAX_LEVEL {
+            for entry in std::fs::read_dir(temp_dir.path().join(level.to_string())).unwrap() {
+                let path = entry.unwrap().path();
+                if path
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .parse::<u64>()
+                    .is_ok()
+                {
+                    tables.push(path);
+                }
+            }
+        }
+        assert_eq!(tables.len(), 1);
+
+        let reader = read_table(&tables[0]).unwrap();
+        assert_eq!(reader.kv_total(), 1000);
+        let kvs: Vec<(InternalKey, Value)> = reader.map(|kv| kv.unwrap()).collect();
+        assert_eq!(expected.into_iter().collect::<Vec<_>>(), kvs);
+
+        // not an sstable
+        let invalid = temp_dir.path().join("invalid");
+        std::fs::write(&invalid, vec![0u8; 100]).unwrap();
+        assert!(read_table(&invalid).is_err());
+    }
+
+    /// Write `blocks` followed by a footer whose index block starts at `index_offset`.
+    fn write_table(path: &Path, blocks: Vec<u8>, index_offset: u32, kv_total: u32) {
+        let mut cursor = Cursor::new(blocks);
+        cursor.seek(SeekFrom::End(0)).unwrap();
+        let footer = Footer {
+            index_block_offset: index_offset,
+            index_block_length: cursor.get_ref().len() as u32 - index_offset,
+            filter_length: 0,
+            kv_total,
+            compression_type: CompressionType::NoCompression,
+            checksum_type: ChecksumType::NoChecksum,
+            prefix_length: 0,
+            prefix_bloom: false,
+            separated_values: false,
+            dictionary_values: false,
+            format_version: FORMAT_VERSION,
+        };
+        footer.write_to_file(&mut cursor).unwrap();
+        std::fs::write(path, cursor.into_inner()).unwrap();
+    }
+
+    #[test]
+    fn test_implausible_lengths() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("implausible_lengths")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path().join("table");
+
+        // min key length far beyond the index block
+        write_table(&path, u32::MAX.to_le_bytes().to_vec(), 0, 0);
+        assert_eq!(
+            read_table(&path).err(),
+            Some(KVLiteError::Corruption(format!(
+                "key length {} in index block is implausible",
+                u32::MAX
+            )))
+        );
+
+        // a key that fits in the index block but exceeds the limit
+        let length = DEFAULT_MAX_KEY_BYTES as u32 + 1;
+        let mut index = length.to_le_bytes().to_vec();
+        index.resize(index.len() + length as usize, b'k');
+        write_table(&path, index, 0, 0);
+        assert_eq!(
+            read_table(&path).err(),
+            Some(KVLiteError::Corruption(format!(
+                "key length {} in index block is implausible",
+                length
+            )))
+        );
+
+        // value length of the only entry of the data block runs past the block
+        let mut blocks = vec![];
+        blocks.extend_from_slice(&1u32.to_le_bytes());
+        blocks.extend_from_slice(&u32::MAX.to_le_bytes());
+        blocks.push(b'k');
+        let restarts_offset = blocks.len() as u32;
+        blocks.extend_from_slice(&0u32.to_le_bytes());
+        let index_offset = blocks.len() as u32;
+        blocks.extend_from_slice(&1u32.to_le_bytes());
+        blocks.push(b'k');
+        for n in [0, index_offset, restarts_offset, 1] {
+            blocks.extend_from_slice(&n.to_le_bytes());
+        }
+        blocks.push(b'k');
+        write_table(&path, blocks, index_offset, 1);
+        let mut reader = read_table(&path).unwrap();
+        assert_eq!(
+            reader.next().unwrap().err(),
+            Some(KVLiteError::Corruption(format!(
+                "key length 1 and value length {} in data block are implausible",
+                u32::MAX
+            )))
+        );
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_read_v1_table() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("read_v1_table")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path().join("table");
+        let kvs: Vec<(InternalKey, Value)> = vec![
+            (Vec::from("k1"), Vec::from("v1")),
+            (Vec::from("k2"), Vec::from("value2")),
+            (Vec::from("k3"), Vec::from("v3")),
+        ];
+
+        // a data block of the entries, each of which is a restart point
+        let mut bytes = vec![];
+        let mut restarts = vec![];
+        for (key, value) in &kvs {
+            restarts.push(bytes.len() as u32);
+            bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(key);
+            bytes.extend_from_slice(value);
+        }
+        let restarts_offset = bytes.len() as u32;
+        for restart in restarts {
+            bytes.extend_from_slice(&restart.to_le_bytes());
+        }
+        // the index block
+        let index_offset = bytes.len() as u32;
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(b"k1");
+        for n in [0, index_offset, restarts_offset, 2] {
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        bytes.extend_from_slice(b"k3");
+        let index_length = bytes.len() as u32 - index_offset;
+        // the filter block, a bare bit vector
+        let mut filter = BloomFilter::create_filter(kvs.len());
+        for (key, _) in &kvs {
+            filter.add(key);
+        }
+        bytes.extend_from_slice(&filter.0);
+        let blocks = bytes.clone();
+        // the 20-byte footer of the tables written before the format version was added
+        for n in [index_offset, index_length, filter.len(), kvs.len() as u32] {
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        bytes.extend_from_slice(&0xdb991122u32.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+
+        let reader = read_table(&path).unwrap();
+        assert_eq!(reader.kv_total(), 3);
+        let kvs_read: Vec<(InternalKey, Value)> = reader.map(|kv| kv.unwrap()).collect();
+        assert_eq!(kvs_read, kvs);
+
+        // opened by the database, with the filter block
+        let db_path = temp_dir.path().to_str().unwrap();
+        std::fs::create_dir(temp_dir.path().join("1")).unwrap();
+        std::fs::write(sstable_file(db_path, 1, 1), &bytes).unwrap();
+        let handle = TableReadHandle::open(db_path, 1, 1, &Options::default());
+        assert_eq!(handle.kv_total(), 3);
+        let cache = handle.load_table_cache();
+        let stats = StatsCounters::default();
+        for (key, value) in &kvs {
+            assert_eq!(
+                handle
+                    .query_sstable_with_cache(key, &cache, None, &stats)
+                    .unwrap(),
+                Some(value.clone())
+            );
+        }
+        assert_eq!(
+            handle
+                .query_sstable_with_cache(&Vec::from("k0"), &cache, None, &stats)
+                .unwrap(),
+            None
+        );
+
+        // the 24-byte footers of the unreleased layouts have the same magic number
+        let mut bytes = blocks;
+        for n in [index_offset, index_length, filter.len(), kvs.len() as u32] {
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[0, 0, 0, 0]);
+        bytes.extend_from_slice(&0xdb991122u32.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(
+            read_table(&path).err(),
+            Some(KVLiteError::Custom(
+                "unsupported sstable layout of footer magic number 0xdb991122".into()
+            ))
+        );
+
+        // a format version newer than the reader
+        let mut cursor = Cursor::new(vec![]);
+        Footer {
+            index_block_offset: 0,
+            index_block_length: 0,
+            filter_length: 0,
+            kv_total: 0,
+            compression_type: CompressionType::NoCompression,
+            checksum_type: ChecksumType::NoChecksum,
+            prefix_length: 0,
+            prefix_bloom: false,
+            separated_values: false,
+            dictionary_values: false,
+            format_version: FORMAT_VERSION,
+        }
+        .write_to_file(&mut cursor)
+        .unwrap();
+        let mut bytes = cursor.into_inner();
+        let version_offset = bytes.len() - 6;
+        bytes[version_offset..version_offset + 2]
+            .copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(
+            read_table(&path).err(),
+            Some(KVLiteError::Custom(format!(
+                "unsupported sstable format version {}",
+                FORMAT_VERSION + 1
+            )))
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! Value log, which keeps large values out of sstables.
+//!
+//! If [Options::value_log_threshold] is not 0, the values longer than it are moved out of
+//! the sstables written by flush and bulk ingestion. They are appended to a value log file
+//! named by the table id, and the sstable stores a [ValuePointer] to them instead.
+//! Compaction copies the pointers and never rewrites the values, so it writes much less
+//! for large values.
+//!
+//! The footer of such an sstable records that its values are separated, and each of its
+//! values is stored as:
+//!
+//! ```text
+//! +----------------------+     +-----------------------------------+
+//! | 0 (u8) | value bytes |  or | 1 (u8) | file id | offset | length |
+//! +----------------------+     +-----------------------------------+
+//!                                          u64       u64      u32
+//! ```
+//!
+//! Tombstones are still empty. A value log file is the concatenation of its values, and
+//! is removed by [crate::db::no_transaction_db::NoTransactionDB::collect_value_log_garbage]
+//! once no sstable points to it any more.
+//!
+//! [Options::value_log_threshold]: crate::db::options::Options::value_log_threshold
+
+use crate::db::Value;
+use crate::error::KVLiteError;
+use crate::ioutils::sync_dir;
+use crate::sstable::{TableID, TEMP_FILE_SUFFIX};
+use crate::Result;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Directory of the value log files under the database path.
+pub const VALUE_LOG_DIR: &str = "vlog";
+
+const INLINE_TAG: u8 = 0;
+const POINTER_TAG: u8 = 1;
+const POINTER_LENGTH: usize = 1 + 8 + 8 + 4;
+
+/// Directory of the value log files of the database at `db_path`.
+pub fn value_log_dir(db_path: &str) -> String {
+    format!("{}/{}", db_path, VALUE_LOG_DIR)
+}
+
+/// Path of a value log file, which is named by the id of the table written with it.
+pub fn value_log_file(dir: &str, file_id: TableID) -> String {
+    format!("{}/{}", dir, file_id)
+}
+
+/// Position of a value in the value log.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct ValuePointer {
+    pub(crate) file_id: TableID,
+    pub(crate) offset: u64,
+    pub(crate) length: u32,
+}
+
+/// A value as stored in an sstable whose values are separated.
+pub(crate) enum StoredValue<'a> {
+    Tombstone,
+    Inline(&'a [u8]),
+    Pointer(ValuePointer),
+}
+
+impl<'a> StoredValue<'a> {
+    pub(crate) fn decode(stored: &'a [u8]) -> Result<StoredValue<'a>> {
+        match stored.first() {
+            None => Ok(StoredValue::Tombstone),
+            Some(&INLINE_TAG) => Ok(StoredValue::Inline(&stored[1..])),
+            Some(&POINTER_TAG) if stored.len() == POINTER_LENGTH => {
+                Ok(StoredValue::Pointer(ValuePointer {
+                    file_id: u64::from_le_bytes(stored[1..9].try_into().unwrap()),
+                    offset: u64::from_le_bytes(stored[9..17].try_into().unwrap()),
+                    length: u32::from_le_bytes(stored[17..21].try_into().unwrap()),
+                }))
+            }
+            Some(tag) => Err(KVLiteError::Corruption(format!(
+                "invalid separated value with tag {} and length {}",
+                tag,
+                stored.len()
+            ))),
+        }
+    }
+}
+
+/// Store `value` inline in an sstable whose values are separated.
+pub(crate) fn encode_inline(value: Value) -> Value {
+    if value.is_empty() {
+        return value;
+    }
+    let mut stored = Vec::with_capacity(value.len() + 1);
+    stored.push(INLINE_TAG);
+    stored.extend_from_slice(&value);
+    stored
+}
+
+fn encode_pointer(pointer: ValuePointer) -> Value {
+    let mut stored = Vec::with_capacity(POINTER_LENGTH);
+    stored.push(POINTER_TAG);
+    stored.extend_from_slice(&pointer.file_id.to_le_bytes());
+    stored.extend_from_slice(&pointer.offset.to_le_bytes());
+    stored.extend_from_slice(&pointer.length.to_le_bytes());
+    stored
+}
+
+/// Get the value which `stored` stands for, reading the value log in `dir` if needed.
+pub(crate) fn load_value(dir: &str, stored: Value) -> Result<Value> {
+    let pointer = match StoredValue::decode(&stored)? {
+        StoredValue::Tombstone => return Ok(stored),
+        StoredValue::Inline(_) => {
+            let mut stored = stored;
+            stored.remove(0);
+            return Ok(stored);
+        }
+        StoredValue::Pointer(pointer) => pointer,
+    };
+    let mut file = File::open(value_log_file(dir, pointer.file_id))?;
+    file.seek(SeekFrom::Start(pointer.offset))?;
+    let mut value = vec![0; pointer.length as usize];
+    file.read_exact(&mut value)?;
+    Ok(value)
+}
+
+/// Writer of the value log file of one sstable. The file is created on the first large
+/// value under a temporary name, and renamed together with the sstable.
+pub(crate) struct ValueLogWriter {
+    dir: String,
+    file_id: TableID,
+    threshold: usize,
+    file: Option<BufWriter<File>>,
+    offset: u64,
+}
+
+impl ValueLogWriter {
+    pub(crate) fn new(dir: String, file_id: TableID, threshold: usize) -> ValueLogWriter {
+        ValueLogWriter {
+            dir,
+            file_id,
+            threshold,
+            file: None,
+            offset: 0,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    /// Bytes appended to the value log file.
+    #[inline]
+    pub(crate) fn bytes_written(&self) -> u64 {
+        self.offset
+    }
+
+    fn temp_file(&self) -> String {
+        format!(
+            "{}{}",
+            value_log_file(&self.dir, self.file_id),
+            TEMP_FILE_SUFFIX
+        )
+    }
+
+    /// Encode `value` to be stored in the sstable, appending it to the value log file
+    /// if it is longer than the threshold.
+    pub(crate) fn store(&mut self, value: Value) -> Result<Value> {
+        if value.len() <= self.threshold {
+            return Ok(encode_inline(value));
+        }
+        if self.file.is_none() {
+            std::fs::create_dir_all(&self.dir)?;
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.temp_file())?;
+            self.file = Some(BufWriter::new(file));
+        }
+        self.file.as_mut().unwrap().write_all(&value)?;
+        let pointer = ValuePointer {
+            file_id: self.file_id,
+            offset: self.offset,
+            length: value.len() as u32,
+        };
+        self.offset += value.len() as u64;
+        Ok(encode_pointer(pointer))
+    }
+
+    /// Sync the value log file, before the sstable pointing to it is synced.
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+            file.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Rename the value log file from its temporary name, if it has been created.
+    pub(crate) fn rename(&self) -> Result<()> {
+        if self.file.is_some() {
+            std::fs::rename(self.temp_file(), value_log_file(&self.dir, self.file_id))?;
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the value log file of an sstable that will not be used.
+    pub(crate) fn discard(self) {
+        if self.file.is_some() {
+            let temp_file = self.temp_file();
+            drop(self);
+            if let Err(e) = std::fs::remove_file(&temp_file) {
+                warn!("failed to remove {}: {:?}", temp_file, e);
+            }
+        }
+    }
+}
+
+/// Ids of the value log files in `dir`, skipping the files being written.
+pub(crate) fn list_value_logs(dir: &Path) -> Result<Vec<TableID>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut file_ids = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        if let Some(file_id) = entry?.file_name().to_str().and_then(|n| n.parse().ok()) {
+            file_ids.push(file_id);
+        }
+    }
+    Ok(file_ids)
+}
@@ -0,0 +1,145 @@
+use crate::db::key_types::{InternalKey, MemKey};
+use crate::db::MAX_LEVEL;
+use crate::memory::MemTable;
+use crate::sstable::manager::level_0::Level0Manager;
+use crate::sstable::manager::level_n::LevelNManager;
+use crate::sstable::table_handle::TableReadHandle;
+use crate::sstable::table_reader::TableReader;
+use crate::sstable::TableID;
+use crate::wal::WAL;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+/// Result of [crate::db::DB::verify].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VerifyReport {
+    /// Count of the sstables read.
+    pub tables_checked: usize,
+    /// Count of the key-value pairs read, tombstones included.
+    pub kvs_checked: u64,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl VerifyReport {
+    /// Whether no anomaly is found.
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.anomalies.is_empty()
+    }
+}
+
+/// Something wrong found in an sstable, or between two sstables of a level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub level: usize,
+    pub table_id: TableID,
+    /// Offset of the data block in which the anomaly is found, if it is in a data block.
+    pub block_offset: Option<u32>,
+    pub message: String,
+}
+
+/// Read every live sstable from the beginning to the end, and check the key order
+/// between the sstables of each level other than level 0.
+///
+/// Only one anomaly is reported for each sstable, because nothing after a corrupted
+/// block can be trusted.
+pub(crate) fn verify<SK, UK, M, L>(
+    level0_manager: &Arc<Level0Manager<SK, UK, M, L>>,
+    leveln_manager: &Arc<LevelNManager>,
+) -> VerifyReport
+where
+    SK: 'static + MemKey,
+    UK: 'static + MemKey,
+    M: 'static + MemTable<SK, UK>,
+    L: 'static + WAL<SK, UK>,
+{
+    let max_key_length = leveln_manager.options().max_key_bytes;
+    let mut report = VerifyReport::default();
+
+    let level0_tables: Vec<Arc<TableReadHandle>> = {
+        let guard = level0_manager.get_level0_tables_lock().read().unwrap();
+        guard.values().cloned().collect()
+    };
+    for table in &level0_tables {
+        verify_table(table, max_key_length, &mut report);
+    }
+
+    for level in 1..=MAX_LEVEL {
+        let tables: Vec<Arc<TableReadHandle>> = {
+            let lock =
+                leveln_manager.get_level_tables_lock(unsafe { NonZeroUsize::new_unchecked(level) });
+            let guard = lock.read().unwrap();
+            guard.values().cloned().collect()
+        };
+        for table in &tables {
+            verify_table(table, max_key_length, &mut report);
+        }
+        // sorted by max key, so each table should start after the previous one ends
+        for pair in tables.windows(2) {
+            if pair[0].max_key() >= pair[1].min_key() {
+                report.anomalies.push(Anomaly {
+                    level,
+                    table_id: pair[1].table_id(),
+                    block_offset: None,
+                    message: format!("overlaps with table {}", pair[0].table_id()),
+                });
+            }
+        }
+    }
+    report
+}
+
+fn verify_table(table: &TableReadHandle, max_key_length: usize, report: &mut VerifyReport) {
+    report.tables_checked += 1;
+    let anomaly = |block_offset: Option<u32>, message: String| Anomaly {
+        level: table.level(),
+        table_id: table.table_id(),
+        block_offset,
+        message,
+    };
+
+    let mut reader = match TableReader::new(
+        table.create_buf_reader_with_pos(),
+        table.file_size(),
+        max_key_length,
+    ) {
+        Ok(reader) => reader,
+        Err(e) => {
+            report.anomalies.push(anomaly(None, e.to_string()));
+            return;
+        }
+    };
+
+    let mut first_key: Option<InternalKey> = None;
+    let mut last_key: Option<InternalKey> = None;
+    while let Some(kv) = reader.next() {
+        match kv {
+            Ok((key, _)) => {
+                report.kvs_checked += 1;
+                if first_key.is_none() {
+                    first_key = Some(key.clone());
+                }
+                last_key = Some(key);
+            }
+            Err(e) => {
+                report
+                    .anomalies
+                    .push(anomaly(reader.block_offset(), e.to_string()));
+                return;
+            }
+        }
+    }
+
+    if first_key.as_ref() != Some(table.min_key()) || last_key.as_ref() != Some(table.max_key()) {
+        report.anomalies.push(anomaly(
+            None,
+            format!(
+                "keys range from {:?} to {:?}, but the index records {:?} to {:?}",
+                first_key,
+                last_key,
+                table.min_key(),
+                table.max_key()
+            ),
+        ));
+    }
+}
@@ -1,100 +1,152 @@
+//! Each record of the log is
+//!
 //! ```text
-//! +-------------------+
-//! | START_TRANSACTION | u64
-//! +-------------------+
-//! | LSN1              | u64
-//! +-------------------+
-//! | key1 length       | u64
-//! +-------------------+
-//! | value1 length     | u64
-//! +-------------------+
-//! | key1              | variant length
-//! +-------------------+
-//! | value1            | variant length
-//! +-------------------+
-//! | key2 length       |
-//! +-------------------+
-//! | value2 length     |
-//! +-------------------+
-//! | key2              |
-//! +-------------------+
-//! | value2            |
-//! +-------------------+
-//! | ...               |
-//! +-------------------+
-//! | END_TRANSACTION   | u64
-//! +-------------------+
-//! | LSN2              | transaction with single command can emit `START_TRANSACTION` and
-//! +-------------------+ `END_TRANSACTION`
-//! | key3 length       |
-//! +-------------------+
-//! | value3 length     |
-//! +-------------------+
-//! | key3              |
-//! +-------------------+
-//! | value3            |
-//! +-------------------+
+//! +----------+------+----------------+---------+
+//! | checksum | type | payload length | payload |
+//! +----------+------+----------------+---------+
+//!    u32       u8          u32
 //! ```
+//!
+//! `checksum` is the murmur hash of `type`, `payload length` and `payload`. The
+//! payload of each type of [WALRecord] is
+//!
+//! ```text
+//! Put:               | LSN u64 | key length u32 | key | value |
+//! Delete:            | LSN u64 | key |
+//! BeginTransaction:  | LSN u64 |
+//! CommitTransaction: | LSN u64 |
+//! Batch:             | LSN u64 | record 1 | record 2 | ... | record n |
+//! ```
+//!
+//! A `Batch` holds the `Put` and `Delete` records of a write batch, each encoded as a
+//! whole record, and its LSN is the largest one of them. The batch is recovered as a
+//! whole with its checksum, so that a write batch is applied atomically.
+//!
+//! The records between the `BeginTransaction` and the `CommitTransaction` of the same
+//! LSN are recovered only if the commit is found, so that a transaction is applied
+//! atomically. The records of different transactions may interleave, and a transaction
+//! open when the mutable log is frozen begins again in the new one. A truncated record,
+//! or a checksum mismatch of the last record, is taken as the torn tail of a crash, and
+//! the log is truncated to the end of the last complete record when it is loaded. A
+//! checksum mismatch of a record followed by others is reported as
+//! [KVLiteError::Corruption] instead.
 use crate::db::key_types::{InternalKey, LSNKey, MemKey, LSN};
 use crate::db::options::WriteOptions;
 use crate::db::Value;
 use crate::error::KVLiteError;
-use crate::ioutils::{read_bytes_exact, read_u64, BufReaderWithPos};
+use crate::hash::murmur_hash;
+use crate::ioutils::BufReaderWithPos;
 use crate::memory::MemTable;
-use crate::wal::{TransactionWAL, WALInner, WAL};
+use crate::wal::{ReplayDigest, TransactionWAL, WALInner, WALRecord, WAL};
 use crate::Result;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const PUT: u8 = 1;
+const DELETE: u8 = 2;
+const BEGIN_TRANSACTION: u8 = 3;
+const COMMIT_TRANSACTION: u8 = 4;
+const BATCH: u8 = 5;
 
-const START_TRANSACTION: u64 = u64::MAX;
-const END_TRANSACTION: u64 = u64::MIN;
+/// Length of `checksum`, `type` and `payload length`.
+const RECORD_HEADER_SIZE: usize = 9;
+const CHECKSUM_SEED: u32 = 0xbc9f1d34;
 
 pub struct LSNWriteAheadLog {
     inner: WALInner,
+    /// The min and max LSN of the records in `log0` and `log1`, `None` if the log is empty.
+    lsn_ranges: [Option<(LSN, LSN)>; 2],
+    /// The transactions which have begun but not committed yet. They begin again in the
+    /// new `log1` when it is frozen, so that their records in it are never recovered
+    /// without the commit, even if `log0` is cleared.
+    open_transactions: BTreeSet<LSN>,
+}
+
+/// A record read back from the log.
+enum LoggedRecord {
+    Put {
+        lsn: LSN,
+        key: InternalKey,
+        value: Value,
+    },
+    Delete {
+        lsn: LSN,
+        key: InternalKey,
+    },
+    BeginTransaction(LSN),
+    CommitTransaction(LSN),
+    Batch {
+        lsn: LSN,
+        records: Vec<LoggedRecord>,
+    },
+}
+
+impl LoggedRecord {
+    fn lsn(&self) -> LSN {
+        match self {
+            LoggedRecord::Put { lsn, .. }
+            | LoggedRecord::Delete { lsn, .. }
+            | LoggedRecord::BeginTransaction(lsn)
+            | LoggedRecord::CommitTransaction(lsn)
+            | LoggedRecord::Batch { lsn, .. } => *lsn,
+        }
+    }
+}
+
+/// Warn about the transactions left in `pending` after loading the logs, which are
+/// never recovered.
+fn discard_pending(pending: HashMap<LSN, Vec<LoggedRecord>>) {
+    for lsn in pending.keys() {
+        warn!("discard transaction {} without commit", lsn);
+    }
 }
 
 impl<UK: MemKey> WAL<LSNKey<UK>, UK> for LSNWriteAheadLog {
-    fn open_and_load_logs(
+    fn open_and_replay_logs(
         db_path: &str,
         mut_mem_table: &mut impl MemTable<LSNKey<UK>, UK>,
+        mut digest: Option<&mut ReplayDigest<LSNKey<UK>>>,
     ) -> Result<Self> {
-        let wal = LSNWriteAheadLog {
+        let mut wal = LSNWriteAheadLog {
             inner: WALInner::open_logs(db_path)?,
+            lsn_ranges: [None, None],
+            open_transactions: BTreeSet::new(),
         };
-        Self::load_log(wal.inner.log1.get_ref(), mut_mem_table).unwrap();
-        Self::load_log(wal.inner.log0.get_ref(), mut_mem_table).unwrap();
+        // a transaction may begin in the immutable log and commit in the mutable one
+        let mut pending = HashMap::new();
+        Self::load_records(
+            wal.inner.log0.get_ref(),
+            mut_mem_table,
+            &mut pending,
+            digest.as_deref_mut(),
+        )?;
+        Self::load_records(
+            wal.inner.log1.get_ref(),
+            mut_mem_table,
+            &mut pending,
+            digest,
+        )?;
+        discard_pending(pending);
+        // The recovered records are older than all the new ones, whose LSNs are
+        // allocated after the persisted upper bound.
+        for (range, log) in wal
+            .lsn_ranges
+            .iter_mut()
+            .zip([&wal.inner.log0, &wal.inner.log1])
+        {
+            if log.get_ref().metadata()?.len() > 0 {
+                *range = Some((LSN::MIN, LSN::MIN));
+            }
+        }
         Ok(wal)
     }
 
     fn load_log(file: &File, mem_table: &mut impl MemTable<LSNKey<UK>, UK>) -> Result<()> {
-        let mut reader = BufReaderWithPos::new(file)?;
-        reader.seek(SeekFrom::Start(0))?;
-        while let Ok(lsn) = read_u64(&mut reader) {
-            match lsn {
-                START_TRANSACTION => {
-                    let lsn = read_u64(&mut reader)?;
-                    if lsn != START_TRANSACTION && lsn != END_TRANSACTION {
-                        Self::load_kvs_in_lsn(lsn, &mut reader, mem_table)?;
-                    } else {
-                        return Err(KVLiteError::Custom(String::from("invalid log")));
-                    }
-                }
-                END_TRANSACTION => return Err(KVLiteError::Custom(String::from("invalid log"))),
-                lsn => {
-                    let key_length = read_u64(&mut reader)?;
-                    let value_length = read_u64(&mut reader)?;
-                    let key: InternalKey = read_bytes_exact(&mut reader, key_length)?;
-                    let lsn_key = LSNKey::new(UK::from(key), lsn);
-                    if value_length > 0 {
-                        let value = read_bytes_exact(&mut reader, value_length)?;
-                        mem_table.set(lsn_key, value)?;
-                    } else {
-                        mem_table.remove(lsn_key)?;
-                    }
-                }
-            }
-        }
-        reader.seek(SeekFrom::End(0))?;
+        let mut pending = HashMap::new();
+        Self::load_records(file, mem_table, &mut pending, None)?;
+        discard_pending(pending);
         Ok(())
     }
 
@@ -104,74 +156,549 @@ impl<UK: MemKey> WAL<LSNKey<UK>, UK> for LSNWriteAheadLog {
         key: &LSNKey<UK>,
         value: Option<&Value>,
     ) -> Result<()> {
-        let internal_key = key.internal_key();
-        let key_length: [u8; 4] = (internal_key.len() as u32).to_le_bytes();
-        self.inner.log1.write_all(&key_length)?;
-        match value {
-            Some(v) => {
-                let value_length = (v.len() as u32).to_le_bytes();
-                self.inner.log1.write_all(&value_length)?;
-                self.inner.log1.write_all(internal_key)?;
-                self.inner.log1.write_all(v)?;
-            }
-            None => {
-                self.inner.log1.write_all(&0u32.to_le_bytes())?;
-                self.inner.log1.write_all(internal_key)?;
-            }
-        }
-        self.inner.log1.flush()?;
-        if write_options.sync {
-            self.inner.log1.get_mut().sync_data()?;
+        let record = match value {
+            Some(value) => WALRecord::Put { key, value },
+            None => WALRecord::Delete { key },
+        };
+        self.append_record(write_options, record)
+    }
+
+    fn append_batch<'a>(
+        &mut self,
+        write_options: &WriteOptions,
+        kvs: impl Iterator<Item = (&'a LSNKey<UK>, Option<&'a Value>)>,
+    ) -> Result<()>
+    where
+        LSNKey<UK>: 'a,
+    {
+        let mut body = Vec::new();
+        let mut lsn_range: Option<(LSN, LSN)> = None;
+        for (key, value) in kvs {
+            let record = match value {
+                Some(value) => WALRecord::Put { key, value },
+                None => WALRecord::Delete { key },
+            };
+            let (bytes, lsn) = encode_record(&record);
+            body.extend_from_slice(&bytes);
+            lsn_range = Some(match lsn_range {
+                Some((min, max)) => (min.min(lsn), max.max(lsn)),
+                None => (lsn, lsn),
+            });
         }
-        Ok(())
+        let (min_lsn, max_lsn) = match lsn_range {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        let bytes = frame_record(BATCH, max_lsn, &body);
+        self.write_bytes(write_options, &bytes, min_lsn, max_lsn)
     }
 
     fn clear_imm_log(&mut self) -> Result<()> {
-        self.inner.clear_imm_log()
+        self.inner.clear_imm_log()?;
+        self.lsn_ranges[0] = None;
+        Ok(())
     }
 
     fn freeze_mut_log(&mut self) -> Result<()> {
-        self.inner.freeze_mut_log()
+        self.inner.freeze_mut_log()?;
+        self.lsn_ranges[0] = self.lsn_ranges[1].take();
+        let no_sync = WriteOptions { sync: false };
+        for lsn in std::mem::take(&mut self.open_transactions) {
+            TransactionWAL::<LSNKey<InternalKey>, InternalKey>::append_record(
+                self,
+                &no_sync,
+                WALRecord::BeginTransaction { lsn },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync_mut_log()
+    }
+
+    fn synced_len(&self) -> u64 {
+        self.inner.synced_len
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.inner.bytes_written
     }
 }
 
 impl<UK: MemKey> TransactionWAL<LSNKey<UK>, UK> for LSNWriteAheadLog {
-    fn start_transaction(&mut self) -> Result<()> {
-        let bytes = START_TRANSACTION.to_le_bytes();
-        self.inner.log1.write_all(&bytes)?;
-        Ok(())
+    fn append_record(
+        &mut self,
+        write_options: &WriteOptions,
+        record: WALRecord<'_, LSNKey<UK>>,
+    ) -> Result<()> {
+        let (bytes, lsn) = encode_record(&record);
+        match record {
+            WALRecord::BeginTransaction { lsn } => {
+                self.open_transactions.insert(lsn);
+            }
+            WALRecord::CommitTransaction { lsn } => {
+                self.open_transactions.remove(&lsn);
+            }
+            WALRecord::Put { .. } | WALRecord::Delete { .. } => {}
+        }
+        self.write_bytes(write_options, &bytes, lsn, lsn)
     }
 
-    fn end_transaction(&mut self) -> Result<()> {
-        let bytes = END_TRANSACTION.to_le_bytes();
-        self.inner.log1.write_all(&bytes)?;
-        Ok(())
+    fn min_lsn(&self) -> Option<LSN> {
+        self.lsn_ranges.iter().flatten().map(|(min, _)| *min).min()
+    }
+
+    fn trim_before(&mut self, lsn: LSN) -> Result<bool> {
+        match self.lsn_ranges[0] {
+            // recovered from the logs, see open_and_replay_logs
+            Some((LSN::MIN, LSN::MIN)) => Ok(false),
+            Some((_, max)) if max < lsn => {
+                WAL::<LSNKey<UK>, UK>::clear_imm_log(self)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
     }
 }
 
 impl LSNWriteAheadLog {
-    fn load_kvs_in_lsn<UK: MemKey>(
-        lsn: LSN,
-        reader: &mut BufReaderWithPos<&File>,
+    /// Append the encoded records `bytes`, whose LSNs are in `[min_lsn, max_lsn]`, to `log1`.
+    fn write_bytes(
+        &mut self,
+        write_options: &WriteOptions,
+        bytes: &[u8],
+        min_lsn: LSN,
+        max_lsn: LSN,
+    ) -> Result<()> {
+        self.inner.log1.write_all(bytes)?;
+        self.inner.log1.flush()?;
+        self.inner.unsynced = true;
+        self.lsn_ranges[1] = Some(match self.lsn_ranges[1] {
+            Some((min, max)) => (min.min(min_lsn), max.max(max_lsn)),
+            None => (min_lsn, max_lsn),
+        });
+        self.inner.bytes_written += bytes.len() as u64;
+        if write_options.sync {
+            self.inner.sync_mut_log()?;
+        }
+        Ok(())
+    }
+
+    /// Apply the records of `file` to `mem_table`, and record them in `digest`. The
+    /// records of the transactions which have not committed yet are kept in `pending`
+    /// by their LSNs.
+    fn load_records<UK: MemKey>(
+        file: &File,
         mem_table: &mut impl MemTable<LSNKey<UK>, UK>,
+        pending: &mut HashMap<LSN, Vec<LoggedRecord>>,
+        mut digest: Option<&mut ReplayDigest<LSNKey<UK>>>,
     ) -> Result<()> {
-        while let Ok(key_length) = read_u64(reader) {
-            match key_length {
-                END_TRANSACTION => return Ok(()),
-                START_TRANSACTION => return Err(KVLiteError::Custom(String::from("invalid log"))),
-                key_length => {
-                    let value_length = read_u64(reader)?;
-                    let key: InternalKey = read_bytes_exact(reader, key_length)?;
-                    let lsn_key = LSNKey::new(UK::from(key), lsn);
-                    if value_length > 0 {
-                        let value = read_bytes_exact(reader, value_length)?;
-                        mem_table.set(lsn_key, value)?;
-                    } else {
-                        mem_table.remove(lsn_key)?;
+        let mut reader = BufReaderWithPos::new(file)?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        // length of the log up to the end of the last complete record
+        let mut read_len = 0;
+        while let Some((record, record_len)) = read_record(&mut reader) {
+            read_len += record_len;
+            match record {
+                // a transaction still open when the log was frozen begins again
+                LoggedRecord::BeginTransaction(lsn) => {
+                    pending.entry(lsn).or_default();
+                }
+                LoggedRecord::CommitTransaction(lsn) => match pending.remove(&lsn) {
+                    Some(records) => {
+                        for record in records {
+                            Self::apply(record, mem_table, digest.as_deref_mut())?;
+                        }
+                    }
+                    None => {
+                        return Err(KVLiteError::Corruption(format!(
+                            "commit of transaction {} without begin",
+                            lsn
+                        )))
                     }
+                },
+                LoggedRecord::Batch { records, .. } => {
+                    for record in records {
+                        Self::apply_or_defer(record, mem_table, pending, digest.as_deref_mut())?;
+                    }
+                }
+                record => Self::apply_or_defer(record, mem_table, pending, digest.as_deref_mut())?,
+            }
+        }
+
+        // drop the torn tail, or else the records appended later are never recovered
+        let file_len = file.metadata()?.len();
+        if read_len < file_len {
+            if !is_torn_tail(file, read_len, file_len)? {
+                return Err(KVLiteError::Corruption(format!(
+                    "corrupted log record at offset {} of {} bytes",
+                    read_len, file_len
+                )));
+            }
+            warn!("truncate log from {} bytes to {} bytes", file_len, read_len);
+            file.set_len(read_len)?;
+        }
+        reader.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Apply `record` to `mem_table`, or keep it in `pending` if its transaction has not
+    /// committed yet.
+    fn apply_or_defer<UK: MemKey>(
+        record: LoggedRecord,
+        mem_table: &mut impl MemTable<LSNKey<UK>, UK>,
+        pending: &mut HashMap<LSN, Vec<LoggedRecord>>,
+        digest: Option<&mut ReplayDigest<LSNKey<UK>>>,
+    ) -> Result<()> {
+        match pending.get_mut(&record.lsn()) {
+            Some(records) => {
+                records.push(record);
+                Ok(())
+            }
+            None => Self::apply(record, mem_table, digest),
+        }
+    }
+
+    fn apply<UK: MemKey>(
+        record: LoggedRecord,
+        mem_table: &mut impl MemTable<LSNKey<UK>, UK>,
+        digest: Option<&mut ReplayDigest<LSNKey<UK>>>,
+    ) -> Result<()> {
+        match record {
+            LoggedRecord::Put { lsn, key, value } if !value.is_empty() => {
+                let key = LSNKey::new(UK::from(key), lsn);
+                if let Some(digest) = digest {
+                    digest.record(&key, Some(&value));
                 }
+                mem_table.set(key, value)
             }
+            LoggedRecord::Put { lsn, key, .. } | LoggedRecord::Delete { lsn, key } => {
+                let key = LSNKey::new(UK::from(key), lsn);
+                if let Some(digest) = digest {
+                    digest.record(&key, None);
+                }
+                mem_table.remove(key)
+            }
+            LoggedRecord::BeginTransaction(_)
+            | LoggedRecord::CommitTransaction(_)
+            | LoggedRecord::Batch { .. } => Ok(()),
+        }
+    }
+}
+
+/// Encode `record` in the format described in the module document, and return it with
+/// the LSN of the record.
+fn encode_record<UK: MemKey>(record: &WALRecord<'_, LSNKey<UK>>) -> (Vec<u8>, LSN) {
+    let (record_type, lsn) = match record {
+        WALRecord::Put { key, .. } => (PUT, key.lsn()),
+        WALRecord::Delete { key } => (DELETE, key.lsn()),
+        WALRecord::BeginTransaction { lsn } => (BEGIN_TRANSACTION, *lsn),
+        WALRecord::CommitTransaction { lsn } => (COMMIT_TRANSACTION, *lsn),
+    };
+    let mut body = Vec::new();
+    match record {
+        WALRecord::Put { key, value } => {
+            let internal_key = key.internal_key();
+            body.extend_from_slice(&(internal_key.len() as u32).to_le_bytes());
+            body.extend_from_slice(internal_key);
+            body.extend_from_slice(value);
+        }
+        WALRecord::Delete { key } => body.extend_from_slice(key.internal_key()),
+        WALRecord::BeginTransaction { .. } | WALRecord::CommitTransaction { .. } => {}
+    }
+    (frame_record(record_type, lsn, &body), lsn)
+}
+
+/// Frame the payload of `lsn` and `body` with the header of a record of `record_type`.
+fn frame_record(record_type: u8, lsn: LSN, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0u8; RECORD_HEADER_SIZE];
+    bytes[4] = record_type;
+    bytes.extend_from_slice(&lsn.to_le_bytes());
+    bytes.extend_from_slice(body);
+    let payload_length = (bytes.len() - RECORD_HEADER_SIZE) as u32;
+    bytes[5..RECORD_HEADER_SIZE].copy_from_slice(&payload_length.to_le_bytes());
+    let checksum = murmur_hash(&bytes[4..], CHECKSUM_SEED);
+    bytes[..4].copy_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+/// Whether the record at `offset` of `file`, which cannot be read, is the last one in
+/// the log, i.e. it is the torn tail of a crash rather than corruption in the middle.
+fn is_torn_tail(mut file: &File, offset: u64, file_len: u64) -> Result<bool> {
+    if offset + RECORD_HEADER_SIZE as u64 > file_len {
+        return Ok(true);
+    }
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut header)?;
+    let payload_length = u32::from_le_bytes(header[5..].try_into().unwrap()) as u64;
+    Ok(offset + RECORD_HEADER_SIZE as u64 + payload_length >= file_len)
+}
+
+/// Read the next record and its length in the log. Return `None` at the end of the log,
+/// or if the record is truncated or corrupted.
+fn read_record(reader: &mut impl Read) -> Option<(LoggedRecord, u64)> {
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    reader.read_exact(&mut header).ok()?;
+    let payload_length = u32::from_le_bytes(header[5..].try_into().unwrap()) as u64;
+    // not allocated up front, the length may be garbage
+    let mut payload = Vec::new();
+    reader
+        .by_ref()
+        .take(payload_length)
+        .read_to_end(&mut payload)
+        .ok()?;
+    if payload.len() as u64 != payload_length {
+        return None;
+    }
+
+    let checksum = u32::from_le_bytes(header[..4].try_into().unwrap());
+    if checksum != murmur_hash(&[&header[4..], &payload[..]].concat(), CHECKSUM_SEED)
+        || payload.len() < 8
+    {
+        warn!("corrupted log record");
+        return None;
+    }
+    let lsn = u64::from_le_bytes(payload[..8].try_into().unwrap());
+    let body = &payload[8..];
+    let record = match header[4] {
+        PUT => {
+            let key_length = u32::from_le_bytes(body.get(..4)?.try_into().unwrap()) as usize;
+            let key = body.get(4..4 + key_length)?.to_vec();
+            let value = body[4 + key_length..].to_vec();
+            LoggedRecord::Put { lsn, key, value }
         }
-        Err(KVLiteError::Custom(String::from("invalid log")))
+        DELETE => LoggedRecord::Delete {
+            lsn,
+            key: body.to_vec(),
+        },
+        BEGIN_TRANSACTION => LoggedRecord::BeginTransaction(lsn),
+        COMMIT_TRANSACTION => LoggedRecord::CommitTransaction(lsn),
+        BATCH => {
+            let mut records = Vec::new();
+            let mut body = body;
+            while !body.is_empty() {
+                let (record, _) = read_record(&mut body)?;
+                records.push(record);
+            }
+            LoggedRecord::Batch { lsn, records }
+        }
+        _ => {
+            warn!("unknown type of log record {}", header[4]);
+            return None;
+        }
+    };
+    Some((record, (RECORD_HEADER_SIZE + payload.len()) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::key_types::{InternalKey, LSNKey};
+    use crate::db::options::WriteOptions;
+    use crate::error::KVLiteError;
+    use crate::memory::{InternalKeyValueIterator, MutexSkipMapMemTable};
+    use crate::wal::lsn_wal::{LSNWriteAheadLog, RECORD_HEADER_SIZE};
+    use crate::wal::{TransactionWAL, WALRecord, WAL};
+    use std::fs::OpenOptions;
+
+    type MemTable = MutexSkipMapMemTable<LSNKey<InternalKey>>;
+
+    fn load(path: &str) -> (LSNWriteAheadLog, Vec<InternalKey>) {
+        let mut mem_table = MemTable::default();
+        let wal = LSNWriteAheadLog::open_and_load_logs(path, &mut mem_table).unwrap();
+        let keys = mem_table.kv_iter().map(|(k, _)| k.clone()).collect();
+        (wal, keys)
+    }
+
+    fn put(wal: &mut LSNWriteAheadLog, key: &str, lsn: u64) {
+        let key = LSNKey::new(Vec::from(key), lsn);
+        let value = Vec::from("value");
+        TransactionWAL::<LSNKey<InternalKey>, InternalKey>::append_record(
+            wal,
+            &WriteOptions { sync: false },
+            WALRecord::Put {
+                key: &key,
+                value: &value,
+            },
+        )
+        .unwrap();
+    }
+
+    fn transaction(wal: &mut LSNWriteAheadLog, keys: &[&str], lsn: u64) {
+        TransactionWAL::<LSNKey<InternalKey>, InternalKey>::start_transaction(wal, lsn).unwrap();
+        for key in keys {
+            put(wal, key, lsn);
+        }
+        TransactionWAL::<LSNKey<InternalKey>, InternalKey>::end_transaction(
+            wal,
+            &WriteOptions { sync: true },
+            lsn,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_recover_committed_transactions() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("lsn_wal")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let log = temp_dir.path().join("log").join("1.log");
+
+        let (mut wal, keys) = load(path);
+        assert!(keys.is_empty());
+        put(&mut wal, "k1", 1);
+        transaction(&mut wal, &["k2", "k3"], 2);
+        transaction(&mut wal, &["k4", "k5"], 3);
+        drop(wal);
+        let (wal, keys) = load(path);
+        assert_eq!(keys.len(), 5);
+        drop(wal);
+
+        // crash before the commit of the last transaction
+        let commit_len = (RECORD_HEADER_SIZE + 8) as u64;
+        let file = OpenOptions::new().write(true).open(&log).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - commit_len).unwrap();
+        drop(file);
+        let (mut wal, keys) = load(path);
+        let expected: Vec<InternalKey> = ["k1", "k2", "k3"].iter().map(|k| Vec::from(*k)).collect();
+        assert_eq!(keys, expected);
+
+        // the incomplete transaction is never recovered, and does not swallow the
+        // records appended after reopening
+        put(&mut wal, "k6", 4);
+        drop(wal);
+        let (mut wal, keys) = load(path);
+        assert_eq!(keys.len(), 4);
+        assert_eq!(keys[3], Vec::from("k6"));
+
+        // a torn record at the tail
+        transaction(&mut wal, &["k7"], 5);
+        drop(wal);
+        let file = OpenOptions::new().write(true).open(&log).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+        let (_, keys) = load(path);
+        assert_eq!(keys.len(), 4);
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("lsn_wal")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let log = temp_dir.path().join("log").join("1.log");
+
+        let (mut wal, _) = load(path);
+        put(&mut wal, "k1", 1);
+        put(&mut wal, "k2", 2);
+        drop(wal);
+
+        // flip the last byte of the value of k2
+        let mut bytes = std::fs::read(&log).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&log, bytes).unwrap();
+        let (_, keys) = load(path);
+        assert_eq!(keys, vec![Vec::from("k1")]);
+    }
+
+    #[test]
+    fn test_corruption_in_middle() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("lsn_wal")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let log = temp_dir.path().join("log").join("1.log");
+
+        let (mut wal, _) = load(path);
+        for (i, key) in ["k1", "k2", "k3"].iter().enumerate() {
+            put(&mut wal, key, i as u64 + 1);
+        }
+        drop(wal);
+
+        // flip the last byte of the value of k2, which is followed by k3
+        let record_len = RECORD_HEADER_SIZE + 8 + 4 + "k1".len() + "value".len();
+        let mut bytes = std::fs::read(&log).unwrap();
+        let len = bytes.len();
+        bytes[2 * record_len - 1] ^= 0xff;
+        std::fs::write(&log, bytes).unwrap();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&log)
+            .unwrap();
+        let result = <LSNWriteAheadLog as WAL<LSNKey<InternalKey>, InternalKey>>::load_log(
+            &file,
+            &mut MemTable::default(),
+        );
+        assert!(matches!(result, Err(KVLiteError::Corruption(_))));
+        // nothing is truncated
+        assert_eq!(file.metadata().unwrap().len(), len as u64);
+        drop(file);
+
+        let result = LSNWriteAheadLog::open_and_load_logs(path, &mut MemTable::default());
+        assert!(matches!(result, Err(KVLiteError::Corruption(_))));
+    }
+
+    #[test]
+    fn test_recover_whole_batch() {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("lsn_wal")
+            .tempdir()
+            .unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let log = temp_dir.path().join("log").join("1.log");
+
+        let batch = |wal: &mut LSNWriteAheadLog, keys: &[&str], lsn: u64| {
+            let keys: Vec<_> = keys
+                .iter()
+                .map(|k| LSNKey::new(Vec::from(*k), lsn))
+                .collect();
+            let value = Vec::from("value");
+            WAL::<LSNKey<InternalKey>, InternalKey>::append_batch(
+                wal,
+                &WriteOptions { sync: true },
+                keys.iter().map(|k| (k, Some(&value))),
+            )
+            .unwrap();
+        };
+
+        let (mut wal, _) = load(path);
+        put(&mut wal, "k1", 1);
+        batch(&mut wal, &["k2", "k3"], 2);
+        drop(wal);
+        let (mut wal, keys) = load(path);
+        assert_eq!(keys.len(), 3);
+        assert_eq!(
+            TransactionWAL::<LSNKey<InternalKey>, InternalKey>::min_lsn(&wal),
+            Some(0)
+        );
+
+        // crash in the middle of the batch
+        batch(&mut wal, &["k4", "k5", "k6"], 3);
+        drop(wal);
+        let file = OpenOptions::new().write(true).open(&log).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 12).unwrap();
+        drop(file);
+        let (mut wal, keys) = load(path);
+        let expected: Vec<InternalKey> = ["k1", "k2", "k3"].iter().map(|k| Vec::from(*k)).collect();
+        assert_eq!(keys, expected);
+
+        // the torn batch does not swallow the records appended after reopening
+        put(&mut wal, "k7", 4);
+        drop(wal);
+        let (_, keys) = load(path);
+        assert_eq!(keys.len(), 4);
+        assert_eq!(keys[3], Vec::from("k7"));
     }
 }
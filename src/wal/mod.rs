@@ -1,20 +1,36 @@
-use crate::db::key_types::MemKey;
+use crate::db::key_types::{MemKey, LSN};
 use crate::db::options::WriteOptions;
 use crate::db::Value;
+use crate::error::KVLiteError;
+use crate::hash::murmur_hash;
 use crate::memory::MemTable;
 use crate::Result;
+use std::collections::BTreeMap;
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::BufWriter;
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 
 pub mod lsn_wal;
+pub mod null_wal;
 pub mod simple_wal;
 
 pub trait WAL<SK: MemKey, UK: MemKey>: Sized + Sync + Send {
     /// Open the logs at `db_path` and load to memory tables
-    fn open_and_load_logs(db_path: &str, mut_mem_table: &mut impl MemTable<SK, UK>)
-        -> Result<Self>;
+    fn open_and_load_logs(
+        db_path: &str,
+        mut_mem_table: &mut impl MemTable<SK, UK>,
+    ) -> Result<Self> {
+        Self::open_and_replay_logs(db_path, mut_mem_table, None)
+    }
+
+    /// Like [WAL::open_and_load_logs], and record each record applied to `mut_mem_table`
+    /// in `digest` if it is not `None`.
+    fn open_and_replay_logs(
+        db_path: &str,
+        mut_mem_table: &mut impl MemTable<SK, UK>,
+        digest: Option<&mut ReplayDigest<SK>>,
+    ) -> Result<Self>;
     fn load_log(file: &File, mem_table: &mut impl MemTable<SK, UK>) -> Result<()>;
 
     /// Append a key-value pair to `mut_log`
@@ -25,20 +41,152 @@ pub trait WAL<SK: MemKey, UK: MemKey>: Sized + Sync + Send {
         value: Option<&Value>,
     ) -> Result<()>;
 
+    /// Append the key-value pairs of a write batch to `mut_log` as a whole. Replaying the
+    /// log recovers either all of them or, if the log is torn in the middle of the batch,
+    /// none of them. `mut_log` is synced once if `write_options.sync` is true.
+    fn append_batch<'a>(
+        &mut self,
+        write_options: &WriteOptions,
+        kvs: impl Iterator<Item = (&'a SK, Option<&'a Value>)>,
+    ) -> Result<()>
+    where
+        SK: 'a;
+
     fn clear_imm_log(&mut self) -> Result<()>;
 
     fn freeze_mut_log(&mut self) -> Result<()>;
+
+    /// Sync the writes of `mut_log` that are not synced to disk yet.
+    fn sync(&mut self) -> Result<()>;
+
+    /// Length of `mut_log` that has been synced to disk.
+    fn synced_len(&self) -> u64;
+
+    /// Total bytes appended to the logs since they are opened.
+    fn bytes_written(&self) -> u64;
+}
+
+/// Final state of the keys replayed from the logs, to check the memory table they are
+/// replayed into, see [Options::verify_recovery].
+///
+/// It holds all the replayed keys until the check, so it costs about as much memory as
+/// the keys of the memory table.
+///
+/// [Options::verify_recovery]: crate::db::options::Options::verify_recovery
+pub struct ReplayDigest<SK> {
+    /// Hash of the last value replayed for each key. A tombstone is hashed as an
+    /// empty value.
+    entries: BTreeMap<SK, u32>,
+}
+
+const REPLAY_DIGEST_SEED: u32 = 0xbc9f1d34;
+
+impl<SK: MemKey> Default for ReplayDigest<SK> {
+    fn default() -> Self {
+        ReplayDigest {
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<SK: MemKey> ReplayDigest<SK> {
+    /// Record that `key` is set to `value`, or removed if `value` is `None`.
+    pub fn record(&mut self, key: &SK, value: Option<&Value>) {
+        let value_hash = murmur_hash(value.map_or(&[][..], |v| v), REPLAY_DIGEST_SEED);
+        self.entries.insert(key.clone(), value_hash);
+    }
+
+    /// Count of the replayed keys.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Checksum of the final values of the replayed keys, independent of their order.
+    pub fn checksum(&self) -> u32 {
+        self.entries.iter().fold(0, |sum, (key, value_hash)| {
+            sum.wrapping_add(murmur_hash(key.internal_key(), *value_hash))
+        })
+    }
+
+    /// Check that `mem_table` holds exactly the replayed keys with their final values,
+    /// by recomputing the count and the checksum from it.
+    pub fn verify<UK: MemKey>(&self, mem_table: &impl MemTable<SK, UK>) -> Result<()> {
+        let mut checksum = 0u32;
+        for key in self.entries.keys() {
+            let value = mem_table.get(key)?.unwrap_or_default();
+            let value_hash = murmur_hash(&value, REPLAY_DIGEST_SEED);
+            checksum = checksum.wrapping_add(murmur_hash(key.internal_key(), value_hash));
+        }
+        let expected = self.checksum();
+        if mem_table.len() != self.len() || checksum != expected {
+            return Err(KVLiteError::RecoveryMismatch(format!(
+                "replayed {} records with checksum {:#010x}, but the memory table has {} entries with checksum {:#010x}",
+                self.len(),
+                expected,
+                mem_table.len(),
+                checksum
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A record of a [TransactionWAL].
+pub enum WALRecord<'a, SK> {
+    /// Set `key` to `value`.
+    Put { key: &'a SK, value: &'a Value },
+    /// Insert a tombstone of `key`.
+    Delete { key: &'a SK },
+    /// The records after it belong to the transaction `lsn`, until its commit.
+    BeginTransaction { lsn: LSN },
+    /// The transaction `lsn` is committed. The records of a transaction are recovered
+    /// only if its commit is found in the log.
+    CommitTransaction { lsn: LSN },
 }
 
 pub trait TransactionWAL<SK: MemKey, UK: MemKey>: WAL<SK, UK> {
-    fn start_transaction(&mut self) -> Result<()>;
-    fn end_transaction(&mut self) -> Result<()>;
+    /// Append `record` to `mut_log`.
+    fn append_record(
+        &mut self,
+        write_options: &WriteOptions,
+        record: WALRecord<'_, SK>,
+    ) -> Result<()>;
+
+    fn start_transaction(&mut self, lsn: LSN) -> Result<()> {
+        let no_sync = WriteOptions { sync: false };
+        self.append_record(&no_sync, WALRecord::BeginTransaction { lsn })
+    }
+
+    /// Commit the transaction `lsn`, syncing `mut_log` if `write_options.sync` is true.
+    fn end_transaction(&mut self, write_options: &WriteOptions, lsn: LSN) -> Result<()> {
+        self.append_record(write_options, WALRecord::CommitTransaction { lsn })
+    }
+
+    /// The smallest LSN of the records in the logs, or `None` if the logs are empty.
+    /// The immutable log is cleared once it is written to sstables, so this is the
+    /// oldest LSN which has not been flushed yet.
+    fn min_lsn(&self) -> Option<LSN>;
+
+    /// Clear the immutable log if all its records are older than `lsn`, whose writes
+    /// must be durable in sstables. The mutable log, and the logs recovered when opening,
+    /// whose LSNs are not known, are never trimmed. Return whether the log is cleared.
+    fn trim_before(&mut self, lsn: LSN) -> Result<bool>;
 }
 
 struct WALInner {
     log_path: PathBuf,
     log0: BufWriter<File>,
     log1: BufWriter<File>,
+    /// Whether `log1` has writes that are not synced to disk.
+    unsynced: bool,
+    /// Length of `log1` when it was synced last time.
+    synced_len: u64,
+    /// Total bytes appended to the logs since they are opened.
+    bytes_written: u64,
 }
 
 impl WALInner {
@@ -69,6 +217,9 @@ impl WALInner {
             log_path,
             log0: BufWriter::new(log0),
             log1: BufWriter::new(log1),
+            unsynced: true,
+            synced_len: 0,
+            bytes_written: 0,
         })
     }
 
@@ -82,18 +233,37 @@ impl WALInner {
         std::mem::swap(&mut self.log0, &mut self.log1);
         self.log1.get_mut().set_len(0)?;
         self.log1.get_mut().sync_data()?;
+        self.unsynced = false;
+        self.synced_len = 0;
+        Ok(())
+    }
+
+    fn sync_mut_log(&mut self) -> Result<()> {
+        if self.unsynced {
+            self.log1.flush()?;
+            self.log1.get_mut().sync_data()?;
+            self.unsynced = false;
+            self.synced_len = self.log1.get_ref().metadata()?.len();
+        }
         Ok(())
     }
 }
 
+/// Directory of the write-ahead logs in the database directory.
+pub const LOG_DIR: &str = "log";
+/// Log of the immutable memory table in [LOG_DIR].
+pub const IMM_LOG_FILE: &str = "0.log";
+/// Log of the mutable memory table in [LOG_DIR].
+pub const MUT_LOG_FILE: &str = "1.log";
+
 fn log_path(db_path: &Path) -> PathBuf {
-    db_path.join("log")
+    db_path.join(LOG_DIR)
 }
 
 fn imm_log_file(dir: &Path) -> PathBuf {
-    dir.join("0.log")
+    dir.join(IMM_LOG_FILE)
 }
 
 fn mut_log_file(dir: &Path) -> PathBuf {
-    dir.join("1.log")
+    dir.join(MUT_LOG_FILE)
 }
@@ -0,0 +1,139 @@
+use crate::db::key_types::{MemKey, LSN};
+use crate::db::options::WriteOptions;
+use crate::db::Value;
+use crate::memory::MemTable;
+use crate::wal::{ReplayDigest, TransactionWAL, WALRecord, WAL};
+use crate::Result;
+use std::fs::File;
+
+/// Write-ahead log that discards all the records, for the workloads which prefer
+/// write throughput to durability.
+///
+/// The writes in memory tables are lost if the database is closed or crashes before
+/// they are written to sstables. Nothing is recovered when the database is opened.
+#[derive(Default)]
+pub struct NullWriteAheadLog;
+
+impl<SK: MemKey, UK: MemKey> WAL<SK, UK> for NullWriteAheadLog {
+    fn open_and_replay_logs(
+        _db_path: &str,
+        _mut_mem_table: &mut impl MemTable<SK, UK>,
+        _digest: Option<&mut ReplayDigest<SK>>,
+    ) -> Result<NullWriteAheadLog> {
+        Ok(NullWriteAheadLog)
+    }
+
+    fn load_log(_file: &File, _mem_table: &mut impl MemTable<SK, UK>) -> Result<()> {
+        Ok(())
+    }
+
+    fn append(
+        &mut self,
+        _write_options: &WriteOptions,
+        _key: &SK,
+        _value: Option<&Value>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn append_batch<'a>(
+        &mut self,
+        _write_options: &WriteOptions,
+        _kvs: impl Iterator<Item = (&'a SK, Option<&'a Value>)>,
+    ) -> Result<()>
+    where
+        SK: 'a,
+    {
+        Ok(())
+    }
+
+    fn clear_imm_log(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn freeze_mut_log(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn synced_len(&self) -> u64 {
+        0
+    }
+
+    fn bytes_written(&self) -> u64 {
+        0
+    }
+}
+
+impl<SK: MemKey, UK: MemKey> TransactionWAL<SK, UK> for NullWriteAheadLog {
+    fn append_record(
+        &mut self,
+        _write_options: &WriteOptions,
+        _record: WALRecord<'_, SK>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Always `None`, because no record is kept.
+    fn min_lsn(&self) -> Option<LSN> {
+        None
+    }
+
+    fn trim_before(&mut self, _lsn: LSN) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::key_types::InternalKey;
+    use crate::db::no_transaction_db::NoTransactionDB;
+    use crate::db::options::WriteOptions;
+    use crate::db::DB;
+    use crate::memory::MutexSkipMapMemTable;
+    use crate::wal::null_wal::NullWriteAheadLog;
+
+    #[test]
+    fn test_null_wal() {
+        type TestDB = NoTransactionDB<
+            InternalKey,
+            InternalKey,
+            MutexSkipMapMemTable<InternalKey>,
+            NullWriteAheadLog,
+        >;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("null_wal")
+            .tempdir()
+            .unwrap();
+        let wo = WriteOptions { sync: true };
+        {
+            let db = TestDB::open(temp_dir.path()).unwrap();
+            for i in 0..100 {
+                db.set(
+                    &wo,
+                    format!("key{}", i).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                )
+                .unwrap();
+            }
+            db.remove(&wo, Vec::from("key0")).unwrap();
+            assert!(db.get(&Vec::from("key0")).unwrap().is_none());
+            for i in 1..100 {
+                assert_eq!(
+                    db.get(&format!("key{}", i).into_bytes()).unwrap(),
+                    Some(format!("value{}", i).into_bytes())
+                );
+            }
+            assert_eq!(db.stats().wal_bytes_written, 0);
+        }
+
+        // the writes are not flushed, so nothing survives reopening
+        let db = TestDB::open(temp_dir.path()).unwrap();
+        for i in 0..100 {
+            assert!(db.get(&format!("key{}", i).into_bytes()).unwrap().is_none());
+        }
+    }
+}
@@ -1,45 +1,43 @@
 use crate::db::key_types::{InternalKey, MemKey};
 use crate::db::options::WriteOptions;
 use crate::db::Value;
+use crate::env::file_system::SequentialReadableFile;
 use crate::ioutils::{read_bytes_exact, read_u32, BufReaderWithPos};
 use crate::memory::MemTable;
-use crate::wal::{WALInner, WAL};
+use crate::wal::{ReplayDigest, WALInner, WAL};
 use crate::Result;
 use std::fs::File;
 use std::io::{Seek, SeekFrom, Write};
 
+/// Key length which marks the start of a write batch, followed by the count (u32) of its
+/// records. A key is never this long.
+const BATCH_MARKER: u32 = u32::MAX;
+
 pub struct SimpleWriteAheadLog {
     inner: WALInner,
 }
 
 impl<UK: MemKey> WAL<InternalKey, UK> for SimpleWriteAheadLog {
-    fn open_and_load_logs(
+    fn open_and_replay_logs(
         db_path: &str,
         mut_mem_table: &mut impl MemTable<InternalKey, UK>,
+        mut digest: Option<&mut ReplayDigest<InternalKey>>,
     ) -> Result<SimpleWriteAheadLog> {
         let wal = SimpleWriteAheadLog {
             inner: WALInner::open_logs(db_path)?,
         };
-        Self::load_log(wal.inner.log1.get_ref(), mut_mem_table).unwrap();
-        Self::load_log(wal.inner.log0.get_ref(), mut_mem_table).unwrap();
+        Self::replay_log(
+            wal.inner.log1.get_ref(),
+            mut_mem_table,
+            digest.as_deref_mut(),
+        )
+        .unwrap();
+        Self::replay_log(wal.inner.log0.get_ref(), mut_mem_table, digest).unwrap();
         Ok(wal)
     }
 
     fn load_log(file: &File, mem_table: &mut impl MemTable<InternalKey, UK>) -> Result<()> {
-        let mut reader = BufReaderWithPos::new(file)?;
-        reader.seek(SeekFrom::Start(0))?;
-        while let Ok(key_length) = read_u32(&mut reader) {
-            let value_length = read_u32(&mut reader)?;
-            let key = read_bytes_exact(&mut reader, key_length as u64)?;
-            if value_length > 0 {
-                let value = read_bytes_exact(&mut reader, value_length as u64)?;
-                mem_table.set(key, value)?;
-            } else {
-                mem_table.remove(key)?;
-            }
-        }
-        reader.seek(SeekFrom::End(0))?;
-        Ok(())
+        Self::replay_log(file, mem_table, None)
     }
 
     fn append(
@@ -48,6 +46,51 @@ impl<UK: MemKey> WAL<InternalKey, UK> for SimpleWriteAheadLog {
         key: &InternalKey,
         value: Option<&Value>,
     ) -> Result<()> {
+        let record_len = self.write_record(key, value)?;
+        self.finish_write(write_options, record_len)
+    }
+
+    fn append_batch<'a>(
+        &mut self,
+        write_options: &WriteOptions,
+        kvs: impl Iterator<Item = (&'a InternalKey, Option<&'a Value>)>,
+    ) -> Result<()> {
+        let kvs: Vec<_> = kvs.collect();
+        self.inner.log1.write_all(&BATCH_MARKER.to_le_bytes())?;
+        self.inner
+            .log1
+            .write_all(&(kvs.len() as u32).to_le_bytes())?;
+        let mut batch_len = 8;
+        for (key, value) in kvs {
+            batch_len += self.write_record(key, value)?;
+        }
+        self.finish_write(write_options, batch_len)
+    }
+
+    fn clear_imm_log(&mut self) -> Result<()> {
+        self.inner.clear_imm_log()
+    }
+
+    fn freeze_mut_log(&mut self) -> Result<()> {
+        self.inner.freeze_mut_log()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync_mut_log()
+    }
+
+    fn synced_len(&self) -> u64 {
+        self.inner.synced_len
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.inner.bytes_written
+    }
+}
+
+impl SimpleWriteAheadLog {
+    /// Write a record to the buffer of `log1`, and return its length.
+    fn write_record(&mut self, key: &InternalKey, value: Option<&Value>) -> Result<u64> {
         let key_length: [u8; 4] = (key.len() as u32).to_le_bytes();
         self.inner.log1.write_all(&key_length)?;
         match value {
@@ -62,31 +105,109 @@ impl<UK: MemKey> WAL<InternalKey, UK> for SimpleWriteAheadLog {
                 self.inner.log1.write_all(key)?;
             }
         }
+        Ok((8 + key.len() + value.map_or(0, |v| v.len())) as u64)
+    }
+
+    /// Flush the `len` bytes written to the buffer of `log1`, and sync them if
+    /// `write_options.sync` is true.
+    fn finish_write(&mut self, write_options: &WriteOptions, len: u64) -> Result<()> {
         self.inner.log1.flush()?;
+        self.inner.unsynced = true;
+        self.inner.bytes_written += len;
         if write_options.sync {
-            self.inner.log1.get_mut().sync_data()?;
+            self.inner.sync_mut_log()?;
         }
         Ok(())
     }
 
-    fn clear_imm_log(&mut self) -> Result<()> {
-        self.inner.clear_imm_log()
+    fn replay_log<UK: MemKey>(
+        file: &File,
+        mem_table: &mut impl MemTable<InternalKey, UK>,
+        mut digest: Option<&mut ReplayDigest<InternalKey>>,
+    ) -> Result<()> {
+        let mut reader = BufReaderWithPos::new(file)?;
+        reader.seek(SeekFrom::Start(0))?;
+        // length of the log up to the end of the last complete record or batch
+        let mut read_len = 0;
+        while let Ok(key_length) = read_u32(&mut reader) {
+            let records = if key_length == BATCH_MARKER {
+                // a batch is applied only if all its records are in the log
+                let batch = read_u32(&mut reader).and_then(|count| {
+                    (0..count)
+                        .map(|_| {
+                            let key_length = read_u32(&mut reader)?;
+                            Self::read_record(&mut reader, key_length)
+                        })
+                        .collect::<Result<Vec<_>>>()
+                });
+                match batch {
+                    Ok(records) => records,
+                    Err(_) => {
+                        warn!("discard the incomplete write batch at the end of log");
+                        break;
+                    }
+                }
+            } else {
+                vec![Self::read_record(&mut reader, key_length)?]
+            };
+            for (key, value) in records {
+                if let Some(digest) = digest.as_deref_mut() {
+                    digest.record(&key, value.as_ref());
+                }
+                match value {
+                    Some(value) => mem_table.set(key, value)?,
+                    None => mem_table.remove(key)?,
+                }
+            }
+            read_len = reader.position() as u64;
+        }
+
+        // drop the torn tail, or else the records appended later are never recovered
+        let file_len = file.metadata()?.len();
+        if read_len < file_len {
+            warn!("truncate log from {} bytes to {} bytes", file_len, read_len);
+            file.set_len(read_len)?;
+        }
+        reader.seek(SeekFrom::End(0))?;
+        Ok(())
     }
 
-    fn freeze_mut_log(&mut self) -> Result<()> {
-        self.inner.freeze_mut_log()
+    /// Read the rest of a record whose key length is `key_length`. The value is `None`
+    /// if the record is a tombstone.
+    fn read_record(
+        reader: &mut BufReaderWithPos<&File>,
+        key_length: u32,
+    ) -> Result<(InternalKey, Option<Value>)> {
+        let value_length = read_u32(reader)?;
+        let key = read_bytes_exact(reader, key_length as u64)?;
+        if value_length > 0 {
+            let value = read_bytes_exact(reader, value_length as u64)?;
+            Ok((key, Some(value)))
+        } else {
+            Ok((key, None))
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::db::key_types::InternalKey;
-    use crate::db::options::WriteOptions;
+    use crate::db::no_transaction_db::NoTransactionDB;
+    use crate::db::options::{Options, WriteOptions};
+    use crate::db::{DBCommand, DB};
+    use crate::error::KVLiteError;
     use crate::memory::{InternalKeyValueIterator, MutexSkipMapMemTable, SkipMapMemTable};
     use crate::wal::simple_wal::SimpleWriteAheadLog;
-    use crate::wal::WAL;
+    use crate::wal::{ReplayDigest, WAL};
     use tempfile::TempDir;
 
+    type TestDB = NoTransactionDB<
+        InternalKey,
+        InternalKey,
+        MutexSkipMapMemTable<InternalKey>,
+        SimpleWriteAheadLog,
+    >;
+
     #[test]
     fn test() {
         let temp_dir = TempDir::new().unwrap();
@@ -127,4 +248,161 @@ mod tests {
         wal = SimpleWriteAheadLog::open_and_load_logs(path, &mut mut_mem).unwrap();
         assert!(mut_mem.is_empty());
     }
+
+    /// Append 100 keys, overwrite the even ones and remove the ones divisible by 3.
+    fn write_known_log(path: &str) {
+        let mut mem = MutexSkipMapMemTable::<InternalKey>::default();
+        let mut wal = SimpleWriteAheadLog::open_and_load_logs(path, &mut mem).unwrap();
+        let wo = WriteOptions { sync: false };
+        let mut append = |key: String, value: Option<String>| {
+            <SimpleWriteAheadLog as WAL<InternalKey, InternalKey>>::append(
+                &mut wal,
+                &wo,
+                &key.into_bytes(),
+                value.map(String::into_bytes).as_ref(),
+            )
+            .unwrap();
+        };
+        for i in 0..100 {
+            append(format!("key{}", i), Some(format!("value{}", i)));
+        }
+        for i in (0..100).step_by(2) {
+            append(format!("key{}", i), Some(format!("new{}", i)));
+        }
+        for i in (0..100).step_by(3) {
+            append(format!("key{}", i), None);
+        }
+        <SimpleWriteAheadLog as WAL<InternalKey, InternalKey>>::sync(&mut wal).unwrap();
+    }
+
+    #[test]
+    fn test_verify_recovery() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        write_known_log(path);
+
+        let mut mem = MutexSkipMapMemTable::<InternalKey>::default();
+        let mut digest = ReplayDigest::default();
+        let wal: SimpleWriteAheadLog =
+            SimpleWriteAheadLog::open_and_replay_logs(path, &mut mem, Some(&mut digest)).unwrap();
+        assert_eq!(digest.len(), 100);
+        assert_eq!(mem.len(), 100);
+        digest.verify(&mem).unwrap();
+
+        // the checksum only depends on the final values, not on the replay order
+        let mut replayed = ReplayDigest::<InternalKey>::default();
+        for (key, value) in mem.kv_iter() {
+            replayed.record(key, if value.is_empty() { None } else { Some(value) });
+        }
+        assert_eq!(replayed.checksum(), digest.checksum());
+        drop(wal);
+
+        let db_path = temp_dir.path().join("db");
+        let wo = WriteOptions { sync: true };
+        let options = Options {
+            verify_recovery: true,
+            ..Options::default()
+        };
+        {
+            let db = TestDB::open_with_options(&db_path, options.clone()).unwrap();
+            for i in 0..10 {
+                db.set(&wo, format!("key{}", i).into_bytes(), b"value".to_vec())
+                    .unwrap();
+            }
+            db.remove(&wo, b"key0".to_vec()).unwrap();
+        }
+        let db = TestDB::open_with_options(&db_path, options).unwrap();
+        assert!(db.get(&b"key0".to_vec()).unwrap().is_none());
+        assert_eq!(db.get(&b"key9".to_vec()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_verify_corrupted_recovery() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        write_known_log(path);
+
+        let mut mem = MutexSkipMapMemTable::<InternalKey>::default();
+        let mut digest = ReplayDigest::default();
+        let _wal: SimpleWriteAheadLog =
+            SimpleWriteAheadLog::open_and_replay_logs(path, &mut mem, Some(&mut digest)).unwrap();
+        digest.verify(&mem).unwrap();
+
+        // a value applied wrongly
+        mem.set(b"key1".to_vec(), b"wrong".to_vec()).unwrap();
+        assert!(matches!(
+            digest.verify(&mem),
+            Err(KVLiteError::RecoveryMismatch(_))
+        ));
+        mem.set(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        digest.verify(&mem).unwrap();
+
+        // a tombstone lost
+        mem.set(b"key3".to_vec(), b"value3".to_vec()).unwrap();
+        assert!(matches!(
+            digest.verify(&mem),
+            Err(KVLiteError::RecoveryMismatch(_))
+        ));
+        mem.remove(b"key3".to_vec()).unwrap();
+        digest.verify(&mem).unwrap();
+
+        // a record replayed into the digest only
+        digest.record(&b"key100".to_vec(), Some(&b"value100".to_vec()));
+        assert!(matches!(
+            digest.verify(&mem),
+            Err(KVLiteError::RecoveryMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_recover_whole_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let log = temp_dir.path().join("log").join("1.log");
+
+        let batch = |wal: &mut SimpleWriteAheadLog, keys: &[&str]| {
+            let keys: Vec<InternalKey> = keys.iter().map(|k| Vec::from(*k)).collect();
+            let value = Vec::from("value");
+            <SimpleWriteAheadLog as WAL<InternalKey, InternalKey>>::append_batch(
+                wal,
+                &WriteOptions { sync: true },
+                keys.iter().map(|k| (k, Some(&value))),
+            )
+            .unwrap();
+        };
+
+        let mut mem = MutexSkipMapMemTable::<InternalKey>::default();
+        let mut wal = SimpleWriteAheadLog::open_and_load_logs(path, &mut mem).unwrap();
+        batch(&mut wal, &["k1", "k2"]);
+        mem = MutexSkipMapMemTable::default();
+        wal = SimpleWriteAheadLog::open_and_load_logs(path, &mut mem).unwrap();
+        assert_eq!(mem.len(), 2);
+
+        // crash in the middle of the batch
+        batch(&mut wal, &["k3", "k4", "k5"]);
+        drop(wal);
+        let file = std::fs::OpenOptions::new().write(true).open(&log).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 12).unwrap();
+        drop(file);
+        mem = MutexSkipMapMemTable::default();
+        let mut wal: SimpleWriteAheadLog =
+            SimpleWriteAheadLog::open_and_load_logs(path, &mut mem).unwrap();
+        assert_eq!(mem.len(), 2);
+
+        // the torn batch does not swallow the records appended after reopening
+        <SimpleWriteAheadLog as WAL<InternalKey, InternalKey>>::append(
+            &mut wal,
+            &WriteOptions { sync: true },
+            &Vec::from("k6"),
+            Some(&Vec::from("value")),
+        )
+        .unwrap();
+        drop(wal);
+        mem = MutexSkipMapMemTable::default();
+        let _wal: SimpleWriteAheadLog =
+            SimpleWriteAheadLog::open_and_load_logs(path, &mut mem).unwrap();
+        assert_eq!(mem.len(), 3);
+        assert!(mem.get(&Vec::from("k6")).unwrap().is_some());
+    }
 }